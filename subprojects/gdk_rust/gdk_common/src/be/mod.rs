@@ -16,6 +16,8 @@ use std::fmt::Debug;
 pub use transaction::*;
 
 pub type AssetId = [u8; 32]; // TODO use elements::issuance::AssetId
+/// key of the bitcoin balance where balances are keyed by `AssetId`
+pub const BITCOIN_ASSET_ID: AssetId = [0u8; 32];
 pub type Utxos = Vec<(BEOutPoint, UTXOInfo)>;
 
 #[derive(Debug)]
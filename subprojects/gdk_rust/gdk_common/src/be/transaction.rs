@@ -1,12 +1,16 @@
 use crate::be::*;
 use crate::error::Error;
 use crate::model::Balances;
+use crate::scripts::ScriptType;
 use crate::wally::asset_surjectionproof_size;
 use crate::{ElementsNetwork, NetworkId};
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
 use bitcoin::consensus::encode::deserialize as btc_des;
 use bitcoin::consensus::encode::serialize as btc_ser;
+use bitcoin::consensus::encode::VarInt;
 use bitcoin::hash_types::Txid;
-use bitcoin::Script;
+use bitcoin::{BlockHash, Script};
 use elements::confidential::{Asset, Value};
 use elements::encode::deserialize as elm_des;
 use elements::encode::serialize as elm_ser;
@@ -21,6 +25,73 @@ use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
 
 pub const DUST_VALUE: u64 = 546;
+/// bytes needed to spend a segwit v0 output (outpoint, empty scriptSig, sequence, discounted witness)
+const DUST_SEGWIT_INPUT_SIZE: u64 = 32 + 4 + 1 + (107 / 4) + 4;
+/// bytes needed to spend a legacy output (outpoint, scriptSig with signature and pubkey, sequence)
+const DUST_LEGACY_INPUT_SIZE: u64 = 32 + 4 + 1 + 107 + 4;
+/// dust relay fee rate in sat/kB of bitcoin core, outputs costing more than a third of their
+/// value to spend at this rate are dust
+const DUST_RELAY_FEE_RATE: u64 = 3000;
+/// confirmations needed before a coinbase output can be spent
+pub const COINBASE_MATURITY: u32 = 100;
+/// weight units of a p2sh-p2wpkh input witness: items count, a 72 bytes signature (with sighash
+/// flag, signatures are not low-R grinded so 72 bytes is common) and a 33 bytes compressed pubkey
+pub const P2SH_P2WPKH_WITNESS_WEIGHT: usize = 1 + 1 + 72 + 1 + 33;
+/// bytes of a p2sh-p2wpkh input scriptSig, the push of the 22 bytes redeem script
+pub const P2SH_P2WPKH_SCRIPT_SIG_LEN: usize = 23;
+/// bytes of a p2sh output script_pubkey
+const P2SH_SCRIPT_PUBKEY_LEN: usize = 23;
+/// bytes of a p2wpkh output script_pubkey, version and the push of the 20 bytes pubkey hash
+const P2WPKH_SCRIPT_PUBKEY_LEN: usize = 22;
+/// bytes of a p2pkh output script_pubkey
+const P2PKH_SCRIPT_PUBKEY_LEN: usize = 25;
+/// bytes of a p2pkh input scriptSig, the pushes of a 72 bytes signature and a compressed pubkey
+const P2PKH_SCRIPT_SIG_LEN: usize = 1 + 72 + 1 + 33;
+
+/// bytes of the scriptSig and weight units of the witness of an input spending an output of
+/// `script_type`, and bytes of the script_pubkey of such an output. p2wpkh inputs have the same
+/// witness of p2sh-p2wpkh ones with an empty scriptSig
+fn script_type_sizes(script_type: ScriptType) -> (usize, usize, usize) {
+    match script_type {
+        ScriptType::P2shP2wpkh => {
+            (P2SH_P2WPKH_SCRIPT_SIG_LEN, P2SH_P2WPKH_WITNESS_WEIGHT, P2SH_SCRIPT_PUBKEY_LEN)
+        }
+        ScriptType::P2wpkh => (0, P2SH_P2WPKH_WITNESS_WEIGHT, P2WPKH_SCRIPT_PUBKEY_LEN),
+        ScriptType::P2pkh => (P2PKH_SCRIPT_SIG_LEN, 0, P2PKH_SCRIPT_PUBKEY_LEN),
+    }
+}
+
+/// virtual bytes of an input spending an output of `script_type`: outpoint, sequence, scriptSig
+/// with its length and the witness
+pub fn input_vbytes(script_type: ScriptType) -> u64 {
+    let (script_sig_len, witness_weight, _) = script_type_sizes(script_type);
+    let base = 32 + 4 + 4 + VarInt(script_sig_len as u64).len() + script_sig_len;
+    ((base * 4 + witness_weight + 3) / 4) as u64
+}
+
+/// virtual bytes of an output paying `script_type`: value, script length and script_pubkey
+pub fn output_vbytes(script_type: ScriptType) -> u64 {
+    let (_, _, script_pubkey_len) = script_type_sizes(script_type);
+    (8 + VarInt(script_pubkey_len as u64).len() + script_pubkey_len) as u64
+}
+
+/// minimum value of an output paying `script` to be relayed, it follows bitcoin core `GetDustThreshold`
+/// while for elements the fixed `DUST_VALUE` applied to the policy asset is used
+pub fn dust_threshold(script: &Script, network: NetworkId) -> u64 {
+    match network {
+        NetworkId::Bitcoin(_) => {
+            let script_len = script.len() as u64;
+            let output_size = 8 + VarInt(script_len).len() as u64 + script_len;
+            let input_size = if script.is_v0_p2wpkh() || script.is_v0_p2wsh() {
+                DUST_SEGWIT_INPUT_SIZE
+            } else {
+                DUST_LEGACY_INPUT_SIZE
+            };
+            (output_size + input_size) * DUST_RELAY_FEE_RATE / 1000
+        }
+        NetworkId::Elements(_) => DUST_VALUE,
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
 pub enum BETransaction {
@@ -46,6 +117,34 @@ impl BETransaction {
         }
     }
 
+    pub fn version(&self) -> u32 {
+        match self {
+            Self::Bitcoin(tx) => tx.version as u32,
+            Self::Elements(tx) => tx.version,
+        }
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        match self {
+            Self::Bitcoin(tx) => tx.version = version as i32,
+            Self::Elements(tx) => tx.version = version,
+        }
+    }
+
+    pub fn lock_time(&self) -> u32 {
+        match self {
+            Self::Bitcoin(tx) => tx.lock_time,
+            Self::Elements(tx) => tx.lock_time,
+        }
+    }
+
+    pub fn set_lock_time(&mut self, lock_time: u32) {
+        match self {
+            Self::Bitcoin(tx) => tx.lock_time = lock_time,
+            Self::Elements(tx) => tx.lock_time = lock_time,
+        }
+    }
+
     pub fn from_hex(hex: &str, id: NetworkId) -> Result<Self, crate::error::Error> {
         Self::deserialize(&hex::decode(hex)?, id)
     }
@@ -110,6 +209,33 @@ impl BETransaction {
         }
     }
 
+    /// txids of the previous transactions of this tx that are not known, the values of the
+    /// outputs they create are needed to compute the fee
+    pub fn missing_prevouts(&self, all_txs: &BETransactions) -> HashSet<Txid> {
+        if self.is_coinbase() {
+            return HashSet::new();
+        }
+        self.previous_outputs()
+            .iter()
+            .filter(|o| {
+                !all_txs.txs.contains_key(&o.txid())
+                    && !all_txs.spent_outputs.contains_key(&(o.txid(), o.vout()))
+            })
+            .map(|o| o.txid())
+            .collect()
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        match self {
+            Self::Bitcoin(tx) => tx.is_coin_base(),
+            Self::Elements(tx) => {
+                tx.input.len() == 1
+                    && tx.input[0].previous_output.txid == Txid::default()
+                    && tx.input[0].previous_output.vout == u32::max_value()
+            }
+        }
+    }
+
     pub fn input_len(&self) -> usize {
         match self {
             Self::Bitcoin(tx) => tx.input.len(),
@@ -178,7 +304,36 @@ impl BETransaction {
                     txid: tx.txid(),
                     vout,
                 };
-                Some(all_unblinded.get(&outpoint).unwrap().asset_hex())
+                all_unblinded.get(&outpoint).map(|u| u.asset_hex())
+            }
+        }
+    }
+
+    /// value and asset hex of the output, always known for bitcoin outputs (which have no asset)
+    /// and explicit liquid ones, for confidential outputs only if they are in `all_unblinded`
+    pub fn output_amount(
+        &self,
+        vout: u32,
+        all_unblinded: &HashMap<elements::OutPoint, Unblinded>,
+    ) -> Option<(u64, Option<String>)> {
+        match self {
+            Self::Bitcoin(tx) => Some((tx.output[vout as usize].value, None)),
+            Self::Elements(tx) => {
+                let output = &tx.output[vout as usize];
+                match (output.value, output.asset) {
+                    (Value::Explicit(value), Asset::Explicit(_)) => {
+                        // skip the prefix byte of the explicit asset
+                        let asset = elm_ser(&output.asset);
+                        Some((value, Some(asset_to_hex(&asset[1..]))))
+                    }
+                    _ => {
+                        let outpoint = elements::OutPoint {
+                            txid: tx.txid(),
+                            vout,
+                        };
+                        all_unblinded.get(&outpoint).map(|u| (u.value, Some(u.asset_hex())))
+                    }
+                }
             }
         }
     }
@@ -229,6 +384,66 @@ impl BETransaction {
         Ok(())
     }
 
+    /// add a peg-out output, sending `value` of `asset_hex` to `script` on the parent chain
+    /// identified by `genesis_hash`. The output is left unblinded as required by the peg-out rules
+    pub fn add_pegout_output(
+        &mut self,
+        value: u64,
+        asset_hex: &str,
+        genesis_hash: BlockHash,
+        script: &Script,
+    ) -> Result<(), Error> {
+        match self {
+            BETransaction::Bitcoin(_) => Err(Error::Generic("peg-out requires elements".into())),
+            BETransaction::Elements(tx) => {
+                let asset = asset_to_bin(asset_hex)?;
+                let asset_id = issuance::AssetId::from_slice(&asset)?;
+                let script_pubkey = Builder::new()
+                    .push_opcode(opcodes::all::OP_RETURN)
+                    .push_slice(&genesis_hash[..])
+                    .push_slice(script.as_bytes())
+                    .into_script();
+                let new_out = elements::TxOut {
+                    asset: confidential::Asset::Explicit(asset_id),
+                    value: confidential::Value::Explicit(value),
+                    nonce: confidential::Nonce::Null,
+                    script_pubkey,
+                    witness: TxOutWitness::default(),
+                };
+                tx.output.push(new_out);
+                Ok(())
+            }
+        }
+    }
+
+    /// add an output of zero `asset_hex` to the unspendable `script`, to be blinded with
+    /// `blinding_pubkey`. A tx needs at least a blinded output to be blinded
+    pub fn add_zero_value_output(
+        &mut self,
+        asset_hex: &str,
+        script: &Script,
+        blinding_pubkey: &bitcoin::secp256k1::PublicKey,
+    ) -> Result<(), Error> {
+        match self {
+            BETransaction::Bitcoin(_) => Err(Error::Generic("blinding requires elements".into())),
+            BETransaction::Elements(tx) => {
+                let asset = asset_to_bin(asset_hex)?;
+                let asset_id = issuance::AssetId::from_slice(&asset)?;
+                let bytes = blinding_pubkey.serialize();
+                let byte32: [u8; 32] = bytes[1..].as_ref().try_into().unwrap();
+                let new_out = elements::TxOut {
+                    asset: confidential::Asset::Explicit(asset_id),
+                    value: confidential::Value::Explicit(0),
+                    nonce: confidential::Nonce::Confidential(bytes[0], byte32),
+                    script_pubkey: script.clone(),
+                    witness: TxOutWitness::default(),
+                };
+                tx.output.push(new_out);
+                Ok(())
+            }
+        }
+    }
+
     pub fn scramble(&mut self) {
         let mut rng = thread_rng();
         match self {
@@ -245,22 +460,51 @@ impl BETransaction {
 
     /// estimates the fee of the final transaction given the `fee_rate`
     /// called when the tx is being built and miss things like signatures and changes outputs.
-    pub fn estimated_fee(&self, fee_rate: f64, more_changes: u8) -> u64 {
+    /// In bitcoin inputs are sized from the script of their previous output in `all_txs`, of
+    /// the wallet `script_type` when unknown, and changes are of the wallet `script_type`.
+    /// In elements inputs and changes are always p2sh-p2wpkh
+    pub fn estimated_fee(
+        &self,
+        fee_rate: f64,
+        more_changes: u8,
+        all_txs: &BETransactions,
+        script_type: ScriptType,
+    ) -> u64 {
         let dummy_tx = self.clone();
         match dummy_tx {
             BETransaction::Bitcoin(mut tx) => {
+                let (_, _, script_pubkey_len) = script_type_sizes(script_type);
+                let mut witness_weight = 0;
+                let mut legacy_inputs = 0;
                 for input in tx.input.iter_mut() {
-                    input.witness = vec![vec![0u8; 72], vec![0u8; 33]]; // considering signature sizes (72) and compressed public key (33)
-                    input.script_sig = vec![0u8; 23].into(); // p2shwpkh redeem script size
+                    let input_type = all_txs
+                        .get_previous_output_script_pubkey(&BEOutPoint::Bitcoin(
+                            input.previous_output,
+                        ))
+                        .and_then(|script| ScriptType::from_script_pubkey(&script))
+                        .unwrap_or(script_type);
+                    let (script_sig_len, input_witness_weight, _) = script_type_sizes(input_type);
+                    input.witness = vec![];
+                    input.script_sig = vec![0u8; script_sig_len].into();
+                    witness_weight += input_witness_weight;
+                    if input_witness_weight == 0 {
+                        legacy_inputs += 1;
+                    }
                 }
                 for _ in 0..more_changes {
                     tx.output.push(bitcoin::TxOut {
                         value: 0,
-                        script_pubkey: vec![0u8; 21].into(), //  p2shwpkh output is 1 + hash(20)
+                        script_pubkey: vec![0u8; script_pubkey_len].into(),
                     })
                 }
-                let vbytes = tx.get_weight() as f64 / 4.0;
-                let fee_val = (vbytes * fee_rate * 1.02) as u64; // increasing estimated fee by 2% to stay over relay fee TODO improve fee estimation and lower this
+                // without witnesses get_weight is 4 times the base size, witnesses are added with
+                // the segwit marker and flag (2 WU) and the empty witness of legacy inputs (1 WU)
+                let mut weight = tx.get_weight();
+                if witness_weight > 0 {
+                    weight += 2 + witness_weight + legacy_inputs;
+                }
+                let vbytes = (weight + 3) / 4;
+                let fee_val = (vbytes as f64 * fee_rate).ceil() as u64;
                 info!(
                     "DUMMYTX inputs:{} outputs:{} num_changes:{} vbytes:{} fee_val:{}",
                     tx.input.len(),
@@ -348,13 +592,18 @@ impl BETransaction {
         policy_asset: Option<String>,
         all_txs: &BETransactions,
         unblinded: &HashMap<elements::OutPoint, Unblinded>,
+        script_type: ScriptType,
     ) -> Vec<AssetValue> {
         match self {
             Self::Bitcoin(tx) => {
                 let sum_inputs = sum_inputs(tx, all_txs);
                 let sum_outputs: u64 = tx.output.iter().map(|o| o.value).sum();
-                let estimated_fee = self
-                    .estimated_fee(fee_rate, self.estimated_changes(no_change, all_txs, unblinded)); // send all does not create change
+                let estimated_fee = self.estimated_fee(
+                    fee_rate,
+                    self.estimated_changes(no_change, all_txs, unblinded),
+                    all_txs,
+                    script_type,
+                ); // send all does not create change
                 if sum_outputs + estimated_fee > sum_inputs {
                     vec![AssetValue::new_bitcoin(sum_outputs + estimated_fee - sum_inputs)]
                 } else {
@@ -389,8 +638,12 @@ impl BETransaction {
                     *inputs.entry(asset_hex).or_insert(0) += value;
                 }
 
-                let estimated_fee = self
-                    .estimated_fee(fee_rate, self.estimated_changes(no_change, all_txs, unblinded));
+                let estimated_fee = self.estimated_fee(
+                    fee_rate,
+                    self.estimated_changes(no_change, all_txs, unblinded),
+                    all_txs,
+                    script_type,
+                );
                 *outputs.entry(policy_asset.clone()).or_insert(0) += estimated_fee;
 
                 let mut result = vec![];
@@ -413,11 +666,13 @@ impl BETransaction {
         }
     }
 
-    /// return a Vector with changes of this transaction
+    /// return a Vector with changes of this transaction, policy asset change below `change_dust` goes
+    /// to the fee
     /// requires inputs are greater than outputs for earch asset
     pub fn changes(
         &self,
         estimated_fee: u64,
+        change_dust: u64,
         policy_asset: Option<String>,
         all_txs: &BETransactions,
         unblinded: &HashMap<elements::OutPoint, Unblinded>,
@@ -427,7 +682,7 @@ impl BETransaction {
                 let sum_inputs = sum_inputs(tx, all_txs);
                 let sum_outputs: u64 = tx.output.iter().map(|o| o.value).sum();
                 let change_value = sum_inputs - sum_outputs - estimated_fee;
-                if change_value > DUST_VALUE {
+                if change_value >= change_dust {
                     vec![AssetValue::new_bitcoin(change_value)]
                 } else {
                     vec![]
@@ -465,7 +720,7 @@ impl BETransaction {
                         // from a purely privacy perspective could make sense to always create the change output in liquid, so min change = 0
                         // however elements core use the dust anyway for 2 reasons: rebasing from core and economical considerations
                         sum -= estimated_fee;
-                        if sum > DUST_VALUE {
+                        if sum >= change_dust {
                             // we apply dust rules for liquid bitcoin as elements do
                             result.push(AssetValue::new(asset.to_string(), sum));
                         }
@@ -538,9 +793,12 @@ impl BETransaction {
     ) -> Result<u64, Error> {
         Ok(match self {
             Self::Bitcoin(tx) => {
+                if tx.is_coin_base() {
+                    return Ok(0);
+                }
                 let sum_inputs = sum_inputs(tx, all_txs);
                 let sum_outputs: u64 = tx.output.iter().map(|o| o.value).sum();
-                sum_inputs - sum_outputs
+                checked_fee(sum_inputs, sum_outputs)?
             }
             Self::Elements(tx) => {
                 let has_fee = tx.output.iter().any(|o| o.is_fee());
@@ -565,12 +823,36 @@ impl BETransaction {
                         .filter_map(|o| all_txs.get_previous_output_value(&o, all_unblinded))
                         .sum();
 
-                    sum_inputs - sum_outputs
+                    checked_fee(sum_inputs, sum_outputs)?
                 }
             }
         })
     }
 
+    /// true if any input has a sequence number enforcing a relative locktime (BIP68),
+    /// which is meaningful only for transactions with version 2 or greater
+    pub fn has_relative_locktime(&self) -> bool {
+        let enforced = |sequence: u32| sequence & (1 << 31) == 0;
+        match self {
+            Self::Bitcoin(tx) => tx.input.iter().any(|e| enforced(e.sequence)),
+            Self::Elements(tx) => tx.input.iter().any(|e| enforced(e.sequence)),
+        }
+    }
+
+    /// set the sequence of every input so that the tx signals replaceability (BIP125) if `rbf`
+    /// or doesn't otherwise, in both cases nSequence is disabled and nLocktime is enabled
+    pub fn set_rbf(&mut self, rbf: bool) {
+        let sequence = if rbf {
+            0xffff_fffd
+        } else {
+            0xffff_fffe
+        };
+        match self {
+            Self::Bitcoin(tx) => tx.input.iter_mut().for_each(|i| i.sequence = sequence),
+            Self::Elements(tx) => tx.input.iter_mut().for_each(|i| i.sequence = sequence),
+        }
+    }
+
     pub fn rbf_optin(&self) -> bool {
         match self {
             Self::Bitcoin(tx) => tx.input.iter().any(|e| e.sequence < 0xffff_fffe),
@@ -647,6 +929,23 @@ impl BETransaction {
                 result.insert("btc".to_string(), my_in - my_out);
                 result
             }
+            Self::Elements(_) => self
+                .my_asset_deltas(all_unblinded)
+                .into_iter()
+                .map(|(asset, v)| (asset_to_hex(&asset), v))
+                .collect(),
+        }
+    }
+
+    /// net change of every asset owned by the wallet caused by this transaction, computed from
+    /// the unblinded owned inputs and outputs. Redeposited assets are omitted, in bitcoin it's
+    /// always empty since there are no assets, see `my_balance_changes`
+    pub fn my_asset_deltas(
+        &self,
+        all_unblinded: &HashMap<elements::OutPoint, Unblinded>,
+    ) -> HashMap<AssetId, i64> {
+        match self {
+            Self::Bitcoin(_) => HashMap::new(),
             Self::Elements(tx) => {
                 trace!(
                     "tx_id: {} my_balances elements all_unblinded.len(): {:?}",
@@ -663,8 +962,7 @@ impl BETransaction {
                             outpoint,
                             unblinded.value
                         );
-                        let asset_id_str = unblinded.asset_hex();
-                        *result.entry(asset_id_str).or_default() -= unblinded.value as i64;
+                        *result.entry(unblinded.asset).or_default() -= unblinded.value as i64;
                         // TODO check overflow
                     }
                 }
@@ -680,19 +978,29 @@ impl BETransaction {
                             outpoint,
                             unblinded.value
                         );
-                        let asset_id_str = unblinded.asset_hex();
-                        *result.entry(asset_id_str).or_default() += unblinded.value as i64;
+                        *result.entry(unblinded.asset).or_default() += unblinded.value as i64;
                         // TODO check overflow
                     }
                 }
 
                 // we don't want to see redeposited assets
-                return result.into_iter().filter(|&(_, v)| v != 0).collect();
+                result.into_iter().filter(|&(_, v)| v != 0).collect()
             }
         }
     }
 }
 
+/// inputs values lower than outputs values means that some previous outputs are unknown,
+/// see `BETransaction::missing_prevouts`
+fn checked_fee(sum_inputs: u64, sum_outputs: u64) -> Result<u64, Error> {
+    sum_inputs.checked_sub(sum_outputs).ok_or_else(|| {
+        Error::Generic(format!(
+            "inconsistent fee, inputs {} are less than outputs {}",
+            sum_inputs, sum_outputs
+        ))
+    })
+}
+
 fn sum_inputs(tx: &bitcoin::Transaction, all_txs: &BETransactions) -> u64 {
     tx.input
         .iter()
@@ -701,30 +1009,103 @@ fn sum_inputs(tx: &bitcoin::Transaction, all_txs: &BETransactions) -> u64 {
         .sum()
 }
 
+/// script and value of an output spent by a wallet transaction, `value` is None for liquid
+/// outputs that couldn't be unblinded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpentOutput {
+    pub script: Script,
+    pub value: Option<u64>,
+}
+
 #[derive(Default, Serialize, Deserialize)]
-pub struct BETransactions(HashMap<Txid, BETransaction>);
+pub struct BETransactions {
+    txs: HashMap<Txid, BETransaction>,
+
+    /// outputs spent by wallet txs, so that they are known even if the previous txs are pruned
+    #[serde(default)]
+    spent_outputs: HashMap<(Txid, u32), SpentOutput>,
+}
 
 impl Deref for BETransactions {
     type Target = HashMap<Txid, BETransaction>;
     fn deref(&self) -> &<Self as Deref>::Target {
-        &self.0
+        &self.txs
     }
 }
 impl DerefMut for BETransactions {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.txs
     }
 }
 impl BETransactions {
     pub fn get_previous_output_script_pubkey(&self, outpoint: &BEOutPoint) -> Option<Script> {
-        self.0.get(&outpoint.txid()).map(|tx| tx.output_script(outpoint.vout()))
+        self.get_previous_output(outpoint, &HashMap::new()).map(|spent| spent.script)
     }
     pub fn get_previous_output_value(
         &self,
         outpoint: &BEOutPoint,
         all_unblinded: &HashMap<elements::OutPoint, Unblinded>,
     ) -> Option<u64> {
-        self.0.get(&outpoint.txid()).map(|tx| tx.output_value(outpoint.vout(), &all_unblinded))
+        self.get_previous_output(outpoint, all_unblinded).and_then(|spent| spent.value)
+    }
+
+    /// script and value of the output spent by `outpoint`, if known. None also when the
+    /// previous tx is known but has no output `vout`
+    pub fn get_previous_output(
+        &self,
+        outpoint: &BEOutPoint,
+        all_unblinded: &HashMap<elements::OutPoint, Unblinded>,
+    ) -> Option<SpentOutput> {
+        let (txid, vout) = (outpoint.txid(), outpoint.vout());
+        match self.txs.get(&txid) {
+            Some(tx) if (vout as usize) < tx.output_len() => {
+                let value = match outpoint {
+                    BEOutPoint::Bitcoin(_) => Some(tx.output_value(vout, all_unblinded)),
+                    BEOutPoint::Elements(outpoint) => all_unblinded.get(outpoint).map(|u| u.value),
+                };
+                Some(SpentOutput {
+                    script: tx.output_script(vout),
+                    value,
+                })
+            }
+            Some(_) => None,
+            None => self.spent_outputs.get(&(txid, vout)).cloned(),
+        }
+    }
+
+    /// remember script and value of the outputs spent by `txid` whose previous tx is known
+    pub fn insert_spent_outputs(
+        &mut self,
+        txid: &Txid,
+        all_unblinded: &HashMap<elements::OutPoint, Unblinded>,
+    ) {
+        let previous_outputs = match self.txs.get(txid) {
+            Some(tx) => tx.previous_outputs(),
+            None => return,
+        };
+        for outpoint in previous_outputs {
+            if !self.txs.contains_key(&outpoint.txid()) {
+                continue;
+            }
+            if let Some(spent) = self.get_previous_output(&outpoint, all_unblinded) {
+                self.spent_outputs.insert((outpoint.txid(), outpoint.vout()), spent);
+            }
+        }
+    }
+
+    /// remove every tx not in `keep`, after remembering the outputs spent by the kept ones.
+    /// Returns the number of removed txs
+    pub fn prune(
+        &mut self,
+        keep: &HashSet<Txid>,
+        all_unblinded: &HashMap<elements::OutPoint, Unblinded>,
+    ) -> usize {
+        for txid in keep {
+            self.insert_spent_outputs(txid, all_unblinded);
+        }
+        let before = self.txs.len();
+        self.txs.retain(|txid, _| keep.contains(txid));
+        before - self.txs.len()
     }
 
     pub fn get_previous_output_asset_hex(
@@ -732,9 +1113,10 @@ impl BETransactions {
         outpoint: elements::OutPoint,
         all_unblinded: &HashMap<elements::OutPoint, Unblinded>,
     ) -> Option<String> {
-        self.0
+        self.txs
             .get(&outpoint.txid)
-            .map(|tx| tx.output_asset_hex(outpoint.vout, &all_unblinded).unwrap())
+            .filter(|tx| (outpoint.vout as usize) < tx.output_len())
+            .and_then(|tx| tx.output_asset_hex(outpoint.vout, &all_unblinded))
     }
 }
 
@@ -760,3 +1142,199 @@ impl AssetValue {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_estimated_fee_p2sh_p2wpkh() {
+        let input = bitcoin::TxIn {
+            previous_output: Default::default(),
+            script_sig: Script::new(),
+            sequence: 0xffff_fffe,
+            witness: vec![],
+        };
+        let output = bitcoin::TxOut {
+            value: 10_000,
+            script_pubkey: vec![0u8; P2SH_SCRIPT_PUBKEY_LEN].into(),
+        };
+        let unsigned = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![input.clone(), input],
+            output: vec![output],
+        };
+        let fee_rate = 1.5;
+        let estimated = BETransaction::Bitcoin(unsigned.clone()).estimated_fee(
+            fee_rate,
+            1,
+            &BETransactions::default(),
+            ScriptType::P2shP2wpkh,
+        );
+
+        let mut signed = unsigned;
+        for input in signed.input.iter_mut() {
+            input.script_sig = vec![0u8; P2SH_P2WPKH_SCRIPT_SIG_LEN].into();
+            input.witness = vec![vec![0u8; 72], vec![0u8; 33]];
+        }
+        signed.output.push(signed.output[0].clone());
+        let vsize = (signed.get_weight() + 3) / 4;
+        let expected = (vsize as f64 * fee_rate).ceil() as u64;
+        assert!((estimated as i64 - expected as i64).abs() <= 1);
+    }
+
+    #[test]
+    fn test_estimated_fee_p2wpkh() {
+        let input = bitcoin::TxIn {
+            previous_output: Default::default(),
+            script_sig: Script::new(),
+            sequence: 0xffff_fffe,
+            witness: vec![],
+        };
+        let output = bitcoin::TxOut {
+            value: 10_000,
+            script_pubkey: vec![0u8; P2WPKH_SCRIPT_PUBKEY_LEN].into(),
+        };
+        let unsigned = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![input.clone(), input],
+            output: vec![output],
+        };
+        let fee_rate = 1.5;
+        let unsigned = BETransaction::Bitcoin(unsigned);
+        let no_txs = BETransactions::default();
+        let estimated = unsigned.estimated_fee(fee_rate, 1, &no_txs, ScriptType::P2wpkh);
+        assert!(estimated < unsigned.estimated_fee(fee_rate, 1, &no_txs, ScriptType::P2shP2wpkh));
+
+        // native segwit inputs have an empty scriptSig, all the signature data is in the witness
+        let mut signed = match unsigned {
+            BETransaction::Bitcoin(tx) => tx,
+            _ => unreachable!(),
+        };
+        for input in signed.input.iter_mut() {
+            input.witness = vec![vec![0u8; 72], vec![0u8; 33]];
+        }
+        signed.output.push(signed.output[0].clone());
+        let vsize = (signed.get_weight() + 3) / 4;
+        let expected = (vsize as f64 * fee_rate).ceil() as u64;
+        assert!((estimated as i64 - expected as i64).abs() <= 1);
+    }
+
+    #[test]
+    fn test_estimated_fee_prevout_script_type() {
+        // a p2sh-p2wpkh wallet spending a p2pkh output of an address of another script type
+        let p2pkh = Script::new_p2pkh(&Default::default());
+        let p2sh = Script::new_p2sh(&Default::default());
+        let prev = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![
+                bitcoin::TxOut {
+                    value: 10_000,
+                    script_pubkey: p2pkh,
+                },
+                bitcoin::TxOut {
+                    value: 10_000,
+                    script_pubkey: p2sh.clone(),
+                },
+            ],
+        };
+        let mut all_txs = BETransactions::default();
+        all_txs.insert(prev.txid(), BETransaction::Bitcoin(prev.clone()));
+        let input = |vout| bitcoin::TxIn {
+            previous_output: bitcoin::OutPoint::new(prev.txid(), vout),
+            script_sig: Script::new(),
+            sequence: 0xffff_fffe,
+            witness: vec![],
+        };
+        let unsigned = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![input(0), input(1)],
+            output: vec![bitcoin::TxOut {
+                value: 15_000,
+                script_pubkey: p2sh,
+            }],
+        };
+        let fee_rate = 1.0;
+        let estimated = BETransaction::Bitcoin(unsigned.clone()).estimated_fee(
+            fee_rate,
+            0,
+            &all_txs,
+            ScriptType::P2shP2wpkh,
+        );
+
+        let mut signed = unsigned;
+        signed.input[0].script_sig = vec![0u8; P2PKH_SCRIPT_SIG_LEN].into();
+        signed.input[1].script_sig = vec![0u8; P2SH_P2WPKH_SCRIPT_SIG_LEN].into();
+        signed.input[1].witness = vec![vec![0u8; 72], vec![0u8; 33]];
+        let vsize = (signed.get_weight() + 3) / 4;
+        assert_eq!(estimated, (vsize as f64 * fee_rate).ceil() as u64);
+    }
+
+    #[test]
+    fn test_input_output_vbytes() {
+        assert_eq!(input_vbytes(ScriptType::P2shP2wpkh), 91);
+        assert_eq!(input_vbytes(ScriptType::P2wpkh), 68);
+        assert_eq!(input_vbytes(ScriptType::P2pkh), 148);
+        assert_eq!(output_vbytes(ScriptType::P2shP2wpkh), 32);
+        assert_eq!(output_vbytes(ScriptType::P2wpkh), 31);
+        assert_eq!(output_vbytes(ScriptType::P2pkh), 34);
+    }
+
+    #[test]
+    fn test_dust_threshold() {
+        let network = NetworkId::Bitcoin(bitcoin::Network::Regtest);
+        let p2pkh = Script::new_p2pkh(&Default::default());
+        let p2sh = Script::new_p2sh(&Default::default());
+        let p2wpkh = Script::new_v0_wpkh(&Default::default());
+        assert_eq!(dust_threshold(&p2pkh, network), 546);
+        assert_eq!(dust_threshold(&p2sh, network), 540);
+        assert_eq!(dust_threshold(&p2wpkh, network), 294);
+
+        let elements = NetworkId::Elements(ElementsNetwork::ElementsRegtest);
+        assert_eq!(dust_threshold(&p2wpkh, elements), DUST_VALUE);
+    }
+
+    #[test]
+    fn test_changes_at_dust_threshold() {
+        let prev = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![bitcoin::TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let mut all_txs = BETransactions::default();
+        all_txs.insert(prev.txid(), BETransaction::Bitcoin(prev.clone()));
+        let tx = BETransaction::Bitcoin(bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                previous_output: bitcoin::OutPoint::new(prev.txid(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffe,
+                witness: vec![],
+            }],
+            output: vec![bitcoin::TxOut {
+                value: 9_000,
+                script_pubkey: Script::new(),
+            }],
+        });
+        let p2sh = Script::new_p2sh(&Default::default());
+        let change_dust = dust_threshold(&p2sh, NetworkId::Bitcoin(bitcoin::Network::Regtest));
+        let unblinded = HashMap::new();
+
+        // change exactly at the threshold is kept, one satoshi less goes to the fee
+        let changes = tx.changes(1_000 - change_dust, change_dust, None, &all_txs, &unblinded);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].satoshi, change_dust);
+        let changes = tx.changes(1_001 - change_dust, change_dust, None, &all_txs, &unblinded);
+        assert!(changes.is_empty());
+    }
+}
@@ -24,6 +24,18 @@ impl GDKRUST_json {
 
 pub type Balances = HashMap<String, i64>;
 
+/// the balance of the spendable outputs split by their confirmation status, every field keyed
+/// like `Balances`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BalanceDetail {
+    /// outputs in a block, spendable
+    pub confirmed: Balances,
+    /// outputs of txs still in the mempool
+    pub unconfirmed: Balances,
+    /// coinbase outputs with less than `COINBASE_MATURITY` confirmations, not yet spendable
+    pub immature: Balances,
+}
+
 // =========== v exchange rate stuff v ===========
 
 // TODO use these types from bitcoin-exchange-rates lib once it's in there
@@ -86,6 +98,8 @@ impl ExchangeRateOk {
 pub struct AddressAmount {
     pub address: String, // could be bitcoin or elements
     pub satoshi: u64,
+    /// hex of the asset id sent, required in liquid and ignored in bitcoin
+    #[serde(alias = "asset_id")]
     pub asset_tag: Option<String>,
 }
 
@@ -115,6 +129,31 @@ pub struct CreateTransaction {
     #[serde(default)]
     pub previous_transaction: HashMap<String, Value>,
     pub memo: Option<String>,
+    pub version: Option<u32>, // defaults to 2
+    /// if set, warn with `TransactionMeta::leaves_dust` when the policy asset left in the
+    /// wallet after the transaction would be above zero but below this amount of satoshi
+    #[serde(default)]
+    pub dust_remainder: Option<u64>,
+    /// if true every input signals replaceability (BIP125), if false none does. When not set
+    /// bitcoin transactions signal it and liquid transactions don't
+    #[serde(default)]
+    pub rbf: Option<bool>,
+    /// if set, the `txid:vout` of the only utxo spent by the transaction, what's left after the
+    /// recipients and the fee goes to change
+    #[serde(default)]
+    pub utxo: Option<String>,
+    /// if set, the `txid:vout` of the utxos spent by the transaction, all of them and no others
+    #[serde(default)]
+    pub utxos: Option<Vec<String>>,
+    /// nLockTime of the transaction, a block height or, from 500000000, a unix time. Defaults to
+    /// the tip height known to the wallet, so that the tx can't be mined in a block replacing
+    /// the tip (anti fee sniping). Inputs never have the final sequence, thus it's always enforced
+    #[serde(default)]
+    pub locktime: Option<u32>,
+    /// if true only confirmed and mature outputs are spent, the ones counted by
+    /// `BalanceDetail::confirmed`
+    #[serde(default)]
+    pub confirmed_only: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -123,6 +162,9 @@ pub struct GetTransactionsOpt {
     pub count: usize,
     pub subaccount: usize,
     pub num_confs: Option<usize>,
+    /// download the previous txs missing in the store so that the fee of every listed tx is known
+    #[serde(default)]
+    pub repair_fees: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -134,20 +176,38 @@ pub struct SPVVerifyTx {
     pub encryption_key: String,
     pub tor_proxy: Option<String>,
     pub headers_to_download: Option<usize>, // defaults to 2016, useful to set for testing
+    /// keep only the headers at heights multiple of this value plus the most recent ones, it must
+    /// divide 2016. The chain is not pruned if missing
+    pub headers_checkpoint_interval: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum SPVVerifyResult {
     InProgress,
     Verified,
-    NotVerified,
+    NotVerified(SPVFailure),
     Disabled,
+    Unconfirmed,
+}
+
+/// why a tx proof could not be verified
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum SPVFailure {
+    /// the merkle root computed from the proof differs from the one in the block header
+    MerkleRootMismatch,
+    /// the block header at the tx height is missing or invalid
+    HeaderNotAvailable,
+    /// the server has no proof of the tx in the block at the given height
+    NotInBlock,
+    /// the block header at the tx height doesn't satisfy the signing challenge of the chain
+    InvalidHeaderSignature,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TransactionMeta {
     #[serde(flatten)]
     pub create_transaction: Option<CreateTransaction>,
+    /// the consensus serialization of the tx in hex, see `transaction_hex`
     #[serde(rename = "transaction")]
     pub hex: String,
     pub txid: String,
@@ -158,7 +218,9 @@ pub struct TransactionMeta {
     pub addressees_have_assets: bool,
     pub is_sweep: bool,
     pub satoshi: Balances,
-    pub fee: u64,
+    /// None when the value of some input is unknown, like in received txs whose previous txs
+    /// haven't been downloaded
+    pub fee: Option<u64>,
     pub network: Option<Network>,
     #[serde(rename = "type")]
     pub type_: String, // incoming or outgoing
@@ -166,6 +228,150 @@ pub struct TransactionMeta {
     pub rbf_optin: bool,
     pub user_signed: bool,
     pub spv_verified: SPVVerifyResult,
+    #[serde(default)]
+    pub outputs: Vec<OutputInfo>,
+    #[serde(default)]
+    pub signed_inputs: Vec<SignedInput>,
+    /// the sighash type `sign` uses for every input, by input index. Inputs past the end of the
+    /// list are signed with SIGHASH_ALL
+    #[serde(default)]
+    pub sighash_types: Vec<u32>,
+    /// see `CreateTransaction::dust_remainder`
+    #[serde(default)]
+    pub leaves_dust: bool,
+    /// in liquid, the net change of every asset, serialized in `satoshi` with hex keys
+    #[serde(skip)]
+    pub asset_deltas: HashMap<AssetId, i64>,
+    /// true when the tx has at least `Settings::final_confirmations` confirmations
+    #[serde(default)]
+    pub is_final: bool,
+    /// blocks from the tx block to the tip known to the wallet included, None while in mempool,
+    /// also after a reorg brought back the tx in mempool
+    #[serde(default)]
+    pub confirmations: Option<u32>,
+    /// the user memo of the tx, serialized in the flattened `create_transaction`
+    #[serde(skip)]
+    pub memo: Option<String>,
+    /// non-fatal conditions of a created tx the user should confirm before sending it
+    #[serde(default)]
+    pub warnings: Vec<TxWarning>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TxWarning {
+    /// a recipient address already appears in the wallet history
+    AddressReuse,
+    /// the fee rate is more than twice the estimate for the next block
+    HighFeeRate,
+    /// an input spends an output of an unconfirmed tx
+    SpendsUnconfirmed,
+    /// see `CreateTransaction::dust_remainder`
+    LeavesDust,
+    /// inputs of different addresses are spent together, linking them
+    LowPrivacy,
+}
+
+/// classification of a transaction output as seen by the wallet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputInfo {
+    pub vout: u32,
+    pub address: String, // empty if the script has no address form
+    pub is_mine: bool,
+    pub label: Option<String>,
+    /// the output is the change of a tx sent by the wallet
+    #[serde(default)]
+    pub is_change: bool,
+    /// for outputs of the wallet, the derivation path of the script from the wallet xpub
+    #[serde(default)]
+    pub derivation_path: Option<String>,
+    /// None for liquid confidential outputs not unblinded, like the ones not of the wallet
+    #[serde(default)]
+    pub satoshi: Option<u64>,
+    /// hex of the liquid asset of the output, None in bitcoin or if the output is not unblinded
+    #[serde(default)]
+    pub asset_id: Option<String>,
+}
+
+/// how a transaction pays an invoice, with the missing or exceeding satoshi
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InvoicePayment {
+    Exact,
+    Under(u64),
+    Over(u64),
+}
+
+impl InvoicePayment {
+    pub fn classify(requested: u64, paid: u64) -> Self {
+        if paid < requested {
+            InvoicePayment::Under(requested - paid)
+        } else if paid > requested {
+            InvoicePayment::Over(paid - requested)
+        } else {
+            InvoicePayment::Exact
+        }
+    }
+}
+
+/// which acquired lots are consumed first when coins are disposed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LotMethod {
+    Fifo,
+    Lifo,
+}
+
+/// a liquid asset held by the wallet, metadata are missing for assets not in the registry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AssetHolding {
+    pub asset_id: String,
+    pub satoshi: u64,
+    pub ticker: Option<String>,
+    pub name: Option<String>,
+    pub precision: Option<u8>,
+}
+
+/// wallet txs funding the inputs (`parents`) and spending the outputs (`children`) of a tx,
+/// txids are sorted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxGraph {
+    pub parents: Vec<String>,
+    pub children: Vec<String>,
+}
+
+/// a signed transaction with the outputs spent by its inputs, in input order, so that a third
+/// party can verify its signatures, which commit to the input amounts, without the wallet history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VerificationBundle {
+    #[serde(rename = "transaction")]
+    pub hex: String,
+    pub prevouts: Vec<BundlePrevout>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BundlePrevout {
+    pub value: u64,
+    pub script: bitcoin::Script,
+}
+
+/// a realized gain (or loss if negative), prices and amounts are in the unit of the price oracle
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GainLot {
+    pub acquired_height: u32,
+    pub disposed_height: u32,
+    pub satoshi: u64,
+    pub cost_basis: f64,
+    pub proceeds: f64,
+    pub gain: f64,
+}
+
+/// what has been signed for a transaction input, so that the signature can be checked independently
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedInput {
+    pub index: u32,
+    pub sighash: String, // hex of the signed digest, in the same byte order it is signed
+    pub sighash_type: u32,
+    pub derivation_path: String,
 }
 
 impl From<BETransaction> for TransactionMeta {
@@ -186,13 +392,22 @@ impl From<BETransaction> for TransactionMeta {
             addressees_have_assets: false,
             is_sweep: false,
             satoshi: HashMap::new(),
-            fee: 0,
+            fee: None,
             network: None,
             type_: "unknown".to_string(),
             changes_used: None,
             user_signed: false,
             spv_verified: SPVVerifyResult::InProgress,
             rbf_optin,
+            outputs: vec![],
+            signed_inputs: vec![],
+            sighash_types: vec![],
+            leaves_dust: false,
+            asset_deltas: HashMap::new(),
+            is_final: false,
+            confirmations: None,
+            memo: None,
+            warnings: vec![],
         }
     }
 }
@@ -204,7 +419,7 @@ impl TransactionMeta {
         height: Option<u32>,
         timestamp: Option<u32>,
         satoshi: Balances,
-        fee: u64,
+        fee: Option<u64>,
         network: Network,
         type_: String,
         create_transaction: CreateTransaction,
@@ -227,6 +442,20 @@ impl TransactionMeta {
         wgtx.spv_verified = spv_verified;
         wgtx
     }
+
+    /// the tx hex, as `bitcoin::consensus::encode::serialize_hex` for bitcoin txs. Signed txs
+    /// include the witness, txs of the wallet history are stored and listed without it
+    pub fn transaction_hex(&self) -> &str {
+        &self.hex
+    }
+
+    /// bundle this signed tx with the `prevouts` spent by its inputs, given in input order
+    pub fn verification_bundle(&self, prevouts: Vec<BundlePrevout>) -> VerificationBundle {
+        VerificationBundle {
+            hex: self.hex.clone(),
+            prevouts,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -263,8 +492,11 @@ pub struct TxListItem {
     pub user_signed: bool,
     pub instant: bool,
     pub spv_verified: String,
-    pub fee: u64,
-    pub fee_rate: u64,
+    /// why the tx is "not_verified", e.g. "merkle_root_mismatch"
+    pub spv_failure: Option<String>,
+    /// None when some previous output of the tx is unknown
+    pub fee: Option<u64>,
+    pub fee_rate: Option<u64>,
     pub addressees: Vec<String>, // receiver's addresses
     pub inputs: Vec<AddressIO>,  // tx.input.iter().map(format_gdk_input).collect(),
     pub outputs: Vec<AddressIO>, //tx.output.iter().map(format_gdk_output).collect(),
@@ -300,6 +532,50 @@ pub struct AddressPointer {
     pub pointer: u32, // child_number in bip32 terminology
 }
 
+/// error correction level of a qr code, from the one recovering less damage
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QrErrorCorrection {
+    Low,
+    Medium,
+    Quartile,
+    High,
+}
+
+impl AddressPointer {
+    /// the string to encode in the qr code of the address
+    pub fn qr_payload(&self) -> &str {
+        &self.address
+    }
+
+    /// true if the payload can be encoded in the denser alphanumeric qr mode, which has only
+    /// uppercase letters
+    pub fn fits_qr_alphanumeric(&self) -> bool {
+        self.address
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase() || " $%*+-./:".contains(c))
+    }
+
+    /// the highest error correction level keeping the qr code within version 4 (33x33 modules),
+    /// small enough to be scanned from a phone screen
+    pub fn qr_error_correction(&self) -> QrErrorCorrection {
+        // version 4 capacities in characters for levels High, Quartile, Medium
+        let capacities = if self.fits_qr_alphanumeric() {
+            [50, 67, 90]
+        } else {
+            [34, 46, 62]
+        };
+        let levels =
+            [QrErrorCorrection::High, QrErrorCorrection::Quartile, QrErrorCorrection::Medium];
+        let len = self.qr_payload().len();
+        levels
+            .iter()
+            .zip(capacities.iter())
+            .find(|(_, capacity)| len <= **capacity)
+            .map(|(level, _)| *level)
+            .unwrap_or(QrErrorCorrection::Low)
+    }
+}
+
 // This one is simple enough to derive a serializer
 #[derive(Serialize, Debug, Clone, Deserialize)]
 pub struct FeeEstimate(pub u64);
@@ -314,6 +590,10 @@ pub struct Settings {
     pub altimeout: u32,
     pub pricing: Pricing,
     pub sound: bool,
+    /// confirmations after which a tx is considered final, 6 if not set
+    pub final_confirmations: Option<u32>,
+    /// max fee of a fee bump, as a percentage of the amount sent, 25 if not set
+    pub max_fee_percent: Option<u32>,
 }
 
 /// {"icons":true,"assets":false,"refresh":false}
@@ -331,6 +611,22 @@ pub struct Pricing {
     exchange: String,
 }
 
+/// default of `Settings::final_confirmations`
+pub const FINAL_CONFIRMATIONS: u32 = 6;
+
+/// default of `Settings::max_fee_percent`
+pub const MAX_FEE_PERCENT: u32 = 25;
+
+impl Settings {
+    pub fn final_confirmations(&self) -> u32 {
+        self.final_confirmations.unwrap_or(FINAL_CONFIRMATIONS)
+    }
+
+    pub fn max_fee_percent(&self) -> u32 {
+        self.max_fee_percent.unwrap_or(MAX_FEE_PERCENT)
+    }
+}
+
 impl Default for Settings {
     fn default() -> Self {
         let pricing = Pricing {
@@ -343,6 +639,8 @@ impl Default for Settings {
             altimeout: 600,
             pricing,
             sound: false,
+            final_confirmations: None,
+            max_fee_percent: None,
         }
     }
 }
@@ -360,7 +658,7 @@ impl AddressAmount {
     }
 }
 
-fn now() -> u32 {
+pub fn now() -> u32 {
     let start = SystemTime::now();
     let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("Time went backwards");
     since_the_epoch.as_secs() as u32
@@ -376,8 +674,28 @@ impl SPVVerifyResult {
         match self {
             SPVVerifyResult::InProgress => 0,
             SPVVerifyResult::Verified => 1,
-            SPVVerifyResult::NotVerified => 2,
+            SPVVerifyResult::NotVerified(_) => 2,
             SPVVerifyResult::Disabled => 3,
+            SPVVerifyResult::Unconfirmed => 4,
+        }
+    }
+
+    /// why the tx has not been verified, None unless `NotVerified`
+    pub fn failure(&self) -> Option<SPVFailure> {
+        match self {
+            SPVVerifyResult::NotVerified(reason) => Some(*reason),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SPVFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SPVFailure::MerkleRootMismatch => write!(f, "merkle_root_mismatch"),
+            SPVFailure::HeaderNotAvailable => write!(f, "header_not_available"),
+            SPVFailure::NotInBlock => write!(f, "not_in_block"),
+            SPVFailure::InvalidHeaderSignature => write!(f, "invalid_header_signature"),
         }
     }
 }
@@ -387,8 +705,9 @@ impl Display for SPVVerifyResult {
         match self {
             SPVVerifyResult::InProgress => write!(f, "in_progress"),
             SPVVerifyResult::Verified => write!(f, "verified"),
-            SPVVerifyResult::NotVerified => write!(f, "not_verified"),
+            SPVVerifyResult::NotVerified(_) => write!(f, "not_verified"),
             SPVVerifyResult::Disabled => write!(f, "disabled"),
+            SPVVerifyResult::Unconfirmed => write!(f, "unconfirmed"),
         }
     }
 }
@@ -19,13 +19,41 @@ pub struct Network {
 
     pub tls: Option<bool>,
     pub electrum_url: Option<String>,
+    /// whether the certificate of the TLS electrum server must be valid for its domain, true if
+    /// missing. False accepts any certificate, e.g. self-signed ones of self-hosted servers
     pub validate_domain: Option<bool>,
+    /// hex of the sha256 of the DER certificate of the TLS electrum server, when set it's the only
+    /// certificate accepted and `validate_domain` is ignored
+    pub electrum_cert_sha256: Option<String>,
     pub policy_asset: Option<String>,
     pub sync_interval: Option<u32>,
     pub ct_bits: Option<i32>,
     pub ct_exponent: Option<i32>,
     pub ct_min_value: Option<u64>,
     pub spv_enabled: Option<bool>,
+    /// keep in the headers chain of the spv verification only the headers at heights multiple
+    /// of this value plus the most recent ones, it must divide 2016. Not pruned if missing
+    pub headers_checkpoint_interval: Option<u32>,
+    pub history_page_size: Option<usize>,
+    /// scripts with a history longer than this, e.g. a reused donation address, have their txs
+    /// downloaded and stored in pages before the rest of the sync, notifying the progress
+    pub huge_history_threshold: Option<usize>,
+    pub sync_streaming: Option<bool>,
+    pub gap_limit: Option<u32>,
+    /// if true the wallet db directory depends only on the wallet xpub and the network id, so
+    /// that changing other network parameters, like the electrum url, opens the same db
+    pub db_from_xpub: Option<bool>,
+    /// minimum fee rate in satoshi/kbyte accepted by create_tx, defaults to 1000 in bitcoin and
+    /// 100 in liquid
+    pub min_relay_fee_rate: Option<u64>,
+    /// address of a socks5 proxy, eg. tor, used for every connection to the electrum server
+    pub socks5: Option<String>,
+    /// attempts of a connection or a request to the electrum server failing for network errors,
+    /// defaults to 3
+    pub electrum_retries: Option<u32>,
+    /// milliseconds before retrying a failed electrum connection or request, doubling at every
+    /// retry, defaults to 500
+    pub electrum_retry_delay_ms: Option<u64>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -40,6 +68,16 @@ pub enum NetworkId {
     Bitcoin(bitcoin::Network),
 }
 
+impl ElementsNetwork {
+    /// the bitcoin network this elements network is pegged to
+    pub fn parent_network(self) -> bitcoin::Network {
+        match self {
+            ElementsNetwork::Liquid => bitcoin::Network::Bitcoin,
+            ElementsNetwork::ElementsRegtest => bitcoin::Network::Regtest,
+        }
+    }
+}
+
 impl NetworkId {
     pub fn get_bitcoin_network(self: NetworkId) -> Option<bitcoin::Network> {
         match self {
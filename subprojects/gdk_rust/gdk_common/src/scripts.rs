@@ -1,7 +1,79 @@
+use crate::error::Error;
 use bitcoin::blockdata::script::Builder;
 use bitcoin::hash_types::PubkeyHash;
 use bitcoin::hashes::Hash;
 use bitcoin::{Address, Network, PublicKey, Script};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// single sig script types a receive address can be derived as, the wallet default is `P2shP2wpkh`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ScriptType {
+    #[serde(rename = "p2sh-p2wpkh")]
+    P2shP2wpkh,
+    #[serde(rename = "p2wpkh")]
+    P2wpkh,
+    #[serde(rename = "p2pkh")]
+    P2pkh,
+}
+
+impl ScriptType {
+    /// purpose of the derivation path of the wallets of this script type, as in BIP44, BIP49
+    /// and BIP84
+    pub fn purpose(self) -> u32 {
+        match self {
+            ScriptType::P2shP2wpkh => 49,
+            ScriptType::P2wpkh => 84,
+            ScriptType::P2pkh => 44,
+        }
+    }
+
+    /// the script type of an output paying `script`, None if it's not a single sig one. p2sh
+    /// outputs are assumed to be p2sh-p2wpkh
+    pub fn from_script_pubkey(script: &Script) -> Option<ScriptType> {
+        if script.is_p2sh() {
+            Some(ScriptType::P2shP2wpkh)
+        } else if script.is_v0_p2wpkh() {
+            Some(ScriptType::P2wpkh)
+        } else if script.is_p2pkh() {
+            Some(ScriptType::P2pkh)
+        } else {
+            None
+        }
+    }
+
+    pub fn script_pubkey(self, pk: &PublicKey) -> Script {
+        match self {
+            ScriptType::P2shP2wpkh => p2shwpkh_script(pk),
+            ScriptType::P2wpkh => Address::p2wpkh(pk, Network::Regtest).unwrap().script_pubkey(),
+            ScriptType::P2pkh => p2pkh_script(pk),
+        }
+    }
+}
+
+impl FromStr for ScriptType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "p2sh-p2wpkh" => Ok(ScriptType::P2shP2wpkh),
+            "p2wpkh" => Ok(ScriptType::P2wpkh),
+            "p2pkh" => Ok(ScriptType::P2pkh),
+            _ => Err(Error::Generic(format!("unknown script type {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for ScriptType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptType::P2shP2wpkh => write!(f, "p2sh-p2wpkh"),
+            ScriptType::P2wpkh => write!(f, "p2wpkh"),
+            ScriptType::P2pkh => write!(f, "p2pkh"),
+        }
+    }
+}
 
 // The following scripts are always using regtest network,
 // it is always ok because I am not interested in the address just in the script
@@ -21,3 +93,54 @@ pub fn p2shwpkh_script_sig(public_key: &PublicKey) -> Script {
         .into_script();
     Builder::new().push_slice(internal.as_bytes()).into_script()
 }
+
+/// max size of a standard OP_RETURN output script, as in bitcoin core `MAX_OP_RETURN_RELAY`
+pub const MAX_OP_RETURN_SIZE: usize = 83;
+
+/// whether an output with `script` would be relayed by default policy nodes: p2pk, p2pkh,
+/// p2sh, witness programs and small OP_RETURN outputs
+pub fn is_standard_output_script(script: &Script) -> bool {
+    if script.is_op_return() {
+        return script.len() <= MAX_OP_RETURN_SIZE;
+    }
+    script.is_p2pk() || script.is_p2pkh() || script.is_p2sh() || script.is_witness_program()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scripts::{is_standard_output_script, p2pkh_script, p2shwpkh_script};
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{Address, Network, PublicKey, Script};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_standard_output_script() {
+        let pk = PublicKey::from_str(
+            "0386fe0922d694cef4fa197f9040da7e264b0a0ff38aa2e647545e5a6d6eab5bfc",
+        )
+        .unwrap();
+        assert!(is_standard_output_script(&p2pkh_script(&pk)));
+        assert!(is_standard_output_script(&p2shwpkh_script(&pk)));
+        let p2wpkh = Address::p2wpkh(&pk, Network::Regtest).unwrap().script_pubkey();
+        assert!(is_standard_output_script(&p2wpkh));
+
+        let op_return = |len| {
+            Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .push_slice(&vec![0u8; len])
+                .into_script()
+        };
+        assert!(is_standard_output_script(&op_return(80)));
+        assert!(!is_standard_output_script(&op_return(81)));
+
+        // a bare script with a big push followed by a check
+        let bare = Builder::new()
+            .push_slice(&[1u8; 500])
+            .push_opcode(opcodes::all::OP_DROP)
+            .push_opcode(opcodes::all::OP_PUSHNUM_1)
+            .into_script();
+        assert!(!is_standard_output_script(&bare));
+        assert!(!is_standard_output_script(&Script::new()));
+    }
+}
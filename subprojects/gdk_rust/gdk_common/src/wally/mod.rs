@@ -208,6 +208,18 @@ pub fn asset_blinding_key_to_ec_private_key(
     secp256k1::SecretKey::from_slice(&out).expect("size is 32")
 }
 
+/// blinding key of `script_pubkey` for the invoice `invoice_nonce`, derived as the key of the
+/// script followed by the big endian nonce, so that invoices to the same address are unlinkable
+pub fn invoice_blinding_key_to_ec_private_key(
+    master_blinding_key: &MasterBlindingKey,
+    script_pubkey: &bitcoin::Script,
+    invoice_nonce: u32,
+) -> secp256k1::SecretKey {
+    let mut extended = script_pubkey.to_bytes();
+    extended.extend(&invoice_nonce.to_be_bytes());
+    asset_blinding_key_to_ec_private_key(master_blinding_key, &bitcoin::Script::from(extended))
+}
+
 #[allow(clippy::type_complexity)]
 pub fn asset_unblind(
     pub_key: secp256k1::PublicKey,
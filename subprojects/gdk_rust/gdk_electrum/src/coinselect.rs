@@ -0,0 +1,283 @@
+use crate::error::Error;
+use bitcoin::{OutPoint, Script, TxOut};
+use gdk_common::be::{input_vbytes, output_vbytes, DUST_VALUE};
+use gdk_common::scripts::ScriptType;
+use std::collections::HashMap;
+
+/// branches explored by branch and bound before falling back to largest first
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// inputs chosen to fund a transaction: `total == target + fee + change`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub outpoints: Vec<OutPoint>,
+    /// sum of the values of the selected outputs
+    pub total: u64,
+    /// fee of the selected inputs and of the change output, when there is no change it
+    /// includes the excess over the target, at most `DUST_VALUE`
+    pub fee: u64,
+    /// value of the change output, 0 if a change output is not needed
+    pub change: u64,
+}
+
+impl Selection {
+    pub fn needs_change(&self) -> bool {
+        self.change > 0
+    }
+}
+
+fn fee(vbytes: u64, fee_rate: u64) -> u64 {
+    (vbytes * fee_rate + 999) / 1000
+}
+
+/// select `utxos` of a wallet of `script_type` covering `target` at `fee_rate` satoshi/kbyte,
+/// `target` must include the fee of the parts of the transaction other than inputs and change.
+/// Utxos sharing a script are selected together, as a single candidate worth their sum.
+/// Branch and bound looks for a set of inputs exceeding the target by at most `DUST_VALUE`,
+/// which doesn't need a change output; when there is none inputs are taken largest first
+pub fn select(
+    utxos: &[(OutPoint, TxOut)],
+    target: u64,
+    fee_rate: u64,
+    script_type: ScriptType,
+) -> Result<Selection, Error> {
+    let input_fee = fee(input_vbytes(script_type), fee_rate);
+    // indexes in utxos of the utxos of every script, in order of first appearance
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut group_of: HashMap<&Script, usize> = HashMap::new();
+    for (i, (_, output)) in utxos.iter().enumerate() {
+        let group = *group_of.entry(&output.script_pubkey).or_insert_with(|| {
+            groups.push(vec![]);
+            groups.len() - 1
+        });
+        groups[group].push(i);
+    }
+    // (index in groups, effective value) of groups worth more than the fee to spend them
+    let mut candidates: Vec<(usize, u64)> = groups
+        .iter()
+        .enumerate()
+        .map(|(g, group)| {
+            let value = group.iter().map(|i| utxos[*i].1.value).sum::<u64>();
+            (g, value, group.len() as u64 * input_fee)
+        })
+        .filter(|(_, value, spend_fee)| value > spend_fee)
+        .map(|(g, value, spend_fee)| (g, value - spend_fee))
+        .collect();
+    candidates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let effective: Vec<u64> = candidates.iter().map(|c| c.1).collect();
+    let chosen = match branch_and_bound(&effective, target, DUST_VALUE) {
+        Some(chosen) => chosen,
+        None => {
+            let change_fee = fee(output_vbytes(script_type), fee_rate);
+            return largest_first(utxos, &groups, &candidates, target, change_fee);
+        }
+    };
+    let chosen: Vec<usize> =
+        chosen.iter().flat_map(|c| groups[candidates[*c].0].iter().cloned()).collect();
+    let outpoints: Vec<OutPoint> = chosen.iter().map(|i| utxos[*i].0).collect();
+    let total = chosen.iter().map(|i| utxos[*i].1.value).sum::<u64>();
+    Ok(Selection {
+        outpoints,
+        total,
+        fee: total - target,
+        change: 0,
+    })
+}
+
+/// indexes of `effective` values summing to at least `target` and at most `target + tolerance`,
+/// `effective` must be sorted descending
+fn branch_and_bound(effective: &[u64], target: u64, tolerance: u64) -> Option<Vec<usize>> {
+    // suffix[i] is the sum of effective[i..], used to prune branches that can't reach the target
+    let mut suffix = vec![0u64; effective.len() + 1];
+    for i in (0..effective.len()).rev() {
+        suffix[i] = suffix[i + 1] + effective[i];
+    }
+    let mut chosen = vec![];
+    let mut tries = 0;
+    if search(effective, &suffix, 0, 0, target, tolerance, &mut chosen, &mut tries) {
+        Some(chosen)
+    } else {
+        None
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn search(
+    effective: &[u64],
+    suffix: &[u64],
+    index: usize,
+    current: u64,
+    target: u64,
+    tolerance: u64,
+    chosen: &mut Vec<usize>,
+    tries: &mut usize,
+) -> bool {
+    if current > target + tolerance {
+        return false;
+    }
+    if current >= target {
+        return true;
+    }
+    if index == effective.len() || current + suffix[index] < target {
+        return false;
+    }
+    *tries += 1;
+    if *tries > BNB_MAX_TRIES {
+        return false;
+    }
+    chosen.push(index);
+    let with = current + effective[index];
+    if search(effective, suffix, index + 1, with, target, tolerance, chosen, tries) {
+        return true;
+    }
+    chosen.pop();
+    search(effective, suffix, index + 1, current, target, tolerance, chosen, tries)
+}
+
+/// take the biggest `candidates` groups until the target is covered with a change above dust,
+/// or with an excess small enough to be left as fee
+fn largest_first(
+    utxos: &[(OutPoint, TxOut)],
+    groups: &[Vec<usize>],
+    candidates: &[(usize, u64)],
+    target: u64,
+    change_fee: u64,
+) -> Result<Selection, Error> {
+    let mut outpoints = vec![];
+    let mut total = 0;
+    let mut current = 0;
+    for (g, value) in candidates {
+        for i in groups[*g].iter() {
+            outpoints.push(utxos[*i].0);
+            total += utxos[*i].1.value;
+        }
+        current += value;
+        if current >= target {
+            let excess = current - target;
+            if excess <= DUST_VALUE {
+                break;
+            }
+            if excess > change_fee + DUST_VALUE {
+                let change = excess - change_fee;
+                return Ok(Selection {
+                    outpoints,
+                    total,
+                    fee: total - target - change,
+                    change,
+                });
+            }
+        }
+    }
+    if current < target {
+        return Err(Error::InsufficientFunds);
+    }
+    // the excess is not enough to pay for a change output above dust, it's left as fee
+    Ok(Selection {
+        outpoints,
+        total,
+        fee: total - target,
+        change: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Script, Txid};
+
+    fn utxos(values: &[u64]) -> Vec<(OutPoint, TxOut)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let outpoint = OutPoint::new(Txid::from_slice(&[i as u8 + 1; 32]).unwrap(), 0);
+                let output = TxOut {
+                    value: *value,
+                    script_pubkey: Script::from(vec![i as u8]),
+                };
+                (outpoint, output)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_select_exact_match() {
+        let utxos = utxos(&[100_000, 50_000, 30_000, 20_000]);
+
+        // a single utxo matching exactly, largest first would take 100_000 and create change
+        let selection = select(&utxos, 50_000 - 91, 1000, ScriptType::P2shP2wpkh).unwrap();
+        assert_eq!(selection.outpoints, vec![utxos[1].0]);
+        assert_eq!(selection.fee, 91);
+        assert!(!selection.needs_change());
+
+        // two utxos matching within the dust tolerance, the excess is left as fee
+        let utxos = self::utxos(&[100_000, 60_000, 30_000, 20_000]);
+        let selection = select(&utxos, 49_700, 1000, ScriptType::P2shP2wpkh).unwrap();
+        assert_eq!(selection.outpoints, vec![utxos[2].0, utxos[3].0]);
+        assert_eq!(selection.total, 50_000);
+        assert_eq!(selection.fee, 300);
+        assert_eq!(selection.change, 0);
+    }
+
+    #[test]
+    fn test_select_fallback() {
+        let utxos = utxos(&[60_000, 100_000]);
+        let selection = select(&utxos, 10_000, 1000, ScriptType::P2shP2wpkh).unwrap();
+        assert_eq!(selection.outpoints, vec![utxos[1].0]);
+        assert!(selection.needs_change());
+        assert_eq!(selection.fee, 91 + 32);
+        assert_eq!(selection.total, 10_000 + selection.fee + selection.change);
+
+        let selection = select(&utxos, 150_000, 1000, ScriptType::P2shP2wpkh).unwrap();
+        assert_eq!(selection.outpoints.len(), 2);
+        assert_eq!(selection.total, 150_000 + selection.fee + selection.change);
+
+        assert!(matches!(
+            select(&utxos, 200_000, 1000, ScriptType::P2shP2wpkh),
+            Err(Error::InsufficientFunds)
+        ));
+    }
+
+    #[test]
+    fn test_select_skips_uneconomic() {
+        let utxos = utxos(&[90, 20_000]);
+        let selection = select(&utxos, 19_000, 1000, ScriptType::P2shP2wpkh).unwrap();
+        assert_eq!(selection.outpoints, vec![utxos[1].0]);
+        assert!(matches!(
+            select(&utxos, 19_950, 1000, ScriptType::P2shP2wpkh),
+            Err(Error::InsufficientFunds)
+        ));
+    }
+
+    #[test]
+    fn test_select_script_type() {
+        let utxos = utxos(&[100_000, 50_000, 30_000, 20_000]);
+        let selection = select(&utxos, 50_000 - 68, 1000, ScriptType::P2wpkh).unwrap();
+        assert_eq!(selection.outpoints, vec![utxos[1].0]);
+        assert_eq!(selection.fee, 68);
+
+        let selection = select(&utxos, 10_000, 1000, ScriptType::P2pkh).unwrap();
+        assert!(selection.needs_change());
+        assert_eq!(selection.fee, 148 + 34);
+    }
+
+    #[test]
+    fn test_select_same_script_together() {
+        let mut utxos = utxos(&[30_000, 20_000, 10_000]);
+        utxos[1].1.script_pubkey = utxos[0].1.script_pubkey.clone();
+
+        // the two utxos of the same script match exactly, they are a changeless selection
+        let selection = select(&utxos, 50_000 - 2 * 91, 1000, ScriptType::P2shP2wpkh).unwrap();
+        assert_eq!(selection.outpoints, vec![utxos[0].0, utxos[1].0]);
+        assert_eq!(selection.total, 50_000);
+        assert!(!selection.needs_change());
+
+        // a single utxo of the script is enough but both are spent
+        let selection = select(&utxos, 25_000, 1000, ScriptType::P2shP2wpkh).unwrap();
+        assert_eq!(selection.outpoints.len(), 2);
+        assert!(selection.outpoints.contains(&utxos[1].0));
+        assert_eq!(selection.total, 25_000 + selection.fee + selection.change);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::store::StoreMeta;
+use crate::SyncStats;
 use aes_gcm_siv::aead;
 use serde::ser::Serialize;
 use std::convert::From;
@@ -11,12 +12,40 @@ pub enum Error {
     UnknownCall,
     InvalidMnemonic,
     InsufficientFunds,
+    FeeExceedsCap(u64, u64),
+    FeeRateBelowMinimum(u64, u64),
+    EmptyWallet,
     InvalidAddress,
+    WrongNetwork(usize),
     InvalidAmount,
+    BelowDustLimit(u64, u64),
     EmptyAddressees,
     AssetEmpty,
     InvalidHeaders,
+    ReorgTooDeep,
+    /// headers forking from our chain without more work, our chain is kept
+    StaleFork,
     InvalidSubaccount(u32),
+    InvalidVersion(u32),
+    InvalidSignature(usize, String),
+    MissingPrevout(usize),
+    UtxoNotFound(String),
+    MemoTooLong(usize),
+    AddressNotMine,
+    WalletArchived,
+    WatchOnly,
+    MissingBlindingKey,
+    NonStandardOutput(String),
+    PsbtOutputMismatch,
+    Cancelled,
+    /// the electrum server couldn't be reached even retrying, see `RetryPolicy`
+    ElectrumUnreachable(String),
+    /// the electrum server refused to broadcast a transaction, with the reason it gave
+    BroadcastRejected(String),
+    /// the TLS electrum server certificate isn't the pinned one, with the sha256 of the received
+    PinnedCertificateMismatch(String),
+    /// a blob of the wallet db can't be read back, see `StoreMeta::repair`
+    DbCorrupted(String),
     SendAll,
     PinError,
     AddrParse(String),
@@ -43,13 +72,65 @@ impl Display for Error {
             Error::AddrParse(ref addr) => write!(f, "could not parse SocketAddr `{}`", addr),
             Error::InvalidMnemonic => write!(f, "invalid mnemonic"),
             Error::InsufficientFunds => write!(f, "insufficient funds"),
+            Error::FeeExceedsCap(fee, cap) => {
+                write!(f, "fee {} exceeds the maximum fee {}", fee, cap)
+            }
+            Error::FeeRateBelowMinimum(rate, min) => {
+                write!(f, "fee rate {} is below the minimum relay fee rate {}", rate, min)
+            }
+            Error::BelowDustLimit(value, threshold) => {
+                write!(f, "amount {} is below the dust threshold {}", value, threshold)
+            }
+            Error::EmptyWallet => write!(f, "wallet has no spendable utxos"),
             Error::SendAll => write!(f, "sendall error"),
             Error::InvalidAddress => write!(f, "invalid address"),
+            Error::WrongNetwork(index) => {
+                write!(f, "address of addressee {} is for a different network", index)
+            }
             Error::InvalidAmount => write!(f, "invalid amount"),
             Error::InvalidHeaders => write!(f, "invalid headers"),
+            Error::ReorgTooDeep => write!(f, "reorg deeper than the rollback limit"),
+            Error::StaleFork => write!(f, "fork without more work than our chain"),
             Error::EmptyAddressees => write!(f, "addressees cannot be empty"),
             Error::AssetEmpty => write!(f, "asset_tag cannot be empty in liquid"),
             Error::InvalidSubaccount(sub) => write!(f, "invalid subaccount {}", sub),
+            Error::InvalidVersion(version) => write!(f, "invalid transaction version {}", version),
+            Error::InvalidSignature(index, reason) => {
+                write!(f, "input {} failed verification: {}", index, reason)
+            }
+            Error::MissingPrevout(index) => {
+                write!(f, "input {} spends an output unknown to the wallet", index)
+            }
+            Error::UtxoNotFound(utxo) => write!(f, "utxo {} is not spendable by the wallet", utxo),
+            Error::MemoTooLong(len) => {
+                write!(
+                    f,
+                    "memo of {} bytes is too long, max {}",
+                    len,
+                    crate::interface::MAX_MEMO_LEN
+                )
+            }
+            Error::AddressNotMine => write!(f, "address is not of the wallet"),
+            Error::WalletArchived => write!(f, "wallet is archived, spending is disabled"),
+            Error::WatchOnly => write!(f, "watch-only wallet cannot sign"),
+            Error::MissingBlindingKey => write!(f, "liquid wallet without master blinding key"),
+            Error::Cancelled => write!(f, "operation cancelled"),
+            Error::ElectrumUnreachable(reason) => {
+                write!(f, "electrum server unreachable: {}", reason)
+            }
+            Error::BroadcastRejected(reason) => write!(f, "transaction rejected: {}", reason),
+            Error::PinnedCertificateMismatch(sha256) => {
+                write!(f, "electrum server certificate {} is not the pinned one", sha256)
+            }
+            Error::DbCorrupted(reason) => {
+                write!(f, "wallet db is corrupted: {}, it must be repaired", reason)
+            }
+            Error::NonStandardOutput(address) => {
+                write!(f, "output to {} would make the transaction non-standard", address)
+            }
+            Error::PsbtOutputMismatch => {
+                write!(f, "psbt outputs do not match the expected recipients")
+            }
             Error::UnknownCall => write!(f, "unknown call"),
             Error::Bitcoin(ref btcerr) => write!(f, "bitcoin: {}", btcerr),
             Error::BitcoinHashes(ref btcerr) => write!(f, "bitcoin_hashes: {}", btcerr),
@@ -214,6 +295,18 @@ impl From<PoisonError<RwLockWriteGuard<'_, StoreMeta>>> for Error {
     }
 }
 
+impl From<PoisonError<RwLockReadGuard<'_, SyncStats>>> for Error {
+    fn from(err: PoisonError<RwLockReadGuard<'_, SyncStats>>) -> Self {
+        Error::Generic(err.to_string())
+    }
+}
+
+impl From<PoisonError<RwLockWriteGuard<'_, SyncStats>>> for Error {
+    fn from(err: PoisonError<RwLockWriteGuard<'_, SyncStats>>) -> Self {
+        Error::Generic(err.to_string())
+    }
+}
+
 impl From<aead::Error> for Error {
     fn from(err: aead::Error) -> Self {
         Error::Generic(err.to_string())
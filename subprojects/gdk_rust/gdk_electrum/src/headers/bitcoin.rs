@@ -9,18 +9,55 @@ use bitcoin::util::uint::Uint256;
 use bitcoin::{BlockHash, Txid};
 use bitcoin::{BlockHeader, Network};
 use electrum_client::GetMerkleRes;
+use gdk_common::model::SPVFailure;
 use log::info;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::PathBuf;
 
+/// maximum number of headers replaced when the chain reorgs
+pub const MAX_REORG_DEPTH: u32 = 144;
+
 #[derive(Debug)]
 pub struct HeadersChain {
     path: PathBuf,
     height: u32,
     last: BlockHeader,
     checkpoints: HashMap<u32, BlockHash>,
+    pruned: Option<Pruned>,
+}
+
+/// storage of a pruned chain: `path` contains only the headers at heights multiple of `interval`,
+/// the last headers, enough to handle reorgs, are kept in `recent` and written in `recent_path`
+#[derive(Debug)]
+struct Pruned {
+    interval: u32,
+    recent: VecDeque<BlockHeader>,
+    recent_path: PathBuf,
+}
+
+impl Pruned {
+    /// the last `recent` header is the tip, the file starts with its height
+    fn write_recent(&self, height: u32) -> Result<(), Error> {
+        let mut serialized = height.to_le_bytes().to_vec();
+        for header in self.recent.iter() {
+            serialized.extend(serialize(header));
+        }
+        let mut file = File::create(&self.recent_path)?;
+        file.write_all(&serialized)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn push(&mut self, header: BlockHeader) {
+        self.recent.push_back(header);
+        if self.recent.len() > MAX_REORG_DEPTH as usize + 1 {
+            self.recent.pop_front();
+        }
+    }
 }
 
 impl HeadersChain {
@@ -40,6 +77,7 @@ impl HeadersChain {
                 height,
                 last,
                 checkpoints,
+                pruned: None,
             })
         } else {
             info!("{:?} chain file exists, reading", path);
@@ -57,32 +95,146 @@ impl HeadersChain {
                 height,
                 last,
                 checkpoints,
+                pruned: None,
             })
         }
     }
 
+    /// like `new` but the chain keeps only the headers at heights multiple of
+    /// `checkpoint_interval` and the last `MAX_REORG_DEPTH + 1` ones, the others are needed by
+    /// `verify_tx_proof_with_headers`. The interval must divide `DIFFCHANGE_INTERVAL` so that the
+    /// headers needed to check difficulty adjustments are kept
+    pub fn new_pruned(
+        path: PathBuf,
+        network: Network,
+        checkpoint_interval: u32,
+    ) -> Result<HeadersChain, Error> {
+        if checkpoint_interval == 0 || DIFFCHANGE_INTERVAL % checkpoint_interval != 0 {
+            return Err(Error::Generic(format!(
+                "checkpoint interval {} doesn't divide {}",
+                checkpoint_interval, DIFFCHANGE_INTERVAL
+            )));
+        }
+        let checkpoints = get_checkpoints(network);
+        let mut recent_path = path.clone().into_os_string();
+        recent_path.push(".recent");
+        let recent_path: PathBuf = recent_path.into();
+
+        if !path.exists() || !recent_path.exists() {
+            info!("{:?} pruned chain files don't exist, creating", path);
+            let last = genesis_block(network).header;
+            let mut file = File::create(&path)?;
+            file.write_all(&serialize(&last))?;
+            let pruned = Pruned {
+                interval: checkpoint_interval,
+                recent: vec![last].into(),
+                recent_path,
+            };
+            pruned.write_recent(0)?;
+
+            Ok(HeadersChain {
+                path,
+                height: 0,
+                last,
+                checkpoints,
+                pruned: Some(pruned),
+            })
+        } else {
+            info!("{:?} pruned chain files exist, reading", path);
+            let mut bytes = vec![];
+            File::open(&recent_path)?.read_to_end(&mut bytes)?;
+            if bytes.len() < 84 || (bytes.len() - 4) % 80 != 0 {
+                return Err(Error::InvalidHeaders);
+            }
+            let height = u32::from_le_bytes(bytes[..4].try_into()?);
+            let recent = bytes[4..]
+                .chunks(80)
+                .map(deserialize)
+                .collect::<Result<VecDeque<BlockHeader>, _>>()?;
+            let last = *recent.back().expect("checked not empty");
+
+            Ok(HeadersChain {
+                path,
+                height,
+                last,
+                checkpoints,
+                pruned: Some(Pruned {
+                    interval: checkpoint_interval,
+                    recent,
+                    recent_path,
+                }),
+            })
+        }
+    }
+
+    /// the chain of `network` in the directory `dir`, pruned with `checkpoint_interval` if
+    /// given, see `new_pruned`. Full and pruned chains are kept in different files
+    pub fn open(
+        dir: PathBuf,
+        network: Network,
+        checkpoint_interval: Option<u32>,
+    ) -> Result<HeadersChain, Error> {
+        let mut path = dir;
+        match checkpoint_interval {
+            Some(interval) => {
+                path.push(format!("headers_chain_{}_pruned_{}", network, interval));
+                HeadersChain::new_pruned(path, network, interval)
+            }
+            None => {
+                path.push(format!("headers_chain_{}", network));
+                HeadersChain::new(path, network)
+            }
+        }
+    }
+
     pub fn height(&self) -> u32 {
         self.height
     }
 
     pub fn get(&self, height: u32) -> Result<BlockHeader, Error> {
+        let mut position = height;
+        if let Some(pruned) = &self.pruned {
+            let first_recent = self.first_recent(pruned);
+            if height >= first_recent && height <= self.height {
+                return Ok(pruned.recent[(height - first_recent) as usize]);
+            }
+            if height % pruned.interval != 0 {
+                return Err(Error::Generic(format!("header at height {} is pruned", height)));
+            }
+            position = height / pruned.interval;
+        }
         let mut file = File::open(&self.path)?;
-        file.seek(SeekFrom::Start(height as u64 * 80))?;
+        file.seek(SeekFrom::Start(position as u64 * 80))?;
         let mut buf = [0u8; 80];
         file.read_exact(&mut buf)?;
         let header: BlockHeader = deserialize(&buf)?;
         Ok(header)
     }
 
+    /// height of the first header in `pruned.recent`
+    fn first_recent(&self, pruned: &Pruned) -> u32 {
+        self.height + 1 - pruned.recent.len() as u32
+    }
+
     /// to handle reorgs, it's necessary to remove some of the last headers
     pub fn remove(&mut self, headers_to_remove: u32) -> Result<(), Error> {
-        let headers_to_remove = headers_to_remove.min(self.height - 1);
+        let mut headers_to_remove = headers_to_remove.min(self.height - 1);
+        if let Some(pruned) = &self.pruned {
+            headers_to_remove = headers_to_remove.min(pruned.recent.len() as u32 - 1);
+        }
         let new_height = self.height - headers_to_remove;
-        let new_size = (new_height + 1) as u64 * 80;
+        let new_size = match &self.pruned {
+            Some(pruned) => (new_height / pruned.interval + 1) as u64 * 80,
+            None => (new_height + 1) as u64 * 80,
+        };
         let file = OpenOptions::new().write(true).open(&self.path)?;
         self.last = self.get(new_height)?;
         self.height = new_height;
         file.set_len(new_size)?;
+        if let Some(pruned) = &mut self.pruned {
+            pruned.recent.truncate(pruned.recent.len() - headers_to_remove as usize);
+            pruned.write_recent(new_height)?;
+        }
         Ok(())
     }
 
@@ -90,8 +242,53 @@ impl HeadersChain {
         self.last
     }
 
-    /// write new headers to the file if checks are passed
-    pub fn push(&mut self, new_headers: Vec<BlockHeader>) -> Result<(), Error> {
+    /// download with `fetch(start_height, count)` the headers after our tip and push them. When
+    /// the server chain reorged, the first header doesn't connect to our tip: the download is
+    /// repeated from lower heights, doubling the overlap with our chain up to the rollback
+    /// limit, until the fork point is found. Returns the number of headers written
+    pub fn pull<F>(&mut self, count: usize, mut fetch: F) -> Result<usize, Error>
+    where
+        F: FnMut(u32, usize) -> Result<Vec<BlockHeader>, Error>,
+    {
+        // the genesis block can't be replaced
+        let max_overlap = self.reorg_depth().min(self.height);
+        let mut overlap = 0;
+        loop {
+            let headers = fetch(self.height + 1 - overlap, count + overlap as usize)?;
+            match self.push(headers) {
+                Err(Error::ReorgTooDeep) if overlap < max_overlap => {
+                    overlap = (overlap * 2).max(1).min(max_overlap);
+                    info!("headers don't connect to our tip, downloading from {} back", overlap);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// write new headers to the file if checks are passed, returns how many. If the first header
+    /// doesn't connect to our tip but to one of the last `MAX_REORG_DEPTH` headers, the ones we
+    /// already have are skipped and our headers after the fork are replaced by the others if
+    /// they have more work, `Error::StaleFork` otherwise
+    pub fn push(&mut self, mut new_headers: Vec<BlockHeader>) -> Result<usize, Error> {
+        if let Some(first) = new_headers.first() {
+            if first.prev_blockhash != self.last.block_hash() {
+                let mut fork_height =
+                    self.recent_height(first.prev_blockhash)?.ok_or(Error::ReorgTooDeep)?;
+                let mut known = 0;
+                while known < new_headers.len()
+                    && fork_height < self.height
+                    && self.get(fork_height + 1)? == new_headers[known]
+                {
+                    known += 1;
+                    fork_height += 1;
+                }
+                new_headers.drain(..known);
+                if fork_height < self.height {
+                    self.reorg(fork_height, &new_headers)?;
+                }
+            }
+        }
+        let count = new_headers.len();
         let mut serialized = vec![];
         for new_header in new_headers {
             let new_height = self.height + 1;
@@ -124,7 +321,15 @@ impl HeadersChain {
                 info!("checkpoint {} {} is ok", new_height, hash);
             }
 
-            serialized.extend(serialize(&new_header));
+            match &mut self.pruned {
+                Some(pruned) => {
+                    if new_height % pruned.interval == 0 {
+                        serialized.extend(serialize(&new_header));
+                    }
+                    pruned.push(new_header);
+                }
+                None => serialized.extend(serialize(&new_header)),
+            }
             self.last = new_header;
             self.height = new_height;
         }
@@ -135,7 +340,44 @@ impl HeadersChain {
             self.tip().block_hash(),
             self.path
         );
-        Ok(())
+        Ok(count)
+    }
+
+    /// how many of the last headers can be replaced by a reorg
+    fn reorg_depth(&self) -> u32 {
+        match &self.pruned {
+            Some(pruned) => MAX_REORG_DEPTH.min(pruned.recent.len() as u32 - 1),
+            None => MAX_REORG_DEPTH,
+        }
+    }
+
+    /// height of our header with hash `hash`, searched among the ones a reorg can replace
+    fn recent_height(&self, hash: BlockHash) -> Result<Option<u32>, Error> {
+        if hash == self.last.block_hash() {
+            return Ok(Some(self.height));
+        }
+        for height in (self.height.saturating_sub(self.reorg_depth())..self.height).rev() {
+            if self.get(height + 1)?.prev_blockhash == hash {
+                return Ok(Some(height));
+            }
+        }
+        Ok(None)
+    }
+
+    /// remove the headers after `fork_height`, if `new_headers` replacing them have more work
+    fn reorg(&mut self, fork_height: u32, new_headers: &[BlockHeader]) -> Result<(), Error> {
+        let mut old_work = Uint256::from_u64(0).unwrap();
+        for height in fork_height + 1..=self.height {
+            old_work = old_work + self.get(height)?.work();
+        }
+        let new_work =
+            new_headers.iter().fold(Uint256::from_u64(0).unwrap(), |acc, h| acc + h.work());
+        if new_work <= old_work {
+            info!("ignoring fork at height {} without more work", fork_height);
+            return Err(Error::StaleFork);
+        }
+        info!("reorg at height {}, removing {} headers", fork_height, self.height - fork_height);
+        self.remove(self.height - fork_height)
     }
 
     /// verify the given txid and the proof against our chain of headers
@@ -144,18 +386,53 @@ impl HeadersChain {
         txid: &Txid,
         height: u32,
         merkle: GetMerkleRes,
-    ) -> Result<(), Error> {
-        let calculated_merkle_root = compute_merkle_root(txid, merkle)?;
+    ) -> Result<(), SPVFailure> {
+        if height > self.height {
+            return Err(SPVFailure::HeaderNotAvailable);
+        }
+        let header = self.get(height).map_err(|_| SPVFailure::HeaderNotAvailable)?;
+        check_merkle_root(txid, height, merkle, &header)
+    }
 
-        let header = self.get(height)?;
-        if header.merkle_root == calculated_merkle_root {
-            info!("proof for txid {}, block height {}, merkle root matches", txid, height);
-            Ok(())
-        } else {
-            Err(Error::InvalidHeaders)
+    /// heights of the headers a pruned chain needs to verify a tx at `height`: from `height` to the
+    /// next header stored, excluded. Empty if the header at `height` is stored
+    pub fn missing_headers(&self, height: u32) -> Range<u32> {
+        match &self.pruned {
+            Some(pruned) if height < self.first_recent(pruned) && height % pruned.interval != 0 => {
+                let next_checkpoint = (height / pruned.interval + 1) * pruned.interval;
+                height..next_checkpoint.min(self.first_recent(pruned))
+            }
+            _ => height..height,
         }
     }
 
+    /// like `verify_tx_proof`, with `headers` being the ones at the heights returned by
+    /// `missing_headers`. They are accepted only if they are linked to the next header we store
+    pub fn verify_tx_proof_with_headers(
+        &self,
+        txid: &Txid,
+        height: u32,
+        merkle: GetMerkleRes,
+        headers: &[BlockHeader],
+    ) -> Result<(), SPVFailure> {
+        let missing = self.missing_headers(height);
+        if missing.start == missing.end {
+            return self.verify_tx_proof(txid, height, merkle);
+        }
+        if headers.len() != (missing.end - missing.start) as usize {
+            return Err(SPVFailure::HeaderNotAvailable);
+        }
+        let next = self.get(missing.end).map_err(|_| SPVFailure::HeaderNotAvailable)?;
+        let mut expected = next.prev_blockhash;
+        for header in headers.iter().rev() {
+            if header.block_hash() != expected {
+                return Err(SPVFailure::HeaderNotAvailable);
+            }
+            expected = header.prev_blockhash;
+        }
+        check_merkle_root(txid, height, merkle, &headers[0])
+    }
+
     /// write `serialized` bytes to the file, forcing flush so we are sure next `get()` will have
     /// also this data if requested
     fn flush(&mut self, serialized: &mut Vec<u8>) -> Result<(), Error> {
@@ -165,7 +442,27 @@ impl HeadersChain {
             file.flush()?;
             serialized.clear();
         }
+        if let Some(pruned) = &self.pruned {
+            pruned.write_recent(self.height)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_merkle_root(
+    txid: &Txid,
+    height: u32,
+    merkle: GetMerkleRes,
+    header: &BlockHeader,
+) -> Result<(), SPVFailure> {
+    let calculated_merkle_root =
+        compute_merkle_root(txid, merkle).map_err(|_| SPVFailure::MerkleRootMismatch)?;
+
+    if header.merkle_root == calculated_merkle_root {
+        info!("proof for txid {}, block height {}, merkle root matches", txid, height);
         Ok(())
+    } else {
+        Err(SPVFailure::MerkleRootMismatch)
     }
 }
 
@@ -193,12 +490,15 @@ fn get_checkpoints(network: Network) -> HashMap<u32, BlockHash> {
 
 #[cfg(test)]
 mod test {
-    use crate::headers::bitcoin::HeadersChain;
+    use crate::error::Error;
+    use crate::headers::bitcoin::{HeadersChain, MAX_REORG_DEPTH};
     use bitcoin::consensus::encode::Decodable;
     use bitcoin::hash_types::BlockHash;
     use bitcoin::hashes::hex::FromHex;
-    use bitcoin::{BitcoinHash, BlockHeader, Network};
+    use bitcoin::hashes::Hash;
+    use bitcoin::{BitcoinHash, BlockHeader, Network, TxMerkleNode, Txid};
     use electrum_client::GetMerkleRes;
+    use gdk_common::model::SPVFailure;
     use std::io::Cursor;
     use tempdir::TempDir;
 
@@ -261,11 +561,23 @@ mod test {
             pos: 0,
             merkle: vec![],
         };
-        assert!(
-            chain.verify_tx_proof(&txid, 1, merkle_tree).is_err(),
+        assert_eq!(
+            chain.verify_tx_proof(&txid, 1, merkle_tree),
+            Err(SPVFailure::MerkleRootMismatch),
             "wrong block height should error"
         );
 
+        let merkle_tree = GetMerkleRes {
+            block_height: 200,
+            pos: 0,
+            merkle: vec![],
+        };
+        assert_eq!(
+            chain.verify_tx_proof(&txid, 200, merkle_tree),
+            Err(SPVFailure::HeaderNotAvailable),
+            "height beyond the tip has no header"
+        );
+
         // first non-coinbase tx, changed first byte of merkle proof
         let txid = bitcoin::Txid::from_hex(
             "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
@@ -280,7 +592,10 @@ mod test {
                 78, 71, 61, 31, 44, 34, 11, 250, 120, 17, 28, 80, 130,
             ]],
         };
-        assert!(chain.verify_tx_proof(&txid, block_height as u32, merkle_tree).is_err());
+        assert_eq!(
+            chain.verify_tx_proof(&txid, block_height as u32, merkle_tree),
+            Err(SPVFailure::MerkleRootMismatch)
+        );
 
         assert!(
             chain.push(vec![chain.get(100).unwrap()]).is_err(),
@@ -305,4 +620,210 @@ mod test {
         );
         assert!(chain.get(200).is_err());
     }
+
+    /// mine `count` regtest headers after `prev`, `branch` makes them different from the headers
+    /// of other branches mined after the same `prev`
+    fn mine(prev: &BlockHeader, count: usize, branch: u32) -> Vec<BlockHeader> {
+        let mut headers = vec![];
+        let mut prev = *prev;
+        for _ in 0..count {
+            let mut header = BlockHeader {
+                version: 0x20000000,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: Default::default(),
+                time: prev.time + 600 + branch,
+                bits: prev.bits,
+                nonce: 0,
+            };
+            while header.validate_pow(&header.target()).is_err() {
+                header.nonce += 1;
+            }
+            headers.push(header);
+            prev = header;
+        }
+        headers
+    }
+
+    fn regtest_chain(temp: &TempDir) -> HeadersChain {
+        let mut path = temp.path().to_path_buf();
+        path.push("chain");
+        HeadersChain::new(path, Network::Regtest).unwrap()
+    }
+
+    #[test]
+    fn test_headers_extension() {
+        let temp = TempDir::new("temp_dir").unwrap();
+        let mut chain = regtest_chain(&temp);
+        let headers = mine(&chain.tip(), 5, 0);
+        chain.push(headers[..3].to_vec()).unwrap();
+        chain.push(headers[3..].to_vec()).unwrap();
+        assert_eq!(chain.height(), 5);
+        assert_eq!(chain.tip(), headers[4]);
+        assert_eq!(chain.get(1).unwrap(), headers[0]);
+    }
+
+    #[test]
+    fn test_headers_reorg() {
+        let temp = TempDir::new("temp_dir").unwrap();
+        let mut chain = regtest_chain(&temp);
+        let headers = mine(&chain.tip(), 5, 0);
+        chain.push(headers.clone()).unwrap();
+
+        // a competing tip with the same work is ignored
+        let same_work = mine(&headers[3], 1, 1);
+        assert!(matches!(chain.push(same_work), Err(Error::StaleFork)));
+        assert_eq!(chain.tip(), headers[4]);
+
+        // a 1-block reorg with more work replaces our tip
+        let fork = mine(&headers[3], 2, 1);
+        chain.push(fork.clone()).unwrap();
+        assert_eq!(chain.height(), 6);
+        assert_eq!(chain.get(4).unwrap(), headers[3]);
+        assert_eq!(chain.get(5).unwrap(), fork[0]);
+        assert_eq!(chain.tip(), fork[1]);
+
+        let reopened = regtest_chain(&temp);
+        assert_eq!(reopened.height(), 6);
+        assert_eq!(reopened.tip(), fork[1]);
+    }
+
+    #[test]
+    fn test_headers_reorg_too_deep() {
+        let temp = TempDir::new("temp_dir").unwrap();
+        let mut chain = regtest_chain(&temp);
+        let genesis = chain.tip();
+        let depth = MAX_REORG_DEPTH as usize + 2;
+        let headers = mine(&genesis, depth, 0);
+        chain.push(headers.clone()).unwrap();
+
+        let fork = mine(&genesis, depth + 1, 1);
+        assert!(matches!(chain.push(fork), Err(Error::ReorgTooDeep)));
+        assert_eq!(chain.height(), depth as u32);
+        assert_eq!(chain.tip(), headers[depth - 1]);
+    }
+
+    /// fetch from `server`, where the header at index `i` has height `i`, recording the start
+    /// height of each request
+    fn serve<'a>(
+        server: &'a [BlockHeader],
+        starts: &'a mut Vec<u32>,
+    ) -> impl FnMut(u32, usize) -> Result<Vec<BlockHeader>, Error> + 'a {
+        move |start, count| {
+            starts.push(start);
+            Ok(server.iter().skip(start as usize).take(count).cloned().collect())
+        }
+    }
+
+    #[test]
+    fn test_headers_pull_reorg() {
+        let temp = TempDir::new("temp_dir").unwrap();
+        let mut chain = regtest_chain(&temp);
+        let genesis = chain.tip();
+        let headers = mine(&genesis, 10, 0);
+        chain.push(headers.clone()).unwrap();
+
+        // the server chain reorged 3 blocks deep and grew by one
+        let mut server = vec![genesis];
+        server.extend(headers[..7].iter().cloned());
+        server.extend(mine(&headers[6], 4, 1));
+        let mut starts = vec![];
+        assert_eq!(chain.pull(2016, serve(&server, &mut starts)).unwrap(), 4);
+        assert_eq!(starts, vec![11, 10, 9, 7], "overlap doubles until the fork is found");
+        assert_eq!(chain.height(), 11);
+        assert_eq!(chain.tip(), server[11]);
+        assert_eq!(chain.get(7).unwrap(), headers[6]);
+        assert_eq!(chain.get(8).unwrap(), server[8]);
+
+        let mut starts = vec![];
+        assert_eq!(chain.pull(2016, serve(&server, &mut starts)).unwrap(), 0);
+        assert_eq!(starts, vec![12]);
+    }
+
+    #[test]
+    fn test_headers_pull_reorg_too_deep() {
+        let temp = TempDir::new("temp_dir").unwrap();
+        let mut chain = regtest_chain(&temp);
+        let genesis = chain.tip();
+        let depth = MAX_REORG_DEPTH as usize + 2;
+        let headers = mine(&genesis, depth, 0);
+        chain.push(headers.clone()).unwrap();
+
+        let mut server = vec![genesis];
+        server.extend(mine(&genesis, depth + 1, 1));
+        let mut starts = vec![];
+        let result = chain.pull(2016, serve(&server, &mut starts));
+        assert!(matches!(result, Err(Error::ReorgTooDeep)));
+        assert_eq!(starts.len(), 10, "overlaps 0, 1, 2, .., 128 and {}", MAX_REORG_DEPTH);
+        assert_eq!(*starts.last().unwrap(), depth as u32 + 1 - MAX_REORG_DEPTH);
+        assert_eq!(chain.tip(), headers[depth - 1]);
+    }
+
+    #[test]
+    fn test_headers_pruned() {
+        let temp = TempDir::new("temp_dir").unwrap();
+        let mut path = temp.path().to_path_buf();
+        path.push("chain");
+        assert!(HeadersChain::new_pruned(path.clone(), Network::Regtest, 100).is_err());
+        HeadersChain::open(temp.path().to_path_buf(), Network::Regtest, Some(144)).unwrap();
+        assert!(temp.path().join("headers_chain_regtest_pruned_144.recent").exists());
+        let mut chain = HeadersChain::new_pruned(path.clone(), Network::Regtest, 144).unwrap();
+
+        // a block containing only the coinbase has its txid as merkle root
+        let txid = Txid::from_slice(&[7u8; 32]).unwrap();
+        let mut headers = mine(&chain.tip(), 300, 0);
+        let mut block = mine(&headers[299], 1, 0)[0];
+        block.merkle_root = TxMerkleNode::from_slice(&txid[..]).unwrap();
+        while block.validate_pow(&block.target()).is_err() {
+            block.nonce += 1;
+        }
+        headers.push(block);
+        headers.extend(mine(&block, 699, 0));
+        chain.push(headers.clone()).unwrap();
+        assert_eq!(chain.height(), 1000);
+
+        // headers at multiple of the interval and the last ones are kept
+        let recent_size = 4 + (MAX_REORG_DEPTH as u64 + 1) * 80;
+        let size = |p: &str| std::fs::metadata(temp.path().join(p)).unwrap().len();
+        assert_eq!(size("chain"), (1000 / 144 + 1) * 80);
+        assert_eq!(size("chain.recent"), recent_size);
+        assert!(size("chain") + size("chain.recent") < 1000 * 80 / 4);
+        assert_eq!(chain.get(288).unwrap(), headers[287]);
+        assert_eq!(chain.get(1000).unwrap(), headers[999]);
+        assert!(chain.get(301).is_err());
+
+        let proof = || GetMerkleRes {
+            block_height: 301,
+            pos: 0,
+            merkle: vec![],
+        };
+        assert_eq!(chain.verify_tx_proof(&txid, 301, proof()), Err(SPVFailure::HeaderNotAvailable));
+        assert_eq!(chain.missing_headers(301), 301..432);
+        assert_eq!(chain.missing_headers(432), 432..432);
+        let needed = &headers[300..431];
+        assert_eq!(chain.verify_tx_proof_with_headers(&txid, 301, proof(), needed), Ok(()));
+
+        // headers not linked to the stored ones are refused
+        let mut forged = needed.to_vec();
+        forged[1].nonce += 1;
+        assert_eq!(
+            chain.verify_tx_proof_with_headers(&txid, 301, proof(), &forged),
+            Err(SPVFailure::HeaderNotAvailable)
+        );
+        assert_eq!(
+            chain.verify_tx_proof_with_headers(&txid, 301, proof(), &needed[1..]),
+            Err(SPVFailure::HeaderNotAvailable)
+        );
+
+        // the pruned chain is reloaded and still handles reorgs
+        let mut chain = HeadersChain::new_pruned(path, Network::Regtest, 144).unwrap();
+        assert_eq!(chain.height(), 1000);
+        assert_eq!(chain.tip(), headers[999]);
+        let fork = mine(&headers[997], 3, 1);
+        chain.push(fork.clone()).unwrap();
+        assert_eq!(chain.height(), 1001);
+        assert_eq!(chain.get(998).unwrap(), headers[997]);
+        assert_eq!(chain.tip(), fork[2]);
+        assert_eq!(size("chain.recent"), recent_size);
+        assert_eq!(chain.verify_tx_proof_with_headers(&txid, 301, proof(), needed), Ok(()));
+    }
 }
@@ -11,6 +11,7 @@ use bitcoin::hashes::Hash;
 use bitcoin::secp256k1::{Message, Secp256k1, Signature, VerifyOnly};
 use bitcoin::{PublicKey, Script, Txid};
 use electrum_client::GetMerkleRes;
+use gdk_common::model::SPVFailure;
 use gdk_common::ElementsNetwork;
 use log::info;
 
@@ -50,14 +51,15 @@ impl Verifier {
         txid: &Txid,
         merkle: GetMerkleRes,
         header: &elements::BlockHeader,
-    ) -> Result<(), Error> {
-        self.verify_header(header)?;
-        let root = compute_merkle_root(&txid, merkle)?;
+    ) -> Result<(), SPVFailure> {
+        self.verify_header(header).map_err(|_| SPVFailure::InvalidHeaderSignature)?;
+        let root =
+            compute_merkle_root(&txid, merkle).map_err(|_| SPVFailure::MerkleRootMismatch)?;
         if header.merkle_root == root {
             info!("proof for txid {}, block height {}, merkle root matches", txid, header.height);
             Ok(())
         } else {
-            Err(Error::InvalidHeaders)
+            Err(SPVFailure::MerkleRootMismatch)
         }
     }
 
@@ -154,9 +156,13 @@ impl Verifier {
 #[cfg(test)]
 mod test {
     use crate::headers::liquid::Verifier;
+    use bitcoin::hashes::Hash;
     use bitcoin::Script;
+    use bitcoin::Txid;
+    use electrum_client::GetMerkleRes;
     use elements::encode::deserialize;
     use elements::BlockExtData;
+    use gdk_common::model::SPVFailure;
     use gdk_common::ElementsNetwork;
     use hex;
     use rand::seq::SliceRandom;
@@ -196,6 +202,22 @@ mod test {
         wrong_header.height = 0;
         assert!(verifier.verify_header(&wrong_header).is_err());
 
+        // a proof that doesn't lead to the header merkle root vs a header that doesn't verify
+        let proof = || GetMerkleRes {
+            block_height: 1,
+            pos: 0,
+            merkle: vec![],
+        };
+        let txid = Txid::from_slice(&[1u8; 32]).unwrap();
+        assert_eq!(
+            verifier.verify_tx_proof(&txid, proof(), &block_header),
+            Err(SPVFailure::MerkleRootMismatch)
+        );
+        assert_eq!(
+            verifier.verify_tx_proof(&txid, proof(), &wrong_header),
+            Err(SPVFailure::InvalidHeaderSignature)
+        );
+
         let mut wrong_header = block_header.clone();
         if let BlockExtData::Proof {
             challenge,
@@ -1,17 +1,18 @@
 use crate::determine_electrum_url_from_net;
 use crate::error::Error;
-use crate::headers::bitcoin::HeadersChain;
+use crate::headers::bitcoin::{HeadersChain, MAX_REORG_DEPTH};
 use crate::headers::liquid::Verifier;
+use crate::retry::RetryClient;
 use ::bitcoin::hashes::{hex::FromHex, sha256, sha256d, Hash};
 use ::bitcoin::{TxMerkleNode, Txid};
 use aes_gcm_siv::aead::{generic_array::GenericArray, Aead, NewAead};
 use aes_gcm_siv::Aes256GcmSiv;
-use electrum_client::{ElectrumApi, GetMerkleRes};
-use gdk_common::model::{SPVVerifyResult, SPVVerifyTx};
+use electrum_client::{Client, ElectrumApi, GetMerkleRes};
+use gdk_common::model::{SPVFailure, SPVVerifyResult, SPVVerifyTx};
 use gdk_common::NetworkId;
-use log::info;
+use log::{info, warn};
 use rand::{thread_rng, Rng};
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::PathBuf;
@@ -50,6 +51,23 @@ fn compute_merkle_root(txid: &Txid, merkle: GetMerkleRes) -> Result<TxMerkleNode
     Ok(TxMerkleNode::from_slice(&current)?)
 }
 
+/// download the merkle proof of `txid` at `height`, a server error means the tx is not in that
+/// block while connection errors are returned as such
+pub(crate) fn get_merkle(
+    client: &Client,
+    txid: &Txid,
+    height: u32,
+) -> Result<Result<GetMerkleRes, SPVFailure>, Error> {
+    match client.transaction_get_merkle(txid, height as usize) {
+        Ok(proof) => Ok(Ok(proof)),
+        Err(electrum_client::Error::Protocol(e)) => {
+            warn!("no proof for {} at height {}: {}", txid, height, e);
+            Ok(Err(SPVFailure::NotInBlock))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
 lazy_static! {
     static ref SPV_MUTEX: Mutex<()> = Mutex::new(());
 }
@@ -60,31 +78,54 @@ pub fn spv_verify_tx(input: &SPVVerifyTx) -> Result<SPVVerifyResult, Error> {
 
     info!("spv_verify_tx {:?}", input);
     let txid = Txid::from_hex(&input.txid)?;
+    if input.height == 0 {
+        // mempool txs have no merkle proof
+        return Ok(SPVVerifyResult::Unconfirmed);
+    }
 
     let mut cache: VerifiedCache =
         VerifiedCache::new(&input.path, input.network.id(), &input.encryption_key)?;
-    if cache.contains(&txid)? {
+    if cache.contains(&txid, input.height)? {
         info!("verified cache hit for {}", txid);
         return Ok(SPVVerifyResult::Verified);
     }
 
-    let url = determine_electrum_url_from_net(&input.network)?;
-    let client = url.build_client()?;
+    let mut network = input.network.clone();
+    if network.socks5.is_none() {
+        network.socks5 = input.tor_proxy.clone();
+    }
+    let url = determine_electrum_url_from_net(&network)?;
+    let client = RetryClient::connect(&url, &network)?;
 
     match input.network.id() {
         NetworkId::Bitcoin(bitcoin_network) => {
-            let mut path: PathBuf = (&input.path).into();
-            path.push(format!("headers_chain_{}", bitcoin_network));
-            let mut chain = HeadersChain::new(path, bitcoin_network)?;
+            let path: PathBuf = (&input.path).into();
+            let mut chain =
+                HeadersChain::open(path, bitcoin_network, input.headers_checkpoint_interval)?;
 
             if input.height < chain.height() {
                 info!("chain height ({}) enough to verify, downloading proof", chain.height());
-                let proof = client.transaction_get_merkle(&txid, input.height as usize)?;
-                if chain.verify_tx_proof(&txid, input.height, proof).is_ok() {
-                    cache.write(&txid)?;
-                    Ok(SPVVerifyResult::Verified)
+                let proof =
+                    match client.call("merkle proof", |c| get_merkle(c, &txid, input.height))? {
+                        Ok(proof) => proof,
+                        Err(reason) => return Ok(SPVVerifyResult::NotVerified(reason)),
+                    };
+                let missing = chain.missing_headers(input.height);
+                let headers = if missing.start == missing.end {
+                    vec![]
                 } else {
-                    Ok(SPVVerifyResult::NotVerified)
+                    let (start, count) = (missing.start as usize, missing.len());
+                    client.call("headers", |c| Ok(c.block_headers(start, count)?))?.headers
+                };
+                match chain.verify_tx_proof_with_headers(&txid, input.height, proof, &headers) {
+                    Ok(()) => {
+                        cache.write(&txid, input.height)?;
+                        Ok(SPVVerifyResult::Verified)
+                    }
+                    Err(reason) => {
+                        warn!("proof for {} not verified: {}", txid, reason);
+                        Ok(SPVVerifyResult::NotVerified(reason))
+                    }
                 }
             } else {
                 info!(
@@ -92,32 +133,47 @@ pub fn spv_verify_tx(input: &SPVVerifyTx) -> Result<SPVVerifyResult, Error> {
                     chain.height()
                 );
                 let headers_to_download = input.headers_to_download.unwrap_or(2016).min(2016);
-                let headers =
-                    client.block_headers(chain.height() as usize + 1, headers_to_download)?.headers;
-                if let Err(Error::InvalidHeaders) = chain.push(headers) {
-                    // handle reorgs
-                    chain.remove(144)?;
+                let pulled = chain.pull(headers_to_download, |start, count| {
+                    let start = start as usize;
+                    Ok(client.call("headers", |c| Ok(c.block_headers(start, count)?))?.headers)
+                });
+                match pulled {
+                    // reorgs deeper than MAX_REORG_DEPTH and broken IO writes
+                    Err(Error::InvalidHeaders) | Err(Error::ReorgTooDeep) => {
+                        chain.remove(MAX_REORG_DEPTH)?
+                    }
+                    // a fork without more work keeps our chain
+                    _ => (),
                 }
                 Ok(SPVVerifyResult::InProgress)
             }
         }
         NetworkId::Elements(elements_network) => {
-            let proof = client.transaction_get_merkle(&txid, input.height as usize)?;
+            let proof = match client.call("merkle proof", |c| get_merkle(c, &txid, input.height))? {
+                Ok(proof) => proof,
+                Err(reason) => return Ok(SPVVerifyResult::NotVerified(reason)),
+            };
             let verifier = Verifier::new(elements_network);
-            let header_bytes = client.block_header_raw(input.height as usize)?;
+            let header_bytes =
+                client.call("header", |c| Ok(c.block_header_raw(input.height as usize)?))?;
             let header: elements::BlockHeader = elements::encode::deserialize(&header_bytes)?;
-            if verifier.verify_tx_proof(&txid, proof, &header).is_ok() {
-                cache.write(&txid)?;
-                Ok(SPVVerifyResult::Verified)
-            } else {
-                Ok(SPVVerifyResult::NotVerified)
+            match verifier.verify_tx_proof(&txid, proof, &header) {
+                Ok(()) => {
+                    cache.write(&txid, input.height)?;
+                    Ok(SPVVerifyResult::Verified)
+                }
+                Err(reason) => {
+                    warn!("proof for {} not verified: {}", txid, reason);
+                    Ok(SPVVerifyResult::NotVerified(reason))
+                }
             }
         }
     }
 }
 
+/// txs verified by `spv_verify_tx` with the height they have been verified at
 struct VerifiedCache {
-    set: HashSet<Txid>,
+    set: HashMap<Txid, u32>,
     filepath: PathBuf,
     cipher: Aes256GcmSiv,
 }
@@ -132,7 +188,7 @@ impl VerifiedCache {
         let cipher = Aes256GcmSiv::new(GenericArray::from_slice(&key_bytes));
         let set = match VerifiedCache::read_and_decrypt(&mut filepath, &cipher) {
             Ok(set) => set,
-            Err(_) => HashSet::new(),
+            Err(_) => HashMap::new(),
         };
         Ok(VerifiedCache {
             set,
@@ -144,7 +200,7 @@ impl VerifiedCache {
     fn read_and_decrypt(
         filepath: &mut PathBuf,
         cipher: &Aes256GcmSiv,
-    ) -> Result<HashSet<Txid>, Error> {
+    ) -> Result<HashMap<Txid, u32>, Error> {
         let mut file = File::open(&filepath)?;
         let mut nonce_bytes = [0u8; 12]; // 96 bits
         file.read_exact(&mut nonce_bytes)?;
@@ -155,12 +211,27 @@ impl VerifiedCache {
         Ok(serde_cbor::from_slice(&plaintext)?)
     }
 
-    fn contains(&self, txid: &Txid) -> Result<bool, Error> {
-        Ok(self.set.contains(txid))
+    /// true if `txid` has been verified at `height`. If it has been verified at another height
+    /// the tx may have been reorged, the entry is removed so that it's verified again
+    fn contains(&mut self, txid: &Txid, height: u32) -> Result<bool, Error> {
+        match self.set.get(txid) {
+            Some(h) if *h == height => Ok(true),
+            Some(h) => {
+                info!("{} verified at height {} now at {}, invalidating", txid, h, height);
+                self.set.remove(txid);
+                self.save()?;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn write(&mut self, txid: &Txid, height: u32) -> Result<(), Error> {
+        self.set.insert(txid.clone(), height);
+        self.save()
     }
 
-    fn write(&mut self, txid: &Txid) -> Result<(), Error> {
-        self.set.insert(txid.clone());
+    fn save(&self) -> Result<(), Error> {
         let mut file = File::create(&self.filepath)?;
         let mut nonce_bytes = [0u8; 12]; // 96 bits
         thread_rng().fill(&mut nonce_bytes);
@@ -172,3 +243,44 @@ impl VerifiedCache {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::headers::{spv_verify_tx, VerifiedCache};
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::Txid;
+    use gdk_common::model::{SPVVerifyResult, SPVVerifyTx};
+    use gdk_common::network::Network;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_verified_cache() {
+        let dir = TempDir::new("verified_cache").unwrap();
+        let mut network = Network::default();
+        // nothing listens here, every call reaching the server fails
+        network.electrum_url = Some("127.0.0.1:1".into());
+        network.electrum_retries = Some(1);
+        let txid = "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098";
+        let mut input = SPVVerifyTx {
+            txid: txid.into(),
+            height: 100,
+            path: dir.path().to_str().unwrap().into(),
+            network: network.clone(),
+            encryption_key: "key".into(),
+            ..Default::default()
+        };
+
+        let id = network.id();
+        let mut cache = VerifiedCache::new(&input.path, id, &input.encryption_key).unwrap();
+        cache.write(&Txid::from_hex(txid).unwrap(), 100).unwrap();
+
+        assert!(matches!(spv_verify_tx(&input), Ok(SPVVerifyResult::Verified)));
+        assert!(matches!(spv_verify_tx(&input), Ok(SPVVerifyResult::Verified)));
+
+        // a different height invalidates the cached verification
+        input.height = 101;
+        assert!(spv_verify_tx(&input).is_err());
+        input.height = 100;
+        assert!(spv_verify_tx(&input).is_err());
+    }
+}
@@ -1,67 +1,193 @@
-use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::{Instruction, Script};
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::hashes::{hex::FromHex, Hash};
+use bitcoin::secp256k1::recovery::{RecoverableSignature, RecoveryId};
 use bitcoin::secp256k1::{self, All, Message, Secp256k1};
-use bitcoin::util::address::Address;
-use bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+use bitcoin::util::address::{Address, AddressType};
+use bitcoin::util::bip32::{
+    ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint,
+};
+use bitcoin::util::misc::signed_msg_hash;
+use bitcoin::util::psbt::PartiallySignedTransaction;
 use bitcoin::{BlockHash, PublicKey, SigHashType, Txid};
 use elements;
-use gdk_common::model::{AddressAmount, Balances, GetTransactionsOpt, SPVVerifyResult};
+use gdk_common::model::{
+    AddressAmount, AssetHolding, BalanceDetail, Balances, BundlePrevout, GainLot,
+    GetTransactionsOpt, InvoicePayment, LotMethod, OutputInfo, SPVVerifyResult, SignedInput,
+    TxGraph, TxWarning, VerificationBundle,
+};
 use hex;
-use log::{info, trace};
+use log::{info, trace, warn};
 use rand::Rng;
 
 use gdk_common::mnemonic::Mnemonic;
-use gdk_common::model::{AddressPointer, CreateTransaction, Settings, TransactionMeta};
+use gdk_common::model::{now, AddressPointer, CreateTransaction, Settings, TransactionMeta};
 use gdk_common::network::{ElementsNetwork, Network, NetworkId};
-use gdk_common::scripts::{p2pkh_script, p2shwpkh_script, p2shwpkh_script_sig};
+use gdk_common::scripts::{
+    is_standard_output_script, p2pkh_script, p2shwpkh_script, p2shwpkh_script_sig, ScriptType,
+};
 use gdk_common::wally::*;
 
+use crate::coinselect;
 use crate::error::*;
+use crate::retry::RetryClient;
 use crate::store::*;
+use crate::unblind_output;
 
 use bitcoin::util::bip143::SigHashCache;
 use electrum_client::raw_client::RawClient;
-use electrum_client::Client;
+use electrum_client::{Client, ElectrumApi};
 use elements::confidential::{Asset, Nonce, Value};
 use gdk_common::be::{self, *};
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
+use socks::Socks5Stream;
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
+use std::net::TcpStream;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 pub struct WalletCtx {
     pub secp: Secp256k1<All>,
     pub network: Network,
-    pub mnemonic: Mnemonic,
+    pub mnemonic: Option<Mnemonic>, // None in watch-only wallets
     pub store: Store,
-    pub xprv: ExtendedPrivKey,
+    pub xprv: Option<ExtendedPrivKey>, // None in watch-only wallets
     pub xpub: ExtendedPubKey,
+    pub master_fingerprint: Fingerprint,
     pub master_blinding: Option<MasterBlindingKey>,
-    pub change_max_deriv: u32,
+    pub archived: bool,
+    /// script type of the wallet addresses, see `StoreMeta::script_type`
+    pub script_type: ScriptType,
+    /// BIP44 account of `xpub`, see `account_path`. Every account has its own db
+    pub account: u32,
 }
 
+/// maximum length in bytes of a tx memo
+pub const MAX_MEMO_LEN: usize = 1024;
+
+/// the last value of every variant is the optional socks5 proxy the connection goes through
 #[derive(Clone)]
 pub enum ElectrumUrl {
-    Tls(String, bool), // the bool value indicates if the domain name should be validated
-    Plaintext(String),
+    Tls(String, TlsValidation, Option<String>),
+    Plaintext(String, Option<String>),
+}
+
+/// how the certificate of a TLS electrum server is checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsValidation {
+    /// the certificate must be valid for the server domain name
+    ValidateDomain,
+    /// any certificate is accepted, e.g. self-signed ones of self-hosted servers
+    AcceptInvalid,
+    /// only the certificate with this sha256 of its DER encoding is accepted, whoever signed it
+    Pinned([u8; 32]),
+}
+
+impl TlsValidation {
+    /// `Network::validate_domain`, missing means `ValidateDomain`: invalid certificates are
+    /// accepted only when asked explicitly
+    pub fn from_validate_domain(validate_domain: Option<bool>) -> Self {
+        if validate_domain.unwrap_or(true) {
+            TlsValidation::ValidateDomain
+        } else {
+            TlsValidation::AcceptInvalid
+        }
+    }
+
+    /// `Network::electrum_cert_sha256`, the hex of the certificate sha256
+    pub fn pinned(sha256: &str) -> Result<Self, Error> {
+        let bytes = hex::decode(sha256).map_err(|_| Error::Generic("invalid pin hex".into()))?;
+        let pin = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::Generic("pinned sha256 must be 32 bytes".into()))?;
+        Ok(TlsValidation::Pinned(pin))
+    }
+
+    /// the flag of the electrum client TLS connection
+    pub fn validate_domain(&self) -> bool {
+        *self == TlsValidation::ValidateDomain
+    }
 }
 
 impl ElectrumUrl {
     pub fn build_client(&self) -> Result<Client, Error> {
         match self {
-            ElectrumUrl::Tls(url, validate) => {
-                let client = RawClient::new_ssl(url.as_str(), *validate)?;
+            ElectrumUrl::Tls(url, TlsValidation::Pinned(pin), proxy) => {
+                let stream = pinned_tls_stream(url, proxy.as_deref(), pin)?;
+                Ok(Client::SSL(RawClient::from(stream)))
+            }
+            ElectrumUrl::Tls(url, validation, None) => {
+                let client = RawClient::new_ssl(url.as_str(), validation.validate_domain())?;
                 Ok(Client::SSL(client))
             }
-            ElectrumUrl::Plaintext(url) => {
+            ElectrumUrl::Tls(url, validation, Some(proxy)) => {
+                let validate = validation.validate_domain();
+                let client = RawClient::new_proxy_ssl(url.as_str(), validate, proxy.as_str())?;
+                Ok(Client::SSL(client))
+            }
+            ElectrumUrl::Plaintext(url, None) => {
                 let client = RawClient::new(&url)?;
                 Ok(Client::TCP(client))
             }
+            ElectrumUrl::Plaintext(url, Some(proxy)) => {
+                let client = RawClient::new_proxy(url.as_str(), proxy.as_str())?;
+                Ok(Client::Socks5(client))
+            }
         }
     }
 }
 
+/// open a TLS connection to `url`, through the socks5 `proxy` if any, with a server whose
+/// certificate has sha256 `pin`. The certificate chain and the domain aren't checked, the pin
+/// replaces them
+fn pinned_tls_stream(
+    url: &str,
+    proxy: Option<&str>,
+    pin: &[u8; 32],
+) -> Result<SslStream<TcpStream>, Error> {
+    let tls_err = |e: &dyn std::fmt::Display| Error::Generic(format!("tls to {}: {}", url, e));
+    let domain = url.rsplitn(2, ':').last().unwrap_or(url);
+    let tcp = match proxy {
+        Some(proxy) => Socks5Stream::connect(proxy, url)?.into_inner(),
+        None => TcpStream::connect(url)?,
+    };
+    let mut connector = SslConnector::builder(SslMethod::tls()).map_err(|e| tls_err(&e))?;
+    connector.set_verify(SslVerifyMode::NONE);
+    let mut config = connector.build().configure().map_err(|e| tls_err(&e))?;
+    config.set_verify_hostname(false);
+    let stream = config.connect(domain, tcp).map_err(|e| tls_err(&e))?;
+    let certificate = stream.ssl().peer_certificate().ok_or_else(|| tls_err(&"no certificate"))?;
+    let sha256 = certificate.digest(MessageDigest::sha256()).map_err(|e| tls_err(&e))?;
+    if sha256.as_ref() != &pin[..] {
+        return Err(Error::PinnedCertificateMismatch(hex::encode(sha256)));
+    }
+    Ok(stream)
+}
+
+/// check `proxy` is a `host:port` socks5 proxy address, optionally prefixed by `socks5://`,
+/// returns it without the prefix
+pub fn parse_socks5(proxy: &str) -> Result<String, Error> {
+    let address = proxy.trim_start_matches("socks5://");
+    let err = || Error::Generic(format!("invalid socks5 proxy {:?}", proxy));
+    let mut parts = address.rsplitn(2, ':');
+    let port = parts.next().ok_or_else(err)?;
+    let host = parts.next().ok_or_else(err)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || host.contains('/') || host.contains(char::is_whitespace) {
+        return Err(err());
+    }
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(address.to_string()),
+        _ => Err(err()),
+    }
+}
+
 impl WalletCtx {
     pub fn new(
         store: Store,
@@ -69,25 +195,225 @@ impl WalletCtx {
         network: Network,
         xprv: ExtendedPrivKey,
         xpub: ExtendedPubKey,
+        master_fingerprint: Fingerprint,
         master_blinding: Option<MasterBlindingKey>,
     ) -> Result<Self, Error> {
+        let (archived, script_type) = {
+            let store_read = store.read()?;
+            (store_read.is_archived(), store_read.script_type())
+        };
         Ok(WalletCtx {
-            mnemonic,
+            mnemonic: Some(mnemonic),
             store,
             network, // TODO: from db
             secp: Secp256k1::gen_new(),
-            xprv,
+            xprv: Some(xprv),
+            xpub,
+            master_fingerprint,
+            master_blinding,
+            archived,
+            script_type,
+            account: 0,
+        })
+    }
+
+    /// create a wallet able to sync, show balance and create (but not sign) transactions
+    pub fn new_watch_only(
+        store: Store,
+        network: Network,
+        xpub: ExtendedPubKey,
+        master_fingerprint: Fingerprint,
+        master_blinding: Option<MasterBlindingKey>,
+    ) -> Result<Self, Error> {
+        let (archived, script_type) = {
+            let store_read = store.read()?;
+            (store_read.is_archived(), store_read.script_type())
+        };
+        Ok(WalletCtx {
+            mnemonic: None,
+            store,
+            network,
+            secp: Secp256k1::gen_new(),
+            xprv: None,
             xpub,
+            master_fingerprint,
             master_blinding,
-            change_max_deriv: 0,
+            archived,
+            script_type,
+            account: 0,
         })
     }
 
-    pub fn get_mnemonic(&self) -> &Mnemonic {
-        &self.mnemonic
+    /// open, or create, the wallet of the BIP44 `account` of `master_xprv` with addresses of
+    /// `script_type`. The db of the account is in `db_root` and its name depends on the account
+    /// xpub, so that the addresses and the history of every account are tracked independently
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_account<P: AsRef<Path>>(
+        db_root: P,
+        mnemonic: Mnemonic,
+        master_xprv: &ExtendedPrivKey,
+        script_type: ScriptType,
+        account: u32,
+        master_blinding: Option<MasterBlindingKey>,
+        network: Network,
+    ) -> Result<Self, Error> {
+        let secp = Secp256k1::new();
+        let path = account_path(network.id(), script_type, account)?;
+        info!("opening account {} with derivation path {}", account, path);
+        let xprv = master_xprv.derive_priv(&secp, &path)?;
+        let xpub = ExtendedPubKey::from_private(&secp, &xprv);
+        let db_path = db_root.as_ref().join(crate::wallet_db_name(&xpub, &network));
+        let mut store = StoreMeta::new(&db_path, xpub, master_blinding.clone(), network.id())?;
+        store.set_script_type(script_type)?;
+        let store = Arc::new(RwLock::new(store));
+        let master_fingerprint = master_xprv.fingerprint(&secp);
+        let mut wallet = WalletCtx::new(
+            store,
+            mnemonic,
+            network,
+            xprv,
+            xpub,
+            master_fingerprint,
+            master_blinding,
+        )?;
+        wallet.account = account;
+        Ok(wallet)
+    }
+
+    /// open, or create, the db `wallet_name` in `db_root` of a watch-only wallet, see
+    /// `new_watch_only`
+    pub fn open_watch_only<P: AsRef<Path>>(
+        db_root: P,
+        wallet_name: &str,
+        xpub: ExtendedPubKey,
+        master_fingerprint: Fingerprint,
+        master_blinding: Option<MasterBlindingKey>,
+        network: Network,
+    ) -> Result<Self, Error> {
+        let path = db_root.as_ref().join(wallet_name);
+        info!("opening watch-only wallet db {:?}", path);
+        let store = StoreMeta::new(&path, xpub, master_blinding.clone(), network.id())?;
+        let store = Arc::new(RwLock::new(store));
+        WalletCtx::new_watch_only(store, network, xpub, master_fingerprint, master_blinding)
+    }
+
+    /// export what is needed to recreate this wallet as watch-only on another device, in a
+    /// compact string suitable for a QR code:
+    /// `gdkwatch<version>:<network>:<master fingerprint>:<script type>:<xpub>[:<master blinding>]`
+    pub fn export_watch_blob(&self) -> Result<String, Error> {
+        let mut fields = vec![
+            format!("gdkwatch{}", WATCH_BLOB_VERSION),
+            network_name(self.network.id()).to_string(),
+            self.master_fingerprint.to_string(),
+            self.script_type.to_string(),
+            self.xpub.to_string(),
+        ];
+        if let NetworkId::Elements(_) = self.network.id() {
+            let master_blinding = self.master_blinding()?;
+            fields.push(hex::encode(&master_blinding.0[..]));
+        }
+        Ok(fields.join(":"))
+    }
+
+    /// recreate a watch-only wallet from a string made by `export_watch_blob`, `network` must
+    /// be the same network of the exported wallet
+    pub fn import_watch_blob(
+        blob: &str,
+        network: Network,
+        backend: Box<dyn StoreBackend>,
+    ) -> Result<Self, Error> {
+        let invalid = || Error::Generic("invalid watch-only blob".into());
+        let fields: Vec<&str> = blob.split(':').collect();
+        let is_liquid = network.liquid;
+        match (fields.get(0), fields.len(), is_liquid) {
+            (Some(prefix), 5, false) | (Some(prefix), 6, true)
+                if *prefix == format!("gdkwatch{}", WATCH_BLOB_VERSION) => {}
+            _ => return Err(invalid()),
+        }
+        if fields[1] != network_name(network.id()) {
+            return Err(Error::Generic(format!("watch-only blob is for network {}", fields[1])));
+        }
+        let master_fingerprint = Fingerprint::from_str(fields[2]).map_err(|_| invalid())?;
+        let script_type = ScriptType::from_str(fields[3])?;
+        let xpub = ExtendedPubKey::from_str(fields[4])?;
+        let master_blinding = match fields.get(5) {
+            Some(field) => {
+                let bytes = hex::decode(field)?;
+                if bytes.len() != 64 {
+                    return Err(invalid());
+                }
+                let mut key = [0u8; 64];
+                key.copy_from_slice(&bytes);
+                Some(MasterBlindingKey(key))
+            }
+            None => None,
+        };
+        let mut store =
+            StoreMeta::with_backend(backend, xpub, master_blinding.clone(), network.id())?;
+        store.set_script_type(script_type)?;
+        let store = Arc::new(RwLock::new(store));
+        WalletCtx::new_watch_only(store, network, xpub, master_fingerprint, master_blinding)
+    }
+
+    pub fn is_watch_only(&self) -> bool {
+        self.xprv.is_none()
+    }
+
+    fn signing_key(&self) -> Result<&ExtendedPrivKey, Error> {
+        self.xprv.as_ref().ok_or(Error::WatchOnly)
+    }
+
+    fn master_blinding(&self) -> Result<&MasterBlindingKey, Error> {
+        self.master_blinding.as_ref().ok_or(Error::MissingBlindingKey)
+    }
+
+    /// archive or restore the wallet, an archived wallet keeps syncing but refuses to spend
+    pub fn set_archived(&mut self, archived: bool) -> Result<(), Error> {
+        info!("set_archived {}", archived);
+        self.store.write()?.set_archived(archived)?;
+        self.archived = archived;
+        Ok(())
+    }
+
+    /// derive the wallet addresses with `script_type`, the wallet must be synced again afterwards
+    /// since the history found with the previous scripts is dropped
+    pub fn set_script_type(&mut self, script_type: ScriptType) -> Result<(), Error> {
+        info!("set_script_type {}", script_type);
+        self.store.write()?.set_script_type(script_type)?;
+        self.script_type = script_type;
+        Ok(())
+    }
+
+    pub fn check_not_archived(&self) -> Result<(), Error> {
+        if self.archived {
+            return Err(Error::WalletArchived);
+        }
+        Ok(())
+    }
+
+    /// when enabled change is sent to fresh receive addresses (m/0/*) instead of m/1/*,
+    /// sync scans both chains so change is found anyway when restoring the wallet
+    pub fn set_change_on_receive_chain(&self, enabled: bool) -> Result<(), Error> {
+        info!("set_change_on_receive_chain {}", enabled);
+        self.store.write()?.set_change_on_receive_chain(enabled)
+    }
+
+    pub fn get_mnemonic(&self) -> Result<&Mnemonic, Error> {
+        self.mnemonic.as_ref().ok_or(Error::WatchOnly)
     }
 
     fn derive_address(&self, xpub: &ExtendedPubKey, path: [u32; 2]) -> Result<BEAddress, Error> {
+        self.derive_blinded_address(xpub, path, None)
+    }
+
+    /// like `derive_address`, in liquid the blinding key is derived also from `invoice_nonce` if
+    /// given, see `get_invoice_address`
+    fn derive_blinded_address(
+        &self,
+        xpub: &ExtendedPubKey,
+        path: [u32; 2],
+        invoice_nonce: Option<u32>,
+    ) -> Result<BEAddress, Error> {
         let path: Vec<ChildNumber> = path
             .iter()
             .map(|x| ChildNumber::Normal {
@@ -97,16 +423,20 @@ impl WalletCtx {
         let derived = xpub.derive_pub(&self.secp, &path)?;
         match self.network.id() {
             NetworkId::Bitcoin(network) => {
-                Ok(BEAddress::Bitcoin(Address::p2shwpkh(&derived.public_key, network).unwrap()))
+                let script = self.script_type.script_pubkey(&derived.public_key);
+                let address = Address::from_script(&script, network)
+                    .ok_or_else(fn_err("script without address form"))?;
+                Ok(BEAddress::Bitcoin(address))
             }
             NetworkId::Elements(network) => {
-                let master_blinding_key = self
-                    .master_blinding
-                    .as_ref()
-                    .expect("we are in elements but master blinding is None");
+                let master_blinding_key = self.master_blinding()?;
                 let script = p2shwpkh_script(&derived.public_key);
-                let blinding_key =
-                    asset_blinding_key_to_ec_private_key(&master_blinding_key, &script);
+                let blinding_key = match invoice_nonce {
+                    None => asset_blinding_key_to_ec_private_key(&master_blinding_key, &script),
+                    Some(nonce) => {
+                        invoice_blinding_key_to_ec_private_key(&master_blinding_key, &script, nonce)
+                    }
+                };
                 let public_key = ec_public_key_from_private_key(blinding_key);
                 let blinder = Some(public_key);
                 let addr = elements::Address::p2shwpkh(
@@ -129,166 +459,409 @@ impl WalletCtx {
         Ok(())
     }
 
+    /// height and hash of the blockchain tip recorded by the last sync
     pub fn get_tip(&self) -> Result<(u32, BlockHash), Error> {
         Ok(self.store.read()?.cache.tip)
     }
 
+    /// set the user memo of `txid`, an empty memo removes it
+    pub fn set_tx_memo(&self, txid: &Txid, memo: &str) -> Result<(), Error> {
+        if memo.len() > MAX_MEMO_LEN {
+            return Err(Error::MemoTooLong(memo.len()));
+        }
+        let mut store_write = self.store.write()?;
+        if memo.is_empty() {
+            store_write.remove_memo(txid)
+        } else {
+            store_write.insert_memo(*txid, memo)
+        }
+    }
+
+    pub fn get_tx_memo(&self, txid: &Txid) -> Result<Option<String>, Error> {
+        Ok(self.store.read()?.get_memo(txid).cloned())
+    }
+
+    /// true if the tip recorded by the last sync is the current tip of the server
+    pub fn is_synced(&self, client: &Client) -> Result<bool, Error> {
+        let server_height = client.block_headers_subscribe_raw()?.height as u32;
+        self.is_synced_at(server_height)
+    }
+
+    /// true if the recorded tip is not behind `server_height`
+    fn is_synced_at(&self, server_height: u32) -> Result<bool, Error> {
+        let (height, _) = self.get_tip()?;
+        trace!("is_synced tip:{} server tip:{}", height, server_height);
+        Ok(height >= server_height)
+    }
+
     pub fn list_tx(&self, opt: &GetTransactionsOpt) -> Result<Vec<TransactionMeta>, Error> {
         let store_read = self.store.read()?;
+        let my_txids = sorted_history(&store_read.cache.heights);
+        let page: Vec<Txid> =
+            my_txids.iter().skip(opt.first).take(opt.count).map(|(txid, _)| **txid).collect();
+        drop(my_txids);
+        drop(store_read);
+        self.unblind_missing(&page)?;
+        let store_read = self.store.read()?;
 
         let mut txs = vec![];
-        let mut my_txids: Vec<(&Txid, &Option<u32>)> = store_read.cache.heights.iter().collect();
-        my_txids.sort_by(|a, b| {
-            let height_cmp = b.1.unwrap_or(std::u32::MAX).cmp(&a.1.unwrap_or(std::u32::MAX));
-            match height_cmp {
-                Ordering::Equal => b.0.cmp(a.0),
-                h @ _ => h,
-            }
-        });
+        let final_confirmations =
+            store_read.get_settings().unwrap_or_default().final_confirmations();
+        let tip = store_read.cache.tip.0;
 
-        for (tx_id, height) in my_txids.iter().skip(opt.first).take(opt.count) {
-            trace!("tx_id {}", tx_id);
+        for tx_id in page.iter() {
+            // a sync may have removed the tx in between
+            let height = match store_read.cache.heights.get(tx_id) {
+                Some(height) => *height,
+                None => continue,
+            };
+            txs.push(self.tx_meta(&store_read, tx_id, height, final_confirmations, tip)?);
+        }
+        info!("list_tx {:?}", txs.iter().map(|e| &e.txid).collect::<Vec<&String>>());
 
-            let tx = store_read
-                .cache
-                .all_txs
-                .get(*tx_id)
-                .ok_or_else(fn_err(&format!("list_tx no tx {}", tx_id)))?;
-            let header = height.map(|h| store_read.cache.headers.get(&h)).flatten();
-            trace!("tx_id {} header {:?}", tx_id, header);
-            let mut addressees = vec![];
-            for i in 0..tx.output_len() as u32 {
-                let script = tx.output_script(i);
-                if !script.is_empty() && !store_read.cache.paths.contains_key(&script) {
-                    let address = tx.output_address(i, self.network.id());
-                    trace!("tx_id {}:{} not my script, address {:?}", tx_id, i, address);
-                    addressees.push(AddressAmount {
-                        address: address.unwrap_or_else(|| "".to_string()),
-                        satoshi: 0, // apparently not needed in list_tx addressees
-                        asset_tag: None,
-                    });
-                }
+        Ok(txs)
+    }
+
+    /// the details of the wallet tx `txid`, with the same fields of its entry in `list_tx`,
+    /// `None` if the tx isn't in the wallet history
+    pub fn get_tx_detail(&self, txid: &Txid) -> Result<Option<TransactionMeta>, Error> {
+        self.unblind_missing(std::iter::once(txid))?;
+        let store_read = self.store.read()?;
+        let height = match store_read.cache.heights.get(txid) {
+            Some(height) => *height,
+            None => return Ok(None),
+        };
+        let final_confirmations =
+            store_read.get_settings().unwrap_or_default().final_confirmations();
+        let tip = store_read.cache.tip.0;
+        Ok(Some(self.tx_meta(&store_read, txid, height, final_confirmations, tip)?))
+    }
+
+    /// build the `TransactionMeta` of the wallet tx `tx_id` at `height`, looking up in the store
+    /// only the tx and its previous outputs
+    fn tx_meta(
+        &self,
+        store_read: &StoreMeta,
+        tx_id: &Txid,
+        height: Option<u32>,
+        final_confirmations: u32,
+        tip: u32,
+    ) -> Result<TransactionMeta, Error> {
+        trace!("tx_id {}", tx_id);
+
+        let tx = store_read
+            .cache
+            .all_txs
+            .get(tx_id)
+            .ok_or_else(fn_err(&format!("tx_meta no tx {}", tx_id)))?;
+        let header = height.map(|h| store_read.cache.headers.get(&h)).flatten();
+        trace!("tx_id {} header {:?}", tx_id, header);
+        let unblinded = &store_read.cache.unblinded;
+        let mut addressees = vec![];
+        let mut outputs = vec![];
+        for i in 0..tx.output_len() as u32 {
+            let script = tx.output_script(i);
+            let address = tx.output_address(i, self.network.id()).unwrap_or_default();
+            let path = store_read.cache.paths.get(&script);
+            let is_mine = path.is_some();
+            if !script.is_empty() && !is_mine {
+                trace!("tx_id {}:{} not my script, address {:?}", tx_id, i, address);
+                addressees.push(AddressAmount {
+                    address: address.clone(),
+                    satoshi: 0, // apparently not needed in list_tx addressees
+                    asset_tag: None,
+                });
             }
-            let memo = store_read.get_memo(tx_id).map(|s| s.to_string());
+            let amount = tx.output_amount(i, unblinded);
+            outputs.push(OutputInfo {
+                vout: i,
+                address,
+                is_mine,
+                label: store_read.get_output_label(tx_id, i).cloned(),
+                is_change: path.map_or(false, |p| path_chain(p) == Some(1)),
+                derivation_path: path.map(|p| p.to_string()),
+                satoshi: amount.as_ref().map(|(value, _)| *value),
+                asset_id: amount.and_then(|(_, asset)| asset),
+            });
+        }
+        let memo = store_read.get_memo(tx_id).map(|s| s.to_string());
 
-            let create_transaction = CreateTransaction {
-                addressees,
-                memo,
-                ..Default::default()
-            };
+        let create_transaction = CreateTransaction {
+            addressees,
+            memo: memo.clone(),
+            ..Default::default()
+        };
 
-            let fee = tx.fee(
+        let missing = tx.missing_prevouts(&store_read.cache.all_txs);
+        let fee = if missing.is_empty() {
+            Some(tx.fee(
                 &store_read.cache.all_txs,
                 &store_read.cache.unblinded,
                 &self.network.policy_asset().ok(),
-            )?;
-            trace!("tx_id {} fee {}", tx_id, fee);
+            )?)
+        } else {
+            warn!("tx_id {} fee unknown, missing previous txs {:?}", tx_id, missing);
+            None
+        };
+        trace!("tx_id {} fee {:?}", tx_id, fee);
 
-            let satoshi = tx.my_balance_changes(
-                &store_read.cache.all_txs,
-                &store_read.cache.paths,
-                &store_read.cache.unblinded,
-            );
-            trace!("tx_id {} balances {:?}", tx_id, satoshi);
-
-            // We define an incoming txs if there are more assets received by the wallet than spent
-            // when they are equal it's an outgoing tx because the special asset liquid BTC
-            // is negative due to the fee being paid
-            // TODO how do we label issuance tx?
-            let negatives = satoshi.iter().filter(|(_, v)| **v < 0).count();
-            let positives = satoshi.iter().filter(|(_, v)| **v > 0).count();
-            let (type_, user_signed) = match (
-                positives > negatives,
-                tx.is_redeposit(&store_read.cache.paths, &store_read.cache.all_txs),
-            ) {
-                (_, true) => ("redeposit", true),
-                (true, false) => ("incoming", false),
-                (false, false) => ("outgoing", true),
-            };
+        let satoshi =
+            tx.my_balance_changes(&store_read.cache.all_txs, &store_read.cache.paths, unblinded);
+        trace!("tx_id {} balances {:?}", tx_id, satoshi);
 
-            let spv_verified = if self.network.spv_enabled.unwrap_or(false) {
-                store_read
-                    .cache
-                    .txs_verif
-                    .get(*tx_id)
-                    .unwrap_or(&SPVVerifyResult::InProgress)
-                    .clone()
-            } else {
-                SPVVerifyResult::Disabled
+        // We define an incoming txs if there are more assets received by the wallet than spent
+        // when they are equal it's an outgoing tx because the special asset liquid BTC
+        // is negative due to the fee being paid
+        // TODO how do we label issuance tx?
+        let negatives = satoshi.iter().filter(|(_, v)| **v < 0).count();
+        let positives = satoshi.iter().filter(|(_, v)| **v > 0).count();
+        let (type_, user_signed) = match (
+            positives > negatives,
+            tx.is_redeposit(&store_read.cache.paths, &store_read.cache.all_txs),
+        ) {
+            (_, true) => ("redeposit", true),
+            (true, false) => ("incoming", false),
+            (false, false) => ("outgoing", true),
+        };
+        if type_ == "outgoing" && store_read.change_on_receive_chain() {
+            // change is on the receive chain, in sent txs the outputs of the wallet are change
+            for output in outputs.iter_mut() {
+                output.is_change = output.is_mine;
+            }
+        }
+
+        let spv_verified = if self.network.spv_enabled.unwrap_or(false) {
+            store_read.cache.txs_verif.get(tx_id).unwrap_or(&SPVVerifyResult::InProgress).clone()
+        } else {
+            SPVVerifyResult::Disabled
+        };
+
+        trace!(
+            "tx_id {} type {} user_signed {} spv_verified {:?}",
+            tx_id,
+            type_,
+            user_signed,
+            spv_verified
+        );
+
+        let mut tx_meta = TransactionMeta::new(
+            tx.clone(),
+            height,
+            header.map(|h| h.time()).or_else(|| store_read.cache.first_seen.get(tx_id).cloned()),
+            satoshi,
+            fee,
+            self.network.id().get_bitcoin_network().unwrap_or(bitcoin::Network::Bitcoin),
+            type_.to_string(),
+            create_transaction,
+            user_signed,
+            spv_verified,
+        );
+        tx_meta.outputs = outputs;
+        tx_meta.asset_deltas = tx.my_asset_deltas(unblinded);
+        tx_meta.confirmations = confirmations(tip, height);
+        tx_meta.is_final = tx_meta.confirmations.map_or(false, |c| c >= final_confirmations);
+        tx_meta.memo = memo;
+
+        Ok(tx_meta)
+    }
+
+    /// unblind with the master blinding key the confidential outputs of the wallet in the txs
+    /// `txids` the sync didn't unblind yet, and keep them in the store
+    fn unblind_missing<'a, I>(&self, txids: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = &'a Txid>,
+    {
+        let master_blinding = match self.master_blinding.as_ref() {
+            Some(master_blinding) => master_blinding,
+            None => return Ok(()),
+        };
+        let store_read = self.store.read()?;
+        let mut missing = vec![];
+        for txid in txids {
+            let tx = match store_read.cache.all_txs.get(txid) {
+                Some(BETransaction::Elements(tx)) => tx,
+                _ => continue,
             };
+            for (vout, output) in tx.output.iter().enumerate() {
+                let outpoint = elements::OutPoint {
+                    txid: *txid,
+                    vout: vout as u32,
+                };
+                if !matches!(output.value, Value::Confidential(_, _))
+                    || store_read.cache.unblinded.contains_key(&outpoint)
+                    || !store_read.cache.paths.contains_key(&output.script_pubkey)
+                {
+                    continue;
+                }
+                let nonces = store_read.invoice_nonces(&output.script_pubkey);
+                match unblind_output(master_blinding, &nonces, output.clone()) {
+                    Ok(unblinded) => missing.push((outpoint, unblinded)),
+                    Err(e) => warn!("tx_id {}:{} cannot be unblinded {:?}", txid, vout, e),
+                }
+            }
+        }
+        drop(store_read);
+        if !missing.is_empty() {
+            self.store.write()?.cache.unblinded.extend(missing);
+        }
+        Ok(())
+    }
 
-            trace!(
-                "tx_id {} type {} user_signed {} spv_verified {:?}",
-                tx_id,
-                type_,
-                user_signed,
-                spv_verified
-            );
+    /// download again the wallet history at or above `from_height`, the whole history when None,
+    /// with a full sync, see `StoreMeta::clear_history_from`. Settings, memos and labels are kept
+    pub fn rescan(&self, client: &RetryClient, from_height: Option<u32>) -> Result<(), Error> {
+        info!("rescan from height {:?}", from_height);
+        self.store.write()?.clear_history_from(from_height)?;
+        let syncer = crate::Syncer {
+            store: self.store.clone(),
+            master_blinding: self.master_blinding.clone(),
+            network: self.network.clone(),
+            stats: Default::default(),
+            notify: crate::NativeNotif(None),
+        };
+        syncer.sync(client, None, true)?;
+        Ok(())
+    }
 
-            let tx_meta = TransactionMeta::new(
-                tx.clone(),
-                **height,
-                header.map(|h| h.time()),
-                satoshi,
-                fee,
-                self.network.id().get_bitcoin_network().unwrap_or(bitcoin::Network::Bitcoin),
-                type_.to_string(),
-                create_transaction,
-                user_signed,
-                spv_verified,
-            );
+    /// like `list_tx` but first downloads with `client` the previous txs that are missing in the
+    /// store, so that the fee of the listed txs can be computed
+    pub fn list_tx_repair(
+        &self,
+        opt: &GetTransactionsOpt,
+        client: &Client,
+    ) -> Result<Vec<TransactionMeta>, Error> {
+        self.download_missing_prevouts(opt, |page| {
+            Ok(client.batch_transaction_get_raw(page.iter().cloned())?)
+        })?;
+        self.list_tx(opt)
+    }
 
-            txs.push(tx_meta);
+    /// download with `fetch` the previous txs of the txs listed by `list_tx` with `opt` missing
+    /// in the store, returns the number of txs downloaded
+    fn download_missing_prevouts<F>(
+        &self,
+        opt: &GetTransactionsOpt,
+        fetch: F,
+    ) -> Result<usize, Error>
+    where
+        F: FnMut(&[&Txid]) -> Result<Vec<Vec<u8>>, Error>,
+    {
+        let store_read = self.store.read()?;
+        let all_txs = &store_read.cache.all_txs;
+        let missing: HashSet<Txid> = sorted_history(&store_read.cache.heights)
+            .iter()
+            .skip(opt.first)
+            .take(opt.count)
+            .filter_map(|(txid, _)| all_txs.get(*txid))
+            .flat_map(|tx| tx.missing_prevouts(all_txs))
+            .collect();
+        drop(store_read);
+        if missing.is_empty() {
+            return Ok(0);
         }
-        info!("list_tx {:?}", txs.iter().map(|e| &e.txid).collect::<Vec<&String>>());
+        info!("downloading {} missing previous txs", missing.len());
+        let missing: Vec<&Txid> = missing.iter().collect();
+        let page_size = self.network.history_page_size.unwrap_or(crate::HISTORY_PAGE_SIZE).max(1);
+        let downloaded = crate::download_txs_by_id(&missing, page_size, self.network.id(), fetch)?;
+        let count = downloaded.len();
+        let mut store_write = self.store.write()?;
+        for (txid, mut tx) in downloaded {
+            tx.strip_witness();
+            store_write.cache.all_txs.insert(txid, tx);
+        }
+        store_write.flush()?;
+        Ok(count)
+    }
 
-        Ok(txs)
+    /// like `list_tx` but split in confirmed txs, sorted by height descending, and mempool txs,
+    /// sorted by first seen time descending
+    pub fn list_tx_split(
+        &self,
+        opt: &GetTransactionsOpt,
+    ) -> Result<(Vec<TransactionMeta>, Vec<TransactionMeta>), Error> {
+        let (confirmed, mut mempool): (Vec<_>, Vec<_>) =
+            self.list_tx(opt)?.into_iter().partition(|tx| tx.height.is_some());
+        mempool.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then_with(|| b.txid.cmp(&a.txid)));
+        Ok((confirmed, mempool))
+    }
+
+    /// the spendable outputs of the wallet, frozen ones and immature coinbase outputs excluded
+    pub fn utxos(&self) -> Result<Utxos, Error> {
+        self.list_utxos(false, false)
+    }
+
+    /// the unspent outputs of the wallet, frozen ones and immature coinbase outputs included
+    pub fn all_utxos(&self) -> Result<Utxos, Error> {
+        self.list_utxos(true, true)
+    }
+
+    /// exclude the output `outpoint` from coin selection until `unfreeze_utxo` is called
+    pub fn freeze_utxo(&self, outpoint: &BEOutPoint) -> Result<(), Error> {
+        info!("freeze_utxo {:?}", outpoint);
+        self.store.write()?.set_frozen(outpoint.txid(), outpoint.vout(), true)
     }
 
-    fn utxos(&self) -> Result<Utxos, Error> {
+    pub fn unfreeze_utxo(&self, outpoint: &BEOutPoint) -> Result<(), Error> {
+        info!("unfreeze_utxo {:?}", outpoint);
+        self.store.write()?.set_frozen(outpoint.txid(), outpoint.vout(), false)
+    }
+
+    fn list_utxos(&self, include_frozen: bool, include_immature: bool) -> Result<Utxos, Error> {
         info!("start utxos");
 
         let store_read = self.store.read()?;
         let mut utxos = vec![];
         let spent = store_read.spent()?;
-        for tx_id in store_read.cache.heights.keys() {
+        for (tx_id, height) in store_read.cache.heights.iter() {
             let tx = store_read
                 .cache
                 .all_txs
                 .get(tx_id)
                 .ok_or_else(fn_err(&format!("utxos no tx {}", tx_id)))?;
+            // a tx spending them before maturity would be rejected
+            if !include_immature
+                && tx.is_coinbase()
+                && confirmations(store_read.cache.tip.0, *height).unwrap_or(0) < COINBASE_MATURITY
+            {
+                continue;
+            }
             let tx_utxos: Vec<(BEOutPoint, UTXOInfo)> = match tx {
                 BETransaction::Bitcoin(tx) => tx
                     .output
-                    .clone()
-                    .into_iter()
+                    .iter()
                     .enumerate()
                     .filter(|(_, output)| output.value > DUST_VALUE)
-                    .map(|(vout, output)| (BEOutPoint::new_bitcoin(tx.txid(), vout as u32), output))
-                    .filter(|(_, output)| {
-                        store_read.cache.paths.contains_key(&output.script_pubkey)
-                    })
+                    .filter(|(_, output)| store_read.is_mine(&output.script_pubkey))
+                    .map(|(vout, output)| (BEOutPoint::new_bitcoin(*tx_id, vout as u32), output))
                     .filter(|(outpoint, _)| !spent.contains(&outpoint))
+                    .filter(|(outpoint, _)| {
+                        include_frozen || !store_read.is_frozen(outpoint.txid(), outpoint.vout())
+                    })
                     .map(|(outpoint, output)| {
                         (
                             outpoint,
-                            UTXOInfo::new("btc".to_string(), output.value, output.script_pubkey),
+                            UTXOInfo::new(
+                                "btc".to_string(),
+                                output.value,
+                                output.script_pubkey.clone(),
+                            ),
                         )
                     })
                     .collect(),
                 BETransaction::Elements(tx) => {
                     let policy_asset = self.network.policy_asset_id()?;
                     tx.output
-                        .clone()
-                        .into_iter()
+                        .iter()
                         .enumerate()
+                        .filter(|(_, output)| store_read.is_mine(&output.script_pubkey))
                         .map(|(vout, output)| {
-                            (BEOutPoint::new_elements(tx.txid(), vout as u32), output)
-                        })
-                        .filter(|(_, output)| {
-                            store_read.cache.paths.contains_key(&output.script_pubkey)
+                            (BEOutPoint::new_elements(*tx_id, vout as u32), output)
                         })
                         .filter(|(outpoint, _)| !spent.contains(&outpoint))
+                        .filter(|(outpoint, _)| {
+                            include_frozen
+                                || !store_read.is_frozen(outpoint.txid(), outpoint.vout())
+                        })
                         .filter_map(|(outpoint, output)| {
                             if let BEOutPoint::Elements(el_outpoint) = outpoint {
                                 if let Some(unblinded) =
@@ -304,7 +877,7 @@ impl WalletCtx {
                                         UTXOInfo::new(
                                             unblinded.asset_hex(),
                                             unblinded.value,
-                                            output.script_pubkey,
+                                            output.script_pubkey.clone(),
                                         ),
                                     ));
                                 }
@@ -321,7 +894,61 @@ impl WalletCtx {
         Ok(utxos)
     }
 
+    /// the balance of the spendable outputs, frozen ones and immature coinbase outputs excluded
     pub fn balance(&self) -> Result<Balances, Error> {
+        self.balance_of(self.utxos()?)
+    }
+
+    /// the balance of the wallet, frozen and immature coinbase outputs included
+    pub fn all_balance(&self) -> Result<Balances, Error> {
+        self.balance_of(self.all_utxos()?)
+    }
+
+    /// the balance of the unspent outputs, frozen ones excluded, split in confirmed,
+    /// unconfirmed and immature coinbase outputs according to the tip known to the wallet
+    pub fn balance_detail(&self) -> Result<BalanceDetail, Error> {
+        let (mut confirmed, mut unconfirmed, mut immature) = (vec![], vec![], vec![]);
+        for (utxo, status) in self.utxos_with_status()? {
+            match status {
+                UtxoStatus::Confirmed => confirmed.push(utxo),
+                UtxoStatus::Unconfirmed => unconfirmed.push(utxo),
+                UtxoStatus::Immature => immature.push(utxo),
+            }
+        }
+        Ok(BalanceDetail {
+            confirmed: self.balance_of(confirmed)?,
+            unconfirmed: self.balance_of(unconfirmed)?,
+            immature: self.balance_of(immature)?,
+        })
+    }
+
+    /// the unspent outputs, frozen ones excluded, with their confirmation status
+    fn utxos_with_status(&self) -> Result<Vec<((BEOutPoint, UTXOInfo), UtxoStatus)>, Error> {
+        let utxos = self.list_utxos(false, true)?;
+        let store_read = self.store.read()?;
+        let tip = store_read.cache.tip.0;
+        utxos
+            .into_iter()
+            .map(|utxo| {
+                let txid = utxo.0.txid();
+                let height = store_read.cache.heights.get(&txid).cloned().flatten();
+                let coinbase = store_read
+                    .cache
+                    .all_txs
+                    .get(&txid)
+                    .ok_or_else(fn_err(&format!("utxos_with_status no tx {}", txid)))?
+                    .is_coinbase();
+                let status = match confirmations(tip, height) {
+                    None => UtxoStatus::Unconfirmed,
+                    Some(confs) if coinbase && confs < COINBASE_MATURITY => UtxoStatus::Immature,
+                    Some(_) => UtxoStatus::Confirmed,
+                };
+                Ok((utxo, status))
+            })
+            .collect()
+    }
+
+    fn balance_of(&self, utxos: Utxos) -> Result<Balances, Error> {
         info!("start balance");
         let mut result = HashMap::new();
         match self.network.id() {
@@ -330,58 +957,466 @@ impl WalletCtx {
                 result.entry(self.network.policy_asset.as_ref().unwrap().clone()).or_insert(0)
             }
         };
-        for (_, info) in self.utxos()?.iter() {
+        for (_, info) in utxos.iter() {
             *result.entry(info.asset.clone()).or_default() += info.value as i64;
         }
         Ok(result)
     }
 
-    #[allow(clippy::cognitive_complexity)]
-    pub fn create_tx(&self, request: &mut CreateTransaction) -> Result<TransactionMeta, Error> {
-        info!("create_tx {:?}", request);
-
-        // TODO put checks into CreateTransaction::validate, add check asset_tag are valid asset hex
-        // eagerly check for address validity
-        for address in request.addressees.iter().map(|a| &a.address) {
-            match self.network.id() {
-                NetworkId::Bitcoin(network) => {
-                    if let Ok(address) = bitcoin::Address::from_str(address) {
-                        info!("address.network:{} network:{}", address.network, network);
-                        if address.network == network
-                            || (address.network == bitcoin::Network::Testnet
-                                && network == bitcoin::Network::Regtest)
-                        {
-                            continue;
-                        }
-                    }
-                    return Err(Error::InvalidAddress);
+    /// the amount held of every asset. In liquid, owned outputs not unblinded by the sync are
+    /// unblinded with the master blinding key and the policy asset is always present.
+    /// In bitcoin there is a single entry, keyed by `BITCOIN_ASSET_ID`
+    pub fn balances(&self) -> Result<HashMap<AssetId, u64>, Error> {
+        info!("start balances");
+        let mut result = HashMap::new();
+        if let NetworkId::Bitcoin(_) = self.network.id() {
+            let total = self.utxos()?.iter().map(|(_, info)| info.value).sum();
+            result.insert(BITCOIN_ASSET_ID, total);
+            return Ok(result);
+        }
+        result.insert(self.network.policy_asset_id()?, 0);
+        let master_blinding = self.master_blinding()?;
+        let store_read = self.store.read()?;
+        let spent = store_read.spent()?;
+        for txid in store_read.cache.heights.keys() {
+            let tx = match store_read.cache.all_txs.get(txid) {
+                Some(BETransaction::Elements(tx)) => tx,
+                _ => return Err(Error::Generic(format!("balances no liquid tx {}", txid))),
+            };
+            for (vout, output) in tx.output.iter().enumerate() {
+                let outpoint = elements::OutPoint {
+                    txid: *txid,
+                    vout: vout as u32,
+                };
+                if !store_read.is_mine(&output.script_pubkey)
+                    || spent.contains(&BEOutPoint::Elements(outpoint))
+                {
+                    continue;
                 }
-                NetworkId::Elements(network) => {
-                    if let Ok(address) = elements::Address::from_str(address) {
-                        info!(
-                            "address.params:{:?} address_params(network):{:?}",
-                            address.params,
-                            address_params(network)
-                        );
-                        if address.params == address_params(network) {
-                            continue;
+                let (asset, value) = match store_read.cache.unblinded.get(&outpoint) {
+                    Some(unblinded) => (unblinded.asset, unblinded.value),
+                    None => {
+                        let nonces = store_read.invoice_nonces(&output.script_pubkey);
+                        match unblind_output(master_blinding, &nonces, output.clone()) {
+                            Ok(unblinded) => (unblinded.asset, unblinded.value),
+                            Err(e) => {
+                                info!("balances cannot unblind {}: {}", outpoint, e);
+                                continue;
+                            }
                         }
                     }
-                    return Err(Error::InvalidAddress);
-                }
+                };
+                *result.entry(asset).or_default() += value;
             }
         }
+        Ok(result)
+    }
 
-        if request.addressees.is_empty() {
-            return Err(Error::EmptyAddressees);
+    /// liquid assets with a positive balance, sorted by asset id, with the metadata of the
+    /// cached asset registry
+    pub fn list_assets(&self) -> Result<Vec<AssetHolding>, Error> {
+        if let NetworkId::Bitcoin(_) = self.network.id() {
+            return Err(Error::Generic("assets are supported only in liquid".into()));
         }
-
-        let subaccount = request.subaccount.unwrap_or(0);
-        if subaccount != 0 {
-            return Err(Error::InvalidSubaccount(subaccount));
+        let mut balances: BTreeMap<String, u64> = BTreeMap::new();
+        for (_, info) in self.utxos()?.iter() {
+            *balances.entry(info.asset.clone()).or_default() += info.value;
         }
+        let registry = self.get_asset_registry()?.unwrap_or_default();
+        let holdings = balances
+            .into_iter()
+            .map(|(asset_id, satoshi)| {
+                let entry = &registry[&asset_id];
+                let string = |field: &str| entry[field].as_str().map(|s| s.to_string());
+                AssetHolding {
+                    ticker: string("ticker"),
+                    name: string("name"),
+                    precision: entry["precision"].as_u64().map(|p| p as u8),
+                    asset_id,
+                    satoshi,
+                }
+            })
+            .collect();
+        Ok(holdings)
+    }
 
-        if !request.previous_transaction.is_empty() {
+    /// check how the wallet tx `txid` pays each of the `invoices`, a single tx may pay many
+    /// invoices and an invoice address may receive more than one output, which are summed.
+    /// Each output pays a single invoice: invoices to the same address take, in order, an output
+    /// of exactly their amount if any, otherwise the remaining outputs until they are paid.
+    /// In liquid only outputs of the invoice asset (the policy asset if missing) are considered
+    pub fn check_invoices(
+        &self,
+        txid: &str,
+        invoices: &[AddressAmount],
+    ) -> Result<Vec<InvoicePayment>, Error> {
+        let txid = Txid::from_hex(txid)?;
+        let store_read = self.store.read()?;
+        let tx = store_read
+            .cache
+            .all_txs
+            .get(&txid)
+            .ok_or_else(fn_err(&format!("check_invoices no tx {}", txid)))?;
+        let mut result = vec![];
+        let mut consumed = HashSet::new();
+        for invoice in invoices {
+            let script = match self.network.id() {
+                NetworkId::Bitcoin(_) => bitcoin::Address::from_str(&invoice.address)
+                    .map_err(|_| Error::InvalidAddress)?
+                    .script_pubkey(),
+                NetworkId::Elements(_) => elements::Address::from_str(&invoice.address)
+                    .map_err(|_| Error::InvalidAddress)?
+                    .script_pubkey(),
+            };
+            let asset = match self.network.id() {
+                NetworkId::Bitcoin(_) => None,
+                NetworkId::Elements(_) => Some(
+                    invoice
+                        .asset_tag
+                        .clone()
+                        .or_else(|| self.network.policy_asset.clone())
+                        .ok_or(Error::AssetEmpty)?,
+                ),
+            };
+            let mut available = vec![];
+            for vout in 0..tx.output_len() as u32 {
+                if tx.output_script(vout) != script || consumed.contains(&vout) {
+                    continue;
+                }
+                let value = match tx {
+                    BETransaction::Bitcoin(tx) => tx.output[vout as usize].value,
+                    BETransaction::Elements(tx) => {
+                        let outpoint = elements::OutPoint {
+                            txid,
+                            vout,
+                        };
+                        match store_read.cache.unblinded.get(&outpoint) {
+                            Some(unblinded) if Some(unblinded.asset_hex()) == asset => {
+                                unblinded.value
+                            }
+                            _ => continue,
+                        }
+                    }
+                };
+                available.push((vout, value));
+            }
+            let mut paid = 0u64;
+            match available.iter().find(|(_, value)| *value == invoice.satoshi) {
+                Some((vout, value)) => {
+                    consumed.insert(*vout);
+                    paid = *value;
+                }
+                None => {
+                    for (vout, value) in available {
+                        if paid >= invoice.satoshi {
+                            break;
+                        }
+                        consumed.insert(vout);
+                        paid += value;
+                    }
+                }
+            }
+            result.push(InvoicePayment::classify(invoice.satoshi, paid));
+        }
+        Ok(result)
+    }
+
+    /// the outputs spent by the inputs of `txid`, as used to compute fee and balance changes.
+    /// Inputs whose previous output is unknown to the wallet are missing from the result
+    pub fn prevouts_for(&self, txid: &str) -> Result<HashMap<BEOutPoint, SpentOutput>, Error> {
+        let txid = Txid::from_hex(txid)?;
+        let store_read = self.store.read()?;
+        let all_txs = &store_read.cache.all_txs;
+        let tx = all_txs.get(&txid).ok_or_else(fn_err(&format!("prevouts_for no tx {}", txid)))?;
+        let mut result = HashMap::new();
+        for outpoint in tx.previous_outputs() {
+            match all_txs.get_previous_output(&outpoint, &store_read.cache.unblinded) {
+                Some(spent) => {
+                    result.insert(outpoint, spent);
+                }
+                None => info!("prevouts_for {} unknown previous output {:?}", txid, outpoint),
+            }
+        }
+        Ok(result)
+    }
+
+    /// the wallet txs directly connected to the wallet tx `txid`, the ones funding its inputs
+    /// and the ones spending its outputs, txs not in the wallet history are ignored
+    pub fn tx_graph(&self, txid: &str) -> Result<TxGraph, Error> {
+        let txid = Txid::from_hex(txid)?;
+        let store_read = self.store.read()?;
+        let heights = &store_read.cache.heights;
+        let all_txs = &store_read.cache.all_txs;
+        if !heights.contains_key(&txid) {
+            return Err(Error::Generic(format!("tx_graph {} is not a wallet tx", txid)));
+        }
+        let tx = all_txs.get(&txid).ok_or_else(fn_err(&format!("tx_graph no tx {}", txid)))?;
+
+        let parents: HashSet<Txid> = tx
+            .previous_output_txids()
+            .into_iter()
+            .filter(|parent| heights.contains_key(parent))
+            .collect();
+        let mut children = HashSet::new();
+        for child in heights.keys() {
+            let child_tx =
+                all_txs.get(child).ok_or_else(fn_err(&format!("tx_graph no tx {}", child)))?;
+            if child_tx.previous_output_txids().contains(&txid) {
+                children.insert(*child);
+            }
+        }
+
+        let sorted = |txids: HashSet<Txid>| {
+            let mut txids: Vec<String> = txids.iter().map(|t| t.to_string()).collect();
+            txids.sort();
+            txids
+        };
+        Ok(TxGraph {
+            parents: sorted(parents),
+            children: sorted(children),
+        })
+    }
+
+    /// match the disposed amounts of the policy asset (btc in bitcoin) against the previously
+    /// acquired lots, in the order given by `method`. `price_oracle` returns the price of one
+    /// whole coin at the given height. Only confirmed transactions are considered, a disposal
+    /// includes the fee paid
+    pub fn capital_gains<F>(
+        &self,
+        method: LotMethod,
+        price_oracle: F,
+    ) -> Result<Vec<GainLot>, Error>
+    where
+        F: Fn(u32) -> f64,
+    {
+        let asset = self.policy_asset_hex()?;
+        let opt = GetTransactionsOpt {
+            count: std::usize::MAX,
+            ..Default::default()
+        };
+        let mut changes: Vec<(u32, i64)> = self
+            .list_tx(&opt)?
+            .iter()
+            .filter_map(|tx| Some((tx.height?, *tx.satoshi.get(&asset)?)))
+            .filter(|(_, change)| *change != 0)
+            .collect();
+        // acquisitions first when in the same block, a coin could be spent in the block it is received
+        changes.sort_by_key(|(height, change)| (*height, *change < 0));
+
+        let to_btc = |satoshi: u64| satoshi as f64 / 100_000_000.0;
+        let mut lots: VecDeque<(u32, u64)> = VecDeque::new();
+        let mut result = vec![];
+        for (height, change) in changes {
+            if change > 0 {
+                lots.push_back((height, change as u64));
+                continue;
+            }
+            let mut to_dispose = (-change) as u64;
+            while to_dispose > 0 {
+                let lot = match method {
+                    LotMethod::Fifo => lots.front_mut(),
+                    LotMethod::Lifo => lots.back_mut(),
+                }
+                .ok_or_else(|| Error::Generic(format!("disposal at {} exceeds lots", height)))?;
+                let satoshi = lot.1.min(to_dispose);
+                let cost_basis = to_btc(satoshi) * price_oracle(lot.0);
+                let proceeds = to_btc(satoshi) * price_oracle(height);
+                result.push(GainLot {
+                    acquired_height: lot.0,
+                    disposed_height: height,
+                    satoshi,
+                    cost_basis,
+                    proceeds,
+                    gain: proceeds - cost_basis,
+                });
+                lot.1 -= satoshi;
+                to_dispose -= satoshi;
+                if lot.1 == 0 {
+                    match method {
+                        LotMethod::Fifo => lots.pop_front(),
+                        LotMethod::Lifo => lots.pop_back(),
+                    };
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// distance between the current internal index and the highest internal index used by a tx
+    /// in the wallet history. Signed but never broadcasted txs make it grow, when it gets near
+    /// `BATCH_SIZE` change of future txs could be missed when restoring the wallet
+    pub fn internal_gap(&self) -> Result<u32, Error> {
+        self.store.read()?.internal_gap()
+    }
+
+    /// how many more confirmations the coinbase output `outpoint` needs before it can be spent,
+    /// `None` if the output isn't from a coinbase or it is already mature
+    pub fn maturity_remaining(&self, outpoint: &BEOutPoint) -> Result<Option<u32>, Error> {
+        let store_read = self.store.read()?;
+        let txid = outpoint.txid();
+        let tx = store_read
+            .cache
+            .all_txs
+            .get(&txid)
+            .ok_or_else(fn_err(&format!("maturity_remaining no tx {}", txid)))?;
+        if !tx.is_coinbase() {
+            return Ok(None);
+        }
+        let height = store_read.cache.heights.get(&txid).cloned().flatten();
+        let confirmations = confirmations(store_read.cache.tip.0, height).unwrap_or(0);
+        Ok(match COINBASE_MATURITY.saturating_sub(confirmations) {
+            0 => None,
+            remaining => Some(remaining),
+        })
+    }
+
+    /// convert the requested fee rate from satoshi/kbyte to satoshi/byte, using the network
+    /// minimum if not specified
+    fn fee_rate_per_byte(&self, fee_rate: Option<u64>) -> f64 {
+        (fee_rate.unwrap_or_else(|| self.min_relay_fee_rate()) as f64) / 1000.0
+    }
+
+    /// fee rates in satoshi/kbyte to confirm within each of the `targets` blocks, estimated by
+    /// the electrum server. Estimates below `min_relay_fee_rate`, or missing, are raised to it
+    pub fn estimate_fees(&self, client: &Client, targets: &[u16]) -> Result<Vec<u64>, Error> {
+        let estimates = client.batch_estimate_fee(targets.iter().map(|t| *t as usize))?;
+        Ok(fee_rates(&estimates, self.min_relay_fee_rate()))
+    }
+
+    /// the lowest fee rate in satoshi/kbyte accepted by `create_tx`, see
+    /// `Network::min_relay_fee_rate`
+    pub fn min_relay_fee_rate(&self) -> u64 {
+        self.network.min_relay_fee_rate.unwrap_or_else(|| match self.network.id() {
+            NetworkId::Bitcoin(_) => 1000,
+            NetworkId::Elements(_) => 100,
+        })
+    }
+
+    /// the key of the policy asset in `Balances` and `UTXOInfo`, "btc" in bitcoin
+    fn policy_asset_hex(&self) -> Result<String, Error> {
+        match self.network.id() {
+            NetworkId::Bitcoin(_) => Ok("btc".to_string()),
+            NetworkId::Elements(_) => {
+                self.network.policy_asset.clone().ok_or_else(fn_err("missing policy asset"))
+            }
+        }
+    }
+
+    /// sum of the policy asset UTXOs (btc in bitcoin) worth spending at `fee_rate`
+    /// (satoshi/kbyte), each reduced by the fee its input adds to a transaction
+    pub fn effective_balance(&self, fee_rate: u64) -> Result<u64, Error> {
+        let asset = self.policy_asset_hex()?;
+        let fee_rate = self.fee_rate_per_byte(Some(fee_rate));
+        let empty = BETransaction::new(self.network.id());
+        let mut with_input = empty.clone();
+        with_input.add_input(match self.network.id() {
+            NetworkId::Bitcoin(_) => BEOutPoint::Bitcoin(bitcoin::OutPoint::default()),
+            NetworkId::Elements(_) => BEOutPoint::Elements(elements::OutPoint::default()),
+        });
+        // the dummy input is of the wallet script type
+        let no_txs = BETransactions::default();
+        let with_input_fee = with_input.estimated_fee(fee_rate, 0, &no_txs, self.script_type);
+        let input_fee = with_input_fee.saturating_sub(empty.estimated_fee(
+            fee_rate,
+            0,
+            &no_txs,
+            self.script_type,
+        ));
+        info!("effective_balance input_fee:{}", input_fee);
+
+        let utxos = self.utxos()?;
+        Ok(utxos
+            .iter()
+            .filter(|(_, i)| i.asset == asset)
+            .map(|(_, i)| i.value.saturating_sub(input_fee))
+            .sum())
+    }
+
+    /// count the wallet's UTXOs of the policy asset (btc in bitcoin) by value range.
+    /// `buckets` are the inclusive upper bounds of each range, in increasing order; UTXOs
+    /// greater than the last bound are counted in an extra `std::u64::MAX` bucket
+    pub fn utxo_value_histogram(&self, buckets: &[u64]) -> Result<Vec<(u64, usize)>, Error> {
+        let asset = self.policy_asset_hex()?;
+        let utxos = self.utxos()?;
+        let values = utxos.iter().filter(|(_, i)| i.asset == asset).map(|(_, i)| i.value);
+        value_histogram(values, buckets)
+    }
+
+    /// true if `address` parses as an address of the wallet network, confidential and
+    /// unconfidential addresses are both valid on liquid. Unparsable addresses are not valid.
+    pub fn validate_address(&self, address: &str) -> Result<bool, Error> {
+        let valid = match self.network.id() {
+            NetworkId::Bitcoin(network) => match bitcoin::Address::from_str(address) {
+                Ok(address) => {
+                    address.network == network
+                        || (address.network == bitcoin::Network::Testnet
+                            && network == bitcoin::Network::Regtest)
+                }
+                Err(_) => false,
+            },
+            NetworkId::Elements(network) => match elements::Address::from_str(address) {
+                Ok(address) => address.params == address_params(network),
+                Err(_) => false,
+            },
+        };
+        Ok(valid)
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    pub fn create_tx(&self, request: &mut CreateTransaction) -> Result<TransactionMeta, Error> {
+        info!("create_tx {:?}", request);
+        self.check_not_archived()?;
+
+        // TODO put checks into CreateTransaction::validate, add check asset_tag are valid asset hex
+        // eagerly check for address validity
+        for (index, address) in request.addressees.iter().map(|a| &a.address).enumerate() {
+            match self.network.id() {
+                NetworkId::Bitcoin(network) => {
+                    if let Ok(address) = bitcoin::Address::from_str(address) {
+                        info!("address.network:{} network:{}", address.network, network);
+                        if address.network == network
+                            || (address.network == bitcoin::Network::Testnet
+                                && network == bitcoin::Network::Regtest)
+                        {
+                            continue;
+                        }
+                        return Err(Error::WrongNetwork(index));
+                    }
+                    if elements::Address::from_str(address).is_ok() {
+                        return Err(Error::WrongNetwork(index));
+                    }
+                    return Err(Error::InvalidAddress);
+                }
+                NetworkId::Elements(network) => {
+                    if let Ok(address) = elements::Address::from_str(address) {
+                        info!(
+                            "address.params:{:?} address_params(network):{:?}",
+                            address.params,
+                            address_params(network)
+                        );
+                        if address.params == address_params(network) {
+                            continue;
+                        }
+                        return Err(Error::WrongNetwork(index));
+                    }
+                    if bitcoin::Address::from_str(address).is_ok() {
+                        return Err(Error::WrongNetwork(index));
+                    }
+                    return Err(Error::InvalidAddress);
+                }
+            }
+        }
+
+        if request.addressees.is_empty() {
+            return Err(Error::EmptyAddressees);
+        }
+
+        let subaccount = request.subaccount.unwrap_or(self.account);
+        if subaccount != self.account {
+            return Err(Error::InvalidSubaccount(subaccount));
+        }
+
+        if !request.previous_transaction.is_empty() {
             return Err(Error::Generic("bump not supported".into()));
         }
 
@@ -393,16 +1428,21 @@ impl WalletCtx {
 
         if !send_all {
             for address_amount in request.addressees.iter() {
-                if address_amount.satoshi <= be::DUST_VALUE {
-                    match self.network.id() {
-                        NetworkId::Bitcoin(_) => return Err(Error::InvalidAmount),
-                        NetworkId::Elements(_) => {
-                            if address_amount.asset_tag == self.network.policy_asset {
-                                // we apply dust rules for liquid bitcoin as elements do
-                                return Err(Error::InvalidAmount);
-                            }
+                let script = match self.network.id() {
+                    NetworkId::Bitcoin(_) => bitcoin::Address::from_str(&address_amount.address)
+                        .map_err(|_| Error::InvalidAddress)?
+                        .script_pubkey(),
+                    NetworkId::Elements(_) => {
+                        if address_amount.asset_tag != self.network.policy_asset {
+                            continue;
                         }
+                        // we apply dust rules for liquid bitcoin as elements do
+                        Script::new()
                     }
+                };
+                let threshold = be::dust_threshold(&script, self.network.id());
+                if address_amount.satoshi < threshold {
+                    return Err(Error::BelowDustLimit(address_amount.satoshi, threshold));
                 }
             }
         }
@@ -413,16 +1453,47 @@ impl WalletCtx {
             }
         }
 
-        // convert from satoshi/kbyte to satoshi/byte
-        let default_value = match self.network.id() {
-            NetworkId::Bitcoin(_) => 1000,
-            NetworkId::Elements(_) => 100,
-        };
-        let fee_rate = (request.fee_rate.unwrap_or(default_value) as f64) / 1000.0;
+        // versions greater than 2 are non-standard and would not be relayed
+        let version = request.version.unwrap_or(2);
+        request.version = Some(version); // accept default 2, but always return the value
+        if !(1..=2).contains(&version) {
+            return Err(Error::InvalidVersion(version));
+        }
+
+        if let Some(fee_rate) = request.fee_rate {
+            let min = self.min_relay_fee_rate();
+            if fee_rate < min {
+                return Err(Error::FeeRateBelowMinimum(fee_rate, min));
+            }
+        }
+        let fee_rate = self.fee_rate_per_byte(request.fee_rate);
         info!("target fee_rate {:?} satoshi/byte", fee_rate);
 
-        let utxos = self.utxos()?;
+        let utxos = if request.confirmed_only.unwrap_or(false) {
+            self.utxos_with_status()?
+                .into_iter()
+                .filter(|(_, status)| *status == UtxoStatus::Confirmed)
+                .map(|(utxo, _)| utxo)
+                .collect()
+        } else {
+            self.utxos()?
+        };
         info!("utxos len:{} utxos:{:?}", utxos.len(), utxos);
+        if utxos.is_empty() {
+            // frozen outputs aren't spendable but the wallet isn't empty
+            if self.all_utxos()?.is_empty() {
+                return Err(Error::EmptyWallet);
+            }
+            return Err(Error::InsufficientFunds);
+        }
+        let utxos = match (request.utxo.as_ref(), request.utxos.as_ref()) {
+            (Some(_), Some(_)) => {
+                return Err(Error::Generic("utxo and utxos are mutually exclusive".into()))
+            }
+            (Some(utxo), None) => only_utxos(utxos, &[utxo.clone()])?,
+            (None, Some(selected)) => only_utxos(utxos, selected)?,
+            (None, None) => utxos,
+        };
 
         if send_all {
             // send_all works by creating a dummy tx with all utxos, estimate the fee and set the
@@ -446,10 +1517,18 @@ impl WalletCtx {
                 dummy_tx
                     .add_output(&out.address, out.satoshi, out.asset_tag.clone())
                     .map_err(|_| Error::InvalidAddress)?;
-                let estimated_fee = dummy_tx.estimated_fee(fee_rate, 0) + 3; // estimating 3 satoshi more as estimating less would later result in InsufficientFunds
-                total_amount_utxos
+                let estimated_fee = {
+                    let store_read = self.store.read()?;
+                    dummy_tx.estimated_fee(fee_rate, 0, &store_read.cache.all_txs, self.script_type)
+                } + 3; // estimating 3 satoshi more as estimating less would later result in InsufficientFunds
+                let to_send = total_amount_utxos
                     .checked_sub(estimated_fee)
-                    .ok_or_else(|| Error::InsufficientFunds)?
+                    .ok_or_else(|| Error::InsufficientFunds)?;
+                let threshold = be::dust_threshold(&dummy_tx.output_script(0), self.network.id());
+                if to_send < threshold {
+                    return Err(Error::BelowDustLimit(to_send, threshold));
+                }
+                to_send
             } else {
                 total_amount_utxos
             };
@@ -460,6 +1539,7 @@ impl WalletCtx {
         }
 
         let mut tx = BETransaction::new(self.network.id());
+        tx.set_version(version);
         // transaction is created in 3 steps:
         // 1) adding requested outputs to tx outputs
         // 2) adding enough utxso to inputs such that tx outputs and estimated fees are covered
@@ -469,18 +1549,321 @@ impl WalletCtx {
         for out in request.addressees.iter() {
             tx.add_output(&out.address, out.satoshi, out.asset_tag.clone())
                 .map_err(|_| Error::InvalidAddress)?;
+            let script = tx.output_script(tx.output_len() as u32 - 1);
+            if !is_standard_output_script(&script) {
+                return Err(Error::NonStandardOutput(out.address.clone()));
+            }
+        }
+
+        self.fund_tx(tx, request, fee_rate, &utxos)
+    }
+
+    /// create an unsigned replacement (BIP125) of the unconfirmed wallet tx `txid` paying
+    /// `new_fee_rate` satoshi/kbyte. The replacement spends the same inputs and pays the same
+    /// recipients, the higher fee is taken from the change output, adding the biggest confirmed
+    /// utxos of the wallet if the change is not enough, since BIP125 doesn't allow new
+    /// unconfirmed inputs. Supported only in bitcoin
+    pub fn bump_fee(&self, txid: &Txid, new_fee_rate: u64) -> Result<TransactionMeta, Error> {
+        info!("bump_fee {} new_fee_rate {}", txid, new_fee_rate);
+        self.check_not_archived()?;
+        if let NetworkId::Elements(_) = self.network.id() {
+            return Err(Error::Generic("bump_fee is supported only in bitcoin".into()));
+        }
+        let fee_rate = self.fee_rate_per_byte(Some(new_fee_rate));
+        let utxos = self.utxos()?;
+
+        let store_read = self.store.read()?;
+        match store_read.cache.heights.get(txid) {
+            Some(None) => (),
+            Some(Some(_)) => return Err(Error::Generic(format!("tx {} is confirmed", txid))),
+            None => return Err(Error::Generic(format!("tx {} not in wallet", txid))),
+        }
+        let all_txs = &store_read.cache.all_txs;
+        let unblinded = &store_read.cache.unblinded;
+        let original = all_txs.get(txid).ok_or_else(fn_err(&format!("bump_fee no tx {}", txid)))?;
+        let old_fee = original.fee(all_txs, unblinded, &None)?;
+        let mut tx = match original {
+            BETransaction::Bitcoin(tx) => tx.clone(),
+            BETransaction::Elements(_) => return Err(Error::Generic("not a bitcoin tx".into())),
+        };
+
+        // every input must be signed again, so they must be of the wallet
+        for input in tx.input.iter_mut() {
+            let prev_script = all_txs
+                .get_previous_output_script_pubkey(&BEOutPoint::Bitcoin(input.previous_output));
+            if !prev_script.map_or(false, |s| store_read.is_mine(&s)) {
+                return Err(Error::Generic(format!("tx {} spends not owned inputs", txid)));
+            }
+            input.script_sig = Script::new();
+            input.witness = vec![];
+        }
+
+        let change_vout = tx.output.iter().position(|o| store_read.is_change(&o.script_pubkey));
+        let payments: u64 = tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(vout, _)| Some(*vout) != change_vout)
+            .map(|(_, o)| o.value)
+            .sum();
+
+        // outputs of the replaced tx can't be spent by the replacement, and neither can the
+        // outputs of other unconfirmed txs
+        let confirmed =
+            |o: &BEOutPoint| matches!(store_read.cache.heights.get(&o.txid()), Some(Some(_)));
+        let mut spare: Vec<&(BEOutPoint, UTXOInfo)> =
+            utxos.iter().filter(|(o, i)| i.asset == "btc" && confirmed(o)).collect();
+        spare.sort_by(|a, b| (a.1).value.cmp(&(b.1).value));
+
+        let more_changes = if change_vout.is_none() {
+            1
+        } else {
+            0
+        };
+        // the change output is kept, or added, only if it's not dust
+        let (change_chain, last_index) = if store_read.change_on_receive_chain() {
+            (0, store_read.cache.indexes.external)
+        } else {
+            (1, store_read.cache.indexes.internal)
+        };
+        let change_address = self.derive_address(&self.xpub, [change_chain, last_index + 1])?;
+        let change_script = match change_vout {
+            Some(vout) => tx.output[vout].script_pubkey.clone(),
+            None => change_address.script_pubkey(),
+        };
+        let change_dust = be::dust_threshold(&change_script, self.network.id());
+        let change = loop {
+            let dummy_tx = BETransaction::Bitcoin(tx.clone());
+            // BIP125 requires to pay at least the replaced fee plus the relay fee of the replacement
+            let fee =
+                dummy_tx.estimated_fee(fee_rate, more_changes, all_txs, self.script_type).max(
+                    old_fee + dummy_tx.estimated_fee(1.0, more_changes, all_txs, self.script_type),
+                );
+            let inputs: u64 = tx
+                .input
+                .iter()
+                .filter_map(|i| {
+                    all_txs.get_previous_output_value(
+                        &BEOutPoint::Bitcoin(i.previous_output),
+                        unblinded,
+                    )
+                })
+                .sum();
+            match inputs.checked_sub(payments + fee) {
+                Some(change) if change >= change_dust => break change,
+                _ => match spare.pop() {
+                    Some((BEOutPoint::Bitcoin(outpoint), _)) => tx.input.push(bitcoin::TxIn {
+                        previous_output: *outpoint,
+                        script_sig: Script::new(),
+                        sequence: 0xffff_fffd,
+                        witness: vec![],
+                    }),
+                    _ => return Err(Error::InsufficientFunds),
+                },
+            }
+        };
+
+        let changes_used = match change_vout {
+            Some(vout) => {
+                tx.output[vout].value = change;
+                0
+            }
+            None => {
+                let mut betx = BETransaction::Bitcoin(tx);
+                betx.add_output(&change_address.to_string(), change, None)?;
+                tx = match betx {
+                    BETransaction::Bitcoin(tx) => tx,
+                    BETransaction::Elements(_) => unreachable!(),
+                };
+                1
+            }
+        };
+
+        let mut replacement = BETransaction::Bitcoin(tx);
+        replacement.set_rbf(true);
+        let fee_val = replacement.fee(all_txs, unblinded, &None)?;
+        info!("bump_fee {} fee {} -> {}", txid, old_fee, fee_val);
+
+        // repeated bumps could burn a large part of the amount sent
+        let max_fee_percent = store_read.get_settings().unwrap_or_default().max_fee_percent();
+        let max_fee = payments * max_fee_percent as u64 / 100;
+        if fee_val > max_fee {
+            return Err(Error::FeeExceedsCap(fee_val, max_fee));
+        }
+
+        let mut satoshi =
+            replacement.my_balance_changes(all_txs, &store_read.cache.paths, unblinded);
+        for (_, v) in satoshi.iter_mut() {
+            *v = v.abs();
+        }
+        let network = self.network.id().get_bitcoin_network().unwrap_or(bitcoin::Network::Bitcoin);
+        let addressees = match &replacement {
+            BETransaction::Bitcoin(tx) => tx
+                .output
+                .iter()
+                .filter(|o| !store_read.is_mine(&o.script_pubkey))
+                .map(|o| AddressAmount {
+                    address: Address::from_script(&o.script_pubkey, network)
+                        .map(|a| a.to_string())
+                        .unwrap_or_default(),
+                    satoshi: o.value,
+                    asset_tag: None,
+                })
+                .collect(),
+            BETransaction::Elements(_) => unreachable!(),
+        };
+        let request = CreateTransaction {
+            addressees,
+            fee_rate: Some(new_fee_rate),
+            memo: store_read.get_memo(txid).cloned(),
+            version: Some(replacement.version()),
+            rbf: Some(true),
+            locktime: Some(replacement.lock_time()),
+            ..Default::default()
+        };
+
+        let mut created_tx = TransactionMeta::new(
+            replacement,
+            None,
+            None,
+            satoshi,
+            Some(fee_val),
+            network,
+            "outgoing".to_string(),
+            request,
+            true,
+            SPVVerifyResult::InProgress,
+        );
+        created_tx.changes_used = Some(changes_used);
+        Ok(created_tx)
+    }
+
+    /// create a liquid transaction moving `satoshi` L-BTC to `bitcoin_address` on the parent chain,
+    /// fees are paid in L-BTC at the rate estimated by the server of `client` for the next block
+    pub fn create_pegout(
+        &self,
+        satoshi: u64,
+        bitcoin_address: &str,
+        client: &Client,
+    ) -> Result<TransactionMeta, Error> {
+        info!("create_pegout {} to {}", satoshi, bitcoin_address);
+        self.check_not_archived()?;
+        let network = match self.network.id() {
+            NetworkId::Elements(network) => network,
+            NetworkId::Bitcoin(_) => {
+                return Err(Error::Generic("peg-out is supported only in liquid".into()))
+            }
+        };
+        let parent = network.parent_network();
+        let address =
+            bitcoin::Address::from_str(bitcoin_address).map_err(|_| Error::InvalidAddress)?;
+        if address.network != parent
+            && !(address.network == bitcoin::Network::Testnet
+                && parent == bitcoin::Network::Regtest)
+        {
+            return Err(Error::InvalidAddress);
+        }
+        if satoshi <= be::DUST_VALUE {
+            return Err(Error::InvalidAmount);
         }
+        let policy_asset = self.network.policy_asset.clone().ok_or(Error::AssetEmpty)?;
+
+        let fee_rate = self.estimate_fees(client, &[1])?[0];
+        let request = CreateTransaction {
+            fee_rate: Some(fee_rate),
+            send_all: Some(false),
+            ..Default::default()
+        };
+        let fee_rate = self.fee_rate_per_byte(Some(fee_rate));
+        let genesis_hash = genesis_block(parent).block_hash();
+
+        let mut tx = BETransaction::new(self.network.id());
+        tx.add_pegout_output(satoshi, &policy_asset, genesis_hash, &address.script_pubkey())?;
+
+        let utxos = self.utxos()?;
+        self.fund_tx(tx, &request, fee_rate, &utxos)
+    }
+
+    /// STEP 2) and 3) of transaction creation, `tx` must already contain the requested outputs
+    fn fund_tx(
+        &self,
+        mut tx: BETransaction,
+        request: &CreateTransaction,
+        fee_rate: f64,
+        utxos: &Utxos,
+    ) -> Result<TransactionMeta, Error> {
+        let send_all = request.send_all.unwrap_or(false);
+        let version = tx.version();
+        // anti fee sniping, see bitcoin core `DiscourageFeeSniping`
+        let lock_time = match request.locktime {
+            Some(lock_time) => lock_time,
+            None => self.get_tip()?.0,
+        };
+        tx.set_lock_time(lock_time);
+        let recipients: Vec<Script> =
+            (0..tx.output_len() as u32).map(|i| tx.output_script(i)).collect();
 
         // STEP 2) add utxos until tx outputs are covered (including fees) or fail
         let store_read = self.store.read()?;
         let mut used_utxo: HashSet<BEOutPoint> = HashSet::new();
+        let bitcoin_outputs = match &tx {
+            BETransaction::Bitcoin(btx) if !send_all => {
+                Some(btx.output.iter().map(|o| o.value).sum::<u64>())
+            }
+            _ => None,
+        };
+        // set when coin selection found inputs covering the outputs and the fee without change
+        let mut changeless = false;
+        if request.utxos.is_some() {
+            // the inputs are chosen by the caller, the loop below only checks they are enough
+            for (outpoint, _) in utxos.iter() {
+                used_utxo.insert(outpoint.clone());
+                tx.add_input(outpoint.clone());
+            }
+        } else if let Some(outputs) = bitcoin_outputs {
+            // choose the inputs with coin selection, the loop below only checks they are enough
+            let btc_utxos: Vec<(bitcoin::OutPoint, bitcoin::TxOut)> = utxos
+                .iter()
+                .filter_map(|(o, i)| match o {
+                    BEOutPoint::Bitcoin(outpoint) => Some((
+                        *outpoint,
+                        bitcoin::TxOut {
+                            value: i.value,
+                            script_pubkey: i.script.clone(),
+                        },
+                    )),
+                    BEOutPoint::Elements(_) => None,
+                })
+                .collect();
+            // fee of the outputs and of the segwit marker and flag, inputs and change are
+            // accounted by the selection
+            let mut base_fee =
+                tx.estimated_fee(fee_rate, 0, &store_read.cache.all_txs, self.script_type);
+            if self.script_type != ScriptType::P2pkh {
+                base_fee += fee_rate.ceil() as u64;
+            }
+            // UTXO with same script are selected, and spent, together
+            let selection = coinselect::select(
+                &btc_utxos,
+                outputs + base_fee,
+                (fee_rate * 1000.0) as u64,
+                self.script_type,
+            )?;
+            info!("coin selection {:?}", selection);
+            for outpoint in selection.outpoints.iter() {
+                used_utxo.insert(BEOutPoint::Bitcoin(*outpoint));
+                tx.add_input(BEOutPoint::Bitcoin(*outpoint));
+            }
+            changeless = !selection.needs_change();
+        }
         loop {
             let mut needs = tx.needs(
                 fee_rate,
-                send_all,
+                send_all || changeless,
                 self.network.policy_asset.clone(),
                 &store_read.cache.all_txs,
                 &store_read.cache.unblinded,
+                self.script_type,
             ); // Vec<(asset_string, satoshi)  "policy asset" is last, in bitcoin asset_string="btc" and max 1 element
             info!("needs: {:?}", needs);
             if needs.is_empty() {
@@ -501,6 +1884,8 @@ impl WalletCtx {
 
             match self.network.id() {
                 NetworkId::Bitcoin(_) => {
+                    // the selection wasn't enough, its change can't be trusted anymore
+                    changeless = false;
                     // UTXO with same script must be spent together
                     for other_utxo in utxos.iter() {
                         if (other_utxo.1).script == (utxo.1).script {
@@ -521,27 +1906,91 @@ impl WalletCtx {
             }
         }
 
-        // STEP 3) adding change(s)
-        let estimated_fee = tx.estimated_fee(
-            fee_rate,
-            tx.estimated_changes(send_all, &store_read.cache.all_txs, &store_read.cache.unblinded),
-        );
-        let changes = tx.changes(
-            estimated_fee,
-            self.network.policy_asset.clone(),
-            &store_read.cache.all_txs,
-            &store_read.cache.unblinded,
-        ); // Vec<Change> asset, value
+        if let Some(rbf) = request.rbf {
+            tx.set_rbf(rbf);
+        }
+
+        // relative locktimes are not enforced with version 1, refuse to build a misleading tx
+        if version < 2 && tx.has_relative_locktime() {
+            return Err(Error::InvalidVersion(version));
+        }
+
+        // STEP 3) adding change(s), none if coin selection found a changeless solution
+        let more_changes = if changeless {
+            0
+        } else {
+            tx.estimated_changes(send_all, &store_read.cache.all_txs, &store_read.cache.unblinded)
+        };
+        let estimated_fee =
+            tx.estimated_fee(fee_rate, more_changes, &store_read.cache.all_txs, self.script_type);
+        let (change_chain, last_index) = if store_read.change_on_receive_chain() {
+            (0, store_read.cache.indexes.external)
+        } else {
+            (1, store_read.cache.indexes.internal)
+        };
+        // all change addresses share the same script type, thus the same dust threshold
+        let change_script =
+            self.derive_address(&self.xpub, [change_chain, last_index + 1])?.script_pubkey();
+        let change_dust = be::dust_threshold(&change_script, self.network.id());
+        let changes = if changeless {
+            vec![]
+        } else {
+            tx.changes(
+                estimated_fee,
+                change_dust,
+                self.network.policy_asset.clone(),
+                &store_read.cache.all_txs,
+                &store_read.cache.unblinded,
+            )
+        }; // Vec<Change> asset, value
+        let mut change_paths = HashMap::new();
         for (i, change) in changes.iter().enumerate() {
-            let change_index = store_read.cache.indexes.internal + i as u32 + 1;
-            let change_address = self.derive_address(&self.xpub, [1, change_index])?.to_string();
+            let change_index = last_index + i as u32 + 1;
+            let change_address = self.derive_address(&self.xpub, [change_chain, change_index])?;
             info!(
                 "adding change to {} of {} asset {:?}",
                 &change_address, change.satoshi, change.asset
             );
-            tx.add_output(&change_address, change.satoshi, Some(change.asset.clone()))?;
+            change_paths.insert(
+                change_address.script_pubkey(),
+                (format!("m/{}/{}", change_chain, change_index), change),
+            );
+            tx.add_output(&change_address.to_string(), change.satoshi, Some(change.asset.clone()))?;
+        }
+        // blinding needs at least a blinded output: when every output is explicit, eg. a peg-out
+        // without change, a zero value OP_RETURN takes the place of the change counted in the fee
+        if let BETransaction::Elements(etx) = &tx {
+            if etx.output.iter().all(|o| o.nonce == Nonce::Null) {
+                let script = bitcoin::blockdata::script::Builder::new()
+                    .push_opcode(bitcoin::blockdata::opcodes::all::OP_RETURN)
+                    .into_script();
+                let blinding_key =
+                    asset_blinding_key_to_ec_private_key(self.master_blinding()?, &script);
+                let blinding_pubkey = ec_public_key_from_private_key(blinding_key);
+                info!("adding zero value output to blind a tx without change");
+                tx.add_zero_value_output(&self.policy_asset_hex()?, &script, &blinding_pubkey)?;
+            }
         }
 
+        // what's left of the policy asset: unused utxos and change
+        let asset = self.policy_asset_hex()?;
+        let unused: u64 = utxos
+            .iter()
+            .filter(|(o, i)| i.asset == asset && !used_utxo.contains(o))
+            .map(|(_, i)| i.value)
+            .sum();
+        let change: u64 = changes.iter().filter(|c| c.asset == asset).map(|c| c.satoshi).sum();
+        let remaining = unused + change;
+        let leaves_dust = request
+            .dust_remainder
+            .map_or(false, |threshold| remaining > 0 && remaining < threshold);
+        info!("remaining {} leaves_dust {}", remaining, leaves_dust);
+        let mut warnings = self.tx_warnings(&store_read, &recipients, &used_utxo, utxos, fee_rate);
+        if leaves_dust {
+            warnings.push(TxWarning::LeavesDust);
+        }
+        info!("warnings {:?}", warnings);
+
         // randomize inputs and outputs, BIP69 has been rejected because lacks wallets adoption
         tx.scramble();
 
@@ -561,62 +2010,208 @@ impl WalletCtx {
         for (_, v) in satoshi.iter_mut() {
             *v = v.abs();
         }
+        // change scripts usually are not yet in the wallet paths, what goes back to the wallet
+        // is not sent
+        for (script, (_, change)) in change_paths.iter() {
+            if store_read.cache.paths.contains_key(script) {
+                continue;
+            }
+            if let Some(v) = satoshi.get_mut(&change.asset) {
+                *v -= change.satoshi as i64;
+            }
+        }
+
+        let mut outputs = vec![];
+        for i in 0..tx.output_len() as u32 {
+            let script = tx.output_script(i);
+            let change_path = change_paths.get(&script).map(|(path, _)| path);
+            let path = store_read.cache.paths.get(&script).map(|p| p.to_string());
+            let amount = tx.output_amount(i, &store_read.cache.unblinded);
+            outputs.push(OutputInfo {
+                vout: i,
+                address: tx.output_address(i, self.network.id()).unwrap_or_default(),
+                is_mine: change_path.is_some() || path.is_some(),
+                label: None,
+                is_change: change_path.is_some(),
+                derivation_path: change_path.cloned().or(path),
+                satoshi: amount.as_ref().map(|(value, _)| *value),
+                asset_id: amount.and_then(|(_, asset)| asset),
+            });
+        }
 
         let mut created_tx = TransactionMeta::new(
             tx,
             None,
             None,
             satoshi,
-            fee_val,
+            Some(fee_val),
             self.network.id().get_bitcoin_network().unwrap_or(bitcoin::Network::Bitcoin),
             "outgoing".to_string(),
-            request.clone(),
+            CreateTransaction {
+                locktime: Some(lock_time),
+                ..request.clone()
+            },
             true,
             SPVVerifyResult::InProgress,
         );
         created_tx.changes_used = Some(changes.len() as u32);
+        created_tx.outputs = outputs;
+        created_tx.leaves_dust = leaves_dust;
+        created_tx.warnings = warnings;
         info!("returning: {:?}", created_tx);
 
         Ok(created_tx)
     }
 
-    // TODO when we can serialize psbt
-    //pub fn sign(&self, psbt: PartiallySignedTransaction) -> Result<PartiallySignedTransaction, Error> { Err(Error::Generic("NotImplemented".to_string())) }
-
-    fn internal_sign_bitcoin(
+    /// the soft conditions of a tx paying `recipients` and spending `inputs`, see `TxWarning`
+    fn tx_warnings(
         &self,
-        tx: &Transaction,
-        input_index: usize,
+        store_read: &StoreMeta,
+        recipients: &[Script],
+        inputs: &HashSet<BEOutPoint>,
+        utxos: &Utxos,
+        fee_rate: f64,
+    ) -> Vec<TxWarning> {
+        let mut warnings = vec![];
+
+        let history_scripts: HashSet<Script> = store_read
+            .cache
+            .heights
+            .keys()
+            .filter_map(|txid| store_read.cache.all_txs.get(txid))
+            .flat_map(|tx| (0..tx.output_len() as u32).map(move |i| tx.output_script(i)))
+            .collect();
+        if recipients.iter().any(|s| history_scripts.contains(s)) {
+            warnings.push(TxWarning::AddressReuse);
+        }
+
+        // the estimate at index 1 is for the next block, see `try_get_fee_estimates`
+        let next_block = store_read.fee_estimates().get(1).map_or(0, |e| e.0);
+        if next_block > 0 && (fee_rate * 1000.0) as u64 > next_block * 2 {
+            warnings.push(TxWarning::HighFeeRate);
+        }
+
+        if inputs.iter().any(|o| store_read.cache.heights.get(&o.txid()) == Some(&None)) {
+            warnings.push(TxWarning::SpendsUnconfirmed);
+        }
+
+        let input_scripts: HashSet<&Script> =
+            utxos.iter().filter(|(o, _)| inputs.contains(o)).map(|(_, i)| &i.script).collect();
+        if input_scripts.len() > 1 {
+            warnings.push(TxWarning::LowPrivacy);
+        }
+
+        warnings
+    }
+
+    /// sign the inputs of `psbt` spending wallet outputs, filling `partial_sigs` and the final
+    /// script_sig and witness. Inputs not of the wallet are left untouched. Bitcoin only.
+    /// If `expected` is given the psbt is signed only if it pays exactly those recipients,
+    /// plus any number of outputs to the wallet (change), see `check_psbt_outputs`
+    pub fn sign_psbt(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+        expected: Option<&[AddressAmount]>,
+    ) -> Result<PartiallySignedTransaction, Error> {
+        info!("sign_psbt");
+        self.check_not_archived()?;
+        self.signing_key()?;
+        if let NetworkId::Elements(_) = self.network.id() {
+            return Err(Error::Generic("psbt are supported only in bitcoin".into()));
+        }
+        let store_read = self.store.read()?;
+        let tx = psbt.global.unsigned_tx.clone();
+        if let Some(expected) = expected {
+            check_psbt_outputs(&tx, expected, &store_read.cache.paths)?;
+        }
+        for (i, input) in psbt.inputs.iter_mut().enumerate() {
+            let prev_output = tx.input[i].previous_output;
+            let out = match &input.witness_utxo {
+                Some(out) => out.clone(),
+                None => match store_read.get_bitcoin_tx(&prev_output.txid) {
+                    Ok(prev_tx) => match prev_tx.output.get(prev_output.vout as usize) {
+                        Some(out) => out.clone(),
+                        None => continue,
+                    },
+                    Err(_) => continue,
+                },
+            };
+            let derivation_path = match store_read.cache.paths.get(&out.script_pubkey) {
+                Some(path) => path.clone(),
+                None => continue,
+            };
+            info!("input#{} prev_output:{:?} path:{}", i, prev_output, derivation_path);
+
+            let (script_sig, witness, _) = self.internal_sign_bitcoin(
+                &tx,
+                i,
+                &derivation_path,
+                out.value,
+                &out.script_pubkey,
+                input.sighash_type.unwrap_or(SigHashType::All),
+            )?;
+            let public_key = self.xpub.derive_pub(&self.secp, &derivation_path)?.public_key;
+            // the signature is the first witness element or, for p2pkh, the first push
+            let signature = match witness.first() {
+                Some(signature) => signature.clone(),
+                None => match script_sig.instructions().next() {
+                    Some(Ok(Instruction::PushBytes(signature))) => signature.to_vec(),
+                    _ => return Err(Error::Generic("missing signature".into())),
+                },
+            };
+            input.partial_sigs.insert(public_key, signature);
+            input.final_script_sig = Some(script_sig).filter(|s| !s.is_empty());
+            input.final_script_witness = Some(witness).filter(|w| !w.is_empty());
+        }
+        Ok(psbt)
+    }
+
+    fn internal_sign_bitcoin(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
         path: &DerivationPath,
         value: u64,
-    ) -> (Script, Vec<Vec<u8>>) {
-        let xprv = self.xprv.derive_priv(&self.secp, &path).unwrap();
+        prev_script: &Script,
+        sighash_type: SigHashType,
+    ) -> Result<(Script, Vec<Vec<u8>>, [u8; 32]), Error> {
+        let xprv = self.signing_key()?.derive_priv(&self.secp, &path)?;
         let private_key = &xprv.private_key;
         let public_key = &PublicKey::from_private_key(&self.secp, private_key);
         let witness_script = p2pkh_script(public_key);
 
-        let hash = SigHashCache::new(tx).signature_hash(
-            input_index,
-            &witness_script,
-            value,
-            SigHashType::All,
-        );
+        let hash = if prev_script.is_p2pkh() {
+            tx.signature_hash(input_index, prev_script, sighash_type.as_u32())
+        } else {
+            SigHashCache::new(tx).signature_hash(input_index, &witness_script, value, sighash_type)
+        };
 
-        let message = Message::from_slice(&hash.into_inner()[..]).unwrap();
+        let hash = hash.into_inner();
+        let message = Message::from_slice(&hash[..]).unwrap();
         let signature = self.secp.sign(&message, &private_key.key);
 
         let mut signature = signature.serialize_der().to_vec();
-        signature.push(SigHashType::All as u8);
+        signature.push(sighash_type as u8);
 
-        let script_sig = p2shwpkh_script_sig(public_key);
-        let witness = vec![signature, public_key.to_bytes()];
+        // inputs of addresses derived with a non default script type, see `get_address`
+        let (script_sig, witness) = if prev_script.is_p2pkh() {
+            let script_sig = bitcoin::blockdata::script::Builder::new()
+                .push_slice(&signature)
+                .push_key(public_key)
+                .into_script();
+            (script_sig, vec![])
+        } else if prev_script.is_v0_p2wpkh() {
+            (Script::new(), vec![signature, public_key.to_bytes()])
+        } else {
+            (p2shwpkh_script_sig(public_key), vec![signature, public_key.to_bytes()])
+        };
         info!(
             "added size len: script_sig:{} witness:{}",
             script_sig.len(),
             witness.iter().map(|v| v.len()).sum::<usize>()
         );
 
-        (script_sig, witness)
+        Ok((script_sig, witness, hash))
     }
 
     pub fn internal_sign_elements(
@@ -625,8 +2220,9 @@ impl WalletCtx {
         input_index: usize,
         derivation_path: &DerivationPath,
         value: Value,
-    ) -> (Script, Vec<Vec<u8>>) {
-        let xprv = self.xprv.derive_priv(&self.secp, &derivation_path).unwrap();
+        sighash_type: SigHashType,
+    ) -> Result<(Script, Vec<Vec<u8>>, [u8; 32]), Error> {
+        let xprv = self.signing_key()?.derive_priv(&self.secp, &derivation_path)?;
         let private_key = &xprv.private_key;
         let public_key = &PublicKey::from_private_key(&self.secp, private_key);
 
@@ -636,13 +2232,13 @@ impl WalletCtx {
             input_index,
             &script_code,
             &value,
-            SigHashType::All.as_u32(),
+            sighash_type.as_u32(),
             true, // segwit
         );
         let message = secp256k1::Message::from_slice(&sighash[..]).unwrap();
         let signature = self.secp.sign(&message, &private_key.key);
         let mut signature = signature.serialize_der().to_vec();
-        signature.push(SigHashType::All as u8);
+        signature.push(sighash_type as u8);
 
         let script_sig = p2shwpkh_script_sig(public_key);
         let witness = vec![signature, public_key.to_bytes()];
@@ -651,13 +2247,135 @@ impl WalletCtx {
             script_sig.len(),
             witness.iter().map(|v| v.len()).sum::<usize>()
         );
-        (script_sig, witness)
+        Ok((script_sig, witness, sighash.into_inner()))
+    }
+
+    /// sign input `input_index` of `tx` with the key derived at `path`, even if the path isn't
+    /// in the wallet db, so that recovery tools can spend outputs of non standard derivations.
+    /// The input is signed as p2shwpkh, in liquid `value` is committed as explicit.
+    /// Returns the public key and the DER signature followed by the sighash type
+    pub fn sign_input_with_path(
+        &self,
+        tx: &BETransaction,
+        input_index: usize,
+        path: &DerivationPath,
+        value: u64,
+    ) -> Result<(PublicKey, Vec<u8>), Error> {
+        info!("sign_input_with_path input#{} path:{}", input_index, path);
+        if input_index >= tx.input_len() {
+            return Err(Error::Generic(format!("input index {} out of range", input_index)));
+        }
+        let xprv = self.signing_key()?.derive_priv(&self.secp, path)?;
+        let public_key = PublicKey::from_private_key(&self.secp, &xprv.private_key);
+        let (_, mut witness, _) = match tx {
+            BETransaction::Bitcoin(tx) => self.internal_sign_bitcoin(
+                tx,
+                input_index,
+                path,
+                value,
+                &p2shwpkh_script(&public_key),
+                SigHashType::All,
+            )?,
+            BETransaction::Elements(tx) => self.internal_sign_elements(
+                tx,
+                input_index,
+                path,
+                Value::Explicit(value),
+                SigHashType::All,
+            )?,
+        };
+        Ok((public_key, witness.swap_remove(0)))
+    }
+
+    /// sign `message` with the key of the wallet `address`, the base64 signature is in the
+    /// format of bitcoin core `signmessage` with the header of segwit addresses as in BIP137.
+    /// Bitcoin only
+    pub fn sign_message(&self, address: &str, message: &str) -> Result<String, Error> {
+        info!("sign_message address:{}", address);
+        let address = self.message_address(address)?;
+        let header = message_header(&address)?;
+        let script = address.script_pubkey();
+        let path = {
+            let store_read = self.store.read()?;
+            match store_read.cache.paths.get(&script) {
+                Some(path) => path.clone(),
+                None => self.find_path(&store_read, &script)?.ok_or(Error::AddressNotMine)?,
+            }
+        };
+        let xprv = self.signing_key()?.derive_priv(&self.secp, &path)?;
+        let msg = Message::from_slice(&signed_msg_hash(message)[..])?;
+        let (rec_id, sig) =
+            self.secp.sign_recoverable(&msg, &xprv.private_key.key).serialize_compact();
+        let mut bytes = vec![header + rec_id.to_i32() as u8];
+        bytes.extend(&sig[..]);
+        Ok(base64::encode(&bytes))
+    }
+
+    /// whether `signature` of `message` was made by the key of `address`, see `sign_message`
+    pub fn verify_message(
+        &self,
+        address: &str,
+        message: &str,
+        signature: &str,
+    ) -> Result<bool, Error> {
+        let address = self.message_address(address)?;
+        let bytes = base64::decode(signature)
+            .map_err(|_| Error::Generic("signature is not base64".into()))?;
+        if bytes.len() != 65 || bytes[0] < 27 {
+            return Ok(false);
+        }
+        let rec_id = RecoveryId::from_i32(((bytes[0] - 27) & 3) as i32)?;
+        let sig = RecoverableSignature::from_compact(&bytes[1..], rec_id)?;
+        let msg = Message::from_slice(&signed_msg_hash(message)[..])?;
+        let key = match self.secp.recover(&msg, &sig) {
+            Ok(key) => key,
+            Err(_) => return Ok(false),
+        };
+        let public_key = PublicKey {
+            compressed: bytes[0] >= 31,
+            key,
+        };
+        let expected = match address.address_type() {
+            Some(AddressType::P2pkh) => Some(Address::p2pkh(&public_key, address.network)),
+            Some(AddressType::P2sh) => Address::p2shwpkh(&public_key, address.network).ok(),
+            Some(AddressType::P2wpkh) => Address::p2wpkh(&public_key, address.network).ok(),
+            _ => None,
+        };
+        Ok(expected.map_or(false, |e| e.script_pubkey() == address.script_pubkey()))
+    }
+
+    fn message_address(&self, address: &str) -> Result<Address, Error> {
+        if let NetworkId::Elements(_) = self.network.id() {
+            return Err(Error::Generic("message signing is supported only in bitcoin".into()));
+        }
+        Address::from_str(address).map_err(|_| Error::InvalidAddress)
+    }
+
+    /// the path of `script` among the default scripts of the wallet given out so far, for
+    /// scripts not yet seen by the sync
+    fn find_path(
+        &self,
+        store_read: &StoreMeta,
+        script: &Script,
+    ) -> Result<Option<DerivationPath>, Error> {
+        let indexes = &store_read.cache.indexes;
+        for (chain, last) in [(0, indexes.external), (1, indexes.internal)].iter() {
+            for index in 0..=*last {
+                if &self.derive_address(&self.xpub, [*chain, index])?.script_pubkey() == script {
+                    return Ok(Some(DerivationPath::from_str(&format!("m/{}/{}", chain, index))?));
+                }
+            }
+        }
+        Ok(None)
     }
 
     pub fn sign(&self, request: &TransactionMeta) -> Result<TransactionMeta, Error> {
         info!("sign");
+        self.check_not_archived()?;
+        self.signing_key()?;
         let be_tx = BETransaction::deserialize(&hex::decode(&request.hex)?, self.network.id())?;
         let store_read = self.store.read()?;
+        let mut signed_inputs = vec![];
         let mut betx: TransactionMeta = match be_tx {
             BETransaction::Bitcoin(tx) => {
                 let mut out_tx = tx.clone();
@@ -666,7 +2384,11 @@ impl WalletCtx {
                     let prev_output = tx.input[i].previous_output;
                     info!("input#{} prev_output:{:?}", i, prev_output);
                     let prev_tx = store_read.get_bitcoin_tx(&prev_output.txid)?;
-                    let out = prev_tx.output[prev_output.vout as usize].clone();
+                    let out = prev_tx
+                        .output
+                        .get(prev_output.vout as usize)
+                        .ok_or(Error::MissingPrevout(i))?
+                        .clone();
                     let derivation_path: DerivationPath = store_read
                         .cache
                         .paths
@@ -678,8 +2400,16 @@ impl WalletCtx {
                         i, prev_output, derivation_path
                     );
 
-                    let (script_sig, witness) =
-                        self.internal_sign_bitcoin(&tx, i, &derivation_path, out.value);
+                    let sighash_type = input_sighash_type(request, i, tx.output.len())?;
+                    let (script_sig, witness, sighash) = self.internal_sign_bitcoin(
+                        &tx,
+                        i,
+                        &derivation_path,
+                        out.value,
+                        &out.script_pubkey,
+                        sighash_type,
+                    )?;
+                    signed_inputs.push(signed_input(i, sighash, sighash_type, &derivation_path));
 
                     out_tx.input[i].script_sig = script_sig;
                     out_tx.input[i].witness = witness;
@@ -700,7 +2430,11 @@ impl WalletCtx {
                     let prev_output = tx.input[i].previous_output;
                     info!("input#{} prev_output:{:?}", i, prev_output);
                     let prev_tx = store_read.get_liquid_tx(&prev_output.txid)?;
-                    let out = prev_tx.output[prev_output.vout as usize].clone();
+                    let out = prev_tx
+                        .output
+                        .get(prev_output.vout as usize)
+                        .ok_or(Error::MissingPrevout(i))?
+                        .clone();
                     let derivation_path: DerivationPath = store_read
                         .cache
                         .paths
@@ -708,8 +2442,15 @@ impl WalletCtx {
                         .ok_or_else(|| Error::Generic("can't find derivation path".into()))?
                         .clone();
 
-                    let (script_sig, witness) =
-                        self.internal_sign_elements(&tx, i, &derivation_path, out.value);
+                    let sighash_type = input_sighash_type(request, i, tx.output.len())?;
+                    let (script_sig, witness, sighash) = self.internal_sign_elements(
+                        &tx,
+                        i,
+                        &derivation_path,
+                        out.value,
+                        sighash_type,
+                    )?;
+                    signed_inputs.push(signed_input(i, sighash, sighash_type, &derivation_path));
 
                     tx.input[i].script_sig = script_sig;
                     tx.input[i].witness.script_witness = witness;
@@ -731,17 +2472,40 @@ impl WalletCtx {
 
         betx.fee = request.fee;
         betx.create_transaction = request.create_transaction.clone();
+        betx.signed_inputs = signed_inputs;
 
         drop(store_read);
         let mut store_write = self.store.write()?;
 
         let changes_used = request.changes_used.unwrap_or(0);
-        if changes_used > 0 {
+        if changes_used > 0 && store_write.change_on_receive_chain() {
+            info!("tx used {} changes on the receive chain", changes_used);
+            // don't give out the change addresses to receive
+            store_write.cache.indexes.external += changes_used;
+        } else if changes_used > 0 {
             info!("tx used {} changes", changes_used);
             // The next sync would update the internal index but we increment the internal index also
             // here after sign so that if we immediately create another tx we are not reusing addresses
-            // This implies signing multiple times without broadcasting leads to gaps in the internal chain
-            store_write.cache.indexes.internal += changes_used;
+            // This implies signing multiple times without broadcasting leads to gaps in the internal chain,
+            // the reservation is logged so that unused indexes are reclaimed at the next wallet open.
+            // The gap never reaches the gap limit, otherwise the sync would not find the change of
+            // the txs broadcasted later, once there the last change indexes are reused
+            let gap_limit = self.network.gap_limit.unwrap_or(crate::GAP_LIMIT).max(1);
+            let gap = store_write.internal_gap()?;
+            if gap + changes_used < gap_limit {
+                let first = store_write.cache.indexes.internal + 1;
+                store_write.reserve_change_indexes(
+                    Txid::from_hex(&betx.txid)?,
+                    first,
+                    changes_used,
+                )?;
+                store_write.cache.indexes.internal += changes_used;
+            } else {
+                warn!(
+                    "internal gap {} near the gap limit {}, reusing change indexes",
+                    gap, gap_limit
+                );
+            }
         }
 
         if let Some(memo) = request.create_transaction.as_ref().and_then(|c| c.memo.as_ref()) {
@@ -751,6 +2515,155 @@ impl WalletCtx {
         Ok(betx)
     }
 
+    /// the signed bitcoin `tx` with the previous outputs of its inputs taken from the wallet
+    /// store, see `verify_bundle`
+    pub fn verification_bundle(&self, tx: &TransactionMeta) -> Result<VerificationBundle, Error> {
+        let be_tx = BETransaction::deserialize(&hex::decode(&tx.hex)?, self.network.id())?;
+        if let BETransaction::Elements(_) = be_tx {
+            return Err(Error::Generic("verification bundle is supported only in bitcoin".into()));
+        }
+        let store_read = self.store.read()?;
+        let mut prevouts = vec![];
+        for outpoint in be_tx.previous_outputs() {
+            let spent = store_read
+                .cache
+                .all_txs
+                .get_previous_output(&outpoint, &store_read.cache.unblinded)
+                .ok_or_else(fn_err(&format!("verification_bundle unknown {:?}", outpoint)))?;
+            prevouts.push(BundlePrevout {
+                value: spent.value.unwrap_or(0), // always known in bitcoin
+                script: spent.script,
+            });
+        }
+        Ok(tx.verification_bundle(prevouts))
+    }
+
+    /// broadcast the signed `tx_meta` and add it to the wallet history as unconfirmed without
+    /// waiting for the next sync, so that its inputs aren't spent again and the addresses it pays
+    /// aren't given out. The reason of a refusal of the server is in `Error::BroadcastRejected`
+    pub fn broadcast(
+        &self,
+        client: &RetryClient,
+        tx_meta: &TransactionMeta,
+    ) -> Result<Txid, Error> {
+        info!("broadcast {}", tx_meta.txid);
+        self.check_not_archived()?;
+        let bytes = hex::decode(&tx_meta.hex)?;
+        let mut tx = BETransaction::deserialize(&bytes, self.network.id())?;
+        let txid = tx.txid();
+        let broadcasted =
+            client.call("broadcast", |c| match c.transaction_broadcast_raw(&bytes) {
+                Ok(txid) => Ok(txid),
+                Err(electrum_client::Error::Protocol(e)) => {
+                    let reason = e.get("message").and_then(|m| m.as_str()).map(|m| m.to_string());
+                    Err(Error::BroadcastRejected(reason.unwrap_or_else(|| e.to_string())))
+                }
+                Err(e) => Err(e.into()),
+            })?;
+        if broadcasted != txid {
+            return Err(Error::Generic(format!(
+                "server returned txid {} broadcasting {}",
+                broadcasted, txid
+            )));
+        }
+
+        let mut store_write = self.store.write()?;
+        let cache = &mut store_write.cache;
+        for vout in 0..tx.output_len() as u32 {
+            let path = match cache.paths.get(&tx.output_script(vout)) {
+                Some(path) => path,
+                None => continue,
+            };
+            if let [ChildNumber::Normal {
+                index: chain,
+            }, ChildNumber::Normal {
+                index,
+            }] = path.as_ref()
+            {
+                match chain {
+                    0 => cache.last_used.external = cache.last_used.external.max(*index),
+                    1 => cache.last_used.internal = cache.last_used.internal.max(*index),
+                    _ => (),
+                }
+            }
+        }
+        cache.indexes.external = cache.indexes.external.max(cache.last_used.external);
+        cache.indexes.internal = cache.indexes.internal.max(cache.last_used.internal);
+        cache.heights.entry(txid).or_insert(None);
+        cache.first_seen.entry(txid).or_insert_with(now);
+        tx.strip_witness();
+        cache.all_txs.insert(txid, tx);
+        cache.all_txs.insert_spent_outputs(&txid, &cache.unblinded);
+        store_write.commit_change_reservations();
+        store_write.flush()?;
+        Ok(txid)
+    }
+
+    /// verify every input of a signed transaction against the previous outputs stored in the
+    /// wallet, catching signing bugs before the network rejects the transaction.
+    /// Only p2shwpkh inputs, the ones created by this wallet, are supported.
+    pub fn verify_signed_tx(&self, tx: &TransactionMeta) -> Result<(), Error> {
+        info!("verify_signed_tx {}", tx.txid);
+        let be_tx = BETransaction::deserialize(&hex::decode(&tx.hex)?, self.network.id())?;
+        let store_read = self.store.read()?;
+        match be_tx {
+            BETransaction::Bitcoin(tx) => {
+                let mut cache = SigHashCache::new(&tx);
+                for (i, input) in tx.input.iter().enumerate() {
+                    let prev_output = input.previous_output;
+                    let prev_tx = store_read.get_bitcoin_tx(&prev_output.txid)?;
+                    let out = prev_tx.output.get(prev_output.vout as usize).ok_or_else(|| {
+                        Error::InvalidSignature(i, "missing previous output".into())
+                    })?;
+                    verify_p2shwpkh_input(
+                        &self.secp,
+                        i,
+                        &input.script_sig,
+                        &input.witness,
+                        &out.script_pubkey,
+                        |script_code, sighash_type| {
+                            cache
+                                .signature_hash(i, script_code, out.value, sighash_type)
+                                .into_inner()
+                        },
+                    )?;
+                }
+            }
+            BETransaction::Elements(tx) => {
+                for (i, input) in tx.input.iter().enumerate() {
+                    let prev_output = input.previous_output;
+                    let prev_tx = store_read.get_liquid_tx(&prev_output.txid)?;
+                    let out = prev_tx.output.get(prev_output.vout as usize).ok_or_else(|| {
+                        Error::InvalidSignature(i, "missing previous output".into())
+                    })?;
+                    verify_p2shwpkh_input(
+                        &self.secp,
+                        i,
+                        &input.script_sig,
+                        &input.witness.script_witness,
+                        &out.script_pubkey,
+                        |script_code, sighash_type| {
+                            tx_get_elements_signature_hash(
+                                &tx,
+                                i,
+                                script_code,
+                                &out.value,
+                                sighash_type.as_u32(),
+                                true, // segwit
+                            )
+                            .into_inner()
+                        },
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// blind the outputs of a tx created by `create_tx` with a confidential nonce, computing the
+    /// asset and value commitments, range and surjection proofs. Explicit outputs and the fee stay
+    /// unblinded. It's done by `sign`, right before signing, since `create_tx` returns a tx still
+    /// open to changes (fee bumps, added inputs) that would invalidate the blinding factors
     fn blind_tx(&self, tx: &mut elements::Transaction) -> Result<(), Error> {
         info!("blind_tx {}", tx.txid());
         let mut input_assets = vec![];
@@ -785,133 +2698,206 @@ impl WalletCtx {
         let ct_bits = self.network.ct_bits.expect("ct_bits not set in network");
         info!("ct params ct_exp:{}, ct_bits:{}", ct_exp, ct_bits);
 
+        // explicit outputs (eg. peg-outs) are not blinded, they take part in the final value
+        // blinding factor computation with zero blinding factors, placed before the blinded ones
+        // since the final vbf is assigned to the last blinded output
+        let explicit: Vec<usize> = (0..tx.output.len())
+            .filter(|i| !tx.output[*i].is_fee() && tx.output[*i].nonce == Nonce::Null)
+            .collect();
+        let to_blind: Vec<usize> = (0..tx.output.len())
+            .filter(|i| !tx.output[*i].is_fee() && tx.output[*i].nonce != Nonce::Null)
+            .collect();
+        let last_blinded =
+            *to_blind.last().ok_or_else(|| Error::Generic("no output to blind".into()))?;
+
         let mut output_blinded_values = vec![];
-        for output in tx.output.iter() {
-            if !output.is_fee() {
-                output_blinded_values.push(output.minimum_value());
-            }
+        for i in explicit.iter().chain(to_blind.iter()) {
+            output_blinded_values.push(tx.output[*i].minimum_value());
         }
         info!("output_blinded_values {:?}", output_blinded_values);
         let mut all_values = vec![];
         all_values.extend(input_values);
         all_values.extend(output_blinded_values);
         let in_num = tx.input.len();
-        let out_num = tx.output.len();
 
-        let output_abfs: Vec<Vec<u8>> = (0..out_num - 1).map(|_| random32()).collect();
-        let mut output_vbfs: Vec<Vec<u8>> = (0..out_num - 2).map(|_| random32()).collect();
+        let mut output_abfs = vec![vec![0u8; 32]; tx.output.len()];
+        let mut output_vbfs = vec![vec![0u8; 32]; tx.output.len()];
+        for i in to_blind.iter() {
+            output_abfs[*i] = random32();
+            if *i != last_blinded {
+                output_vbfs[*i] = random32();
+            }
+        }
 
         let mut all_abfs = vec![];
         all_abfs.extend(input_abfs.to_vec());
-        all_abfs.extend(output_abfs.iter().cloned().flatten().collect::<Vec<u8>>());
+        for i in explicit.iter().chain(to_blind.iter()) {
+            all_abfs.extend(output_abfs[*i].iter());
+        }
 
         let mut all_vbfs = vec![];
         all_vbfs.extend(input_vbfs.to_vec());
-        all_vbfs.extend(output_vbfs.iter().cloned().flatten().collect::<Vec<u8>>());
+        for i in explicit.iter().chain(to_blind.iter()).filter(|i| **i != last_blinded) {
+            all_vbfs.extend(output_vbfs[*i].iter());
+        }
 
         let last_vbf = asset_final_vbf(all_values, in_num as u32, all_abfs, all_vbfs);
-        output_vbfs.push(last_vbf.to_vec());
+        output_vbfs[last_blinded] = last_vbf.to_vec();
 
-        for (i, mut output) in tx.output.iter_mut().enumerate() {
+        for i in to_blind {
+            let output = &mut tx.output[i];
             info!("output {:?}", output);
-            if !output.is_fee() {
-                match (output.value, output.asset, output.nonce) {
-                    (Value::Explicit(value), Asset::Explicit(asset), Nonce::Confidential(_, _)) => {
-                        info!("value: {}", value);
-                        let nonce = elements::encode::serialize(&output.nonce);
-                        let blinding_pubkey = PublicKey::from_slice(&nonce).unwrap();
-                        let blinding_key = asset_blinding_key_to_ec_private_key(
-                            self.master_blinding.as_ref().unwrap(),
-                            &output.script_pubkey,
-                        );
-                        let blinding_public_key = ec_public_key_from_private_key(blinding_key);
-                        let mut output_abf = [0u8; 32];
-                        output_abf.copy_from_slice(&(&output_abfs[i])[..]);
-                        let mut output_vbf = [0u8; 32];
-                        output_vbf.copy_from_slice(&(&output_vbfs[i])[..]);
-                        let asset = asset.clone().into_inner();
-
-                        let output_generator =
-                            asset_generator_from_bytes(&asset.into_inner(), &output_abf);
-                        let output_value_commitment =
-                            asset_value_commitment(value, output_vbf, output_generator);
-                        let min_value = if output.script_pubkey.is_provably_unspendable() {
-                            0
-                        } else {
-                            1
-                        };
+            match (output.value, output.asset, output.nonce) {
+                (Value::Explicit(value), Asset::Explicit(asset), Nonce::Confidential(_, _)) => {
+                    info!("value: {}", value);
+                    let nonce = elements::encode::serialize(&output.nonce);
+                    let blinding_pubkey = PublicKey::from_slice(&nonce).unwrap();
+                    let blinding_key = asset_blinding_key_to_ec_private_key(
+                        self.master_blinding()?,
+                        &output.script_pubkey,
+                    );
+                    let blinding_public_key = ec_public_key_from_private_key(blinding_key);
+                    let mut output_abf = [0u8; 32];
+                    output_abf.copy_from_slice(&(&output_abfs[i])[..]);
+                    let mut output_vbf = [0u8; 32];
+                    output_vbf.copy_from_slice(&(&output_vbfs[i])[..]);
+                    let asset = asset.clone().into_inner();
 
-                        let rangeproof = asset_rangeproof(
-                            value,
-                            blinding_pubkey.key,
-                            blinding_key,
-                            asset.into_inner(),
-                            output_abf,
-                            output_vbf,
-                            output_value_commitment,
-                            &output.script_pubkey,
-                            output_generator,
-                            min_value,
-                            ct_exp,
-                            ct_bits,
-                        );
-                        trace!("asset: {}", hex::encode(&asset));
-                        trace!("output_abf: {}", hex::encode(&output_abf));
-                        trace!(
-                            "output_generator: {}",
-                            hex::encode(&elements::encode::serialize(&output_generator))
-                        );
-                        trace!("input_assets: {}", hex::encode(&input_assets));
-                        trace!("input_abfs: {}", hex::encode(&input_abfs));
-                        trace!("input_ags: {}", hex::encode(&input_ags));
-                        trace!("in_num: {}", in_num);
-
-                        let surjectionproof = asset_surjectionproof(
-                            asset.into_inner(),
-                            output_abf,
-                            output_generator,
-                            output_abf,
-                            &input_assets,
-                            &input_abfs,
-                            &input_ags,
-                            in_num,
-                        );
-                        trace!("surjectionproof: {}", hex::encode(&surjectionproof));
-
-                        let bytes = blinding_public_key.serialize();
-                        let byte32: [u8; 32] = bytes[1..].as_ref().try_into().unwrap();
-                        output.nonce =
-                            elements::confidential::Nonce::Confidential(bytes[0], byte32);
-                        output.asset = output_generator;
-                        output.value = output_value_commitment;
-                        info!(
-                            "added size len: surjectionproof:{} rangeproof:{}",
-                            surjectionproof.len(),
-                            rangeproof.len()
-                        );
-                        output.witness.surjection_proof = surjectionproof;
-                        output.witness.rangeproof = rangeproof;
-                    }
-                    _ => panic!("create_tx created things not right"),
+                    let output_generator =
+                        asset_generator_from_bytes(&asset.into_inner(), &output_abf);
+                    let output_value_commitment =
+                        asset_value_commitment(value, output_vbf, output_generator);
+                    let min_value = if output.script_pubkey.is_provably_unspendable() {
+                        0
+                    } else {
+                        1
+                    };
+
+                    let rangeproof = asset_rangeproof(
+                        value,
+                        blinding_pubkey.key,
+                        blinding_key,
+                        asset.into_inner(),
+                        output_abf,
+                        output_vbf,
+                        output_value_commitment,
+                        &output.script_pubkey,
+                        output_generator,
+                        min_value,
+                        ct_exp,
+                        ct_bits,
+                    );
+                    trace!("asset: {}", hex::encode(&asset));
+                    trace!("output_abf: {}", hex::encode(&output_abf));
+                    trace!(
+                        "output_generator: {}",
+                        hex::encode(&elements::encode::serialize(&output_generator))
+                    );
+                    trace!("input_assets: {}", hex::encode(&input_assets));
+                    trace!("input_abfs: {}", hex::encode(&input_abfs));
+                    trace!("input_ags: {}", hex::encode(&input_ags));
+                    trace!("in_num: {}", in_num);
+
+                    let surjectionproof = asset_surjectionproof(
+                        asset.into_inner(),
+                        output_abf,
+                        output_generator,
+                        output_abf,
+                        &input_assets,
+                        &input_abfs,
+                        &input_ags,
+                        in_num,
+                    );
+                    trace!("surjectionproof: {}", hex::encode(&surjectionproof));
+
+                    let bytes = blinding_public_key.serialize();
+                    let byte32: [u8; 32] = bytes[1..].as_ref().try_into().unwrap();
+                    output.nonce = elements::confidential::Nonce::Confidential(bytes[0], byte32);
+                    output.asset = output_generator;
+                    output.value = output_value_commitment;
+                    info!(
+                        "added size len: surjectionproof:{} rangeproof:{}",
+                        surjectionproof.len(),
+                        rangeproof.len()
+                    );
+                    output.witness.surjection_proof = surjectionproof;
+                    output.witness.rangeproof = rangeproof;
                 }
+                _ => panic!("create_tx created things not right"),
             }
         }
         Ok(())
     }
 
-    pub fn get_address(&self) -> Result<AddressPointer, Error> {
+    /// get a new receive address, of the wallet default type if `script_type` is None
+    pub fn get_address(&self, script_type: Option<ScriptType>) -> Result<AddressPointer, Error> {
+        self.get_next_address(script_type, true)
+    }
+
+    /// like `get_address`, but when `advance` is false the address at the current external index
+    /// is returned again until a synced tx pays it, so that requesting addresses without
+    /// receiving funds doesn't widen the gap of unused addresses
+    pub fn get_next_address(
+        &self,
+        script_type: Option<ScriptType>,
+        advance: bool,
+    ) -> Result<AddressPointer, Error> {
+        let script_type = script_type.unwrap_or(self.script_type);
+        let network = match (self.network.id(), script_type) {
+            (_, script_type) if script_type == self.script_type => None,
+            (NetworkId::Bitcoin(network), _) => Some(network),
+            (NetworkId::Elements(_), _) => {
+                return Err(Error::Generic(format!("{} addresses are not supported", script_type)))
+            }
+        };
         let pointer = {
-            let store = &mut self.store.write()?.cache;
-            store.indexes.external += 1;
-            store.indexes.external
+            let mut store_write = self.store.write()?;
+            let current = store_write.cache.indexes.external;
+            if !advance && current > 0 && !is_external_used(&store_write, current) {
+                current
+            } else {
+                store_write.cache.indexes.external += 1;
+                store_write.cache.indexes.external
+            }
+        };
+        let address = match network {
+            None => self.derive_address(&self.xpub, [0, pointer])?.to_string(),
+            Some(network) => {
+                let path =
+                    DerivationPath::from(vec![ChildNumber::from(0), ChildNumber::from(pointer)]);
+                let derived = self.xpub.derive_pub(&self.secp, &path)?;
+                let script = script_type.script_pubkey(&derived.public_key);
+                let address = Address::from_script(&script, network)
+                    .ok_or_else(fn_err("script without address form"))?;
+                self.store.write()?.insert_extra_script(script, path)?;
+                address.to_string()
+            }
         };
-        let address = self.derive_address(&self.xpub, [0, pointer])?.to_string();
         Ok(AddressPointer {
             address,
             pointer,
         })
     }
 
+    /// liquid address of the external chain at `pointer` blinded with a key derived also from
+    /// `invoice_nonce`, invoices reusing an address share the script but not the blinding key.
+    /// The nonce is stored so that sync can unblind the outputs paying the invoice
+    pub fn get_invoice_address(
+        &self,
+        pointer: u32,
+        invoice_nonce: u32,
+    ) -> Result<AddressPointer, Error> {
+        if let NetworkId::Bitcoin(_) = self.network.id() {
+            return Err(Error::Generic("invoice blinding is supported only in liquid".into()));
+        }
+        let address = self.derive_blinded_address(&self.xpub, [0, pointer], Some(invoice_nonce))?;
+        self.store.write()?.insert_invoice_nonce(address.script_pubkey(), invoice_nonce)?;
+        Ok(AddressPointer {
+            address: address.to_string(),
+            pointer,
+        })
+    }
+
     pub fn get_asset_icons(&self) -> Result<Option<serde_json::Value>, Error> {
         self.store.read()?.read_asset_icons()
     }
@@ -920,6 +2906,171 @@ impl WalletCtx {
     }
 }
 
+const WATCH_BLOB_VERSION: u32 = 1;
+
+/// verify the signatures of the bitcoin tx in `bundle` against its prevouts, without the
+/// wallet history. Only p2shwpkh inputs are supported, like in `WalletCtx::verify_signed_tx`
+pub fn verify_bundle(bundle: &VerificationBundle) -> Result<(), Error> {
+    let tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&hex::decode(&bundle.hex)?)?;
+    if tx.input.len() != bundle.prevouts.len() {
+        return Err(Error::Generic("a prevout for every input is required".into()));
+    }
+    let secp = Secp256k1::new();
+    let mut cache = SigHashCache::new(&tx);
+    for (i, (input, prevout)) in tx.input.iter().zip(bundle.prevouts.iter()).enumerate() {
+        verify_p2shwpkh_input(
+            &secp,
+            i,
+            &input.script_sig,
+            &input.witness,
+            &prevout.script,
+            |script_code, sighash_type| {
+                cache.signature_hash(i, script_code, prevout.value, sighash_type).into_inner()
+            },
+        )?;
+    }
+    Ok(())
+}
+
+fn verify_p2shwpkh_input<F>(
+    secp: &Secp256k1<All>,
+    index: usize,
+    script_sig: &Script,
+    witness: &[Vec<u8>],
+    prev_script: &Script,
+    mut sighash: F,
+) -> Result<(), Error>
+where
+    F: FnMut(&Script, SigHashType) -> [u8; 32],
+{
+    let fail = |reason: &str| Error::InvalidSignature(index, reason.to_string());
+    if witness.len() != 2 {
+        return Err(fail("witness must contain signature and public key"));
+    }
+    let public_key = PublicKey::from_slice(&witness[1]).map_err(|_| fail("invalid public key"))?;
+    // native segwit inputs have an empty script_sig
+    let expected_script_sig = if p2shwpkh_script(&public_key) == *prev_script {
+        p2shwpkh_script_sig(&public_key)
+    } else if ScriptType::P2wpkh.script_pubkey(&public_key) == *prev_script {
+        Script::new()
+    } else {
+        return Err(fail("public key doesn't match the previous output script"));
+    };
+    if *script_sig != expected_script_sig {
+        return Err(fail("unexpected script_sig"));
+    }
+    let (sighash_byte, der) = witness[0].split_last().ok_or_else(|| fail("empty signature"))?;
+    let sighash_type = SigHashType::from_u32(*sighash_byte as u32);
+    let hash = sighash(&p2pkh_script(&public_key), sighash_type);
+    let message = Message::from_slice(&hash[..])?;
+    let signature =
+        secp256k1::Signature::from_der(der).map_err(|_| fail("invalid signature encoding"))?;
+    secp.verify(&message, &signature, &public_key.key).map_err(|_| fail("signature doesn't verify"))
+}
+
+fn network_name(id: NetworkId) -> &'static str {
+    match id {
+        NetworkId::Bitcoin(bitcoin::Network::Bitcoin) => "bitcoin",
+        NetworkId::Bitcoin(bitcoin::Network::Testnet) => "testnet",
+        NetworkId::Bitcoin(bitcoin::Network::Regtest) => "regtest",
+        NetworkId::Elements(ElementsNetwork::Liquid) => "liquid",
+        NetworkId::Elements(ElementsNetwork::ElementsRegtest) => "elementsregtest",
+    }
+}
+
+/// every output of `tx` must pay one of the `expected` recipients, each matched at most once,
+/// or a wallet script. Every expected recipient must be paid
+fn check_psbt_outputs(
+    tx: &Transaction,
+    expected: &[AddressAmount],
+    paths: &HashMap<Script, DerivationPath>,
+) -> Result<(), Error> {
+    let mut remaining = expected
+        .iter()
+        .map(|a| {
+            let address = Address::from_str(&a.address).map_err(|_| Error::InvalidAddress)?;
+            Ok((address.script_pubkey(), a.satoshi))
+        })
+        .collect::<Result<Vec<(Script, u64)>, Error>>()?;
+    for output in tx.output.iter() {
+        let paid = (output.script_pubkey.clone(), output.value);
+        match remaining.iter().position(|e| *e == paid) {
+            Some(index) => {
+                remaining.remove(index);
+            }
+            None if paths.contains_key(&output.script_pubkey) => (), // change
+            None => return Err(Error::PsbtOutputMismatch),
+        }
+    }
+    if remaining.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::PsbtOutputMismatch)
+    }
+}
+
+/// convert electrum fee `estimates` in BTC/kbyte to satoshi/kbyte, at least `min`. The server
+/// returns -1 for the targets it can't estimate
+fn fee_rates(estimates: &[f64], min: u64) -> Vec<u64> {
+    estimates.iter().map(|e| ((e * 100_000_000.0).round() as u64).max(min)).collect()
+}
+
+/// whether an unspent output is spendable, still unconfirmed or an immature coinbase output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UtxoStatus {
+    Confirmed,
+    Unconfirmed,
+    Immature,
+}
+
+/// confirmations of a tx at `height` with the chain at `tip`, None for unconfirmed txs
+fn confirmations(tip: u32, height: Option<u32>) -> Option<u32> {
+    height.map(|h| tip.saturating_sub(h) + 1)
+}
+
+/// keep in `utxos` only the `selected` ones, given as `txid:vout`, failing if any of them isn't
+/// an unspent output of the wallet
+fn only_utxos(utxos: Utxos, selected: &[String]) -> Result<Utxos, Error> {
+    let mut outpoints = HashSet::new();
+    for utxo in selected {
+        let outpoint = bitcoin::OutPoint::from_str(utxo)
+            .map_err(|_| Error::Generic(format!("invalid utxo {}", utxo)))?;
+        outpoints.insert(outpoint);
+    }
+    let utxos: Utxos = utxos
+        .into_iter()
+        .filter(|(o, _)| outpoints.contains(&bitcoin::OutPoint::new(o.txid(), o.vout())))
+        .collect();
+    for outpoint in outpoints {
+        if !utxos.iter().any(|(o, _)| o.txid() == outpoint.txid && o.vout() == outpoint.vout) {
+            return Err(Error::UtxoNotFound(outpoint.to_string()));
+        }
+    }
+    Ok(utxos)
+}
+
+/// whether a wallet tx pays an address derived at the external index `pointer`, of any type
+fn is_external_used(store: &StoreMeta, pointer: u32) -> bool {
+    let path = DerivationPath::from(vec![ChildNumber::from(0), ChildNumber::from(pointer)]);
+    store.cache.heights.keys().filter_map(|txid| store.cache.all_txs.get(txid)).any(|tx| {
+        (0..tx.output_len() as u32)
+            .any(|vout| store.cache.paths.get(&tx.output_script(vout)) == Some(&path))
+    })
+}
+
+/// wallet txs with their heights, sorted from the most recent, unconfirmed txs first
+fn sorted_history(heights: &HashMap<Txid, Option<u32>>) -> Vec<(&Txid, &Option<u32>)> {
+    let mut my_txids: Vec<(&Txid, &Option<u32>)> = heights.iter().collect();
+    my_txids.sort_by(|a, b| {
+        let height_cmp = b.1.unwrap_or(std::u32::MAX).cmp(&a.1.unwrap_or(std::u32::MAX));
+        match height_cmp {
+            Ordering::Equal => b.0.cmp(a.0),
+            h @ _ => h,
+        }
+    });
+    my_txids
+}
+
 fn address_params(net: ElementsNetwork) -> &'static elements::AddressParams {
     match net {
         ElementsNetwork::Liquid => &elements::AddressParams::LIQUID,
@@ -927,22 +3078,148 @@ fn address_params(net: ElementsNetwork) -> &'static elements::AddressParams {
     }
 }
 
+fn value_histogram<I>(values: I, buckets: &[u64]) -> Result<Vec<(u64, usize)>, Error>
+where
+    I: Iterator<Item = u64>,
+{
+    if buckets.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(Error::Generic("histogram buckets must be strictly increasing".into()));
+    }
+    let mut result: Vec<(u64, usize)> = buckets.iter().map(|b| (*b, 0)).collect();
+    result.push((std::u64::MAX, 0));
+    for value in values {
+        let index = buckets.iter().position(|b| value <= *b).unwrap_or(buckets.len());
+        result[index].1 += 1;
+    }
+    Ok(result)
+}
+
+/// derivation path of the BIP44 `account` for addresses of `script_type`:
+/// m / purpose' / coin_type' / account', with coin_type 0 for bitcoin, 1776 for liquid and 1 for
+/// the test networks as in SLIP44
+pub fn account_path(
+    id: NetworkId,
+    script_type: ScriptType,
+    account: u32,
+) -> Result<DerivationPath, Error> {
+    let coin_type = match id {
+        NetworkId::Bitcoin(bitcoin::Network::Bitcoin) => 0,
+        NetworkId::Elements(ElementsNetwork::Liquid) => 1776,
+        _ => 1,
+    };
+    let path = [
+        ChildNumber::from_hardened_idx(script_type.purpose())?,
+        ChildNumber::from_hardened_idx(coin_type)?,
+        ChildNumber::from_hardened_idx(account)?,
+    ];
+    Ok(DerivationPath::from(&path[..]))
+}
+
+/// the chain, external 0 or internal 1, of a path derived from the wallet xpub
+fn path_chain(path: &DerivationPath) -> Option<u32> {
+    match path.as_ref() {
+        [ChildNumber::Normal {
+            index: chain,
+        }, ChildNumber::Normal {
+            ..
+        }] => Some(*chain),
+        _ => None,
+    }
+}
+
+/// first byte of a message signature of `address` for recovery id 0, see BIP137
+fn message_header(address: &Address) -> Result<u8, Error> {
+    match address.address_type() {
+        Some(AddressType::P2pkh) => Ok(31),
+        Some(AddressType::P2sh) => Ok(35),
+        Some(AddressType::P2wpkh) => Ok(39),
+        _ => Err(Error::Generic(format!("cannot sign messages for address {}", address))),
+    }
+}
+
+/// the sighash type requested in `TransactionMeta::sighash_types` for input `index`, SIGHASH_ALL if
+/// missing. SIGHASH_SINGLE without an output at the same index is refused: legacy inputs would
+/// sign the constant 1 and segwit ones would commit to no output, both unlikely to be intended
+fn input_sighash_type(
+    request: &TransactionMeta,
+    index: usize,
+    outputs: usize,
+) -> Result<SigHashType, Error> {
+    let value = match request.sighash_types.get(index) {
+        Some(value) => *value,
+        None => return Ok(SigHashType::All),
+    };
+    let sighash_type = SigHashType::from_u32(value);
+    if sighash_type.as_u32() != value {
+        return Err(Error::Generic(format!("input#{} non standard sighash {}", index, value)));
+    }
+    let (base, _) = sighash_type.split_anyonecanpay_flag();
+    if base == SigHashType::Single && index >= outputs {
+        return Err(Error::Generic(format!("input#{} SIGHASH_SINGLE without output", index)));
+    }
+    Ok(sighash_type)
+}
+
+fn signed_input(
+    index: usize,
+    sighash: [u8; 32],
+    sighash_type: SigHashType,
+    path: &DerivationPath,
+) -> SignedInput {
+    SignedInput {
+        index: index as u32,
+        sighash: hex::encode(&sighash),
+        sighash_type: sighash_type.as_u32(),
+        derivation_path: path.to_string(),
+    }
+}
+
 fn random32() -> Vec<u8> {
     rand::thread_rng().gen::<[u8; 32]>().to_vec()
 }
 
 #[cfg(test)]
 mod test {
-    use crate::interface::p2shwpkh_script_sig;
-    use bitcoin::consensus::deserialize;
-    use bitcoin::hashes::Hash;
-    use bitcoin::secp256k1::{All, Message, Secp256k1, SecretKey};
-    use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+    use crate::error::Error;
+    use crate::interface::{
+        account_path, fee_rates, p2pkh_script, p2shwpkh_script_sig, parse_socks5, value_histogram,
+        verify_bundle, verify_p2shwpkh_input, ElectrumUrl, TlsValidation, WalletCtx, MAX_MEMO_LEN,
+    };
+    use crate::store::{Indexes, MemoryBackend, StoreMeta};
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin::hashes::{sha256d, Hash};
+    use bitcoin::secp256k1::{All, Message, Secp256k1, SecretKey, Signature};
+    use bitcoin::util::address::AddressType;
+    use bitcoin::util::bip143::SigHashCache;
+    use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey};
     use bitcoin::util::key::PrivateKey;
     use bitcoin::util::key::PublicKey;
+    use bitcoin::util::psbt::PartiallySignedTransaction;
     use bitcoin::Script;
-    use bitcoin::{Address, Network, Transaction};
+    use bitcoin::{Address, Network, OutPoint, SigHashType, Transaction, TxIn, TxOut, Txid};
+    use elements::confidential::Nonce;
+    use elements::TxOutWitness;
+    use gdk_common::be::{
+        asset_to_hex, AssetId, BEBlockHeader, BEOutPoint, BETransaction, Unblinded,
+        BITCOIN_ASSET_ID,
+    };
+    use gdk_common::model::{
+        AddressAmount, CreateTransaction, GetTransactionsOpt, InvoicePayment, LotMethod, OutputInfo,
+    };
+    use gdk_common::model::{AddressPointer, QrErrorCorrection, TransactionMeta, TxWarning};
+    use gdk_common::network::ElementsNetwork;
+    use gdk_common::scripts::ScriptType;
+    use gdk_common::wally::{
+        asset_blinding_key_from_seed, asset_blinding_key_to_ec_private_key,
+        asset_generator_from_bytes, asset_rangeproof, asset_unblind, asset_value_commitment,
+        ec_public_key_from_private_key,
+    };
+    use gdk_common::NetworkId;
+    use std::collections::{HashMap, HashSet};
+    use std::convert::TryInto;
     use std::str::FromStr;
+    use std::sync::{Arc, RwLock};
+    use tempdir::TempDir;
 
     fn p2pkh_hex(pk: &str) -> (PublicKey, Script) {
         let pk = hex::decode(pk).unwrap();
@@ -977,8 +3254,9 @@ mod test {
             "76a91479091972186c449eb1ded22b78e40d009bdf008988ac"
         );
         let value = 1_000_000_000;
-        let comp = SighashComponents::new(&tx);
-        let hash = comp.sighash_all(&tx.input[0], &witness_script, value).into_inner();
+        let hash = SigHashCache::new(&tx)
+            .signature_hash(0, &witness_script, value, SigHashType::All)
+            .into_inner();
 
         assert_eq!(
             &hash[..],
@@ -1028,15 +3306,25 @@ mod test {
             "76a9141790ee5e7710a06ce4a9250c8677c1ec2843844f88ac"
         );
         let value = 10_202;
-        let comp = SighashComponents::new(&tx);
-        let hash = comp.sighash_all(&tx.input[0], &witness_script, value);
+        let hash = SigHashCache::new(&tx)
+            .signature_hash(0, &witness_script, value, SigHashType::All)
+            .into_inner();
 
         assert_eq!(
-            &hash.into_inner()[..],
+            &hash[..],
             &hex::decode("58b15613fc1701b2562430f861cdc5803531d08908df531082cf1828cd0b8995")
                 .unwrap()[..],
         );
 
+        let wallet = memory_wallet_with_backend(xprv, MemoryBackend::default());
+        let path = DerivationPath::from(vec![]);
+        let (wallet_script_sig, wallet_witness, wallet_hash) = wallet
+            .internal_sign_bitcoin(&tx, 0, &path, value, &address.script_pubkey(), SigHashType::All)
+            .unwrap();
+        assert_eq!(wallet_hash, hash);
+        assert_eq!(wallet_witness, tx.input[0].witness);
+        assert_eq!(wallet_script_sig, tx.input[0].script_sig);
+
         let signature = secp.sign(&Message::from_slice(&hash[..]).unwrap(), &private_key.key);
 
         //let mut signature = signature.serialize_der().to_vec();
@@ -1050,4 +3338,2783 @@ mod test {
         let script_sig = p2shwpkh_script_sig(&public_key);
         assert_eq!(tx.input[0].script_sig, script_sig);
     }
+
+    #[test]
+    fn test_value_histogram() {
+        let values = vec![1_000, 2_000, 546, 10_000, 99_999, 100_000, 5_000_000, 3_000];
+        let buckets = [1_000, 10_000, 100_000];
+        let result = value_histogram(values.into_iter(), &buckets).unwrap();
+        assert_eq!(result, vec![(1_000, 2), (10_000, 3), (100_000, 2), (std::u64::MAX, 1)]);
+
+        let result = value_histogram(vec![].into_iter(), &[]).unwrap();
+        assert_eq!(result, vec![(std::u64::MAX, 0)]);
+
+        assert!(value_histogram(vec![].into_iter(), &[10, 10]).is_err());
+        assert!(value_histogram(vec![].into_iter(), &[10, 5]).is_err());
+    }
+
+    /// the master key of the test wallets
+    fn test_xprv() -> ExtendedPrivKey {
+        ExtendedPrivKey::new_master(Network::Regtest, &[1u8; 32]).unwrap()
+    }
+
+    /// a bitcoin regtest wallet of `test_xprv`
+    fn memory_wallet() -> WalletCtx {
+        memory_wallet_with_backend(test_xprv(), MemoryBackend::default())
+    }
+
+    fn memory_wallet_with_backend(xprv: ExtendedPrivKey, backend: MemoryBackend) -> WalletCtx {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let xpub = ExtendedPubKey::from_private(&secp, &xprv);
+        let mut network = gdk_common::network::Network::default();
+        network.development = true;
+        let id = NetworkId::Bitcoin(Network::Regtest);
+        let backend = Box::new(backend);
+        let store = StoreMeta::with_backend(backend, xpub, None, id).unwrap();
+        let store = Arc::new(RwLock::new(store));
+        let mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about".to_string().into();
+        let fingerprint = xprv.fingerprint(&secp);
+        WalletCtx::new(store, mnemonic, network, xprv, xpub, fingerprint, None).unwrap()
+    }
+
+    /// a watch-only elements regtest wallet of `test_xprv` without master blinding key
+    fn liquid_memory_wallet() -> WalletCtx {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let xprv = test_xprv();
+        let xpub = ExtendedPubKey::from_private(&secp, &xprv);
+        let mut network = gdk_common::network::Network::default();
+        network.liquid = true;
+        network.development = true;
+        let id = NetworkId::Elements(ElementsNetwork::ElementsRegtest);
+        let backend = Box::new(MemoryBackend::default());
+        let store = StoreMeta::with_backend(backend, xpub, None, id).unwrap();
+        let store = Arc::new(RwLock::new(store));
+        let fingerprint = xprv.fingerprint(&secp);
+        WalletCtx::new_watch_only(store, network, xpub, fingerprint, None).unwrap()
+    }
+
+    /// add to the wallet history a confirmed tx paying `value` to the first external address
+    fn receive(wallet: &WalletCtx, value: u64) {
+        receive_at(wallet, value, Some(1));
+    }
+
+    fn receive_at(wallet: &WalletCtx, value: u64, height: Option<u32>) -> Txid {
+        receive_to(wallet, 0, value, height)
+    }
+
+    /// a p2wpkh address of the `test_xprv` master key, not one of the wallet
+    fn recipient_address(wallet: &WalletCtx) -> String {
+        Address::p2wpkh(&test_xprv().private_key.public_key(&wallet.secp), Network::Regtest)
+            .unwrap()
+            .to_string()
+    }
+
+    /// a request sending `satoshi` to `recipient_address`
+    fn send_request(wallet: &WalletCtx, satoshi: u64) -> CreateTransaction {
+        let mut request = CreateTransaction::default();
+        request.addressees.push(AddressAmount {
+            address: recipient_address(wallet),
+            satoshi,
+            asset_tag: None,
+        });
+        request
+    }
+
+    /// like `receive_at` paying the external address at `index`
+    fn receive_to(wallet: &WalletCtx, index: usize, value: u64, height: Option<u32>) -> Txid {
+        let script = {
+            let mut store = wallet.store.write().unwrap();
+            let (script, path) = store.get_script_batch(0, 0).unwrap().value.remove(index);
+            store.cache.paths.insert(script.clone(), path.clone());
+            store.cache.scripts.insert(path, script.clone());
+            script
+        };
+        receive_script(wallet, script, value, height)
+    }
+
+    /// a version 2 bitcoin tx without locktime
+    fn transaction(input: Vec<TxIn>, output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input,
+            output,
+        }
+    }
+
+    /// add to the wallet history a tx paying `value` to `script`, which must be of the wallet
+    fn receive_script(wallet: &WalletCtx, script: Script, value: u64, height: Option<u32>) -> Txid {
+        let mut store = wallet.store.write().unwrap();
+
+        let prev = transaction(
+            vec![],
+            vec![TxOut {
+                value: value + 10_000,
+                script_pubkey: Script::new(),
+            }],
+        );
+        let tx = transaction(
+            vec![TxIn {
+                previous_output: OutPoint::new(prev.txid(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffe,
+                witness: vec![],
+            }],
+            vec![TxOut {
+                value,
+                script_pubkey: script,
+            }],
+        );
+        let txid = tx.txid();
+        store.cache.heights.insert(txid, height);
+        store.cache.all_txs.insert(prev.txid(), BETransaction::Bitcoin(prev));
+        store.cache.all_txs.insert(txid, BETransaction::Bitcoin(tx));
+        txid
+    }
+
+    #[test]
+    fn test_list_tx_memory_store() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let txs = wallet.list_tx(&opt).unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].type_, "incoming");
+        assert_eq!(txs[0].fee, Some(10_000));
+        assert_eq!(txs[0].satoshi.get("btc"), Some(&90_000));
+        assert!(txs[0].outputs[0].is_mine);
+    }
+
+    #[test]
+    fn test_archived_wallet() {
+        let mut wallet = memory_wallet();
+        receive(&wallet, 90_000);
+
+        let mut request = send_request(&wallet, 10_000);
+        assert!(wallet.create_tx(&mut request.clone()).is_ok());
+
+        wallet.set_archived(true).unwrap();
+        assert!(matches!(wallet.create_tx(&mut request.clone()), Err(Error::WalletArchived)));
+        assert_eq!(wallet.balance().unwrap().get("btc"), Some(&90_000));
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        assert_eq!(wallet.list_tx(&opt).unwrap().len(), 1);
+        assert!(wallet.store.read().unwrap().is_archived());
+
+        wallet.set_archived(false).unwrap();
+        assert!(wallet.create_tx(&mut request).is_ok());
+    }
+
+    #[test]
+    fn test_internal_gap() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        assert_eq!(wallet.internal_gap().unwrap(), 0);
+
+        let mut request = send_request(&wallet, 10_000);
+        for expected_gap in 1..=3 {
+            // signed and abandoned, never reaching the wallet history
+            let tx = wallet.create_tx(&mut request.clone()).unwrap();
+            assert_eq!(tx.changes_used, Some(1));
+            wallet.sign(&tx).unwrap();
+            assert_eq!(wallet.internal_gap().unwrap(), expected_gap);
+        }
+    }
+
+    #[test]
+    fn test_check_invoices() {
+        let wallet = memory_wallet();
+        let (scripts, txid) = {
+            let mut store = wallet.store.write().unwrap();
+            let scripts: Vec<Script> =
+                store.get_script_batch(0, 0).unwrap().value.into_iter().map(|e| e.0).collect();
+            let output = |i: usize, value| TxOut {
+                value,
+                script_pubkey: scripts[i].clone(),
+            };
+            let tx =
+                transaction(vec![], vec![output(0, 40_000), output(1, 20_000), output(0, 20_000)]);
+            let txid = tx.txid();
+            store.cache.all_txs.insert(txid, BETransaction::Bitcoin(tx));
+            (scripts, txid)
+        };
+        let invoice = |i: usize, satoshi| AddressAmount {
+            address: Address::from_script(&scripts[i], Network::Regtest).unwrap().to_string(),
+            satoshi,
+            asset_tag: None,
+        };
+
+        let invoices =
+            vec![invoice(0, 50_000), invoice(1, 30_000), invoice(1, 20_000), invoice(2, 1)];
+        let result = wallet.check_invoices(&txid.to_string(), &invoices).unwrap();
+        assert_eq!(
+            result,
+            vec![
+                InvoicePayment::Over(10_000),
+                InvoicePayment::Under(10_000),
+                InvoicePayment::Under(20_000),
+                InvoicePayment::Under(1),
+            ],
+            "the output to the second address pays only the first invoice"
+        );
+
+        // two invoices to the same address are paid by distinct outputs
+        let invoices = vec![invoice(0, 20_000), invoice(0, 40_000)];
+        let result = wallet.check_invoices(&txid.to_string(), &invoices).unwrap();
+        assert_eq!(result, vec![InvoicePayment::Exact, InvoicePayment::Exact]);
+    }
+
+    #[test]
+    fn test_watch_blob_roundtrip() {
+        let wallet = memory_wallet();
+        let blob = wallet.export_watch_blob().unwrap();
+        assert!(blob.starts_with("gdkwatch1:regtest:"));
+
+        let backend = Box::new(MemoryBackend::default());
+        let network = wallet.network.clone();
+        let watch_only = WalletCtx::import_watch_blob(&blob, network, backend).unwrap();
+        assert!(watch_only.is_watch_only());
+        assert_eq!(watch_only.xpub, wallet.xpub);
+        assert_eq!(watch_only.master_fingerprint, wallet.master_fingerprint);
+        assert_eq!(watch_only.export_watch_blob().unwrap(), blob);
+        for _ in 0..3 {
+            let watch_only_address = watch_only.get_address(None).unwrap();
+            let address = wallet.get_address(None).unwrap();
+            assert_eq!(watch_only_address.address, address.address);
+            assert_eq!(watch_only_address.pointer, address.pointer);
+        }
+        assert!(matches!(watch_only.get_mnemonic(), Err(Error::WatchOnly)));
+
+        let mut liquid = gdk_common::network::Network::default();
+        liquid.development = true;
+        liquid.liquid = true;
+        let backend = Box::new(MemoryBackend::default());
+        assert!(WalletCtx::import_watch_blob(&blob, liquid, backend).is_err());
+        let backend = Box::new(MemoryBackend::default());
+        let network = wallet.network.clone();
+        assert!(WalletCtx::import_watch_blob(&blob[1..], network, backend).is_err());
+
+        // a native segwit wallet is imported with its script type
+        let mut wallet = memory_wallet();
+        wallet.set_script_type(ScriptType::P2wpkh).unwrap();
+        let blob = wallet.export_watch_blob().unwrap();
+        assert_eq!(blob.split(':').nth(3), Some("p2wpkh"));
+        let backend = Box::new(MemoryBackend::default());
+        let network = wallet.network.clone();
+        let watch_only = WalletCtx::import_watch_blob(&blob, network, backend).unwrap();
+        assert_eq!(watch_only.script_type, ScriptType::P2wpkh);
+        assert_eq!(
+            watch_only.get_address(None).unwrap().address,
+            wallet.get_address(None).unwrap().address
+        );
+        let unknown = blob.replace(":p2wpkh:", ":p2tr:");
+        let backend = Box::new(MemoryBackend::default());
+        let network = wallet.network.clone();
+        assert!(WalletCtx::import_watch_blob(&unknown, network, backend).is_err());
+    }
+
+    #[test]
+    fn test_capital_gains() {
+        let wallet = memory_wallet();
+        {
+            let mut store = wallet.store.write().unwrap();
+            let (script, path) = store.get_script_batch(0, 0).unwrap().value.remove(0);
+            store.cache.paths.insert(script.clone(), path.clone());
+            store.cache.scripts.insert(path, script.clone());
+
+            let tx = |previous_output, outputs: Vec<(&Script, u64)>| {
+                transaction(
+                    vec![TxIn {
+                        previous_output,
+                        script_sig: Script::new(),
+                        sequence: 0xffff_fffe,
+                        witness: vec![],
+                    }],
+                    outputs
+                        .into_iter()
+                        .map(|(script, value)| TxOut {
+                            value,
+                            script_pubkey: script.clone(),
+                        })
+                        .collect(),
+                )
+            };
+            let external = Script::new();
+            let prev_a = tx(OutPoint::default(), vec![(&external, 101_000)]);
+            let prev_b = tx(OutPoint::default(), vec![(&external, 51_000)]);
+            let buy_a = tx(OutPoint::new(prev_a.txid(), 0), vec![(&script, 100_000)]);
+            let buy_b = tx(OutPoint::new(prev_b.txid(), 0), vec![(&script, 50_000)]);
+            let sell = tx(OutPoint::new(buy_a.txid(), 0), vec![(&external, 80_000)]);
+            store.cache.heights.insert(buy_a.txid(), Some(1));
+            store.cache.heights.insert(buy_b.txid(), Some(2));
+            store.cache.heights.insert(sell.txid(), Some(3));
+            for tx in vec![prev_a, prev_b, buy_a, buy_b, sell] {
+                store.cache.all_txs.insert(tx.txid(), BETransaction::Bitcoin(tx));
+            }
+        }
+        let price = |height: u32| height as f64 * 10_000.0;
+
+        // sold 100_000 sat (80_000 + 20_000 fee) at 30_000, bought at 10_000 and 20_000
+        let fifo = wallet.capital_gains(LotMethod::Fifo, price).unwrap();
+        assert_eq!(fifo.len(), 1);
+        assert_eq!((fifo[0].acquired_height, fifo[0].disposed_height), (1, 3));
+        assert_eq!(fifo[0].satoshi, 100_000);
+        assert!((fifo[0].gain - 20.0).abs() < 1e-9);
+
+        let lifo = wallet.capital_gains(LotMethod::Lifo, price).unwrap();
+        assert_eq!(lifo.len(), 2);
+        assert_eq!((lifo[0].acquired_height, lifo[0].satoshi), (2, 50_000));
+        assert_eq!((lifo[1].acquired_height, lifo[1].satoshi), (1, 50_000));
+        assert!((lifo[0].gain - 5.0).abs() < 1e-9);
+        assert!((lifo[1].gain - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_effective_balance() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        receive(&wallet, 500);
+        assert_eq!(wallet.balance().unwrap().get("btc"), Some(&90_500));
+
+        // at 1 sat/vbyte both utxos are worth spending
+        let low = wallet.effective_balance(1_000).unwrap();
+        assert!(low > 90_000 && low < 90_500, "{}", low);
+
+        // at 20 sat/vbyte spending the 500 sat utxo costs more than its value
+        let high = wallet.effective_balance(20_000).unwrap();
+        assert!(high < 90_000 && high > 85_000, "{}", high);
+    }
+
+    #[test]
+    fn test_create_tx_empty_wallet() {
+        let wallet = memory_wallet();
+        let mut request = send_request(&wallet, 10_000);
+        assert!(matches!(wallet.create_tx(&mut request), Err(Error::EmptyWallet)));
+    }
+
+    #[test]
+    fn test_get_address_script_type() {
+        let wallet = memory_wallet();
+        let address_type =
+            |a: &AddressPointer| Address::from_str(&a.address).unwrap().address_type();
+
+        let legacy = wallet.get_address(Some(ScriptType::P2pkh)).unwrap();
+        assert_eq!(address_type(&legacy), Some(AddressType::P2pkh));
+        let script = Address::from_str(&legacy.address).unwrap().script_pubkey();
+        let store = wallet.store.read().unwrap();
+        assert!(store.cache.paths.contains_key(&script));
+        assert!(store.extra_scripts().contains_key(&script));
+        drop(store);
+
+        let native = wallet.get_address(Some(ScriptType::P2wpkh)).unwrap();
+        assert_eq!(address_type(&native), Some(AddressType::P2wpkh));
+
+        let default = wallet.get_address(None).unwrap();
+        assert_eq!(address_type(&default), Some(AddressType::P2sh));
+        assert_eq!((legacy.pointer, native.pointer, default.pointer), (1, 2, 3));
+    }
+
+    #[test]
+    fn test_create_tx_fee_other_script_type() {
+        let wallet = memory_wallet();
+        let legacy = wallet.get_address(Some(ScriptType::P2pkh)).unwrap();
+        let script = Address::from_str(&legacy.address).unwrap().script_pubkey();
+        receive_script(&wallet, script, 90_000, Some(1));
+        let mut request = send_request(&wallet, 10_000);
+
+        // the p2pkh input is bigger than the p2sh-p2wpkh ones of the wallet
+        let created = wallet.create_tx(&mut request).unwrap();
+        let signed = wallet.sign(&created).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+        assert!(tx.input[0].witness.is_empty());
+        let vsize = (tx.get_weight() as u64 + 3) / 4;
+        assert!(created.fee.unwrap() >= vsize, "fee {} vsize {}", created.fee.unwrap(), vsize);
+    }
+
+    #[test]
+    fn test_native_segwit_wallet() {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let xprv = test_xprv();
+        let xpub = ExtendedPubKey::from_private(&secp, &xprv);
+        let mut wallet = memory_wallet();
+        wallet.set_script_type(ScriptType::P2wpkh).unwrap();
+        assert_eq!(wallet.store.read().unwrap().script_type(), ScriptType::P2wpkh);
+        assert!(liquid_memory_wallet().set_script_type(ScriptType::P2wpkh).is_err());
+
+        let address = wallet.get_address(None).unwrap();
+        let path = DerivationPath::from_str("m/0/1").unwrap();
+        let key = xpub.derive_pub(&secp, &path).unwrap();
+        let expected = Address::p2wpkh(&key.public_key, Network::Regtest).unwrap();
+        assert_eq!(address.address, expected.to_string());
+        assert!(address.address.starts_with("bcrt1q"));
+        // synced scripts are native segwit too
+        let batch = wallet.store.read().unwrap().get_script_batch(1, 0).unwrap();
+        assert!(batch.value.iter().all(|(script, _)| script.is_v0_p2wpkh()));
+
+        receive(&wallet, 90_000);
+        let mut request = CreateTransaction::default();
+        request.addressees.push(AddressAmount {
+            address: Address::p2pkh(&xprv.private_key.public_key(&secp), Network::Regtest)
+                .to_string(),
+            satoshi: 10_000,
+            asset_tag: None,
+        });
+        let tx = wallet.create_tx(&mut request).unwrap();
+        let signed = wallet.sign(&tx).unwrap();
+        let signed: Transaction = deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+        assert!(signed.output.iter().any(|o| o.script_pubkey.is_v0_p2wpkh()), "native change");
+
+        // a bare witness with an empty script_sig
+        let input = &signed.input[0];
+        assert!(input.script_sig.is_empty());
+        assert_eq!(input.witness.len(), 2);
+        let prev_script =
+            wallet.store.read().unwrap().get_script_batch(0, 0).unwrap().value[0].0.clone();
+        let mut cache = SigHashCache::new(&signed);
+        verify_p2shwpkh_input(
+            &secp,
+            0,
+            &input.script_sig,
+            &input.witness,
+            &prev_script,
+            |script_code, sighash_type| {
+                cache.signature_hash(0, script_code, 90_000, sighash_type).into_inner()
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_fee_unknown_prevout() {
+        let wallet = memory_wallet();
+        let known = receive_at(&wallet, 10_000, Some(1));
+        let txid = {
+            let mut store = wallet.store.write().unwrap();
+            let script = store.get_script_batch(0, 0).unwrap().value.remove(0).0;
+            // spends an output of the wallet and one of a tx never downloaded, whose value is
+            // unknown and could be greater than the outputs
+            let input = |previous_output| TxIn {
+                previous_output,
+                script_sig: Script::new(),
+                sequence: 0xffff_fffe,
+                witness: vec![],
+            };
+            let tx = transaction(
+                vec![
+                    input(OutPoint::new(known, 0)),
+                    input(OutPoint::new(Txid::from_slice(&[9u8; 32]).unwrap(), 0)),
+                ],
+                vec![TxOut {
+                    value: 50_000,
+                    script_pubkey: script,
+                }],
+            );
+            store.cache.heights.insert(tx.txid(), Some(2));
+            store.cache.all_txs.insert(tx.txid(), BETransaction::Bitcoin(tx.clone()));
+            tx.txid()
+        };
+
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let txs = wallet.list_tx(&opt).unwrap();
+        let tx = txs.iter().find(|tx| tx.txid == txid.to_string()).unwrap();
+        assert_eq!(tx.fee, None);
+        assert_eq!(wallet.get_tx_detail(&txid).unwrap().unwrap().fee, None);
+        let known = txs.iter().find(|tx| tx.txid == known.to_string()).unwrap();
+        assert_eq!(known.fee, Some(10_000));
+    }
+
+    #[test]
+    fn test_clear_history_from() {
+        let wallet = memory_wallet();
+        let old = receive_at(&wallet, 10_000, Some(1));
+        let recent = receive_at(&wallet, 20_000, Some(5));
+        let mempool = receive_at(&wallet, 30_000, None);
+        {
+            let mut store = wallet.store.write().unwrap();
+            store.insert_memo(recent, "rent").unwrap();
+            let header = bitcoin::BlockHeader {
+                version: 1,
+                prev_blockhash: Default::default(),
+                merkle_root: Default::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            };
+            store.cache.headers.insert(1, BEBlockHeader::Bitcoin(header));
+            store.cache.headers.insert(5, BEBlockHeader::Bitcoin(header));
+        }
+        assert_eq!(wallet.balance().unwrap().get("btc"), Some(&60_000));
+
+        assert_eq!(wallet.store.write().unwrap().clear_history_from(Some(3)).unwrap(), 2);
+        {
+            let store = wallet.store.read().unwrap();
+            assert!(store.cache.all_txs.contains_key(&old));
+            assert!(!store.cache.all_txs.contains_key(&recent));
+            assert!(!store.cache.heights.contains_key(&mempool));
+            assert_eq!(store.cache.headers.keys().collect::<Vec<_>>(), vec![&1]);
+            assert_eq!(store.get_memo(&recent).map(|m| m.as_str()), Some("rent"));
+        }
+        assert_eq!(wallet.balance().unwrap().get("btc"), Some(&10_000));
+
+        wallet.store.write().unwrap().clear_history_from(None).unwrap();
+        let store = wallet.store.read().unwrap();
+        assert!(store.cache.all_txs.is_empty());
+        assert!(store.cache.heights.is_empty());
+        assert!(store.cache.headers.is_empty());
+        assert_eq!(store.get_memo(&recent).map(|m| m.as_str()), Some("rent"));
+    }
+
+    #[test]
+    fn test_get_tx_detail() {
+        let wallet = memory_wallet();
+        let confirmed = receive_at(&wallet, 10_000, Some(1));
+        let mempool = receive_at(&wallet, 20_000, None);
+        wallet.store.write().unwrap().insert_memo(mempool, "rent").unwrap();
+
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let listed = wallet.list_tx(&opt).unwrap();
+        for txid in [confirmed, mempool].iter() {
+            let mut detail = wallet.get_tx_detail(txid).unwrap().unwrap();
+            let listed = listed.iter().find(|tx| tx.txid == txid.to_string()).unwrap();
+            // txs without header or first seen time get the time of the call
+            detail.timestamp = listed.timestamp;
+            detail.created_at = listed.created_at.clone();
+            assert_eq!(
+                serde_json::to_value(&detail).unwrap(),
+                serde_json::to_value(listed).unwrap()
+            );
+        }
+
+        let other = Txid::from_slice(&[9u8; 32]).unwrap();
+        assert!(wallet.get_tx_detail(&other).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_next_address() {
+        let wallet = memory_wallet();
+        let first = wallet.get_next_address(None, false).unwrap();
+        let again = wallet.get_next_address(None, false).unwrap();
+        assert_eq!(first.pointer, 1);
+        assert_eq!((again.address, again.pointer), (first.address.clone(), first.pointer));
+
+        // once the address receives funds the next one is returned
+        {
+            let mut store = wallet.store.write().unwrap();
+            let (script, path) = store.get_script_batch(0, 0).unwrap().value.remove(1);
+            store.cache.paths.insert(script.clone(), path.clone());
+            store.cache.scripts.insert(path, script.clone());
+            let tx = transaction(
+                vec![],
+                vec![TxOut {
+                    value: 10_000,
+                    script_pubkey: script,
+                }],
+            );
+            store.cache.heights.insert(tx.txid(), Some(1));
+            store.cache.all_txs.insert(tx.txid(), BETransaction::Bitcoin(tx));
+        }
+        let next = wallet.get_next_address(None, false).unwrap();
+        assert_eq!(next.pointer, 2);
+        assert_ne!(next.address, first.address);
+
+        // `get_address` always advances
+        assert_eq!(wallet.get_address(None).unwrap().pointer, 3);
+    }
+
+    #[test]
+    fn test_get_address_concurrent() {
+        let wallet = Arc::new(memory_wallet());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let wallet = wallet.clone();
+                std::thread::spawn(move || {
+                    (0..10).map(|_| wallet.get_address(None).unwrap()).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let pointers: Vec<AddressPointer> =
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+
+        let indexes: HashSet<u32> = pointers.iter().map(|p| p.pointer).collect();
+        let addresses: HashSet<&String> = pointers.iter().map(|p| &p.address).collect();
+        assert_eq!(indexes, (1..=80).collect());
+        assert_eq!(addresses.len(), 80);
+        assert_eq!(wallet.store.read().unwrap().cache.indexes.external, 80);
+    }
+
+    #[test]
+    fn test_list_tx_split() {
+        let wallet = memory_wallet();
+        let low = receive_at(&wallet, 10_000, Some(3));
+        let old_mempool = receive_at(&wallet, 20_000, None);
+        let high = receive_at(&wallet, 30_000, Some(7));
+        let new_mempool = receive_at(&wallet, 40_000, None);
+        {
+            let mut store = wallet.store.write().unwrap();
+            store.cache.first_seen.insert(old_mempool, 1_000);
+            store.cache.first_seen.insert(new_mempool, 2_000);
+        }
+
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let (confirmed, mempool) = wallet.list_tx_split(&opt).unwrap();
+        let txids =
+            |txs: &[TransactionMeta]| txs.iter().map(|tx| tx.txid.clone()).collect::<Vec<_>>();
+        assert_eq!(txids(&confirmed), vec![high.to_string(), low.to_string()]);
+        assert_eq!(txids(&mempool), vec![new_mempool.to_string(), old_mempool.to_string()]);
+        assert_eq!(mempool[1].timestamp, 1_000);
+    }
+
+    #[test]
+    fn test_list_tx_mempool_first() {
+        let wallet = memory_wallet();
+        let heights = [None, None, Some(5), Some(3)];
+        let txids: Vec<Txid> =
+            (1..5).zip(heights.iter()).map(|(i, h)| receive_at(&wallet, i * 10_000, *h)).collect();
+
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let txs = wallet.list_tx(&opt).unwrap();
+        let heights: Vec<Option<u32>> = txs.iter().map(|tx| tx.height).collect();
+        assert_eq!(heights, vec![None, None, Some(5), Some(3)]);
+        let mut mempool = vec![txids[0], txids[1]];
+        mempool.sort_by(|a, b| b.cmp(a));
+        let mempool: Vec<String> = mempool.iter().map(|txid| txid.to_string()).collect();
+        assert_eq!(vec![txs[0].txid.clone(), txs[1].txid.clone()], mempool);
+        assert_eq!(txs[2].txid, txids[2].to_string());
+        assert_eq!(txs[3].txid, txids[3].to_string());
+    }
+
+    #[test]
+    fn test_sign_input_with_path() {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let xprv = test_xprv();
+        let wallet = memory_wallet();
+        let txid = receive_at(&wallet, 90_000, Some(1));
+
+        let tx = transaction(
+            vec![TxIn {
+                previous_output: OutPoint::new(txid, 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffe,
+                witness: vec![],
+            }],
+            vec![TxOut {
+                value: 80_000,
+                script_pubkey: Script::new(),
+            }],
+        );
+        let be_tx = BETransaction::Bitcoin(tx.clone());
+
+        // a path never derived by the wallet
+        let path = DerivationPath::from_str("m/7/3").unwrap();
+        assert!(wallet.store.read().unwrap().cache.scripts.get(&path).is_none());
+        let (public_key, signature) =
+            wallet.sign_input_with_path(&be_tx, 0, &path, 90_000).unwrap();
+        let expected = ExtendedPubKey::from_private(&secp, &xprv).derive_pub(&secp, &path).unwrap();
+        assert_eq!(public_key, expected.public_key);
+
+        let (der, sighash_type) = signature.split_at(signature.len() - 1);
+        assert_eq!(sighash_type, &[SigHashType::All as u8]);
+        let hash = SigHashCache::new(&tx)
+            .signature_hash(0, &p2pkh_script(&public_key), 90_000, SigHashType::All)
+            .into_inner();
+        let message = Message::from_slice(&hash[..]).unwrap();
+        let signature = Signature::from_der(der).unwrap();
+        secp.verify(&message, &signature, &public_key.key).unwrap();
+
+        assert!(wallet.sign_input_with_path(&be_tx, 1, &path, 90_000).is_err());
+    }
+
+    #[test]
+    fn test_liquid_missing_blinding_key() {
+        let wallet = liquid_memory_wallet();
+
+        assert!(matches!(wallet.get_address(None), Err(Error::MissingBlindingKey)));
+        assert!(matches!(wallet.export_watch_blob(), Err(Error::MissingBlindingKey)));
+    }
+
+    #[test]
+    fn test_liquid_asset_deltas() {
+        let policy_asset = [1u8; 32];
+        let asset = [2u8; 32];
+        let mut wallet = liquid_memory_wallet();
+        wallet.network.policy_asset = Some(hex::encode(&policy_asset));
+        let id = wallet.network.id();
+
+        // spends 100_000 L-BTC, receives back 99_000 L-BTC as change and 500 of `asset`
+        let prev_outpoint = elements::OutPoint {
+            txid: Txid::from_slice(&[3u8; 32]).unwrap(),
+            vout: 0,
+        };
+        let mut tx = BETransaction::new(id);
+        tx.add_input(BEOutPoint::Elements(prev_outpoint));
+        if let BETransaction::Elements(tx) = &mut tx {
+            tx.output.push(elements::TxOut::default());
+            tx.output.push(elements::TxOut::default());
+        }
+        tx.add_fee_if_elements(1_000, &wallet.network.policy_asset().ok()).unwrap();
+        let txid = tx.txid();
+
+        let unblinded = |asset: [u8; 32], value: u64| Unblinded {
+            asset,
+            abf: [0u8; 32],
+            vbf: [0u8; 32],
+            value,
+        };
+        {
+            let mut store = wallet.store.write().unwrap();
+            let cache = &mut store.cache;
+            cache.unblinded.insert(prev_outpoint, unblinded(policy_asset, 100_000));
+            cache.unblinded.insert(
+                elements::OutPoint {
+                    txid,
+                    vout: 0,
+                },
+                unblinded(asset, 500),
+            );
+            cache.unblinded.insert(
+                elements::OutPoint {
+                    txid,
+                    vout: 1,
+                },
+                unblinded(policy_asset, 99_000),
+            );
+            cache.heights.insert(txid, None);
+            cache.all_txs.insert(txid, tx);
+        }
+
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let txs = wallet.list_tx(&opt).unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].fee, Some(1_000));
+        assert_eq!(txs[0].asset_deltas.len(), 2);
+        assert_eq!(txs[0].asset_deltas.get(&policy_asset), Some(&-1_000));
+        assert_eq!(txs[0].asset_deltas.get(&asset), Some(&500));
+        assert_eq!(txs[0].satoshi.get(&hex::encode(&asset)), Some(&500));
+    }
+
+    #[test]
+    fn test_list_tx_after_compact() {
+        let wallet = memory_wallet();
+        let txid = receive_at(&wallet, 90_000, Some(1));
+        assert_eq!(wallet.store.read().unwrap().cache.all_txs.len(), 2);
+
+        assert_eq!(wallet.store.write().unwrap().compact().unwrap(), 1);
+        let store = wallet.store.read().unwrap();
+        assert_eq!(store.cache.all_txs.keys().collect::<Vec<_>>(), vec![&txid]);
+        drop(store);
+
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let txs = wallet.list_tx(&opt).unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].fee, Some(10_000));
+        assert_eq!(txs[0].satoshi.get("btc"), Some(&90_000));
+        assert_eq!(wallet.store.write().unwrap().compact().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_change_on_receive_chain() {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let xprv = test_xprv();
+        let xpub = ExtendedPubKey::from_private(&secp, &xprv);
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        wallet.set_change_on_receive_chain(true).unwrap();
+        assert!(wallet.store.read().unwrap().change_on_receive_chain());
+
+        let mut request = send_request(&wallet, 10_000);
+        let tx = wallet.create_tx(&mut request.clone()).unwrap();
+        assert_eq!(tx.changes_used, Some(1));
+        let signed = wallet.sign(&tx).unwrap();
+        let indexes = wallet.store.read().unwrap().cache.indexes.clone();
+        assert_eq!((indexes.external, indexes.internal), (1, 0));
+
+        // the change is at m/0/1, the first receive address after m/0/0 used by `receive`
+        let path = DerivationPath::from_str("m/0/1").unwrap();
+        let change_key = xpub.derive_pub(&secp, &path).unwrap();
+        let change_script =
+            Address::p2shwpkh(&change_key.public_key, Network::Regtest).unwrap().script_pubkey();
+        let signed_tx: Transaction = deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+        assert!(signed_tx.output.iter().any(|o| o.script_pubkey == change_script));
+
+        // as sync does, make the tx and the external scripts part of the wallet
+        {
+            let mut store = wallet.store.write().unwrap();
+            for (script, path) in store.get_script_batch(0, 0).unwrap().value {
+                store.cache.paths.insert(script.clone(), path.clone());
+                store.cache.scripts.insert(path, script);
+            }
+            store.cache.heights.insert(signed_tx.txid(), Some(2));
+            store.cache.all_txs.insert(signed_tx.txid(), BETransaction::Bitcoin(signed_tx.clone()));
+        }
+
+        // the change is spendable
+        let tx = wallet.create_tx(&mut request.clone()).unwrap();
+        let spending: Transaction = deserialize(&hex::decode(&tx.hex).unwrap()).unwrap();
+        assert_eq!(spending.input[0].previous_output.txid, signed_tx.txid());
+        wallet.sign(&tx).unwrap();
+    }
+
+    #[test]
+    fn test_tx_graph() {
+        let wallet = memory_wallet();
+        let first = receive_at(&wallet, 90_000, Some(1));
+
+        // first -> second -> third, each spending the single output of the previous one
+        let spend = |txid: Txid, value: u64| {
+            transaction(
+                vec![TxIn {
+                    previous_output: OutPoint::new(txid, 0),
+                    script_sig: Script::new(),
+                    sequence: 0xffff_fffe,
+                    witness: vec![],
+                }],
+                vec![TxOut {
+                    value,
+                    script_pubkey: Script::new(),
+                }],
+            )
+        };
+        let second = spend(first, 80_000);
+        let third = spend(second.txid(), 70_000);
+        let (second, third) = {
+            let mut store = wallet.store.write().unwrap();
+            let mut txids = vec![];
+            for (tx, height) in vec![(second, Some(2)), (third, None)] {
+                let txid = tx.txid();
+                store.cache.heights.insert(txid, height);
+                store.cache.all_txs.insert(txid, BETransaction::Bitcoin(tx));
+                txids.push(txid.to_string());
+            }
+            (txids[0].clone(), txids[1].clone())
+        };
+        let first = first.to_string();
+
+        let graph = wallet.tx_graph(&first).unwrap();
+        assert!(graph.parents.is_empty(), "the funding tx is not a wallet tx");
+        assert_eq!(graph.children, vec![second.clone()]);
+
+        let graph = wallet.tx_graph(&second).unwrap();
+        assert_eq!(graph.parents, vec![first.clone()]);
+        assert_eq!(graph.children, vec![third.clone()]);
+
+        let graph = wallet.tx_graph(&third).unwrap();
+        assert_eq!(graph.parents, vec![second]);
+        assert!(graph.children.is_empty());
+
+        let not_wallet = Txid::from_slice(&[7u8; 32]).unwrap().to_string();
+        assert!(wallet.tx_graph(&not_wallet).is_err());
+    }
+
+    #[test]
+    fn test_create_tx_wrong_network() {
+        let xprv = test_xprv();
+        let public_key = xprv.private_key.public_key(&Secp256k1::new());
+        let bitcoin_address = Address::p2wpkh(&public_key, Network::Regtest).unwrap().to_string();
+        let liquid_address =
+            elements::Address::p2wpkh(&public_key, None, &elements::AddressParams::ELEMENTS)
+                .to_string();
+        let request = |addresses: Vec<&String>| {
+            let mut request = CreateTransaction::default();
+            for address in addresses {
+                request.addressees.push(AddressAmount {
+                    address: address.clone(),
+                    satoshi: 10_000,
+                    asset_tag: None,
+                });
+            }
+            request
+        };
+
+        let wallet = memory_wallet();
+        let mut liquid_recipient = request(vec![&bitcoin_address, &liquid_address]);
+        assert!(matches!(wallet.create_tx(&mut liquid_recipient), Err(Error::WrongNetwork(1))));
+
+        let wallet = liquid_memory_wallet();
+        let mut bitcoin_recipient = request(vec![&bitcoin_address]);
+        assert!(matches!(wallet.create_tx(&mut bitcoin_recipient), Err(Error::WrongNetwork(0))));
+
+        let mut invalid = request(vec![&"x".to_string()]);
+        assert!(matches!(wallet.create_tx(&mut invalid), Err(Error::InvalidAddress)));
+    }
+
+    #[test]
+    fn test_prevouts_for() {
+        let wallet = memory_wallet();
+        let received = receive_at(&wallet, 90_000, Some(1));
+
+        let prevouts = wallet.prevouts_for(&received.to_string()).unwrap();
+        assert_eq!(prevouts.len(), 1);
+        let (outpoint, spent) = prevouts.iter().next().unwrap();
+        let store = wallet.store.read().unwrap();
+        let received_tx = store.get_bitcoin_tx(&received).unwrap();
+        assert_eq!(outpoint, &BEOutPoint::Bitcoin(received_tx.input[0].previous_output));
+        assert_eq!(spent.value, Some(100_000));
+        assert_eq!(spent.script, Script::new());
+        let received_script = received_tx.output[0].script_pubkey.clone();
+        drop(store);
+
+        // spends the received output and an output unknown to the wallet
+        let unknown = OutPoint::new(Txid::from_slice(&[7u8; 32]).unwrap(), 3);
+        let tx = transaction(
+            [OutPoint::new(received, 0), unknown]
+                .iter()
+                .map(|previous_output| TxIn {
+                    previous_output: *previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_fffe,
+                    witness: vec![],
+                })
+                .collect(),
+            vec![],
+        );
+        let txid = tx.txid();
+        wallet.store.write().unwrap().cache.all_txs.insert(txid, BETransaction::Bitcoin(tx));
+
+        let prevouts = wallet.prevouts_for(&txid.to_string()).unwrap();
+        assert_eq!(prevouts.len(), 1);
+        let spent = prevouts.get(&BEOutPoint::Bitcoin(OutPoint::new(received, 0))).unwrap();
+        assert_eq!(spent.value, Some(90_000));
+        assert_eq!(spent.script, received_script);
+        assert!(!prevouts.contains_key(&BEOutPoint::Bitcoin(unknown)));
+    }
+
+    #[test]
+    fn test_create_tx_leaves_dust() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let mut request = send_request(&wallet, 10_000);
+        assert!(!wallet.create_tx(&mut request.clone()).unwrap().leaves_dust);
+
+        request.dust_remainder = Some(10_000);
+        assert!(!wallet.create_tx(&mut request.clone()).unwrap().leaves_dust);
+
+        // near-sweep, leaves less than 10_000 satoshi of change
+        request.addressees[0].satoshi = 80_000;
+        assert!(wallet.create_tx(&mut request.clone()).unwrap().leaves_dust);
+
+        // a sweep empties the wallet without leaving dust
+        request.send_all = Some(true);
+        assert!(!wallet.create_tx(&mut request.clone()).unwrap().leaves_dust);
+    }
+
+    #[test]
+    fn test_create_tx_multi_asset() {
+        let xprv = test_xprv();
+        let mut wallet = liquid_memory_wallet();
+        let policy_asset = [3u8; 32];
+        let other_asset = [4u8; 32];
+        wallet.network.policy_asset = Some(asset_to_hex(&policy_asset));
+        wallet.master_blinding = Some(asset_blinding_key_from_seed(&[2u8; 64]));
+
+        let mut store = wallet.store.write().unwrap();
+        let (script, path) = store.get_script_batch(0, 0).unwrap().value.remove(0);
+        store.cache.paths.insert(script.clone(), path.clone());
+        store.cache.scripts.insert(path, script.clone());
+        let output = elements::TxOut {
+            script_pubkey: script,
+            ..Default::default()
+        };
+        let tx = elements::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![output.clone(), output],
+        };
+        let received = [(policy_asset, 50_000), (other_asset, 1_000)];
+        for (vout, (asset, value)) in received.iter().enumerate() {
+            let outpoint = elements::OutPoint {
+                txid: tx.txid(),
+                vout: vout as u32,
+            };
+            let unblinded = Unblinded {
+                asset: *asset,
+                abf: [0u8; 32],
+                vbf: [0u8; 32],
+                value: *value,
+            };
+            store.cache.unblinded.insert(outpoint, unblinded);
+        }
+        store.cache.heights.insert(tx.txid(), Some(1));
+        store.cache.all_txs.insert(tx.txid(), BETransaction::Elements(tx));
+        drop(store);
+
+        let public_key = xprv.private_key.public_key(&wallet.secp);
+        let address = elements::Address::p2wpkh(
+            &public_key,
+            Some(public_key.key),
+            &elements::AddressParams::ELEMENTS,
+        )
+        .to_string();
+        let mut request = CreateTransaction::default();
+        for (asset, satoshi) in &[(policy_asset, 10_000), (other_asset, 300)] {
+            request.addressees.push(AddressAmount {
+                address: address.clone(),
+                satoshi: *satoshi,
+                asset_tag: Some(asset_to_hex(asset)),
+            });
+        }
+        let created = wallet.create_tx(&mut request).unwrap();
+        let tx: elements::Transaction =
+            elements::encode::deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+        assert_eq!(tx.input.len(), 2);
+
+        let sum = |asset: AssetId, fee: bool| -> u64 {
+            let asset = elements::issuance::AssetId::from_slice(&asset).unwrap();
+            tx.output
+                .iter()
+                .filter(|o| o.asset == elements::confidential::Asset::Explicit(asset))
+                .filter(|o| o.script_pubkey.is_empty() == fee)
+                .map(|o| match o.value {
+                    elements::confidential::Value::Explicit(value) => value,
+                    _ => panic!("outputs are blinded when signing"),
+                })
+                .sum()
+        };
+        assert_eq!(sum(policy_asset, true), created.fee.unwrap());
+        assert_eq!(sum(policy_asset, false) + created.fee.unwrap(), 50_000);
+        assert_eq!(sum(other_asset, true), 0);
+        assert_eq!(sum(other_asset, false), 1_000);
+    }
+
+    #[test]
+    fn test_create_pegout_without_change() {
+        use crate::mock::MockElectrum;
+        use elements::confidential::Value;
+
+        let xprv = test_xprv();
+        let mut wallet = liquid_memory_wallet();
+        wallet.xprv = Some(xprv);
+        let policy_asset = [3u8; 32];
+        wallet.network.policy_asset = Some(asset_to_hex(&policy_asset));
+        wallet.network.ct_exponent = Some(0);
+        wallet.network.ct_bits = Some(52);
+        wallet.master_blinding = Some(asset_blinding_key_from_seed(&[2u8; 64]));
+
+        let mut store = wallet.store.write().unwrap();
+        let (script, path) = store.get_script_batch(0, 0).unwrap().value.remove(0);
+        store.cache.paths.insert(script.clone(), path.clone());
+        store.cache.scripts.insert(path, script.clone());
+        let tx = elements::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![elements::TxOut {
+                script_pubkey: script,
+                ..Default::default()
+            }],
+        };
+        let outpoint = elements::OutPoint {
+            txid: tx.txid(),
+            vout: 0,
+        };
+        let unblinded = Unblinded {
+            asset: policy_asset,
+            abf: [0u8; 32],
+            vbf: [0u8; 32],
+            value: 100_000,
+        };
+        store.cache.unblinded.insert(outpoint, unblinded);
+        store.cache.heights.insert(tx.txid(), Some(1));
+        store.cache.all_txs.insert(tx.txid(), BETransaction::Elements(tx));
+        drop(store);
+
+        // no estimate from the server, the fee rate is the minimum one
+        let server = MockElectrum::start(|method, _| match method {
+            "blockchain.estimatefee" => Some(serde_json::json!(-1)),
+            _ => None,
+        });
+        let client = ElectrumUrl::Plaintext(server.url.clone(), None).build_client().unwrap();
+        let address = recipient_address(&wallet);
+        let with_change = wallet.create_pegout(50_000, &address, &client).unwrap();
+        let fee = with_change.fee.unwrap();
+
+        // the utxo covers exactly the peg-out and the fee, every output is explicit
+        let created = wallet.create_pegout(100_000 - fee, &address, &client).unwrap();
+        assert_eq!(created.fee, Some(fee), "the zero value output replaces the change");
+        let signed = wallet.sign(&created).unwrap();
+        let tx: elements::Transaction =
+            elements::encode::deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+        let blinded: Vec<&elements::TxOut> =
+            tx.output.iter().filter(|o| matches!(o.value, Value::Confidential(..))).collect();
+        assert_eq!(blinded.len(), 1);
+        assert!(blinded[0].script_pubkey.is_provably_unspendable());
+        let explicit: Vec<u64> = tx
+            .output
+            .iter()
+            .filter_map(|o| match o.value {
+                Value::Explicit(value) => Some(value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(explicit.iter().sum::<u64>(), 100_000, "peg-out and fee");
+    }
+
+    #[test]
+    fn test_create_tx_changeless_same_script() {
+        let wallet = memory_wallet();
+        receive_to(&wallet, 0, 40_000, Some(1));
+        receive_to(&wallet, 0, 20_000, Some(1));
+        let first = receive_to(&wallet, 1, 25_100, Some(1));
+        let second = receive_to(&wallet, 2, 15_000, Some(1));
+        let mut request = send_request(&wallet, 39_800);
+
+        // the 40_000 utxo alone would be changeless, but it must be spent with the 20_000 one
+        // of the same script, the other two are changeless
+        let created = wallet.create_tx(&mut request).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+        let spent: HashSet<Txid> = tx.input.iter().map(|i| i.previous_output.txid).collect();
+        assert_eq!(spent, vec![first, second].into_iter().collect());
+        assert_eq!(tx.output.len(), 1, "no change");
+        assert_eq!(created.fee, Some(25_100 + 15_000 - 39_800));
+    }
+
+    #[test]
+    fn test_create_tx_send_all() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        receive(&wallet, 50_000);
+        receive(&wallet, 20_000);
+        let mut request = send_request(&wallet, 0);
+        request.send_all = Some(true);
+
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+        assert_eq!(tx.input.len(), 3);
+        assert_eq!(tx.output.len(), 1);
+        assert_eq!(created.changes_used, Some(0));
+        assert_eq!(tx.output[0].value, 90_000 + 50_000 + 20_000 - created.fee.unwrap());
+
+        let mut two = request.clone();
+        two.addressees.push(two.addressees[0].clone());
+        assert!(matches!(wallet.create_tx(&mut two), Err(Error::SendAll)));
+
+        // what's left after the fee is checked against the dust threshold of the p2wpkh recipient
+        let wallet = memory_wallet();
+        receive(&wallet, 600);
+        assert!(wallet.create_tx(&mut request.clone()).is_ok(), "not dust for p2wpkh");
+        let wallet = memory_wallet();
+        receive(&wallet, 400);
+        assert!(matches!(wallet.create_tx(&mut request), Err(Error::BelowDustLimit(_, 294))));
+    }
+
+    #[test]
+    fn test_create_tx_single_utxo() {
+        let wallet = memory_wallet();
+        receive_at(&wallet, 90_000, Some(100));
+        let txid = receive_at(&wallet, 50_000, Some(101));
+        let mut request = send_request(&wallet, 30_000);
+        request.utxo = Some(format!("{}:0", txid));
+
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.input[0].previous_output, OutPoint::new(txid, 0));
+        assert_eq!(tx.output.len(), 2);
+        let change: u64 = tx.output.iter().map(|o| o.value).filter(|v| *v != 30_000).sum();
+        assert_eq!(change, 50_000 - 30_000 - created.fee.unwrap());
+
+        // the other utxo is not used even if the selected one is not enough
+        let mut too_much = request.clone();
+        too_much.addressees[0].satoshi = 50_000;
+        assert!(matches!(wallet.create_tx(&mut too_much), Err(Error::InsufficientFunds)));
+
+        let mut unknown = request.clone();
+        unknown.utxo = Some(format!("{}:1", txid));
+        assert!(matches!(wallet.create_tx(&mut unknown), Err(Error::UtxoNotFound(_))));
+    }
+
+    #[test]
+    fn test_address_qr() {
+        let wallet = memory_wallet();
+
+        let address = wallet.get_address(None).unwrap();
+        assert_eq!(address.qr_payload(), address.address);
+        assert!(!address.fits_qr_alphanumeric());
+        assert_eq!(address.qr_error_correction(), QrErrorCorrection::Quartile);
+
+        let native = wallet.get_address(Some(ScriptType::P2wpkh)).unwrap();
+        assert_eq!(native.qr_payload(), native.address);
+        assert!(!native.fits_qr_alphanumeric());
+        let upper = AddressPointer {
+            address: native.address.to_uppercase(),
+            pointer: native.pointer,
+        };
+        assert!(upper.fits_qr_alphanumeric());
+        assert_eq!(upper.qr_error_correction(), QrErrorCorrection::High);
+    }
+
+    #[test]
+    fn test_balances() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let balances = wallet.balances().unwrap();
+        assert_eq!(balances.len(), 1);
+        assert_eq!(balances[&BITCOIN_ASSET_ID], 90_000);
+
+        let mut wallet = liquid_memory_wallet();
+        let policy_asset = [3u8; 32];
+        let other_asset = [4u8; 32];
+        wallet.network.policy_asset = Some(asset_to_hex(&policy_asset));
+        assert!(matches!(wallet.balances(), Err(Error::MissingBlindingKey)));
+        let master_blinding = asset_blinding_key_from_seed(&[2u8; 64]);
+        wallet.master_blinding = Some(master_blinding.clone());
+        assert_eq!(wallet.balances().unwrap()[&policy_asset], 0);
+
+        let mut store = wallet.store.write().unwrap();
+        let (script, path) = store.get_script_batch(0, 0).unwrap().value.remove(0);
+        store.cache.paths.insert(script.clone(), path.clone());
+        store.cache.scripts.insert(path, script.clone());
+        // an output blinded by a sender to the wallet blinding key of `script`
+        let blinded = |asset: AssetId, value: u64| {
+            let receiver_sk = asset_blinding_key_to_ec_private_key(&master_blinding, &script);
+            let receiver_pk = ec_public_key_from_private_key(receiver_sk);
+            let sender_sk = SecretKey::from_slice(&[5u8; 32]).unwrap();
+            let sender_pk = ec_public_key_from_private_key(sender_sk).serialize();
+            let (abf, vbf) = ([6u8; 32], [7u8; 32]);
+            let generator = asset_generator_from_bytes(&asset, &abf);
+            let commitment = asset_value_commitment(value, vbf, generator);
+            let rangeproof = asset_rangeproof(
+                value,
+                receiver_pk,
+                sender_sk,
+                asset,
+                abf,
+                vbf,
+                commitment,
+                &script,
+                generator,
+                1,
+                0,
+                52,
+            );
+            elements::TxOut {
+                asset: generator,
+                value: commitment,
+                nonce: Nonce::Confidential(sender_pk[0], sender_pk[1..].try_into().unwrap()),
+                script_pubkey: script.clone(),
+                witness: TxOutWitness {
+                    surjection_proof: vec![],
+                    rangeproof,
+                },
+            }
+        };
+        let tx = elements::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![blinded(policy_asset, 50_000), blinded(other_asset, 1_000)],
+        };
+        store.cache.heights.insert(tx.txid(), Some(1));
+        store.cache.all_txs.insert(tx.txid(), BETransaction::Elements(tx));
+        drop(store);
+
+        let balances = wallet.balances().unwrap();
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances[&policy_asset], 50_000);
+        assert_eq!(balances[&other_asset], 1_000);
+    }
+
+    #[test]
+    fn test_invoice_address() {
+        let mut wallet = liquid_memory_wallet();
+        wallet.master_blinding = Some(asset_blinding_key_from_seed(&[2u8; 64]));
+        let address = |a: &AddressPointer| elements::Address::from_str(&a.address).unwrap();
+
+        let first = wallet.get_invoice_address(3, 1).unwrap();
+        let second = wallet.get_invoice_address(3, 2).unwrap();
+        assert_eq!(first.pointer, 3);
+        let (first, second) = (address(&first), address(&second));
+        assert_eq!(first.script_pubkey(), second.script_pubkey());
+        assert_ne!(first.blinding_pubkey, second.blinding_pubkey);
+
+        let plain = wallet.derive_address(&wallet.xpub, [0, 3]).unwrap();
+        assert_eq!(plain.script_pubkey(), first.script_pubkey());
+        assert_ne!(plain.blinding_pubkey(), first.blinding_pubkey);
+
+        let mut nonces = wallet.store.read().unwrap().invoice_nonces(&first.script_pubkey());
+        nonces.sort();
+        assert_eq!(nonces, vec![1, 2]);
+
+        assert!(memory_wallet().get_invoice_address(3, 1).is_err());
+    }
+
+    #[test]
+    fn test_validate_address() {
+        let xprv = test_xprv();
+        let public_key = xprv.private_key.public_key(&Secp256k1::new());
+        let address = |network| Address::p2wpkh(&public_key, network).unwrap().to_string();
+        let mainnet = address(Network::Bitcoin);
+        let testnet = address(Network::Testnet);
+        let regtest = address(Network::Regtest);
+
+        let mut wallet = memory_wallet();
+        assert!(wallet.validate_address(&regtest).unwrap());
+        assert!(wallet.validate_address(&testnet).unwrap());
+        assert!(!wallet.validate_address(&mainnet).unwrap());
+        assert!(!wallet.validate_address("not an address").unwrap());
+
+        wallet.network.development = false;
+        assert!(wallet.validate_address(&testnet).unwrap());
+        assert!(!wallet.validate_address(&mainnet).unwrap());
+        assert!(!wallet.validate_address(&regtest).unwrap());
+
+        wallet.network.mainnet = true;
+        assert!(wallet.validate_address(&mainnet).unwrap());
+        assert!(!wallet.validate_address(&testnet).unwrap());
+
+        let liquid =
+            |blinder, params| elements::Address::p2wpkh(&public_key, blinder, params).to_string();
+        let confidential = liquid(Some(public_key.key), &elements::AddressParams::ELEMENTS);
+        let unconfidential = liquid(None, &elements::AddressParams::ELEMENTS);
+        let liquid_mainnet = liquid(Some(public_key.key), &elements::AddressParams::LIQUID);
+
+        let mut wallet = liquid_memory_wallet();
+        assert!(wallet.validate_address(&confidential).unwrap());
+        assert!(wallet.validate_address(&unconfidential).unwrap());
+        assert!(!wallet.validate_address(&liquid_mainnet).unwrap());
+        assert!(!wallet.validate_address(&regtest).unwrap());
+
+        wallet.network.development = false;
+        wallet.network.mainnet = true;
+        assert!(wallet.validate_address(&liquid_mainnet).unwrap());
+        assert!(!wallet.validate_address(&confidential).unwrap());
+    }
+
+    #[test]
+    fn test_download_missing_prevouts() {
+        let wallet = memory_wallet();
+        let txid = receive_at(&wallet, 90_000, Some(1));
+        let prev = {
+            let mut store = wallet.store.write().unwrap();
+            let prev_txid = store.cache.all_txs.get(&txid).unwrap().previous_output_txids()[0];
+            store.cache.all_txs.remove(&prev_txid).unwrap()
+        };
+
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        assert_eq!(wallet.list_tx(&opt).unwrap()[0].fee, None, "fee not computable");
+
+        let mut requested = vec![];
+        let downloaded = wallet
+            .download_missing_prevouts(&opt, |page| {
+                requested.extend(page.iter().map(|txid| **txid));
+                Ok(vec![prev.serialize()])
+            })
+            .unwrap();
+        assert_eq!(downloaded, 1);
+        assert_eq!(requested, vec![prev.txid()]);
+        assert_eq!(wallet.list_tx(&opt).unwrap()[0].fee, Some(10_000));
+
+        let nothing_missing = wallet.download_missing_prevouts(&opt, |_| unreachable!()).unwrap();
+        assert_eq!(nothing_missing, 0);
+    }
+
+    #[test]
+    fn test_create_tx_rbf() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        receive(&wallet, 80_000);
+        let mut request = send_request(&wallet, 100_000);
+        let sequences = |request: &CreateTransaction| {
+            let created = wallet.create_tx(&mut request.clone()).unwrap();
+            let tx: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+            assert_eq!(tx.input.len(), 2);
+            tx.input.iter().map(|i| i.sequence).collect::<HashSet<u32>>()
+        };
+
+        // bitcoin txs signal replaceability by default
+        assert_eq!(sequences(&request), vec![0xffff_fffd].into_iter().collect());
+        request.rbf = Some(true);
+        assert_eq!(sequences(&request), vec![0xffff_fffd].into_iter().collect());
+        request.rbf = Some(false);
+        assert_eq!(sequences(&request), vec![0xffff_fffe].into_iter().collect());
+
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        let signed = wallet.sign(&created).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+        assert!(tx.input.iter().all(|i| i.sequence == 0xffff_fffe), "sign keeps the sequence");
+    }
+
+    /// sign `created` and add it to the wallet history as unconfirmed, as if broadcasted, with
+    /// its change scripts as a sync would do
+    fn sign_and_store(wallet: &WalletCtx, created: &TransactionMeta) -> (Txid, Transaction) {
+        let signed = wallet.sign(created).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+        let mut store = wallet.store.write().unwrap();
+        for output in created.outputs.iter().filter(|o| o.is_change) {
+            let path = DerivationPath::from_str(output.derivation_path.as_ref().unwrap()).unwrap();
+            let script = tx.output[output.vout as usize].script_pubkey.clone();
+            store.cache.paths.insert(script.clone(), path.clone());
+            store.cache.scripts.insert(path, script);
+        }
+        store.cache.heights.insert(tx.txid(), None);
+        store.cache.all_txs.insert(tx.txid(), BETransaction::Bitcoin(tx.clone()));
+        (tx.txid(), tx)
+    }
+
+    #[test]
+    fn test_sign_out_of_range_vout() {
+        let wallet = memory_wallet();
+        let received = receive_at(&wallet, 90_000, Some(1));
+        let tx = transaction(
+            vec![TxIn {
+                previous_output: OutPoint::new(received, 5),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffe,
+                witness: vec![],
+            }],
+            vec![TxOut {
+                value: 80_000,
+                script_pubkey: Script::new(),
+            }],
+        );
+        let be_tx = BETransaction::Bitcoin(tx);
+        {
+            let store = wallet.store.read().unwrap();
+            let outpoint = BEOutPoint::Bitcoin(OutPoint::new(received, 5));
+            assert!(store
+                .cache
+                .all_txs
+                .get_previous_output_value(&outpoint, &store.cache.unblinded)
+                .is_none());
+            assert!(store.cache.all_txs.get_previous_output_script_pubkey(&outpoint).is_none());
+            assert!(be_tx.fee(&store.cache.all_txs, &store.cache.unblinded, &None).is_err());
+        }
+        let meta: TransactionMeta = be_tx.into();
+        assert!(matches!(wallet.sign(&meta), Err(Error::MissingPrevout(0))));
+    }
+
+    #[test]
+    fn test_verification_bundle() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let mut request = send_request(&wallet, 10_000);
+        let created = wallet.create_tx(&mut request).unwrap();
+        let signed = wallet.sign(&created).unwrap();
+
+        let mut bundle = wallet.verification_bundle(&signed).unwrap();
+        assert_eq!(bundle.hex, signed.hex);
+        assert_eq!(bundle.prevouts.len(), 1);
+        assert_eq!(bundle.prevouts[0].value, 90_000);
+        verify_bundle(&bundle).unwrap();
+
+        // signatures commit to the amount spent
+        bundle.prevouts[0].value -= 1;
+        assert!(matches!(verify_bundle(&bundle), Err(Error::InvalidSignature(0, _))));
+
+        bundle.prevouts.clear();
+        assert!(verify_bundle(&bundle).is_err());
+    }
+
+    #[test]
+    fn test_bump_fee() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let mut request = send_request(&wallet, 10_000);
+        request.fee_rate = Some(1000);
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        let (txid, original) = sign_and_store(&wallet, &created);
+        let change = |wallet: &WalletCtx, tx: &Transaction| {
+            let store = wallet.store.read().unwrap();
+            tx.output
+                .iter()
+                .find(|o| store.cache.paths.contains_key(&o.script_pubkey))
+                .unwrap()
+                .value
+        };
+
+        let bumped = wallet.bump_fee(&txid, 5000).unwrap();
+        let replacement: Transaction = deserialize(&hex::decode(&bumped.hex).unwrap()).unwrap();
+        assert!(bumped.fee.unwrap() > created.fee.unwrap());
+        assert_eq!(
+            change(&wallet, &original) - change(&wallet, &replacement),
+            bumped.fee.unwrap() - created.fee.unwrap()
+        );
+        assert_eq!(replacement.input.len(), original.input.len());
+        assert_eq!(replacement.input[0].previous_output, original.input[0].previous_output);
+        assert!(replacement.input.iter().all(|i| i.sequence == 0xffff_fffd));
+        assert_eq!(bumped.changes_used, Some(0));
+        assert!(wallet.bump_fee(&Txid::default(), 5000).is_err(), "not a wallet tx");
+
+        // the change on the receive chain isn't taken for a recipient
+        let wallet = memory_wallet();
+        wallet.set_change_on_receive_chain(true).unwrap();
+        receive(&wallet, 90_000);
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        let (txid, original) = sign_and_store(&wallet, &created);
+        let bumped = wallet.bump_fee(&txid, 5000).unwrap();
+        let replacement: Transaction = deserialize(&hex::decode(&bumped.hex).unwrap()).unwrap();
+        assert_eq!(replacement.output.len(), original.output.len());
+        assert_eq!(bumped.changes_used, Some(0));
+        assert_eq!(
+            change(&wallet, &original) - change(&wallet, &replacement),
+            bumped.fee.unwrap() - created.fee.unwrap()
+        );
+
+        // a sweep has no change and the wallet has no other confirmed utxos
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        request.send_all = Some(true);
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        let (txid, original) = sign_and_store(&wallet, &created);
+        receive_to(&wallet, 1, 50_000, None);
+        assert!(matches!(wallet.bump_fee(&txid, 5000), Err(Error::InsufficientFunds)));
+
+        let confirmed = receive_to(&wallet, 2, 50_000, Some(1));
+        let bumped = wallet.bump_fee(&txid, 5000).unwrap();
+        let replacement: Transaction = deserialize(&hex::decode(&bumped.hex).unwrap()).unwrap();
+        assert_eq!(replacement.input.len(), original.input.len() + 1);
+        assert_eq!(replacement.input.last().unwrap().previous_output.txid, confirmed);
+        assert_eq!(bumped.changes_used, Some(1));
+    }
+
+    #[test]
+    fn test_bump_fee_cap() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let mut request = send_request(&wallet, 10_000);
+        request.fee_rate = Some(1000);
+        let created = wallet.create_tx(&mut request).unwrap();
+        let (txid, _) = sign_and_store(&wallet, &created);
+
+        // more than 2_500 satoshi, 25% of the amount sent
+        match wallet.bump_fee(&txid, 20_000) {
+            Err(Error::FeeExceedsCap(fee, cap)) => {
+                assert_eq!(cap, 2_500);
+                assert!(fee > cap);
+            }
+            other => panic!("unexpected {:?}", other.map(|tx| tx.fee)),
+        }
+
+        let mut settings = wallet.get_settings().unwrap();
+        settings.max_fee_percent = Some(50);
+        wallet.change_settings(&settings).unwrap();
+        assert!(wallet.bump_fee(&txid, 20_000).unwrap().fee.unwrap() <= 5_000);
+    }
+
+    #[test]
+    fn test_list_tx_is_final() {
+        let wallet = memory_wallet();
+        receive_at(&wallet, 90_000, Some(100));
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let set_tip = |height| wallet.store.write().unwrap().cache.tip.0 = height;
+
+        set_tip(104); // 5 confirmations
+        assert!(!wallet.list_tx(&opt).unwrap()[0].is_final);
+        set_tip(105);
+        assert!(wallet.list_tx(&opt).unwrap()[0].is_final);
+
+        let mut settings = wallet.get_settings().unwrap();
+        settings.final_confirmations = Some(10);
+        wallet.change_settings(&settings).unwrap();
+        assert!(!wallet.list_tx(&opt).unwrap()[0].is_final);
+        set_tip(109);
+        assert!(wallet.list_tx(&opt).unwrap()[0].is_final);
+
+        receive_at(&wallet, 80_000, None);
+        let txs = wallet.list_tx(&opt).unwrap();
+        assert!(!txs.iter().find(|tx| tx.height.is_none()).unwrap().is_final);
+    }
+
+    #[test]
+    fn test_list_tx_confirmations() {
+        let wallet = memory_wallet();
+        let txid = receive_at(&wallet, 90_000, Some(100));
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let set_tip = |height| wallet.store.write().unwrap().cache.tip.0 = height;
+
+        set_tip(100);
+        assert_eq!(wallet.list_tx(&opt).unwrap()[0].confirmations, Some(1));
+        set_tip(110);
+        assert_eq!(wallet.list_tx(&opt).unwrap()[0].confirmations, Some(11));
+
+        // a reorg brings the tx back in mempool, as a sync replacing the history would do
+        wallet.store.write().unwrap().cache.heights.insert(txid, None);
+        let txs = wallet.list_tx(&opt).unwrap();
+        assert_eq!(txs[0].confirmations, None);
+        assert!(!txs[0].is_final);
+    }
+
+    #[test]
+    fn test_list_assets() {
+        let policy_asset = [1u8; 32];
+        let asset = [2u8; 32];
+        let mut wallet = liquid_memory_wallet();
+        wallet.network.policy_asset = Some(hex::encode(&policy_asset));
+        let script = Script::from(vec![0x51]);
+
+        let mut tx = BETransaction::new(wallet.network.id());
+        if let BETransaction::Elements(tx) = &mut tx {
+            for _ in 0..2 {
+                tx.output.push(elements::TxOut {
+                    script_pubkey: script.clone(),
+                    ..Default::default()
+                });
+            }
+        }
+        let txid = tx.txid();
+        let unblinded = |asset: [u8; 32], value: u64| Unblinded {
+            asset,
+            abf: [0u8; 32],
+            vbf: [0u8; 32],
+            value,
+        };
+        let asset_id = unblinded(asset, 0).asset_hex();
+        {
+            let mut store = wallet.store.write().unwrap();
+            let cache = &mut store.cache;
+            let outpoint = |vout| elements::OutPoint {
+                txid,
+                vout,
+            };
+            cache.unblinded.insert(outpoint(0), unblinded(policy_asset, 100_000));
+            cache.unblinded.insert(outpoint(1), unblinded(asset, 500));
+            cache.paths.insert(script, DerivationPath::from_str("m/0/1").unwrap());
+            cache.heights.insert(txid, Some(1));
+            cache.all_txs.insert(txid, tx);
+            let mut registry = serde_json::Map::new();
+            registry.insert(
+                asset_id.clone(),
+                serde_json::json!({"asset_id": &asset_id, "name": "Test", "ticker": "TST", "precision": 2}),
+            );
+            store.write_asset_registry(&serde_json::Value::Object(registry)).unwrap();
+        }
+
+        let assets = wallet.list_assets().unwrap();
+        assert_eq!(assets.len(), 2);
+        let held = assets.iter().find(|a| a.asset_id == asset_id).unwrap();
+        assert_eq!(held.satoshi, 500);
+        assert_eq!(held.ticker.as_deref(), Some("TST"));
+        assert_eq!(held.name.as_deref(), Some("Test"));
+        assert_eq!(held.precision, Some(2));
+        let policy = assets.iter().find(|a| a.asset_id != asset_id).unwrap();
+        assert_eq!(policy.satoshi, 100_000);
+        assert_eq!(policy.ticker, None, "not in the registry");
+
+        assert!(memory_wallet().list_assets().is_err());
+    }
+
+    #[test]
+    fn test_sign_psbt() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let mut request = send_request(&wallet, 10_000);
+        let created = wallet.create_tx(&mut request).unwrap();
+        let unsigned: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+        let signed = wallet.sign(&created).unwrap();
+        let signed: Transaction = deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(unsigned.clone()).unwrap();
+        let psbt = wallet.sign_psbt(psbt, None).unwrap();
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+        assert_eq!(psbt.extract_tx(), signed);
+
+        // an input of someone else is not signed
+        let mut with_foreign = unsigned;
+        with_foreign.input.push(TxIn {
+            previous_output: OutPoint::new(Txid::from_slice(&[9u8; 32]).unwrap(), 0),
+            script_sig: Script::new(),
+            sequence: 0xffff_fffd,
+            witness: vec![],
+        });
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(with_foreign).unwrap();
+        let psbt = wallet.sign_psbt(psbt, None).unwrap();
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+        assert!(psbt.inputs[1].partial_sigs.is_empty());
+        assert!(psbt.inputs[1].final_script_witness.is_none());
+    }
+
+    #[test]
+    fn test_sign_psbt_expected_outputs() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let mut request = send_request(&wallet, 10_000);
+        let recipient = request.addressees[0].clone();
+        let created = wallet.create_tx(&mut request).unwrap();
+        let unsigned: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+        let expected = vec![recipient.clone()];
+        let psbt =
+            |tx: &Transaction| PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
+
+        assert!(wallet.sign_psbt(psbt(&unsigned), Some(&expected)).is_ok());
+
+        let mut tampered = unsigned.clone();
+        let attacker = ExtendedPrivKey::new_master(Network::Regtest, &[2u8; 32])
+            .unwrap()
+            .private_key
+            .public_key(&wallet.secp);
+        let index = tampered.output.iter().position(|o| o.value == recipient.satoshi).unwrap();
+        tampered.output[index].script_pubkey =
+            Address::p2wpkh(&attacker, Network::Regtest).unwrap().script_pubkey();
+        assert!(matches!(
+            wallet.sign_psbt(psbt(&tampered), Some(&expected)),
+            Err(Error::PsbtOutputMismatch)
+        ));
+
+        let mut more = recipient;
+        more.satoshi += 1;
+        assert!(matches!(
+            wallet.sign_psbt(psbt(&unsigned), Some(&[more])),
+            Err(Error::PsbtOutputMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_tip_persisted() {
+        use crate::mock::MockElectrum;
+        use crate::{NativeNotif, Syncer};
+        use bitcoin::hashes::hex::ToHex;
+
+        let xprv = test_xprv();
+        let backend = MemoryBackend::default();
+        let header = bitcoin::BlockHeader {
+            version: 0x20000000,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            time: 1_630_000_000,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        let raw = serialize(&header).to_hex();
+        let server = MockElectrum::start(move |method, _| match method {
+            "blockchain.headers.subscribe" => {
+                Some(serde_json::json!({"height": 700_000, "hex": raw.clone()}))
+            }
+            "blockchain.scripthash.get_history" => Some(serde_json::json!([])),
+            _ => None,
+        });
+
+        let wallet = memory_wallet_with_backend(xprv, backend.clone());
+        let syncer = Syncer {
+            store: wallet.store.clone(),
+            master_blinding: None,
+            network: wallet.network.clone(),
+            stats: Default::default(),
+            notify: NativeNotif(None),
+        };
+        syncer.sync(&server.client(), None, true).unwrap();
+        assert_eq!(wallet.get_tip().unwrap(), (700_000, header.block_hash()));
+        drop(syncer);
+        drop(wallet);
+
+        let wallet = memory_wallet_with_backend(xprv, backend);
+        assert_eq!(wallet.get_tip().unwrap(), (700_000, header.block_hash()));
+    }
+
+    #[test]
+    fn test_parse_socks5() {
+        assert_eq!(parse_socks5("127.0.0.1:9050").unwrap(), "127.0.0.1:9050");
+        assert_eq!(parse_socks5("socks5://localhost:9050").unwrap(), "localhost:9050");
+        assert_eq!(parse_socks5("[::1]:9050").unwrap(), "[::1]:9050");
+        for invalid in &["", "127.0.0.1", ":9050", "127.0.0.1:0", "127.0.0.1:port", "a b:9050"] {
+            assert!(parse_socks5(invalid).is_err(), "{:?} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn test_build_client_socks5() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        // a fake proxy recording the socks5 greeting and closing the connection
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 2];
+            stream.read_exact(&mut greeting).unwrap();
+            greeting
+        });
+
+        let onion = "explorerzydxu5ecjrkwceayqybizmpjjznk5izmitf2modhcusuqlid.onion:110";
+        let url = ElectrumUrl::Plaintext(onion.into(), Some(proxy));
+        assert!(url.build_client().is_err());
+        assert_eq!(handle.join().unwrap()[0], 5, "socks version");
+    }
+
+    #[test]
+    fn test_build_client_tls() {
+        use openssl::asn1::Asn1Time;
+        use openssl::bn::BigNum;
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::ssl::{SslAcceptor, SslMethod};
+        use openssl::x509::{X509NameBuilder, X509};
+        use std::net::TcpListener;
+
+        // a server with a self-signed certificate, completing the handshake of every connection
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _ = acceptor.accept(stream.unwrap());
+            }
+        });
+
+        let url = |validation| ElectrumUrl::Tls(format!("localhost:{}", port), validation, None);
+        let sha256 = cert.digest(MessageDigest::sha256()).unwrap();
+        let pin: [u8; 32] = sha256.as_ref().try_into().unwrap();
+        assert!(url(TlsValidation::ValidateDomain).build_client().is_err(), "self-signed");
+        assert!(url(TlsValidation::AcceptInvalid).build_client().is_ok());
+        assert!(url(TlsValidation::Pinned(pin)).build_client().is_ok());
+        let result = url(TlsValidation::Pinned([7u8; 32])).build_client();
+        assert!(
+            matches!(result, Err(Error::PinnedCertificateMismatch(s)) if s == hex::encode(&sha256))
+        );
+
+        assert_eq!(TlsValidation::pinned(&hex::encode(&pin)).unwrap(), TlsValidation::Pinned(pin));
+        assert!(TlsValidation::pinned("00ff").is_err());
+        assert!(!TlsValidation::Pinned(pin).validate_domain());
+
+        assert_eq!(TlsValidation::from_validate_domain(None), TlsValidation::ValidateDomain);
+        assert_eq!(TlsValidation::from_validate_domain(Some(false)), TlsValidation::AcceptInvalid);
+        assert_eq!(TlsValidation::from_validate_domain(Some(true)), TlsValidation::ValidateDomain);
+        assert!(TlsValidation::ValidateDomain.validate_domain());
+        assert!(!TlsValidation::AcceptInvalid.validate_domain());
+    }
+
+    #[test]
+    fn test_is_synced() {
+        let wallet = memory_wallet();
+        wallet.store.write().unwrap().cache.tip.0 = 700_000;
+
+        assert!(wallet.is_synced_at(700_000).unwrap());
+        assert!(!wallet.is_synced_at(700_001).unwrap());
+    }
+
+    #[test]
+    fn test_tx_memo() {
+        let xprv = test_xprv();
+        let backend = MemoryBackend::default();
+        let wallet = memory_wallet_with_backend(xprv, backend.clone());
+        let txid = receive_at(&wallet, 90_000, Some(100));
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        assert_eq!(wallet.list_tx(&opt).unwrap()[0].memo, None);
+
+        let too_long = "x".repeat(MAX_MEMO_LEN + 1);
+        assert!(matches!(wallet.set_tx_memo(&txid, &too_long), Err(Error::MemoTooLong(_))));
+        wallet.set_tx_memo(&txid, "rent").unwrap();
+        assert_eq!(wallet.get_tx_memo(&txid).unwrap(), Some("rent".to_string()));
+        drop(wallet);
+
+        let wallet = memory_wallet_with_backend(xprv, backend);
+        assert_eq!(wallet.list_tx(&opt).unwrap()[0].memo, Some("rent".to_string()));
+        wallet.set_tx_memo(&txid, "").unwrap();
+        assert_eq!(wallet.get_tx_memo(&txid).unwrap(), None);
+    }
+
+    #[test]
+    fn test_create_tx_warnings() {
+        let wallet = memory_wallet();
+        receive_at(&wallet, 90_000, Some(100));
+        let mut request = send_request(&wallet, 10_000);
+        assert!(wallet.create_tx(&mut request.clone()).unwrap().warnings.is_empty());
+
+        request.fee_rate = Some(5_000);
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        assert_eq!(created.warnings, vec![TxWarning::HighFeeRate]);
+
+        // paying again the wallet first address, spending unconfirmed change
+        let wallet = memory_wallet();
+        receive_at(&wallet, 90_000, None);
+        let script = wallet.store.read().unwrap().cache.scripts.values().next().unwrap().clone();
+        request.addressees[0].address =
+            Address::from_script(&script, Network::Regtest).unwrap().to_string();
+        request.fee_rate = None;
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        assert!(created.warnings.contains(&TxWarning::AddressReuse));
+        assert!(created.warnings.contains(&TxWarning::SpendsUnconfirmed));
+        assert!(!created.warnings.contains(&TxWarning::LowPrivacy));
+    }
+
+    #[test]
+    fn test_create_tx_min_fee_rate() {
+        let mut wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let mut request = send_request(&wallet, 10_000);
+        assert_eq!(wallet.min_relay_fee_rate(), 1000);
+
+        request.fee_rate = Some(500);
+        assert!(matches!(
+            wallet.create_tx(&mut request.clone()),
+            Err(Error::FeeRateBelowMinimum(500, 1000))
+        ));
+        request.fee_rate = Some(1000);
+        assert!(wallet.create_tx(&mut request.clone()).is_ok());
+
+        wallet.network.min_relay_fee_rate = Some(250);
+        request.fee_rate = Some(500);
+        assert!(wallet.create_tx(&mut request.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_fee_rates() {
+        let estimates = [0.0002, 0.0000123, 0.000005, -1.0];
+        assert_eq!(fee_rates(&estimates, 1000), vec![20_000, 1230, 1000, 1000]);
+        assert_eq!(fee_rates(&estimates, 100), vec![20_000, 1230, 500, 100]);
+        assert!(fee_rates(&[], 1000).is_empty());
+    }
+
+    #[test]
+    fn test_create_tx_below_dust() {
+        let xprv = test_xprv();
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let public_key = xprv.private_key.public_key(&wallet.secp);
+        let p2pkh = Address::p2pkh(&public_key, Network::Regtest);
+        let p2wpkh = Address::p2wpkh(&public_key, Network::Regtest).unwrap();
+        for (address, threshold) in [(p2pkh, 546), (p2wpkh, 294)].iter() {
+            let mut request = CreateTransaction::default();
+            request.addressees.push(AddressAmount {
+                address: address.to_string(),
+                satoshi: threshold - 1,
+                asset_tag: None,
+            });
+            let result = wallet.create_tx(&mut request.clone());
+            assert!(
+                matches!(result, Err(Error::BelowDustLimit(v, t)) if v == threshold - 1 && t == *threshold)
+            );
+            request.addressees[0].satoshi = *threshold;
+            assert!(wallet.create_tx(&mut request).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_change_index_within_gap() {
+        let mut wallet = memory_wallet();
+        wallet.network.gap_limit = Some(3);
+        receive(&wallet, 90_000);
+        let mut request = send_request(&wallet, 10_000);
+        let internal = || wallet.store.read().unwrap().cache.indexes.internal;
+
+        // created and discarded txs don't consume change indexes
+        for _ in 0..5 {
+            let tx = wallet.create_tx(&mut request.clone()).unwrap();
+            assert_eq!(tx.changes_used, Some(1));
+            assert_eq!(internal(), 0);
+        }
+
+        // signed and never broadcasted txs advance the index up to the gap limit, then the
+        // last change index is reused
+        for _ in 0..5 {
+            let tx = wallet.create_tx(&mut request.clone()).unwrap();
+            wallet.sign(&tx).unwrap();
+        }
+        assert_eq!(internal(), 2);
+        assert_eq!(wallet.internal_gap().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_change_output_flagged() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        let mut request = send_request(&wallet, 10_000);
+        let tx = wallet.create_tx(&mut request).unwrap();
+        let changes: Vec<&OutputInfo> = tx.outputs.iter().filter(|o| o.is_change).collect();
+        assert_eq!(tx.outputs.len(), 2);
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].is_mine);
+        assert_eq!(changes[0].derivation_path.as_deref(), Some("m/1/1"));
+        // the change is not part of the sent amount
+        assert_eq!(tx.satoshi.get("btc"), Some(&(10_000 + tx.fee.unwrap() as i64)));
+
+        // as sync does, make the tx and the internal scripts part of the wallet
+        let signed = wallet.sign(&tx).unwrap();
+        let signed_tx: Transaction = deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+        {
+            let mut store = wallet.store.write().unwrap();
+            for (script, path) in store.get_script_batch(1, 0).unwrap().value {
+                store.cache.paths.insert(script.clone(), path.clone());
+                store.cache.scripts.insert(path, script);
+            }
+            store.cache.heights.insert(signed_tx.txid(), None);
+            store.cache.all_txs.insert(signed_tx.txid(), BETransaction::Bitcoin(signed_tx));
+        }
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let txs = wallet.list_tx(&opt).unwrap();
+        let sent = txs.iter().find(|t| t.txid == signed.txid).unwrap();
+        assert_eq!(sent.type_, "outgoing");
+        assert_eq!(sent.satoshi.get("btc"), Some(&-(10_000 + tx.fee.unwrap() as i64)));
+        let changes: Vec<&OutputInfo> = sent.outputs.iter().filter(|o| o.is_change).collect();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].derivation_path.as_deref(), Some("m/1/1"));
+        let received = txs.iter().find(|t| t.txid != signed.txid).unwrap();
+        assert!(received.outputs.iter().all(|o| !o.is_change));
+    }
+
+    #[test]
+    fn test_open_watch_only() {
+        let xprv = test_xprv();
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let xpub = ExtendedPubKey::from_private(&secp, &xprv);
+        let mut network = gdk_common::network::Network::default();
+        network.development = true;
+        let db_root = TempDir::new("watch_only").unwrap();
+        let name = crate::wallet_db_name(&xpub, &network);
+        let fingerprint = xprv.fingerprint(&secp);
+        let wallet =
+            WalletCtx::open_watch_only(db_root.path(), &name, xpub, fingerprint, None, network)
+                .unwrap();
+        assert!(wallet.is_watch_only());
+        assert!(db_root.path().join(&name).exists());
+
+        // as sync does
+        receive(&wallet, 90_000);
+        assert_eq!(wallet.balance().unwrap().get("btc"), Some(&90_000));
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        assert_eq!(wallet.list_tx(&opt).unwrap().len(), 1);
+        assert!(wallet.get_address(None).is_ok());
+
+        let mut request = send_request(&wallet, 10_000);
+        let tx = wallet.create_tx(&mut request).unwrap();
+        assert!(matches!(wallet.sign(&tx), Err(Error::WatchOnly)));
+    }
+
+    #[test]
+    fn test_sign_message() {
+        let wallet = memory_wallet();
+        let message = "This is an example of a signed message.";
+        for script_type in [ScriptType::P2shP2wpkh, ScriptType::P2wpkh, ScriptType::P2pkh].iter() {
+            let address = wallet.get_address(Some(*script_type)).unwrap().address;
+            let signature = wallet.sign_message(&address, message).unwrap();
+            assert_eq!(base64::decode(&signature).unwrap().len(), 65);
+            assert!(wallet.verify_message(&address, message, &signature).unwrap());
+            assert!(!wallet.verify_message(&address, "another message", &signature).unwrap());
+        }
+
+        // a key not of the wallet
+        let other = ExtendedPrivKey::new_master(Network::Regtest, &[2u8; 32]).unwrap();
+        let other_key = other.private_key.public_key(&wallet.secp);
+        let address = Address::p2pkh(&other_key, Network::Regtest).to_string();
+        assert!(matches!(wallet.sign_message(&address, message), Err(Error::AddressNotMine)));
+        let mine = wallet.get_address(None).unwrap().address;
+        let signature = wallet.sign_message(&mine, message).unwrap();
+        assert!(!wallet.verify_message(&address, message, &signature).unwrap());
+    }
+
+    #[test]
+    fn test_freeze_utxo() {
+        let wallet = memory_wallet();
+        let txid = receive_at(&wallet, 90_000, Some(1));
+        let outpoint = BEOutPoint::new_bitcoin(txid, 0);
+        let mut request = send_request(&wallet, 10_000);
+
+        wallet.freeze_utxo(&outpoint).unwrap();
+        assert!(wallet.utxos().unwrap().is_empty());
+        assert_eq!(wallet.balance().unwrap().get("btc"), Some(&0));
+        assert!(matches!(wallet.create_tx(&mut request.clone()), Err(Error::InsufficientFunds)));
+        // the coin is still in the db
+        assert_eq!(wallet.all_balance().unwrap().get("btc"), Some(&90_000));
+        assert_eq!(wallet.all_utxos().unwrap()[0].0, outpoint);
+        assert!(wallet.store.read().unwrap().cache.all_txs.get(&txid).is_some());
+
+        wallet.unfreeze_utxo(&outpoint).unwrap();
+        assert_eq!(wallet.utxos().unwrap().len(), 1);
+        assert!(wallet.create_tx(&mut request).is_ok());
+    }
+
+    #[test]
+    fn test_create_tx_selected_utxos() {
+        let wallet = memory_wallet();
+        receive_at(&wallet, 90_000, Some(100));
+        let first = receive_at(&wallet, 50_000, Some(101));
+        let second = receive_at(&wallet, 30_000, Some(102));
+        let mut request = send_request(&wallet, 20_000);
+        request.utxos = Some(vec![format!("{}:0", first), format!("{}:0", second)]);
+
+        // both are used even if one would be enough
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+        let inputs: HashSet<OutPoint> = tx.input.iter().map(|i| i.previous_output).collect();
+        let expected: HashSet<OutPoint> =
+            [OutPoint::new(first, 0), OutPoint::new(second, 0)].iter().cloned().collect();
+        assert_eq!(inputs, expected);
+        let change: u64 = tx.output.iter().map(|o| o.value).filter(|v| *v != 20_000).sum();
+        assert_eq!(change, 80_000 - 20_000 - created.fee.unwrap());
+
+        let mut too_much = request.clone();
+        too_much.addressees[0].satoshi = 80_000;
+        assert!(matches!(wallet.create_tx(&mut too_much), Err(Error::InsufficientFunds)));
+
+        let mut unknown = request.clone();
+        unknown.utxos.as_mut().unwrap().push(format!("{}:1", second));
+        assert!(matches!(wallet.create_tx(&mut unknown), Err(Error::UtxoNotFound(_))));
+    }
+
+    #[test]
+    fn test_is_mine_large_wallet() {
+        let wallet = memory_wallet();
+        let secp = &wallet.secp;
+
+        // 500 scripts for each chain, as a sync would store them
+        let mut mine = vec![];
+        {
+            let mut store = wallet.store.write().unwrap();
+            for chain in 0..2 {
+                for batch in 0..25 {
+                    for (script, path) in store.get_script_batch(chain, batch).unwrap().value {
+                        store.cache.paths.insert(script.clone(), path.clone());
+                        store.cache.scripts.insert(path, script.clone());
+                        mine.push(script);
+                    }
+                }
+            }
+        }
+        assert_eq!(mine.len(), 1000);
+        let other = ExtendedPrivKey::new_master(Network::Regtest, &[2u8; 32]).unwrap();
+        let foreign = Address::p2wpkh(&other.private_key.public_key(secp), Network::Regtest)
+            .unwrap()
+            .script_pubkey();
+        let mut output: Vec<TxOut> = mine
+            .iter()
+            .map(|script| TxOut {
+                value: 1_000,
+                script_pubkey: script.clone(),
+            })
+            .collect();
+        output.extend((0..10).map(|_| TxOut {
+            value: 1_000,
+            script_pubkey: foreign.clone(),
+        }));
+        let tx = transaction(vec![], output);
+        {
+            let mut store = wallet.store.write().unwrap();
+            store.cache.heights.insert(tx.txid(), Some(1));
+            store.cache.all_txs.insert(tx.txid(), BETransaction::Bitcoin(tx.clone()));
+        }
+
+        // the scripts of the wallet derived again from the xpub
+        let derived: HashSet<Script> = (0..2)
+            .flat_map(|chain| (0..500).map(move |index| (chain, index)))
+            .map(|(chain, index)| {
+                let path = DerivationPath::from_str(&format!("m/{}/{}", chain, index)).unwrap();
+                let key = wallet.xpub.derive_pub(secp, &path).unwrap();
+                Address::p2shwpkh(&key.public_key, Network::Regtest).unwrap().script_pubkey()
+            })
+            .collect();
+        let store = wallet.store.read().unwrap();
+        for output in tx.output.iter() {
+            assert_eq!(
+                store.is_mine(&output.script_pubkey),
+                derived.contains(&output.script_pubkey)
+            );
+        }
+        drop(store);
+
+        assert_eq!(wallet.utxos().unwrap().len(), 1000);
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let txs = wallet.list_tx(&opt).unwrap();
+        let outputs = &txs[0].outputs;
+        assert_eq!(outputs.len(), 1010);
+        for (output, txout) in outputs.iter().zip(tx.output.iter()) {
+            assert_eq!(output.is_mine, derived.contains(&txout.script_pubkey));
+        }
+    }
+
+    #[test]
+    fn test_live_scripts() {
+        let wallet = memory_wallet();
+        let txid = receive_at(&wallet, 90_000, Some(1));
+        let script = wallet.store.read().unwrap().get_script_batch(0, 0).unwrap().value.remove(0).0;
+        let below = Indexes {
+            external: 1,
+            internal: 0,
+        };
+        let live = |below: &Indexes| wallet.store.read().unwrap().live_scripts(below).unwrap();
+
+        // m/0/0 has an unspent output, it's live only if an incremental sync would skip it
+        assert!(live(&below).contains_key(&script));
+        assert!(live(&Indexes::default()).is_empty());
+
+        let spend = transaction(
+            vec![TxIn {
+                previous_output: OutPoint::new(txid, 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffe,
+                witness: vec![],
+            }],
+            vec![TxOut {
+                value: 80_000,
+                script_pubkey: Script::new(),
+            }],
+        );
+        let spend_txid = spend.txid();
+        {
+            let mut store = wallet.store.write().unwrap();
+            store.cache.all_txs.insert(spend_txid, BETransaction::Bitcoin(spend));
+            store.cache.heights.insert(spend_txid, None);
+        }
+        assert!(live(&below).contains_key(&script), "spent by an unconfirmed tx");
+
+        wallet.store.write().unwrap().cache.heights.insert(spend_txid, Some(2));
+        assert!(live(&below).is_empty(), "spent by a confirmed tx");
+    }
+
+    #[test]
+    fn test_blind_tx() {
+        let mut wallet = liquid_memory_wallet();
+        let master_blinding = asset_blinding_key_from_seed(&[2u8; 64]);
+        wallet.master_blinding = Some(master_blinding.clone());
+        wallet.network.ct_exponent = Some(0);
+        wallet.network.ct_bits = Some(52);
+        let policy_asset = [3u8; 32];
+        let asset_id = elements::issuance::AssetId::from_slice(&policy_asset).unwrap();
+
+        let txid = Txid::from_slice(&[8u8; 32]).unwrap();
+        wallet.store.write().unwrap().cache.unblinded.insert(
+            elements::OutPoint {
+                txid,
+                vout: 0,
+            },
+            Unblinded {
+                asset: policy_asset,
+                abf: [6u8; 32],
+                vbf: [7u8; 32],
+                value: 100_000,
+            },
+        );
+
+        // a single output send, as built by `create_tx`
+        let address = wallet.get_address(None).unwrap().address;
+        let mut tx = BETransaction::new(wallet.network.id());
+        tx.add_input(BEOutPoint::new_elements(txid, 0));
+        tx.add_output(&address, 90_000, Some(asset_to_hex(&policy_asset))).unwrap();
+        let fee_asset = elements::confidential::Asset::Explicit(asset_id);
+        tx.add_fee_if_elements(10_000, &Some(fee_asset)).unwrap();
+        let mut tx = match tx {
+            BETransaction::Elements(tx) => tx,
+            _ => unreachable!(),
+        };
+        wallet.blind_tx(&mut tx).unwrap();
+
+        assert!(tx.output[1].is_fee(), "the fee stays explicit");
+        assert_eq!(tx.output[1].value, elements::confidential::Value::Explicit(10_000));
+
+        let output = &tx.output[0];
+        assert!(matches!(output.value, elements::confidential::Value::Confidential(_, _)));
+        assert!(matches!(output.asset, elements::confidential::Asset::Confidential(_, _)));
+        assert!(!output.witness.surjection_proof.is_empty());
+        let script = output.script_pubkey.clone();
+        let receiver_sk = asset_blinding_key_to_ec_private_key(&master_blinding, &script);
+        // the range proof validates, rewinding it with the receiver blinding key
+        let nonce = elements::encode::serialize(&output.nonce);
+        let sender_pk = bitcoin::secp256k1::PublicKey::from_slice(&nonce).unwrap();
+        let (asset, _, _, value) = asset_unblind(
+            sender_pk,
+            receiver_sk,
+            output.witness.rangeproof.clone(),
+            elements::encode::serialize(&output.value),
+            script,
+            elements::encode::serialize(&output.asset),
+        )
+        .unwrap();
+        assert_eq!((asset, value), (policy_asset, 90_000));
+    }
+
+    #[test]
+    fn test_tx_meta_unblinds_outputs() {
+        let mut wallet = liquid_memory_wallet();
+        wallet.master_blinding = Some(asset_blinding_key_from_seed(&[2u8; 64]));
+        wallet.network.ct_exponent = Some(0);
+        wallet.network.ct_bits = Some(52);
+        let policy_asset = [3u8; 32];
+        let asset_id = elements::issuance::AssetId::from_slice(&policy_asset).unwrap();
+
+        let prev = elements::OutPoint {
+            txid: Txid::from_slice(&[8u8; 32]).unwrap(),
+            vout: 0,
+        };
+        let unblinded = Unblinded {
+            asset: policy_asset,
+            abf: [6u8; 32],
+            vbf: [7u8; 32],
+            value: 100_000,
+        };
+        wallet.store.write().unwrap().cache.unblinded.insert(prev, unblinded);
+
+        // pays 90_000 to the wallet and 5_000 to an address whose script is not of the wallet
+        let mine = wallet.get_address(None).unwrap();
+        let other = wallet.get_address(None).unwrap().address;
+        let mut tx = BETransaction::new(wallet.network.id());
+        tx.add_input(BEOutPoint::new_elements(prev.txid, 0));
+        tx.add_output(&mine.address, 90_000, Some(asset_to_hex(&policy_asset))).unwrap();
+        tx.add_output(&other, 5_000, Some(asset_to_hex(&policy_asset))).unwrap();
+        let fee_asset = elements::confidential::Asset::Explicit(asset_id);
+        tx.add_fee_if_elements(5_000, &Some(fee_asset)).unwrap();
+        let mut tx = match tx {
+            BETransaction::Elements(tx) => tx,
+            _ => unreachable!(),
+        };
+        wallet.blind_tx(&mut tx).unwrap();
+        let txid = tx.txid();
+
+        {
+            let mut store = wallet.store.write().unwrap();
+            // the input is not of the wallet and the sync didn't unblind the output yet
+            store.cache.unblinded.clear();
+            let path = DerivationPath::from_str(&format!("m/0/{}", mine.pointer)).unwrap();
+            store.cache.paths.insert(tx.output[0].script_pubkey.clone(), path);
+            store.cache.heights.insert(txid, Some(1));
+            store.cache.all_txs.insert(txid, BETransaction::Elements(tx));
+        }
+
+        let meta = wallet.get_tx_detail(&txid).unwrap().unwrap();
+        let first = elements::OutPoint {
+            txid,
+            vout: 0,
+        };
+        assert!(wallet.store.read().unwrap().cache.unblinded.contains_key(&first), "kept");
+        let asset_hex = asset_to_hex(&policy_asset);
+        assert_eq!(meta.satoshi.get(&asset_hex), Some(&90_000));
+        assert_eq!(meta.type_, "incoming");
+        let amounts: Vec<_> =
+            meta.outputs.iter().map(|o| (o.is_mine, o.satoshi, o.asset_id.clone())).collect();
+        assert_eq!(
+            amounts,
+            vec![
+                (true, Some(90_000), Some(asset_hex.clone())),
+                (false, None, None),
+                (false, Some(5_000), Some(asset_hex)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sign_sighash_single_anyonecanpay() {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let wallet = memory_wallet();
+        let txid = receive_at(&wallet, 90_000, Some(1));
+
+        let mut tx = transaction(
+            vec![TxIn {
+                previous_output: OutPoint::new(txid, 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffd,
+                witness: vec![],
+            }],
+            vec![
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: Script::from(vec![0x51]),
+                },
+                TxOut {
+                    value: 30_000,
+                    script_pubkey: Script::from(vec![0x52]),
+                },
+            ],
+        );
+        let mut request: TransactionMeta = BETransaction::Bitcoin(tx.clone()).into();
+        request.sighash_types = vec![SigHashType::SinglePlusAnyoneCanPay.as_u32()];
+        let signed = wallet.sign(&request).unwrap();
+        assert_eq!(signed.signed_inputs[0].sighash_type, 0x83);
+        wallet.verify_signed_tx(&signed).unwrap();
+
+        let signed_tx: Transaction = deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+        let witness = &signed_tx.input[0].witness;
+        let public_key = PublicKey::from_slice(&witness[1]).unwrap();
+        let (flag, der) = witness[0].split_last().unwrap();
+        assert_eq!(*flag, 0x83);
+
+        // BIP143 digest built by hand: with ANYONECANPAY the other inputs aren't committed and
+        // with SINGLE only the output at the same index is
+        let script_code = p2pkh_script(&public_key);
+        let mut preimage = vec![];
+        preimage.extend(&serialize(&tx.version));
+        preimage.extend(&[0u8; 32]); // hashPrevouts
+        preimage.extend(&[0u8; 32]); // hashSequence
+        preimage.extend(&serialize(&tx.input[0].previous_output));
+        preimage.extend(&serialize(&script_code));
+        preimage.extend(&serialize(&90_000u64));
+        preimage.extend(&serialize(&tx.input[0].sequence));
+        preimage.extend(&sha256d::Hash::hash(&serialize(&tx.output[0]))[..]);
+        preimage.extend(&serialize(&tx.lock_time));
+        preimage.extend(&serialize(&0x83u32));
+        let hash = sha256d::Hash::hash(&preimage).into_inner();
+        assert_eq!(signed.signed_inputs[0].sighash, hex::encode(&hash));
+        let message = Message::from_slice(&hash[..]).unwrap();
+        let signature = Signature::from_der(der).unwrap();
+        secp.verify(&message, &signature, &public_key.key).unwrap();
+
+        // the signature stays valid when inputs and outputs are added by others
+        tx.input.push(TxIn {
+            previous_output: OutPoint::new(Txid::from_inner([7u8; 32]), 7),
+            script_sig: Script::new(),
+            sequence: 0,
+            witness: vec![],
+        });
+        tx.output[1].value = 1_000;
+        let other = SigHashCache::new(&tx)
+            .signature_hash(0, &script_code, 90_000, SigHashType::SinglePlusAnyoneCanPay)
+            .into_inner();
+        assert_eq!(hash, other);
+
+        request.sighash_types = vec![0x04];
+        assert!(wallet.sign(&request).is_err());
+        tx.input.truncate(1);
+        tx.output.clear();
+        let mut request: TransactionMeta = BETransaction::Bitcoin(tx).into();
+        request.sighash_types = vec![SigHashType::None.as_u32()];
+        assert!(wallet.sign(&request).is_ok());
+        request.sighash_types = vec![SigHashType::Single.as_u32()];
+        assert!(wallet.sign(&request).is_err());
+    }
+
+    #[test]
+    fn test_broadcast() {
+        use crate::mock::MockElectrum;
+        use crate::retry::{RetryClient, RetryPolicy};
+        use std::time::Duration;
+
+        let wallet = memory_wallet();
+        let received = receive_at(&wallet, 90_000, Some(1));
+        let change = {
+            let mut store = wallet.store.write().unwrap();
+            let (script, path) = store.get_script_batch(1, 0).unwrap().value.remove(3);
+            store.cache.paths.insert(script.clone(), path.clone());
+            store.cache.scripts.insert(path, script.clone());
+            script
+        };
+        let tx = transaction(
+            vec![TxIn {
+                previous_output: OutPoint::new(received, 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffd,
+                witness: vec![],
+            }],
+            vec![
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: Script::from(vec![0x51]),
+                },
+                TxOut {
+                    value: 39_000,
+                    script_pubkey: change,
+                },
+            ],
+        );
+        let signed = wallet.sign(&BETransaction::Bitcoin(tx).into()).unwrap();
+        let txid = Txid::from_str(&signed.txid).unwrap();
+
+        // a refusal of the server is reported with its reason, isn't retried and the tx isn't
+        // stored
+        let server = MockElectrum::failing(|_, _| {
+            Some(Err(serde_json::json!({ "code": 1, "message": "min relay fee not met" })))
+        });
+        match wallet.broadcast(&server.client(), &signed) {
+            Err(Error::BroadcastRejected(reason)) => assert_eq!(reason, "min relay fee not met"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(server.requests("blockchain.transaction.broadcast").len(), 1);
+        assert!(wallet.store.read().unwrap().cache.heights.get(&txid).is_none());
+
+        // a dropped connection is retried
+        let server = MockElectrum::dropping(1, |_, params| {
+            let hex = params[0].as_str()?;
+            let tx: Transaction = deserialize(&hex::decode(hex).ok()?).ok()?;
+            Some(serde_json::json!(tx.txid().to_string()))
+        });
+        let client = RetryClient::with_policy(
+            &ElectrumUrl::Plaintext(server.url.clone(), None),
+            RetryPolicy {
+                attempts: 2,
+                base_delay: Duration::from_millis(1),
+            },
+        )
+        .unwrap();
+        assert_eq!(wallet.broadcast(&client, &signed).unwrap(), txid);
+        assert_eq!(server.connections(), 2);
+        let requests = server.requests("blockchain.transaction.broadcast");
+        assert_eq!(requests, vec![vec![serde_json::json!(signed.hex)]]);
+
+        {
+            let store = wallet.store.read().unwrap();
+            assert_eq!(store.cache.heights.get(&txid), Some(&None));
+            assert!(store.cache.all_txs.get(&txid).is_some());
+            assert!(store.cache.first_seen.contains_key(&txid));
+            assert_eq!(store.cache.last_used.internal, 3);
+            assert!(store.cache.indexes.internal >= 3);
+        }
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let txs = wallet.list_tx(&opt).unwrap();
+        assert_eq!(txs[0].txid, signed.txid);
+        assert_eq!(txs[0].height, None);
+    }
+
+    #[test]
+    fn test_accounts() {
+        let master = test_xprv();
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let mut network = gdk_common::network::Network::default();
+        network.development = true;
+        let db_root = TempDir::new("accounts").unwrap();
+        let mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about".to_string();
+        let open = |account| {
+            WalletCtx::open_account(
+                db_root.path(),
+                mnemonic.clone().into(),
+                &master,
+                ScriptType::P2shP2wpkh,
+                account,
+                None,
+                network.clone(),
+            )
+            .unwrap()
+        };
+        let first = open(0);
+        let second = open(1);
+
+        let path = DerivationPath::from_str("m/49'/1'/1'").unwrap();
+        assert_eq!(account_path(network.id(), ScriptType::P2shP2wpkh, 1).unwrap(), path);
+        let expected =
+            ExtendedPubKey::from_private(&secp, &master.derive_priv(&secp, &path).unwrap());
+        assert_eq!(second.xpub, expected);
+        assert_eq!(second.account, 1);
+        assert_ne!(first.xpub, second.xpub);
+        assert_eq!(first.master_fingerprint, second.master_fingerprint);
+        assert_eq!(
+            account_path(NetworkId::Bitcoin(Network::Bitcoin), ScriptType::P2wpkh, 2).unwrap(),
+            DerivationPath::from_str("m/84'/0'/2'").unwrap()
+        );
+
+        // every account derives its own addresses and keeps its own indexes
+        let first_address = first.get_address(None).unwrap();
+        first.get_address(None).unwrap();
+        let second_address = second.get_address(None).unwrap();
+        assert_ne!(first_address.address, second_address.address);
+        assert_eq!(first_address.pointer, second_address.pointer);
+        assert_eq!(first.store.read().unwrap().cache.indexes.external, 2);
+        assert_eq!(second.store.read().unwrap().cache.indexes.external, 1);
+
+        receive(&first, 90_000);
+        receive(&second, 40_000);
+        assert_eq!(first.balance().unwrap().get("btc"), Some(&90_000));
+        assert_eq!(second.balance().unwrap().get("btc"), Some(&40_000));
+
+        let mut request = CreateTransaction::default();
+        request.addressees.push(AddressAmount {
+            address: first_address.address,
+            satoshi: 10_000,
+            asset_tag: None,
+        });
+        assert!(second.create_tx(&mut request.clone()).is_ok());
+        request.subaccount = Some(1);
+        assert!(second.create_tx(&mut request.clone()).is_ok());
+        assert!(matches!(first.create_tx(&mut request), Err(Error::InvalidSubaccount(1))));
+
+        // the account db is found again when reopened
+        drop(second);
+        let second = open(1);
+        assert_eq!(second.balance().unwrap().get("btc"), Some(&40_000));
+        assert_eq!(second.store.read().unwrap().cache.indexes.external, 1);
+    }
+
+    #[test]
+    fn test_transaction_hex() {
+        use bitcoin::consensus::encode::serialize_hex;
+
+        let wallet = memory_wallet();
+        let txid = receive_at(&wallet, 90_000, Some(1));
+        let opt = GetTransactionsOpt {
+            count: 10,
+            ..Default::default()
+        };
+        let listed = wallet.list_tx(&opt).unwrap().remove(0);
+        let stored = wallet.store.read().unwrap().get_bitcoin_tx(&txid).unwrap();
+        assert_eq!(listed.transaction_hex(), serialize_hex(&stored));
+        let tx: Transaction = deserialize(&hex::decode(listed.transaction_hex()).unwrap()).unwrap();
+        assert_eq!(tx, stored);
+
+        let spending = transaction(
+            vec![TxIn {
+                previous_output: OutPoint::new(txid, 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffd,
+                witness: vec![],
+            }],
+            vec![TxOut {
+                value: 80_000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        );
+        let signed = wallet.sign(&BETransaction::Bitcoin(spending).into()).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(signed.transaction_hex()).unwrap()).unwrap();
+        assert_eq!(serialize_hex(&tx), signed.transaction_hex());
+        assert_eq!(tx.txid().to_string(), signed.txid);
+        assert_eq!(tx.input[0].witness.len(), 2);
+    }
+
+    #[test]
+    fn test_create_tx_locktime() {
+        let wallet = memory_wallet();
+        receive(&wallet, 90_000);
+        wallet.store.write().unwrap().cache.tip.0 = 700_123;
+        let mut request = send_request(&wallet, 50_000);
+        let created_tx = |request: &mut CreateTransaction| {
+            let created = wallet.create_tx(request).unwrap();
+            let tx: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+            tx
+        };
+
+        // anti fee sniping by default
+        let created = wallet.create_tx(&mut request.clone()).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+        assert_eq!(tx.lock_time, 700_123);
+        assert_eq!(created.create_transaction.unwrap().locktime, Some(700_123));
+
+        for rbf in [None, Some(true), Some(false)].iter() {
+            let mut request = request.clone();
+            request.locktime = Some(750_000);
+            request.rbf = *rbf;
+            let tx = created_tx(&mut request);
+            assert_eq!(tx.lock_time, 750_000);
+            assert!(tx.input.iter().all(|i| i.sequence < 0xffff_ffff), "locktime is enforced");
+        }
+
+        let mut request = request.clone();
+        request.locktime = Some(0);
+        let created = wallet.create_tx(&mut request).unwrap();
+        let signed = wallet.sign(&created).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&signed.hex).unwrap()).unwrap();
+        assert_eq!(tx.lock_time, 0, "sign keeps the locktime");
+    }
+
+    #[test]
+    fn test_balance_detail() {
+        let wallet = memory_wallet();
+        let confirmed = receive_at(&wallet, 100_000, Some(1));
+        let mempool = receive_at(&wallet, 50_000, None);
+        wallet.store.write().unwrap().cache.tip.0 = 10;
+
+        let detail = wallet.balance_detail().unwrap();
+        assert_eq!(detail.confirmed.get("btc"), Some(&100_000));
+        assert_eq!(detail.unconfirmed.get("btc"), Some(&50_000));
+        assert_eq!(detail.immature.get("btc"), Some(&0));
+        assert_eq!(wallet.balance().unwrap().get("btc"), Some(&150_000));
+
+        let mut request = send_request(&wallet, 60_000);
+        let inputs = |request: &mut CreateTransaction| {
+            let created = wallet.create_tx(request).unwrap();
+            let tx: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+            tx.input.iter().map(|i| i.previous_output.txid).collect::<HashSet<_>>()
+        };
+
+        // outputs on the same script are spent together
+        let all = inputs(&mut request.clone());
+        assert!(all.contains(&confirmed) && all.contains(&mempool));
+
+        request.confirmed_only = Some(true);
+        let only_confirmed = inputs(&mut request.clone());
+        assert_eq!(only_confirmed.into_iter().collect::<Vec<_>>(), vec![confirmed]);
+
+        request.addressees[0].satoshi = 120_000;
+        assert!(matches!(wallet.create_tx(&mut request), Err(Error::InsufficientFunds)));
+    }
+
+    #[test]
+    fn test_immature_coinbase() {
+        let wallet = memory_wallet();
+        receive_at(&wallet, 100_000, Some(1));
+        let coinbase = {
+            let mut store = wallet.store.write().unwrap();
+            let script = store.get_script_batch(0, 0).unwrap().value.remove(0).0;
+            let tx = transaction(
+                vec![TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: Script::from(vec![1, 60]),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                }],
+                vec![TxOut {
+                    value: 5_000_000_000,
+                    script_pubkey: script,
+                }],
+            );
+            let txid = tx.txid();
+            store.cache.heights.insert(txid, Some(60));
+            store.cache.all_txs.insert(txid, BETransaction::Bitcoin(tx));
+            store.cache.tip.0 = 100;
+            txid
+        };
+
+        assert_eq!(wallet.utxos().unwrap().len(), 1);
+        assert_eq!(wallet.balance().unwrap().get("btc"), Some(&100_000));
+        assert_eq!(wallet.balance_detail().unwrap().immature.get("btc"), Some(&5_000_000_000));
+        assert!(wallet.all_utxos().unwrap().iter().any(|(o, _)| o.txid() == coinbase));
+
+        let mut request = send_request(&wallet, 200_000);
+        assert!(matches!(wallet.create_tx(&mut request.clone()), Err(Error::InsufficientFunds)));
+
+        // 100 confirmations
+        wallet.store.write().unwrap().cache.tip.0 = 159;
+        assert_eq!(wallet.balance().unwrap().get("btc"), Some(&5_000_100_000));
+        let created = wallet.create_tx(&mut request).unwrap();
+        let tx: Transaction = deserialize(&hex::decode(&created.hex).unwrap()).unwrap();
+        assert!(tx.input.iter().any(|i| i.previous_output.txid == coinbase));
+    }
 }
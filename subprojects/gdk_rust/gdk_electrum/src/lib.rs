@@ -1,5 +1,3 @@
-mod store;
-
 #[macro_use]
 extern crate serde_json;
 
@@ -9,16 +7,22 @@ extern crate lazy_static;
 use log::{debug, info, trace, warn};
 use serde_json::Value;
 
+pub mod coinselect;
 pub mod error;
 pub mod headers;
 pub mod interface;
+#[cfg(test)]
+mod mock;
 pub mod pin;
+pub mod retry;
+pub mod store;
 
 use crate::error::Error;
-use crate::interface::{ElectrumUrl, WalletCtx};
+use crate::interface::{account_path, parse_socks5, ElectrumUrl, TlsValidation, WalletCtx};
 use crate::store::*;
 
-use bitcoin::hashes::{hex::FromHex, sha256, Hash};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::{sha256, Hash};
 use bitcoin::secp256k1::{self, Secp256k1, SecretKey};
 use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey};
 use bitcoin::{BlockHash, Script, Txid};
@@ -29,31 +33,34 @@ use gdk_common::mnemonic::Mnemonic;
 use gdk_common::model::*;
 use gdk_common::network::Network;
 use gdk_common::password::Password;
+use gdk_common::scripts::ScriptType;
 use gdk_common::session::Session;
 use gdk_common::wally::{
     self, asset_blinding_key_from_seed, asset_blinding_key_to_ec_private_key, asset_unblind,
-    MasterBlindingKey,
+    invoice_blinding_key_to_ec_private_key, MasterBlindingKey,
 };
 
 use elements::confidential::{self, Asset, Nonce};
-use gdk_common::{ElementsNetwork, NetworkId};
+use gdk_common::NetworkId;
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use crate::headers::bitcoin::HeadersChain;
+use crate::headers::bitcoin::{HeadersChain, MAX_REORG_DEPTH};
 use crate::headers::liquid::Verifier;
-use crate::headers::ChainOrVerifier;
+use crate::headers::{get_merkle, ChainOrVerifier};
 use crate::pin::PinManager;
+use crate::retry::RetryClient;
 use aes::Aes256;
 use bitcoin::blockdata::constants::DIFFCHANGE_INTERVAL;
 use block_modes::block_padding::Pkcs7;
 use block_modes::BlockMode;
 use block_modes::Cbc;
-use electrum_client::{Client, ElectrumApi};
+use electrum_client::{Batch, Client, ElectrumApi, Param};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use rand::Rng;
@@ -64,10 +71,77 @@ use std::thread::JoinHandle;
 
 type Aes256Cbc = Cbc<Aes256, Pkcs7>;
 
+/// default max number of transactions requested to the server in a single batch call
+pub const HISTORY_PAGE_SIZE: usize = 500;
+
+/// default number of txs in the history of a script above which it's downloaded on its own,
+/// see `Syncer::store_huge_histories`
+pub const HUGE_HISTORY_THRESHOLD: usize = 1000;
+
+/// default number of consecutive unused scripts after which sync stops scanning a chain
+pub const GAP_LIMIT: u32 = BATCH_SIZE;
+
+/// max levels of previous txs downloaded by a sync, see `download_previous_txs`
+pub const MAX_PREVIOUS_DEPTH: usize = 5;
+
 pub struct Syncer {
     pub store: Store,
     pub master_blinding: Option<MasterBlindingKey>,
     pub network: Network,
+    /// statistics of the last sync, reset when a sync starts
+    pub stats: RwLock<SyncStats>,
+    /// receives the progress of the download of huge histories
+    pub notify: NativeNotif,
+}
+
+/// requests made by a sync and approximate bytes received, to tune batch sizes and gap limits
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncStats {
+    pub history_requests: u32,
+    pub tx_requests: u32,
+    pub header_requests: u32,
+    pub bytes_downloaded: u64,
+    /// txs and headers found already stored, whose write has been skipped
+    pub skipped_writes: u32,
+    /// pages of the histories above `huge_history_threshold`, see `Syncer::store_huge_histories`
+    pub huge_history_pages: u32,
+}
+
+/// approximate size of an entry of a script history in the json response
+const HISTORY_ENTRY_BYTES: u64 = 100;
+
+impl SyncStats {
+    pub fn add_history(&mut self, result: &[Vec<GetHistoryRes>]) {
+        self.history_requests += 1;
+        let entries: usize = result.iter().map(|h| h.len()).sum();
+        self.bytes_downloaded += entries as u64 * HISTORY_ENTRY_BYTES;
+    }
+
+    /// raw txs and headers are transferred hex encoded
+    pub fn add_txs(&mut self, txs: &[Vec<u8>]) {
+        self.tx_requests += 1;
+        self.bytes_downloaded += txs.iter().map(|tx| tx.len() as u64 * 2).sum::<u64>();
+    }
+
+    pub fn add_headers(&mut self, headers: &[Vec<u8>]) {
+        self.header_requests += 1;
+        self.bytes_downloaded += headers.iter().map(|h| h.len() as u64 * 2).sum::<u64>();
+    }
+
+    /// a batch of txs and headers, see `Fetch`
+    pub fn add_page(&mut self, page: &[Fetch], raw: &[Vec<u8>]) {
+        if page.iter().any(|item| matches!(item, Fetch::Tx(_))) {
+            self.tx_requests += 1;
+        }
+        if page.iter().any(|item| matches!(item, Fetch::Header(_))) {
+            self.header_requests += 1;
+        }
+        self.bytes_downloaded += raw.iter().map(|r| r.len() as u64 * 2).sum::<u64>();
+    }
+
+    pub fn add_skipped(&mut self, skipped: usize) {
+        self.skipped_writes += skipped as u32;
+    }
 }
 
 pub struct Tipper {
@@ -145,25 +219,81 @@ fn notify_fee(notif: NativeNotif, fees: &[FeeEstimate]) {
     notify(notif, data);
 }
 
+fn notify_sync_progress(notif: NativeNotif, downloaded: usize, total: usize) {
+    let data =
+        json!({"sync_progress":{"downloaded":downloaded,"total":total},"event":"sync_progress"});
+    notify(notif, data);
+}
+
+fn notify_db_repaired(notif: NativeNotif, repair: &Repair) {
+    let data = json!({"db_repaired":repair,"event":"db_repaired"});
+    notify(notif, data);
+}
+
+/// open the wallet db at `path`. A corrupted db is repaired instead of failing the login, the
+/// caller is told with a "db_repaired" event whether a rescan is needed and whether settings,
+/// memos and labels have been lost
+fn open_store(
+    path: &Path,
+    xpub: ExtendedPubKey,
+    master_blinding: Option<MasterBlindingKey>,
+    id: NetworkId,
+    notif: NativeNotif,
+) -> Result<StoreMeta, Error> {
+    match StoreMeta::new(path, xpub, master_blinding.clone(), id) {
+        Err(Error::DbCorrupted(reason)) => {
+            warn!("wallet db is corrupted: {}, repairing", reason);
+            let repair = StoreMeta::repair(&FileBackend::new(path)?, &xpub)?;
+            notify_db_repaired(notif, &repair);
+            StoreMeta::new(path, xpub, master_blinding, id)
+        }
+        result => result,
+    }
+}
+
+/// name of the directory of the wallet db. It depends on the xpub and on the whole network
+/// configuration unless `Network::db_from_xpub` is set, in which case only the network id is used
+pub fn wallet_db_name(xpub: &ExtendedPubKey, network: &Network) -> String {
+    let wallet_desc = if network.db_from_xpub.unwrap_or(false) {
+        format!("{}{:?}", xpub, network.id())
+    } else {
+        format!("{}{:?}", xpub, network)
+    };
+    hex::encode(sha256::Hash::hash(wallet_desc.as_bytes()))
+}
+
 fn determine_electrum_url(
     url: &Option<String>,
     tls: Option<bool>,
     validate_domain: Option<bool>,
+    cert_sha256: &Option<String>,
+    socks5: &Option<String>,
 ) -> Result<ElectrumUrl, Error> {
     let url = url.as_ref().ok_or_else(|| Error::Generic("network url is missing".into()))?;
     if url == "" {
         return Err(Error::Generic("network url is empty".into()));
     }
+    let proxy = socks5.as_deref().map(parse_socks5).transpose()?;
 
     if tls.unwrap_or(false) {
-        Ok(ElectrumUrl::Tls(url.into(), validate_domain.unwrap_or(false)))
+        let validation = match cert_sha256 {
+            Some(sha256) => TlsValidation::pinned(sha256)?,
+            None => TlsValidation::from_validate_domain(validate_domain),
+        };
+        Ok(ElectrumUrl::Tls(url.into(), validation, proxy))
     } else {
-        Ok(ElectrumUrl::Plaintext(url.into()))
+        Ok(ElectrumUrl::Plaintext(url.into(), proxy))
     }
 }
 
 pub fn determine_electrum_url_from_net(network: &Network) -> Result<ElectrumUrl, Error> {
-    determine_electrum_url(&network.electrum_url, network.tls, network.validate_domain)
+    determine_electrum_url(
+        &network.electrum_url,
+        network.tls,
+        network.validate_domain,
+        &network.electrum_cert_sha256,
+        &network.socks5,
+    )
 }
 
 impl ElectrumSession {
@@ -214,7 +344,8 @@ fn try_get_fee_estimates(client: &Client) -> Result<Vec<FeeEstimate>, Error> {
 fn make_txlist_item(tx: &TransactionMeta) -> TxListItem {
     let type_ = tx.type_.clone();
     let len = tx.hex.len() / 2;
-    let fee_rate = (tx.fee as f64 / len as f64) as u64;
+    let fee = tx.fee;
+    let fee_rate = fee.map(|fee| (fee as f64 / len as f64) as u64);
     let addressees = tx
         .create_transaction
         .as_ref()
@@ -228,7 +359,7 @@ fn make_txlist_item(tx: &TransactionMeta) -> TxListItem {
         block_height: tx.height.unwrap_or_default(),
         created_at: tx.created_at.clone(),
         type_,
-        memo: tx.create_transaction.as_ref().and_then(|c| c.memo.clone()).unwrap_or("".to_string()),
+        memo: tx.memo.clone().unwrap_or_default(),
         txhash: tx.txid.clone(),
         transaction_size: len,
         transaction: tx.hex.clone(), // FIXME
@@ -240,8 +371,9 @@ fn make_txlist_item(tx: &TransactionMeta) -> TxListItem {
         server_signed: false,    // TODO: TransactionMeta -> TxListItem server_signed
         user_signed: tx.user_signed,
         spv_verified: tx.spv_verified.to_string(),
+        spv_failure: tx.spv_verified.failure().map(|reason| reason.to_string()),
         instant: false,
-        fee: tx.fee,
+        fee,
         fee_rate,
         addressees,              // notice the extra "e" -- its intentional
         inputs: vec![],          // tx.input.iter().map(format_gdk_input).collect(),
@@ -338,28 +470,14 @@ impl Session<Error> for ElectrumSession {
             ExtendedPrivKey::new_master(bitcoin::network::constants::Network::Testnet, &seed)?;
 
         // BIP44: m / purpose' / coin_type' / account' / change / address_index
-        // coin_type = 0 bitcoin, 1 testnet, 1776 liquid bitcoin as defined in https://github.com/satoshilabs/slips/blob/master/slip-0044.md
-        // slip44 suggest 1 for every testnet, so we are using it also for regtest
-        let coin_type: u32 = match self.network.id() {
-            NetworkId::Bitcoin(bitcoin_network) => match bitcoin_network {
-                bitcoin::Network::Bitcoin => 0,
-                bitcoin::Network::Testnet => 1,
-                bitcoin::Network::Regtest => 1,
-            },
-            NetworkId::Elements(elements_network) => match elements_network {
-                ElementsNetwork::Liquid => 1776,
-                ElementsNetwork::ElementsRegtest => 1,
-            },
-        };
         // since we use P2WPKH-nested-in-P2SH it is 49 https://github.com/bitcoin/bips/blob/master/bip-0049.mediawiki
-        let path_string = format!("m/49'/{}'/0'", coin_type);
-        info!("Using derivation path {}/0|1/*", path_string);
-        let path = DerivationPath::from_str(&path_string)?;
+        let path = account_path(self.network.id(), ScriptType::P2shP2wpkh, 0)?;
+        info!("Using derivation path {}/0|1/*", path);
+        let master_fingerprint = xprv.fingerprint(&secp);
         let xprv = xprv.derive_priv(&secp, &path)?;
         let xpub = ExtendedPubKey::from_private(&secp, &xprv);
 
-        let wallet_desc = format!("{}{:?}", xpub, self.network);
-        let wallet_id = hex::encode(sha256::Hash::hash(wallet_desc.as_bytes()));
+        let wallet_id = wallet_db_name(&xpub, &self.network);
         let sync_interval = self.network.sync_interval.unwrap_or(7);
 
         let master_blinding = if self.network.liquid {
@@ -376,11 +494,12 @@ impl Session<Error> for ElectrumSession {
         info!("Store root path: {:?}", path);
         let store = match self.get_wallet() {
             Ok(wallet) => wallet.store.clone(),
-            Err(_) => Arc::new(RwLock::new(StoreMeta::new(
+            Err(_) => Arc::new(RwLock::new(open_store(
                 &path,
                 xpub,
                 master_blinding.clone(),
                 self.network.id(),
+                self.notify.clone(),
             )?)),
         };
 
@@ -440,9 +559,9 @@ impl Session<Error> for ElectrumSession {
         if self.network.spv_enabled.unwrap_or(false) {
             let checker = match self.network.id() {
                 NetworkId::Bitcoin(network) => {
-                    let mut path: PathBuf = self.data_root.as_str().into();
-                    path.push(format!("headers_chain_{}", network));
-                    ChainOrVerifier::Chain(HeadersChain::new(path, network)?)
+                    let path: PathBuf = self.data_root.as_str().into();
+                    let interval = self.network.headers_checkpoint_interval;
+                    ChainOrVerifier::Chain(HeadersChain::open(path, network, interval)?)
                 }
                 NetworkId::Elements(network) => {
                     let verifier = Verifier::new(network);
@@ -456,6 +575,7 @@ impl Session<Error> for ElectrumSession {
             };
 
             let headers_url = self.url.clone();
+            let headers_network = self.network.clone();
             let (close_headers, r) = channel();
             self.closer.senders.push(close_headers);
             let mut chunk_size = DIFFCHANGE_INTERVAL as usize;
@@ -468,7 +588,7 @@ impl Session<Error> for ElectrumSession {
                         break;
                     }
 
-                    if let Ok(client) = headers_url.build_client() {
+                    if let Ok(client) = RetryClient::connect(&headers_url, &headers_network) {
                         loop {
                             if r.try_recv().is_ok() {
                                 info!("closing headers thread");
@@ -482,9 +602,13 @@ impl Session<Error> for ElectrumSession {
                                         info!("headers found: {}", headers_found);
                                     }
                                 }
-                                Err(Error::InvalidHeaders) => {
-                                    // this should handle reorgs and also broke IO writes update
-                                    if headers.remove(144).is_err() {
+                                Err(Error::StaleFork) => {
+                                    info!("ignoring server headers fork without more work");
+                                    break;
+                                }
+                                Err(Error::InvalidHeaders) | Err(Error::ReorgTooDeep) => {
+                                    // this should handle deep reorgs and also broke IO writes update
+                                    if headers.remove(MAX_REORG_DEPTH).is_err() {
                                         break;
                                     }
                                 }
@@ -524,6 +648,8 @@ impl Session<Error> for ElectrumSession {
             store: store.clone(),
             master_blinding: master_blinding.clone(),
             network: self.network.clone(),
+            stats: RwLock::new(SyncStats::default()),
+            notify: self.notify.clone(),
         };
 
         let tipper = Tipper {
@@ -538,6 +664,7 @@ impl Session<Error> for ElectrumSession {
                 self.network.clone(),
                 xprv,
                 xpub,
+                master_fingerprint,
                 master_blinding,
             )?;
 
@@ -581,10 +708,14 @@ impl Session<Error> for ElectrumSession {
         let syncer_url = self.url.clone();
         let syncer_handle = thread::spawn(move || {
             info!("starting syncer thread");
+            // the first sync after login is full, to drop txs replaced or reorged out while
+            // offline, the following ones are incremental
+            let mut force_full = true;
             loop {
-                match syncer_url.build_client() {
-                    Ok(client) => match syncer.sync(&client) {
+                match RetryClient::connect(&syncer_url, &syncer.network) {
+                    Ok(client) => match syncer.sync_any(&client, force_full) {
                         Ok(new_txs) => {
+                            force_full = false;
                             if new_txs {
                                 info!("there are new transactions");
                                 let mockup_json = json!({"event":"transaction","transaction":{"subaccounts":[0]}});
@@ -620,7 +751,11 @@ impl Session<Error> for ElectrumSession {
     fn get_receive_address(&self, addr_details: &Value) -> Result<AddressPointer, Error> {
         debug!("get_receive_address {:?}", addr_details);
         let w = self.get_wallet()?;
-        let a = w.get_address()?;
+        let script_type = match addr_details.get("address_type").and_then(|t| t.as_str()) {
+            Some(script_type) => Some(ScriptType::from_str(script_type)?),
+            None => None,
+        };
+        let a = w.get_address(script_type)?;
         debug!("get_address {:?}", a);
         Ok(a)
     }
@@ -670,7 +805,13 @@ impl Session<Error> for ElectrumSession {
     }
 
     fn get_transactions(&self, opt: &GetTransactionsOpt) -> Result<TxsResult, Error> {
-        let txs = self.get_wallet()?.list_tx(opt)?.iter().map(make_txlist_item).collect();
+        let wallet = self.get_wallet()?;
+        let txs = if opt.repair_fees.unwrap_or(false) {
+            wallet.list_tx_repair(opt, &self.url.build_client()?)?
+        } else {
+            wallet.list_tx(opt)?
+        };
+        let txs = txs.iter().map(make_txlist_item).collect();
 
         Ok(TxsResult(txs))
     }
@@ -689,10 +830,15 @@ impl Session<Error> for ElectrumSession {
             return Err(Error::Generic("Only memo_type GA_MEMO_USER(0) is supported".into()));
         }
         let txid = Txid::from_hex(txid)?;
-        if memo.len() > 1024 {
-            return Err(Error::Generic("Too long memo (max 1024)".into()));
+        self.get_wallet()?.set_tx_memo(&txid, memo)
+    }
+
+    fn set_output_label(&self, txid: &str, vout: u32, label: &str) -> Result<(), Error> {
+        let txid = Txid::from_hex(txid)?;
+        if label.len() > 1024 {
+            return Err(Error::Generic("Too long label (max 1024)".into()));
         }
-        self.get_wallet()?.store.write()?.insert_memo(txid, memo)?;
+        self.get_wallet()?.store.write()?.insert_output_label(txid, vout, label)?;
 
         Ok(())
     }
@@ -723,6 +869,9 @@ impl Session<Error> for ElectrumSession {
         let transaction = BETransaction::from_hex(&tx_hex, self.network.id())?;
 
         info!("broadcast_transaction {:#?}", transaction.txid());
+        if let Ok(wallet) = self.get_wallet() {
+            wallet.check_not_archived()?;
+        }
         let client = self.url.build_client()?;
         let hex = hex::decode(tx_hex)?;
         let txid = client.transaction_broadcast_raw(&hex)?;
@@ -747,7 +896,7 @@ impl Session<Error> for ElectrumSession {
     }
 
     fn get_mnemonic(&self) -> Result<&Mnemonic, Error> {
-        self.get_wallet().map(|wallet| wallet.get_mnemonic())
+        self.get_wallet()?.get_mnemonic()
     }
 
     fn get_settings(&self) -> Result<Settings, Error> {
@@ -811,29 +960,43 @@ impl Tipper {
         let height = header.height as u32;
         let tip_height = self.store.read()?.cache.tip.0;
         if height != tip_height {
-            let hash = BEBlockHeader::deserialize(&header.header, self.network.id())?.block_hash();
-            info!("saving in store new tip {:?}", (height, hash));
-            self.store.write()?.cache.tip = (height, hash);
+            store_tip(&mut self.store.write()?, height, &header.header, self.network.id())?;
         }
         Ok(height)
     }
 }
 
+/// save in the store the tip at `height` with the given raw `header`, returns true if it changed.
+/// The tip is kept in the cache, it's persisted at the next flush
+pub fn store_tip(
+    store: &mut StoreMeta,
+    height: u32,
+    header: &[u8],
+    id: NetworkId,
+) -> Result<bool, Error> {
+    let hash = BEBlockHeader::deserialize(header, id)?.block_hash();
+    if store.cache.tip == (height, hash) {
+        return Ok(false);
+    }
+    info!("saving in store new tip {:?}", (height, hash));
+    store.cache.tip = (height, hash);
+    Ok(true)
+}
+
 impl Headers {
-    pub fn ask(&mut self, chunk_size: usize, client: &Client) -> Result<usize, Error> {
+    pub fn ask(&mut self, chunk_size: usize, client: &RetryClient) -> Result<usize, Error> {
         if let ChainOrVerifier::Chain(chain) = &mut self.checker {
             info!("asking headers, current height:{} chunk_size:{} ", chain.height(), chunk_size);
-            let headers = client.block_headers(chain.height() as usize + 1, chunk_size)?.headers;
-            let len = headers.len();
-            chain.push(headers)?;
-            Ok(len)
+            chain.pull(chunk_size, |start, count| {
+                client.call("headers", |c| Ok(c.block_headers(start as usize, count)?.headers))
+            })
         } else {
             // Liquid doesn't need to download the header's chain
             Ok(0)
         }
     }
 
-    pub fn get_proofs(&mut self, client: &Client) -> Result<usize, Error> {
+    pub fn get_proofs(&mut self, client: &RetryClient) -> Result<usize, Error> {
         let store_read = self.store.read()?;
         let needs_proof: Vec<(Txid, u32)> = self
             .store
@@ -850,27 +1013,39 @@ impl Headers {
 
         let mut txs_verified = HashMap::new();
         for (txid, height) in needs_proof {
-            let proof = client.transaction_get_merkle(&txid, height as usize)?;
-            let verified = match &self.checker {
-                ChainOrVerifier::Chain(chain) => {
-                    chain.verify_tx_proof(&txid, height, proof).is_ok()
+            let proof = client.call("merkle proof", |c| get_merkle(c, &txid, height))?;
+            let verified = match (proof, &self.checker) {
+                (Err(reason), _) => Err(reason),
+                (Ok(proof), ChainOrVerifier::Chain(chain)) => {
+                    // a pruned chain needs the headers from the tx one to the next stored
+                    let missing = chain.missing_headers(height);
+                    let headers = if missing.start == missing.end {
+                        vec![]
+                    } else {
+                        let (start, count) = (missing.start as usize, missing.len());
+                        client.call("headers", |c| Ok(c.block_headers(start, count)?))?.headers
+                    };
+                    chain.verify_tx_proof_with_headers(&txid, height, proof, &headers)
                 }
-                ChainOrVerifier::Verifier(verifier) => {
+                (Ok(proof), ChainOrVerifier::Verifier(verifier)) => {
                     if let Some(BEBlockHeader::Elements(header)) =
                         self.store.read()?.cache.headers.get(&height)
                     {
-                        verifier.verify_tx_proof(&txid, proof, &header).is_ok()
+                        verifier.verify_tx_proof(&txid, proof, &header)
                     } else {
-                        false
+                        Err(SPVFailure::HeaderNotAvailable)
                     }
                 }
             };
-            if verified {
-                info!("proof for {} verified!", txid);
-                txs_verified.insert(txid, SPVVerifyResult::Verified);
-            } else {
-                warn!("proof for {} not verified!", txid);
-                txs_verified.insert(txid, SPVVerifyResult::NotVerified);
+            match verified {
+                Ok(()) => {
+                    info!("proof for {} verified!", txid);
+                    txs_verified.insert(txid, SPVVerifyResult::Verified);
+                }
+                Err(reason) => {
+                    warn!("proof for {} not verified: {}", txid, reason);
+                    txs_verified.insert(txid, SPVVerifyResult::NotVerified(reason));
+                }
             }
         }
         let proofs_done = txs_verified.len();
@@ -893,75 +1068,135 @@ struct DownloadTxResult {
 }
 
 impl Syncer {
-    pub fn sync(&self, client: &Client) -> Result<bool, Error> {
-        info!("start sync");
+    /// history of `scripts`, retrying on network errors as configured in the network. The
+    /// scripts are identified by their scripthashes cached in the store
+    fn script_histories<'s, I>(
+        &self,
+        client: &RetryClient,
+        scripts: I,
+    ) -> Result<Vec<Vec<GetHistoryRes>>, Error>
+    where
+        I: IntoIterator<Item = &'s Script>,
+    {
+        let mut batch = Batch::default();
+        let store_read = self.store.read()?;
+        for script in scripts {
+            let script_hash = hex::encode(store_read.script_hash(script)?);
+            batch.raw("blockchain.scripthash.get_history".into(), vec![Param::String(script_hash)]);
+        }
+        drop(store_read);
+        let values = client.call("script history", |c| Ok(c.batch_call(&batch)?))?;
+        values.into_iter().map(|value| Ok(serde_json::from_value(value)?)).collect()
+    }
+
+    /// sync the wallet with the electrum server, returns true if the store changed.
+    /// When `cancel` is set the sync stops between batches with `Error::Cancelled`, downloaded
+    /// scripts and transactions are kept in the store so the next sync doesn't fetch them again.
+    ///
+    /// Unless `force_full` is set, each chain is scanned from the batch of the last used index
+    /// found by the previous sync, and of the scripts before it only the ones returned by
+    /// `StoreMeta::live_scripts` are queried. The known history of the txs touching only the
+    /// other scripts is kept, see `StoreMeta::skipped_history`, the one of every other tx is
+    /// replaced by the server one, so replaced, evicted or reorged out txs are dropped
+    pub fn sync(
+        &self,
+        client: &RetryClient,
+        cancel: Option<&AtomicBool>,
+        force_full: bool,
+    ) -> Result<bool, Error> {
+        info!("start sync force_full:{}", force_full);
         let start = Instant::now();
+        *self.stats.write()? = SyncStats::default();
+        let tip = client.call("tip", |c| Ok(c.block_headers_subscribe_raw()?))?;
 
         let mut history_txs_id = HashSet::new();
         let mut heights_set = HashSet::new();
-        let mut txid_height = HashMap::new();
         let mut scripts = HashMap::new();
 
-        let mut last_used = Indexes::default();
+        let (mut last_used, mut txid_height, live_scripts) = if force_full {
+            (Indexes::default(), HashMap::new(), HashMap::new())
+        } else {
+            let store_read = self.store.read()?;
+            let last_used = store_read.cache.last_used.clone();
+            let below = Indexes {
+                external: first_batch(&last_used, 0) * BATCH_SIZE,
+                internal: first_batch(&last_used, 1) * BATCH_SIZE,
+            };
+            let live_scripts = store_read.live_scripts(&below)?;
+            let skipped_history = store_read.skipped_history(&below, &live_scripts);
+            (last_used, skipped_history, live_scripts)
+        };
+        let first = last_used.clone();
+        let gap_limit = self.gap_limit();
         let mut wallet_chains = vec![0, 1];
         wallet_chains.shuffle(&mut thread_rng());
         for i in wallet_chains {
-            let mut batch_count = 0;
+            let mut batch_count = first_batch(&first, i);
+            let mut unused = 0;
             loop {
+                if is_cancelled(cancel) {
+                    return self.cancel_sync(scripts, DownloadTxResult::default());
+                }
                 let batch = self.store.read()?.get_script_batch(i, batch_count)?;
                 let result: Vec<Vec<GetHistoryRes>> =
-                    client.batch_script_get_history(batch.value.iter().map(|e| &e.0))?;
+                    self.script_histories(client, batch.value.iter().map(|e| &e.0))?;
+                self.stats.write()?.add_history(&result);
+                self.store_huge_histories(&result, &batch.value, cancel, client)?;
+
                 if !batch.cached {
                     scripts.extend(batch.value);
                 }
-                let max = result
-                    .iter()
-                    .enumerate()
-                    .filter(|(_, v)| !v.is_empty())
-                    .map(|(i, _)| i as u32)
-                    .max();
-                if let Some(max) = max {
-                    if i == 0 {
-                        last_used.external = max + batch_count * BATCH_SIZE;
-                    } else {
-                        last_used.internal = max + batch_count * BATCH_SIZE;
-                    }
-                };
+                update_last_used(&mut last_used, i, batch_count, &result);
+                unused = count_unused(unused, &result);
 
                 let flattened: Vec<GetHistoryRes> = result.into_iter().flatten().collect();
                 trace!("{}/batch({}) {:?}", i, batch_count, flattened.len());
 
-                if flattened.is_empty() {
-                    break;
-                }
-
-                for el in flattened {
-                    // el.height = -1 means unconfirmed with unconfirmed parents
-                    // el.height =  0 means unconfirmed with confirmed parents
-                    // but we threat those tx the same
-                    let height = el.height.max(0);
-                    heights_set.insert(height as u32);
-                    if height == 0 {
-                        txid_height.insert(el.tx_hash, None);
-                    } else {
-                        txid_height.insert(el.tx_hash, Some(height as u32));
-                    }
+                add_history(flattened, &mut history_txs_id, &mut heights_set, &mut txid_height);
 
-                    history_txs_id.insert(el.tx_hash);
+                if unused >= gap_limit {
+                    break;
                 }
-
                 batch_count += 1;
             }
         }
 
-        let new_txs = self.download_txs(&history_txs_id, &scripts, &client)?;
-        let headers = self.download_headers(&heights_set, &client)?;
+        if !live_scripts.is_empty() {
+            trace!("querying {} live scripts before the scanned batches", live_scripts.len());
+            let result: Vec<Vec<GetHistoryRes>> =
+                self.script_histories(client, live_scripts.keys())?;
+            self.stats.write()?.add_history(&result);
+            let flattened = result.into_iter().flatten().collect();
+            add_history(flattened, &mut history_txs_id, &mut heights_set, &mut txid_height);
+        }
 
-        let store_indexes = self.store.read()?.cache.indexes.clone();
+        let extra_scripts = self.store.read()?.extra_scripts().clone();
+        if !extra_scripts.is_empty() {
+            let result: Vec<Vec<GetHistoryRes>> =
+                self.script_histories(client, extra_scripts.keys())?;
+            self.stats.write()?.add_history(&result);
+            let flattened = result.into_iter().flatten().collect();
+            add_history(flattened, &mut history_txs_id, &mut heights_set, &mut txid_height);
+        }
+
+        if is_cancelled(cancel) {
+            return self.cancel_sync(scripts, DownloadTxResult::default());
+        }
+        let (new_txs, headers) =
+            self.download_txs_and_headers(&history_txs_id, &heights_set, &scripts, client)?;
+        if is_cancelled(cancel) {
+            return self.cancel_sync(scripts, new_txs);
+        }
+
+        let (store_indexes, store_heights_changed) = {
+            let store_read = self.store.read()?;
+            (store_read.cache.indexes.clone(), store_read.cache.heights != txid_height)
+        };
 
         let changed = if !new_txs.txs.is_empty()
             || !headers.is_empty()
             || store_indexes != last_used
+            || store_heights_changed
             || !scripts.is_empty()
         {
             info!(
@@ -971,88 +1206,276 @@ impl Syncer {
                 txid_height
             );
             let mut store_write = self.store.write()?;
-            store_write.cache.indexes = last_used;
-            store_write.cache.all_txs.extend(new_txs.txs.into_iter());
+            let mut skipped = store_write.insert_txs(new_txs.txs);
             store_write.cache.unblinded.extend(new_txs.unblinds);
-            store_write.cache.headers.extend(headers);
-
-            // height map is used for the live list of transactions, since due to reorg or rbf tx
-            // could disappear from the list, we clear the list and keep only the last values returned by the server
-            store_write.cache.heights.clear();
-            store_write.cache.heights.extend(txid_height.into_iter());
-
+            skipped += store_write.insert_headers(headers);
+            self.stats.write()?.add_skipped(skipped);
             store_write.cache.scripts.extend(scripts.clone().into_iter().map(|(a, b)| (b, a)));
             store_write.cache.paths.extend(scripts.into_iter());
+            store_write.cache.paths.extend(extra_scripts.into_iter());
+            replace_history(&mut store_write, last_used, txid_height);
             store_write.flush()?;
             true
         } else {
             false
         };
-        trace!("changes:{} elapsed {}", changed, start.elapsed().as_millis());
+        self.save_tip(tip.height as u32, &tip.header)?;
+        trace!(
+            "changes:{} elapsed {} {:?}",
+            changed,
+            start.elapsed().as_millis(),
+            self.stats.read()?
+        );
 
         Ok(changed)
     }
 
-    fn download_headers(
+    /// record the server tip seen by the last sync, flushing the store so it survives restarts
+    fn save_tip(&self, height: u32, header: &[u8]) -> Result<(), Error> {
+        let mut store_write = self.store.write()?;
+        if store_tip(&mut store_write, height, header, self.network.id())? {
+            store_write.flush()?;
+        }
+        Ok(())
+    }
+
+    /// sync with the strategy configured in the network, see `sync_streaming`, which always
+    /// scans the whole wallet
+    fn sync_any(&self, client: &RetryClient, force_full: bool) -> Result<bool, Error> {
+        if self.network.sync_streaming.unwrap_or(false) {
+            self.sync_streaming(client, None)
+        } else {
+            self.sync(client, None, force_full)
+        }
+    }
+
+    /// keep what has been downloaded by an interrupted sync, without touching the wallet history
+    /// and indexes which are updated only by a complete sync
+    fn cancel_sync(
         &self,
-        heights_set: &HashSet<u32>,
-        client: &Client,
-    ) -> Result<Vec<(u32, BEBlockHeader)>, Error> {
-        let mut result = vec![];
-        let mut heights_in_db: HashSet<u32> =
-            self.store.read()?.cache.heights.iter().filter_map(|(_, h)| *h).collect();
-        heights_in_db.insert(0);
-        let heights_to_download: Vec<u32> =
-            heights_set.difference(&heights_in_db).cloned().collect();
-        if !heights_to_download.is_empty() {
-            let headers_bytes_downloaded =
-                client.batch_block_header_raw(heights_to_download.clone())?;
-            let mut headers_downloaded: Vec<BEBlockHeader> = vec![];
-            for vec in headers_bytes_downloaded {
-                headers_downloaded.push(BEBlockHeader::deserialize(&vec, self.network.id())?);
+        scripts: HashMap<Script, DerivationPath>,
+        new_txs: DownloadTxResult,
+    ) -> Result<bool, Error> {
+        info!("sync cancelled, keeping {} scripts {} txs", scripts.len(), new_txs.txs.len());
+        if !scripts.is_empty() || !new_txs.txs.is_empty() {
+            let mut store_write = self.store.write()?;
+            let skipped = store_write.insert_txs(new_txs.txs);
+            self.stats.write()?.add_skipped(skipped);
+            store_write.cache.unblinded.extend(new_txs.unblinds);
+            store_write.cache.scripts.extend(scripts.clone().into_iter().map(|(a, b)| (b, a)));
+            store_write.cache.paths.extend(scripts.into_iter());
+            store_write.flush()?;
+        }
+        Err(Error::Cancelled)
+    }
+
+    /// like `sync` but the txs and headers found by every batch of scripts are downloaded and
+    /// stored before requesting the next batch, so that peak memory doesn't depend on the
+    /// wallet size: only the heights of the wallet txs are kept until the end, when the wallet
+    /// history is replaced. When cancelled what has been stored so far is kept
+    pub fn sync_streaming(
+        &self,
+        client: &RetryClient,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<bool, Error> {
+        info!("start streaming sync");
+        let start = Instant::now();
+        *self.stats.write()? = SyncStats::default();
+        let tip = client.call("tip", |c| Ok(c.block_headers_subscribe_raw()?))?;
+
+        let mut txid_height = HashMap::new();
+        let mut changed = false;
+
+        let mut last_used = Indexes::default();
+        let gap_limit = self.gap_limit();
+        let mut wallet_chains = vec![0, 1];
+        wallet_chains.shuffle(&mut thread_rng());
+        for i in wallet_chains {
+            let mut batch_count = 0;
+            let mut unused = 0;
+            loop {
+                if is_cancelled(cancel) {
+                    return Err(Error::Cancelled);
+                }
+                let batch = self.store.read()?.get_script_batch(i, batch_count)?;
+                let result: Vec<Vec<GetHistoryRes>> =
+                    self.script_histories(client, batch.value.iter().map(|e| &e.0))?;
+                self.stats.write()?.add_history(&result);
+                changed |= self.store_huge_histories(&result, &batch.value, cancel, client)?;
+                if is_cancelled(cancel) {
+                    return Err(Error::Cancelled);
+                }
+                update_last_used(&mut last_used, i, batch_count, &result);
+                unused = count_unused(unused, &result);
+
+                let flattened: Vec<GetHistoryRes> = result.into_iter().flatten().collect();
+                trace!("streaming {}/batch({}) {:?}", i, batch_count, flattened.len());
+                let last_batch = unused >= gap_limit;
+                let scripts = if batch.cached {
+                    HashMap::new()
+                } else {
+                    batch.value.into_iter().collect()
+                };
+                changed |=
+                    self.store_history_batch(flattened, scripts, &mut txid_height, client)?;
+
+                if last_batch {
+                    break;
+                }
+                batch_count += 1;
             }
-            info!("headers_downloaded {:?}", &headers_downloaded);
-            for (header, height) in
-                headers_downloaded.into_iter().zip(heights_to_download.into_iter())
-            {
-                result.push((height, header));
+        }
+
+        let extra_scripts = self.store.read()?.extra_scripts().clone();
+        if !extra_scripts.is_empty() {
+            let result: Vec<Vec<GetHistoryRes>> =
+                self.script_histories(client, extra_scripts.keys())?;
+            self.stats.write()?.add_history(&result);
+            let flattened = result.into_iter().flatten().collect();
+            // extra scripts are already in the store, see `StoreMeta::insert_extra_script`
+            changed |=
+                self.store_history_batch(flattened, HashMap::new(), &mut txid_height, client)?;
+        }
+
+        let mut store_write = self.store.write()?;
+        changed |=
+            store_write.cache.heights != txid_height || store_write.cache.indexes != last_used;
+        if changed {
+            replace_history(&mut store_write, last_used, txid_height);
+            store_write.flush()?;
+        }
+        drop(store_write);
+        self.save_tip(tip.height as u32, &tip.header)?;
+        trace!(
+            "streaming changes:{} elapsed {} {:?}",
+            changed,
+            start.elapsed().as_millis(),
+            self.stats.read()?
+        );
+
+        Ok(changed)
+    }
+
+    /// download and store the txs and headers of a batch of history, recording the tx heights
+    /// in `txid_height`. Returns true if the store changed
+    fn store_history_batch(
+        &self,
+        history: Vec<GetHistoryRes>,
+        scripts: HashMap<Script, DerivationPath>,
+        txid_height: &mut HashMap<Txid, Option<u32>>,
+        client: &RetryClient,
+    ) -> Result<bool, Error> {
+        let mut history_txs_id = HashSet::new();
+        let mut heights_set = HashSet::new();
+        add_history(history, &mut history_txs_id, &mut heights_set, txid_height);
+
+        let (new_txs, headers) =
+            self.download_txs_and_headers(&history_txs_id, &heights_set, &scripts, client)?;
+
+        // txs stored by a previous batch may pay scripts discovered only now
+        let mut late_unblinds = vec![];
+        if let NetworkId::Elements(_) = self.network.id() {
+            let mut to_unblind = vec![];
+            let store_read = self.store.read()?;
+            for txid in history_txs_id.iter() {
+                if let Some(BETransaction::Elements(tx)) = store_read.cache.all_txs.get(txid) {
+                    for (vout, output) in tx.output.iter().enumerate() {
+                        let outpoint = elements::OutPoint {
+                            txid: *txid,
+                            vout: vout as u32,
+                        };
+                        if scripts.contains_key(&output.script_pubkey)
+                            && !store_read.cache.unblinded.contains_key(&outpoint)
+                        {
+                            to_unblind.push((outpoint, output.clone()));
+                        }
+                    }
+                }
+            }
+            drop(store_read);
+            for (outpoint, output) in to_unblind {
+                if let Ok(unblinded) = self.try_unblind(outpoint, output) {
+                    late_unblinds.push((outpoint, unblinded));
+                }
             }
         }
 
-        Ok(result)
+        if new_txs.txs.is_empty()
+            && headers.is_empty()
+            && scripts.is_empty()
+            && late_unblinds.is_empty()
+        {
+            return Ok(false);
+        }
+
+        let mut store_write = self.store.write()?;
+        let written = new_txs.txs.len() + headers.len();
+        let skipped = store_write.insert_txs(new_txs.txs) + store_write.insert_headers(headers);
+        self.stats.write()?.add_skipped(skipped);
+        if skipped == written && scripts.is_empty() && late_unblinds.is_empty() {
+            // everything was already stored, nothing to flush
+            return Ok(false);
+        }
+        store_write.cache.unblinded.extend(new_txs.unblinds);
+        store_write.cache.unblinded.extend(late_unblinds);
+        store_write.cache.scripts.extend(scripts.clone().into_iter().map(|(a, b)| (b, a)));
+        store_write.cache.paths.extend(scripts.into_iter());
+        store_write.flush()?;
+        Ok(true)
     }
 
-    fn download_txs(
+    /// download the wallet txs not in the db, then the txs of their previous outputs together
+    /// with the headers at `heights_set` not in the db, in pages of `history_page_size` items.
+    /// Previous txs are returned with the wallet ones, so that they are stored before the fees
+    /// of the wallet txs are computed
+    fn download_txs_and_headers(
         &self,
         history_txs_id: &HashSet<Txid>,
+        heights_set: &HashSet<u32>,
         scripts: &HashMap<Script, DerivationPath>,
-        client: &Client,
-    ) -> Result<DownloadTxResult, Error> {
+        client: &RetryClient,
+    ) -> Result<(DownloadTxResult, Vec<(u32, BEBlockHeader)>), Error> {
         let mut txs = vec![];
         let mut unblinds = vec![];
+        let page_size = self.history_page_size();
 
         let mut txs_in_db = self.store.read()?.cache.all_txs.keys().cloned().collect();
         let txs_to_download: Vec<&Txid> = history_txs_id.difference(&txs_in_db).collect();
-        if !txs_to_download.is_empty() {
-            let txs_bytes_downloaded = client.batch_transaction_get_raw(txs_to_download)?;
-            let mut txs_downloaded: Vec<BETransaction> = vec![];
-            for vec in txs_bytes_downloaded {
-                let tx = BETransaction::deserialize(&vec, self.network.id())?;
-                txs_downloaded.push(tx);
+        let mut fetch_txs = |page: &[&Txid]| -> Result<Vec<Vec<u8>>, Error> {
+            let txs = client
+                .call("transactions", |c| Ok(c.batch_transaction_get_raw(page.iter().cloned())?))?;
+            self.stats.write()?.add_txs(&txs);
+            Ok(txs)
+        };
+
+        // wallet txs stored by previous syncs whose previous txs are missing, e.g. because
+        // the sync storing them was interrupted
+        let mut previous_txs_to_download = HashSet::new();
+        {
+            let store_read = self.store.read()?;
+            let all_txs = &store_read.cache.all_txs;
+            for tx in history_txs_id.iter().filter_map(|txid| all_txs.get(txid)) {
+                if let BETransaction::Bitcoin(_) = tx {
+                    previous_txs_to_download.extend(tx.missing_prevouts(all_txs));
+                }
             }
+        }
+        if !txs_to_download.is_empty() {
+            let txs_downloaded =
+                download_txs_by_id(&txs_to_download, page_size, self.network.id(), &mut fetch_txs)?;
             info!("txs_downloaded {:?}", txs_downloaded.len());
-            let mut previous_txs_to_download = HashSet::new();
-            for mut tx in txs_downloaded.into_iter() {
-                let txid = tx.txid();
+            for (txid, mut tx) in txs_downloaded.into_iter() {
                 txs_in_db.insert(txid);
 
                 if let BETransaction::Elements(tx) = &tx {
                     info!("compute OutPoint Unblinded");
+                    let mine: Vec<bool> = {
+                        let store_read = self.store.read()?;
+                        tx.output.iter().map(|o| store_read.is_mine(&o.script_pubkey)).collect()
+                    };
                     for (i, output) in tx.output.iter().enumerate() {
                         // could be the searched script it's not yet in the store, because created in the current run, thus it's searched also in the `scripts`
-                        if self.store.read()?.cache.paths.contains_key(&output.script_pubkey)
-                            || scripts.contains_key(&output.script_pubkey)
-                        {
+                        if mine[i] || scripts.contains_key(&output.script_pubkey) {
                             let vout = i as u32;
                             let outpoint = elements::OutPoint {
                                 txid: tx.txid(),
@@ -1074,24 +1497,140 @@ impl Syncer {
                 tx.strip_witness();
                 txs.push((txid, tx));
             }
+        }
+
+        // previous txs and headers don't depend on each other, they are requested in the same
+        // batches so that their downloads overlap
+        let mut heights_in_db: HashSet<u32> =
+            self.store.read()?.cache.headers.keys().cloned().collect();
+        heights_in_db.insert(0);
+        let mut items: Vec<Fetch> =
+            previous_txs_to_download.difference(&txs_in_db).map(Fetch::Tx).collect();
+        items.extend(heights_set.difference(&heights_in_db).map(|h| Fetch::Header(*h)));
+        let (previous_txs, headers) =
+            download_fetches(&items, page_size, self.network.id(), |page| {
+                self.fetch_page(page, client)
+            })?;
+        info!("previous txs downloaded {} headers {}", previous_txs.len(), headers.len());
+        let is_mine = |script: &Script| {
+            scripts.contains_key(script)
+                || self.store.read().map(|s| s.is_mine(script)).unwrap_or(false)
+        };
+        let previous_txs = download_previous_txs(
+            previous_txs,
+            &mut txs_in_db,
+            MAX_PREVIOUS_DEPTH,
+            is_mine,
+            |txids| download_txs_by_id(txids, page_size, self.network.id(), &mut fetch_txs),
+        )?;
+        for (txid, mut tx) in previous_txs {
+            tx.strip_witness();
+            txs.push((txid, tx));
+        }
 
-            let txs_to_download: Vec<&Txid> =
-                previous_txs_to_download.difference(&txs_in_db).collect();
-            if !txs_to_download.is_empty() {
-                let txs_bytes_downloaded = client.batch_transaction_get_raw(txs_to_download)?;
-                for vec in txs_bytes_downloaded {
-                    let mut tx = BETransaction::deserialize(&vec, self.network.id())?;
-                    tx.strip_witness();
-                    txs.push((tx.txid(), tx));
+        let result = DownloadTxResult {
+            txs,
+            unblinds,
+        };
+        Ok((result, headers))
+    }
+
+    /// a script with a history longer than `huge_history_threshold`, e.g. a reused donation
+    /// address, would turn the download of its batch into a long sequence of requests without
+    /// anything stored until the end: the missing txs of such scripts are downloaded and stored
+    /// one page at a time, notifying the progress after each page, so that the following
+    /// download of the batch finds them in the db and an interrupted sync resumes from the last
+    /// stored page. Returns true if the store changed
+    fn store_huge_histories(
+        &self,
+        result: &[Vec<GetHistoryRes>],
+        scripts: &[(Script, DerivationPath)],
+        cancel: Option<&AtomicBool>,
+        client: &RetryClient,
+    ) -> Result<bool, Error> {
+        let threshold = self.huge_history_threshold();
+        let page_size = self.history_page_size();
+        let mut changed = false;
+        for (history, (script, path)) in result.iter().zip(scripts.iter()) {
+            if history.len() <= threshold {
+                continue;
+            }
+            let missing: Vec<&GetHistoryRes> = {
+                let store_read = self.store.read()?;
+                let all_txs = &store_read.cache.all_txs;
+                history.iter().filter(|h| !all_txs.contains_key(&h.tx_hash)).collect()
+            };
+            info!(
+                "script {} has a huge history ({} txs, {} missing), downloading in pages of {}",
+                script,
+                history.len(),
+                missing.len(),
+                page_size
+            );
+            let mut script_path = HashMap::new();
+            script_path.insert(script.clone(), path.clone());
+            let mut txid_height = HashMap::new();
+            let mut downloaded = history.len() - missing.len();
+            for page in missing.chunks(page_size) {
+                if is_cancelled(cancel) {
+                    return Ok(changed);
                 }
+                let page_history = page
+                    .iter()
+                    .map(|h| GetHistoryRes {
+                        height: h.height,
+                        tx_hash: h.tx_hash,
+                        fee: h.fee,
+                    })
+                    .collect();
+                changed |= self.store_history_batch(
+                    page_history,
+                    script_path.clone(),
+                    &mut txid_height,
+                    client,
+                )?;
+                self.stats.write()?.huge_history_pages += 1;
+                downloaded += page.len();
+                notify_sync_progress(self.notify.clone(), downloaded, history.len());
             }
-            Ok(DownloadTxResult {
-                txs,
-                unblinds,
-            })
-        } else {
-            Ok(DownloadTxResult::default())
         }
+        Ok(changed)
+    }
+
+    /// request the txs and the headers of `page` in a single batch
+    fn fetch_page(&self, page: &[Fetch], client: &RetryClient) -> Result<Vec<Vec<u8>>, Error> {
+        let mut batch = Batch::default();
+        for item in page {
+            match item {
+                Fetch::Tx(txid) => batch
+                    .raw("blockchain.transaction.get".into(), vec![Param::String(txid.to_hex())]),
+                Fetch::Header(height) => batch
+                    .raw("blockchain.block.header".into(), vec![Param::Usize(*height as usize)]),
+            }
+        }
+        let mut result = vec![];
+        for value in client.call("transactions and headers", |c| Ok(c.batch_call(&batch)?))? {
+            let hex = value
+                .as_str()
+                .ok_or_else(|| Error::Generic(format!("unexpected server response {}", value)))?;
+            result.push(hex::decode(hex)?);
+        }
+        self.stats.write()?.add_page(page, &result);
+        Ok(result)
+    }
+
+    fn history_page_size(&self) -> usize {
+        self.network.history_page_size.unwrap_or(HISTORY_PAGE_SIZE).max(1)
+    }
+
+    fn huge_history_threshold(&self) -> usize {
+        self.network.huge_history_threshold.unwrap_or(HUGE_HISTORY_THRESHOLD)
+    }
+
+    /// wallets restored from other clients may have gaps of unused addresses wider than the
+    /// default one, a greater gap limit finds their funds at the cost of a longer sync
+    fn gap_limit(&self) -> u32 {
+        self.network.gap_limit.unwrap_or(GAP_LIMIT).max(1)
     }
 
     pub fn try_unblind(
@@ -1099,55 +1638,317 @@ impl Syncer {
         outpoint: elements::OutPoint,
         output: elements::TxOut,
     ) -> Result<Unblinded, Error> {
-        match (output.asset, output.value, output.nonce) {
-            (
-                Asset::Confidential(_, _),
-                confidential::Value::Confidential(_, _),
-                Nonce::Confidential(_, _),
-            ) => {
-                let master_blinding = self.master_blinding.as_ref().unwrap();
-
-                let script = output.script_pubkey.clone();
-                let blinding_key = asset_blinding_key_to_ec_private_key(master_blinding, &script);
-                let rangeproof = output.witness.rangeproof.clone();
-                let value_commitment = elements::encode::serialize(&output.value);
-                let asset_commitment = elements::encode::serialize(&output.asset);
-                let nonce_commitment = elements::encode::serialize(&output.nonce);
-                info!(
-                    "commitments len {} {} {}",
-                    value_commitment.len(),
-                    asset_commitment.len(),
-                    nonce_commitment.len()
-                );
-                let sender_pk = secp256k1::PublicKey::from_slice(&nonce_commitment).unwrap();
-
-                let (asset, abf, vbf, value) = asset_unblind(
+        let master_blinding = self.master_blinding.as_ref().ok_or(Error::MissingBlindingKey)?;
+        let nonces = self.store.read()?.invoice_nonces(&output.script_pubkey);
+        let unblinded = unblind_output(master_blinding, &nonces, output)?;
+        info!("Unblinded outpoint:{} {:?}", outpoint, unblinded);
+        Ok(unblinded)
+    }
+}
+
+/// unblind `output` with the blinding key of its script, derived from `master_blinding`, or with
+/// the keys of the invoices `nonces` using the same script
+pub fn unblind_output(
+    master_blinding: &MasterBlindingKey,
+    nonces: &[u32],
+    output: elements::TxOut,
+) -> Result<Unblinded, Error> {
+    match (output.asset, output.value, output.nonce) {
+        (
+            Asset::Confidential(_, _),
+            confidential::Value::Confidential(_, _),
+            Nonce::Confidential(_, _),
+        ) => {
+            let script = output.script_pubkey.clone();
+            // the address key first, then the ones of the invoices using this script
+            let mut blinding_keys =
+                vec![asset_blinding_key_to_ec_private_key(master_blinding, &script)];
+            for nonce in nonces {
+                blinding_keys.push(invoice_blinding_key_to_ec_private_key(
+                    master_blinding,
+                    &script,
+                    *nonce,
+                ));
+            }
+            let rangeproof = output.witness.rangeproof.clone();
+            let value_commitment = elements::encode::serialize(&output.value);
+            let asset_commitment = elements::encode::serialize(&output.asset);
+            let nonce_commitment = elements::encode::serialize(&output.nonce);
+            info!(
+                "commitments len {} {} {}",
+                value_commitment.len(),
+                asset_commitment.len(),
+                nonce_commitment.len()
+            );
+            let sender_pk = secp256k1::PublicKey::from_slice(&nonce_commitment).unwrap();
+
+            let mut result = Err(Error::Generic("no blinding key".into()));
+            for blinding_key in blinding_keys {
+                result = asset_unblind(
                     sender_pk,
                     blinding_key,
-                    rangeproof,
-                    value_commitment,
-                    script,
-                    asset_commitment,
-                )?;
+                    rangeproof.clone(),
+                    value_commitment.clone(),
+                    script.clone(),
+                    asset_commitment.clone(),
+                )
+                .map_err(Error::from);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            let (asset, abf, vbf, value) = result?;
 
-                info!(
-                    "Unblinded outpoint:{} asset:{} value:{}",
-                    outpoint,
-                    hex::encode(&asset),
-                    value
-                );
-
-                let unblinded = Unblinded {
-                    asset,
-                    value,
-                    abf,
-                    vbf,
-                };
-                Ok(unblinded)
+            Ok(Unblinded {
+                asset,
+                value,
+                abf,
+                vbf,
+            })
+        }
+        _ => Err(Error::Generic("received unconfidential or null asset/value/nonce".into())),
+    }
+}
+
+/// call `fetch` on consecutive pages of `items` of at most `page_size` elements,
+/// so that a huge history doesn't end up in a single enormous request
+fn download_paged<T, R, F>(items: &[T], page_size: usize, mut fetch: F) -> Result<Vec<R>, Error>
+where
+    F: FnMut(&[T]) -> Result<Vec<R>, Error>,
+{
+    let mut result = Vec::with_capacity(items.len());
+    let pages = (items.len() + page_size - 1) / page_size;
+    for (i, page) in items.chunks(page_size).enumerate() {
+        result.extend(fetch(page)?);
+        if pages > 1 {
+            info!("downloaded page {}/{} ({}/{} items)", i + 1, pages, result.len(), items.len());
+        }
+    }
+    Ok(result)
+}
+
+/// download the transactions `txids`, the txid of every returned transaction is computed and
+/// checked against the requested ones, so that the result doesn't depend on the order of the
+/// server response
+fn download_txs_by_id<F>(
+    txids: &[&Txid],
+    page_size: usize,
+    id: NetworkId,
+    fetch: F,
+) -> Result<Vec<(Txid, BETransaction)>, Error>
+where
+    F: FnMut(&[&Txid]) -> Result<Vec<Vec<u8>>, Error>,
+{
+    let mut missing: HashSet<Txid> = txids.iter().cloned().cloned().collect();
+    let mut result = Vec::with_capacity(txids.len());
+    for bytes in download_paged(txids, page_size, fetch)? {
+        let tx = BETransaction::deserialize(&bytes, id)?;
+        let txid = tx.txid();
+        if !missing.remove(&txid) {
+            return Err(Error::Generic(format!("server returned unrequested tx {}", txid)));
+        }
+        result.push((txid, tx));
+    }
+    if !missing.is_empty() {
+        return Err(Error::Generic(format!("server did not return {} txs", missing.len())));
+    }
+    Ok(result)
+}
+
+/// the previous txs of the wallet txs, `downloaded`, completed with the previous txs of the ones
+/// paying the wallet, which are wallet txs whose fee is computed too: they may not be in the
+/// history yet when their script is discovered by the same sync. Levels of previous txs are
+/// fetched until none is missing from `known` or `max_depth` levels have been downloaded
+fn download_previous_txs<F, M>(
+    downloaded: Vec<(Txid, BETransaction)>,
+    known: &mut HashSet<Txid>,
+    max_depth: usize,
+    is_mine: M,
+    mut fetch: F,
+) -> Result<Vec<(Txid, BETransaction)>, Error>
+where
+    F: FnMut(&[&Txid]) -> Result<Vec<(Txid, BETransaction)>, Error>,
+    M: Fn(&Script) -> bool,
+{
+    let mut result = vec![];
+    let mut level = downloaded;
+    for depth in 1.. {
+        let mut missing = HashSet::new();
+        for (txid, tx) in level {
+            known.insert(txid);
+            let pays_wallet = (0..tx.output_len() as u32).any(|v| is_mine(&tx.output_script(v)));
+            if pays_wallet && !tx.is_coinbase() {
+                missing.extend(tx.previous_output_txids());
             }
-            _ => Err(Error::Generic("received unconfidential or null asset/value/nonce".into())),
+            result.push((txid, tx));
         }
+        missing.retain(|txid| !known.contains(txid));
+        if missing.is_empty() {
+            break;
+        }
+        if depth >= max_depth {
+            warn!("{} previous txs not downloaded, deeper than {} levels", missing.len(), depth);
+            break;
+        }
+        let missing: Vec<&Txid> = missing.iter().collect();
+        level = fetch(&missing)?;
     }
+    Ok(result)
+}
+
+/// an item requested by the second stage of the sync download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fetch<'a> {
+    Tx(&'a Txid),
+    Header(u32),
+}
+
+/// download the txs and the headers of `items` in pages of at most `page_size` items, `fetch`
+/// must return the raw tx or header of every item of the page, in the same order
+fn download_fetches<F>(
+    items: &[Fetch],
+    page_size: usize,
+    id: NetworkId,
+    fetch: F,
+) -> Result<(Vec<(Txid, BETransaction)>, Vec<(u32, BEBlockHeader)>), Error>
+where
+    F: FnMut(&[Fetch]) -> Result<Vec<Vec<u8>>, Error>,
+{
+    let raw = download_paged(items, page_size, fetch)?;
+    if raw.len() != items.len() {
+        return Err(Error::Generic(format!(
+            "server returned {} items, {} requested",
+            raw.len(),
+            items.len()
+        )));
+    }
+    let mut txs = vec![];
+    let mut headers = vec![];
+    for (item, bytes) in items.iter().zip(raw) {
+        match item {
+            Fetch::Tx(txid) => {
+                let tx = BETransaction::deserialize(&bytes, id)?;
+                if tx.txid() != **txid {
+                    return Err(Error::Generic(format!(
+                        "server returned unrequested tx {}",
+                        tx.txid()
+                    )));
+                }
+                txs.push((**txid, tx));
+            }
+            Fetch::Header(height) => {
+                headers.push((*height, BEBlockHeader::deserialize(&bytes, id)?));
+            }
+        }
+    }
+    Ok((txs, headers))
+}
+
+/// record the txs of a script history with their heights
+fn add_history(
+    history: Vec<GetHistoryRes>,
+    history_txs_id: &mut HashSet<Txid>,
+    heights_set: &mut HashSet<u32>,
+    txid_height: &mut HashMap<Txid, Option<u32>>,
+) {
+    for el in history {
+        let height = history_height(el.height);
+        // mempool txs have no header to download, height 0 is the genesis block
+        if let Some(height) = height {
+            heights_set.insert(height);
+        }
+        txid_height.insert(el.tx_hash, height);
+
+        history_txs_id.insert(el.tx_hash);
+    }
+}
+
+/// height of a tx in the wallet history from the one returned by electrum, `None` for mempool
+/// txs: -1 means unconfirmed with unconfirmed parents, 0 unconfirmed with confirmed parents, but
+/// we treat those txs the same
+fn history_height(height: i32) -> Option<u32> {
+    if height > 0 {
+        Some(height as u32)
+    } else {
+        None
+    }
+}
+
+/// update the last used index of `chain` with the histories of the scripts of a batch
+fn update_last_used(
+    last_used: &mut Indexes,
+    chain: u32,
+    batch_count: u32,
+    result: &[Vec<GetHistoryRes>],
+) {
+    let max = result.iter().enumerate().filter(|(_, v)| !v.is_empty()).map(|(i, _)| i as u32).max();
+    if let Some(max) = max {
+        if chain == 0 {
+            last_used.external = max + batch_count * BATCH_SIZE;
+        } else {
+            last_used.internal = max + batch_count * BATCH_SIZE;
+        }
+    };
+}
+
+/// batch of `chain` where an incremental sync starts scanning, the one containing the last used
+/// index found by the previous sync
+fn first_batch(last_used: &Indexes, chain: u32) -> u32 {
+    let index = if chain == 0 {
+        last_used.external
+    } else {
+        last_used.internal
+    };
+    index / BATCH_SIZE
+}
+
+/// number of consecutive unused scripts at the end of the chain scanned so far, given the
+/// previous count `unused` and the histories of the scripts of the next batch
+fn count_unused(unused: u32, result: &[Vec<GetHistoryRes>]) -> u32 {
+    result.iter().fold(unused, |unused, history| {
+        if history.is_empty() {
+            unused + 1
+        } else {
+            0
+        }
+    })
+}
+
+/// replace the wallet history with the one just synced
+fn replace_history(
+    store_write: &mut StoreMeta,
+    last_used: Indexes,
+    txid_height: HashMap<Txid, Option<u32>>,
+) {
+    store_write.cache.last_used = last_used.clone();
+    store_write.cache.indexes = last_used;
+
+    // height map is used for the live list of transactions, since due to reorg or rbf tx
+    // could disappear from the list, we clear the list and keep only the last values returned by the server
+    store_write.cache.heights.clear();
+    store_write.cache.heights.extend(txid_height.into_iter());
+    let now = now();
+    let cache = &mut store_write.cache;
+    for (txid, _) in cache.heights.iter().filter(|(_, h)| h.is_none()) {
+        cache.first_seen.entry(*txid).or_insert(now);
+    }
+    let heights = &cache.heights;
+    cache.first_seen.retain(|txid, _| heights.get(txid) == Some(&None));
+    for txid in cache.heights.keys() {
+        cache.all_txs.insert_spent_outputs(txid, &cache.unblinded);
+    }
+    store_write.commit_change_reservations();
+
+    // change indexes reserved by txs signed during the sync are not in `last_used`, they must not
+    // be given out again
+    if let Some(highest) = store_write.highest_change_reservation() {
+        let indexes = &mut store_write.cache.indexes;
+        indexes.internal = indexes.internal.max(highest);
+    }
+}
+
+fn is_cancelled(cancel: Option<&AtomicBool>) -> bool {
+    cancel.map(|c| c.load(Ordering::Relaxed)).unwrap_or(false)
 }
 
 fn wait_or_close(r: &Receiver<()>, interval: u32) -> bool {
@@ -1159,3 +1960,667 @@ fn wait_or_close(r: &Receiver<()>, interval: u32) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod test {
+    use crate::store::BATCH_SIZE;
+    use crate::store::{Indexes, MemoryBackend, StoreMeta};
+    use crate::{add_history, count_unused, download_fetches, download_paged, download_txs_by_id};
+    use crate::{download_previous_txs, MAX_PREVIOUS_DEPTH};
+    use crate::{first_batch, replace_history, wallet_db_name, Fetch, SyncStats, Syncer};
+    use crate::{make_txlist_item, open_store, Error, NativeNotif};
+    use bitcoin::consensus::serialize;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+    use bitcoin::{BlockHeader, Network, Transaction, TxOut, Txid};
+    use electrum_client::GetHistoryRes;
+    use gdk_common::be::BETransaction;
+    use gdk_common::model::{CreateTransaction, SPVFailure, SPVVerifyResult, TransactionMeta};
+    use gdk_common::NetworkId;
+    use std::collections::{HashMap, HashSet};
+    use std::str::FromStr;
+    use tempdir::TempDir;
+
+    /// an empty bitcoin regtest store in memory
+    fn memory_store() -> StoreMeta {
+        let secp = Secp256k1::new();
+        let xprv = ExtendedPrivKey::new_master(Network::Regtest, &[1u8; 32]).unwrap();
+        let xpub = ExtendedPubKey::from_private(&secp, &xprv);
+        let id = NetworkId::Bitcoin(Network::Regtest);
+        StoreMeta::with_backend(Box::new(MemoryBackend::default()), xpub, None, id).unwrap()
+    }
+
+    #[test]
+    fn test_download_paged() {
+        let items: Vec<u32> = (0..1234).collect();
+        let mut calls = vec![];
+        let result = download_paged(&items, 500, |page| {
+            calls.push(page.len());
+            Ok(page.iter().map(|i| i * 2).collect())
+        })
+        .unwrap();
+        assert_eq!(calls, vec![500, 500, 234]);
+        assert_eq!(result, items.iter().map(|i| i * 2).collect::<Vec<u32>>());
+
+        let mut calls = 0;
+        let result: Vec<u32> = download_paged(&[], 500, |_: &[u32]| {
+            calls += 1;
+            Ok(vec![])
+        })
+        .unwrap();
+        assert!(result.is_empty());
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn test_download_txs_shuffled() {
+        let txs: Vec<Transaction> = (0..5u64)
+            .map(|i| Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![],
+                output: vec![TxOut {
+                    value: i,
+                    ..Default::default()
+                }],
+            })
+            .collect();
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        let requested: Vec<&Txid> = txids.iter().collect();
+        let id = NetworkId::Bitcoin(Network::Regtest);
+
+        // server answering every page in reverse order
+        let result = download_txs_by_id(&requested, 2, id, |page| {
+            Ok(page
+                .iter()
+                .rev()
+                .map(|txid| serialize(txs.iter().find(|tx| tx.txid() == **txid).unwrap()))
+                .collect())
+        })
+        .unwrap();
+        assert_eq!(result.len(), txs.len());
+        for (txid, tx) in result {
+            assert_eq!(txid, tx.txid());
+            assert!(txids.contains(&txid));
+        }
+
+        // server answering with a tx that was not requested
+        let result = download_txs_by_id(&requested[..2], 2, id, |_| {
+            Ok(vec![serialize(&txs[2]), serialize(&txs[0])])
+        });
+        assert!(result.is_err());
+
+        // server omitting a tx
+        let result = download_txs_by_id(&requested[..2], 2, id, |_| Ok(vec![serialize(&txs[1])]));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_sync_stats() {
+        let txs: Vec<Transaction> = (0..5u64)
+            .map(|i| Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![],
+                output: vec![TxOut {
+                    value: i,
+                    ..Default::default()
+                }],
+            })
+            .collect();
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        let requested: Vec<&Txid> = txids.iter().collect();
+        let id = NetworkId::Bitcoin(Network::Regtest);
+
+        let mut stats = SyncStats::default();
+        let history = vec![
+            vec![],
+            vec![GetHistoryRes {
+                height: 1,
+                tx_hash: txids[0],
+                fee: None,
+            }],
+        ];
+        stats.add_history(&history);
+        stats.add_history(&[vec![], vec![]]);
+        download_txs_by_id(&requested, 2, id, |page| {
+            let raw: Vec<Vec<u8>> = page
+                .iter()
+                .map(|txid| serialize(txs.iter().find(|tx| tx.txid() == **txid).unwrap()))
+                .collect();
+            stats.add_txs(&raw);
+            Ok(raw)
+        })
+        .unwrap();
+        stats.add_headers(&[vec![0u8; 80], vec![0u8; 80]]);
+
+        let tx_bytes: u64 = txs.iter().map(|tx| serialize(tx).len() as u64 * 2).sum();
+        assert_eq!(stats.history_requests, 2);
+        assert_eq!(stats.tx_requests, 3, "5 txs in pages of 2");
+        assert_eq!(stats.header_requests, 1);
+        assert_eq!(stats.bytes_downloaded, 100 + tx_bytes + 2 * 80 * 2);
+    }
+
+    /// number of batches a sync scans with `gap_limit` when only the `used` indexes have history
+    fn scanned_batches(used: &[u32], gap_limit: u32) -> u32 {
+        scanned_batches_from(used, gap_limit, 0)
+    }
+
+    /// like `scanned_batches` for a sync starting from the batch `first`
+    fn scanned_batches_from(used: &[u32], gap_limit: u32, first: u32) -> u32 {
+        let mut unused = 0;
+        for batch_count in first.. {
+            let result: Vec<Vec<GetHistoryRes>> = (0..BATCH_SIZE)
+                .map(|i| {
+                    if used.contains(&(batch_count * BATCH_SIZE + i)) {
+                        vec![GetHistoryRes {
+                            height: 1,
+                            tx_hash: Txid::default(),
+                            fee: None,
+                        }]
+                    } else {
+                        vec![]
+                    }
+                })
+                .collect();
+            unused = count_unused(unused, &result);
+            if unused >= gap_limit {
+                return batch_count + 1 - first;
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_gap_limit() {
+        // with the default gap limit a sync stops at the first batch without history
+        assert_eq!(scanned_batches(&[0, 41], 20), 2);
+        // address 41 is scanned (in the third batch) only with a wider gap limit
+        assert_eq!(scanned_batches(&[0, 41], 50), 5);
+        assert_eq!(scanned_batches(&[], 20), 1);
+        assert_eq!(scanned_batches(&[19], 20), 2);
+    }
+
+    #[test]
+    fn test_add_history_mempool() {
+        let txids: Vec<Txid> = (0..4u8).map(|i| Txid::from_slice(&[i; 32]).unwrap()).collect();
+        let history: Vec<GetHistoryRes> = [-1, 0, 5, 3]
+            .iter()
+            .zip(txids.iter())
+            .map(|(height, txid)| GetHistoryRes {
+                height: *height,
+                tx_hash: *txid,
+                fee: None,
+            })
+            .collect();
+        let mut history_txs_id = HashSet::new();
+        let mut heights_set = HashSet::new();
+        let mut txid_height = HashMap::new();
+        add_history(history, &mut history_txs_id, &mut heights_set, &mut txid_height);
+
+        assert_eq!(history_txs_id.len(), 4);
+        assert_eq!(heights_set, [5, 3].iter().cloned().collect());
+        assert_eq!(txid_height[&txids[0]], None);
+        assert_eq!(txid_height[&txids[1]], None);
+        assert_eq!(txid_height[&txids[2]], Some(5));
+        assert_eq!(txid_height[&txids[3]], Some(3));
+    }
+
+    #[test]
+    fn test_incremental_sync() {
+        let used: Vec<u32> = (0..=200).collect();
+        assert_eq!(scanned_batches(&used, 20), 12);
+
+        let last_used = Indexes {
+            external: 200,
+            internal: 45,
+        };
+        let first = first_batch(&last_used, 0);
+        assert_eq!(first, 10);
+        assert_eq!(first_batch(&last_used, 1), 2);
+        assert_eq!(first_batch(&Indexes::default(), 0), 0);
+        assert_eq!(scanned_batches_from(&used, 20, first), 2);
+
+        // an address used after the last known one extends the scan as in a full sync
+        let mut used = used;
+        used.push(225);
+        assert_eq!(scanned_batches(&used, 20), 13);
+        assert_eq!(scanned_batches_from(&used, 20, first), 3);
+    }
+
+    #[test]
+    fn test_wallet_db_name() {
+        let xpub = ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4YfG9CySHqKHFbaLcD7hSDyqRUtCmMKNim5fkiJtTnFeqKsRHMHSK5ddFrhqRr3Ghv1JtuWkBzikuBqKu1xCpjQ9YxoPGgqU").unwrap();
+        let mut network = gdk_common::network::Network::default();
+        let mut other = network.clone();
+        other.electrum_url = Some("other.example.com:50001".into());
+        assert_ne!(wallet_db_name(&xpub, &network), wallet_db_name(&xpub, &other));
+
+        network.db_from_xpub = Some(true);
+        other.db_from_xpub = Some(true);
+        assert_eq!(wallet_db_name(&xpub, &network), wallet_db_name(&xpub, &other));
+
+        other.development = true; // regtest
+        assert_ne!(wallet_db_name(&xpub, &network), wallet_db_name(&xpub, &other));
+    }
+
+    #[test]
+    fn test_make_txlist_item() {
+        let tx = BETransaction::Bitcoin(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        });
+        let failure = SPVVerifyResult::NotVerified(SPVFailure::MerkleRootMismatch);
+        let meta = TransactionMeta::new(
+            tx,
+            Some(1),
+            None,
+            HashMap::new(),
+            None,
+            Network::Regtest,
+            "incoming".to_string(),
+            CreateTransaction::default(),
+            false,
+            failure,
+        );
+        let item = make_txlist_item(&meta);
+        assert_eq!(item.spv_verified, "not_verified");
+        assert_eq!(item.spv_failure, Some("merkle_root_mismatch".to_string()));
+        assert_eq!((item.fee, item.fee_rate), (None, None), "the prevouts are unknown");
+
+        let mut meta = meta;
+        meta.spv_verified = SPVVerifyResult::Verified;
+        assert_eq!(make_txlist_item(&meta).spv_failure, None);
+    }
+
+    #[test]
+    fn test_open_store_repairs() {
+        let xpub = ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4YfG9CySHqKHFbaLcD7hSDyqRUtCmMKNim5fkiJtTnFeqKsRHMHSK5ddFrhqRr3Ghv1JtuWkBzikuBqKu1xCpjQ9YxoPGgqU").unwrap();
+        let id = NetworkId::Bitcoin(Network::Testnet);
+        let dir = TempDir::new("unit_test").unwrap().into_path().join("store");
+        let txid = Txid::from_slice(&[1u8; 32]).unwrap();
+        let mut store = open_store(&dir, xpub, None, id, NativeNotif(None)).unwrap();
+        store.insert_memo(txid, "memo").unwrap();
+        drop(store);
+
+        // login goes on with the store emptied, the unreadable one is kept aside
+        std::fs::write(dir.join("store"), vec![7u8; 100]).unwrap();
+        assert!(matches!(StoreMeta::new(&dir, xpub, None, id), Err(Error::DbCorrupted(_))));
+        let store = open_store(&dir, xpub, None, id, NativeNotif(None)).unwrap();
+        assert_eq!(store.get_memo(&txid), None);
+        assert!(dir.join("store.corrupted").exists());
+    }
+
+    #[test]
+    fn test_download_fetches() {
+        let txs: Vec<Transaction> = (0..500u64)
+            .map(|i| Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![],
+                output: vec![TxOut {
+                    value: i,
+                    ..Default::default()
+                }],
+            })
+            .collect();
+        let txids: Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        let header = |height: u32| BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            time: height,
+            bits: 0,
+            nonce: 0,
+        };
+        let mut items: Vec<Fetch> = txids.iter().map(Fetch::Tx).collect();
+        items.extend((1..=10).map(Fetch::Header));
+        let id = NetworkId::Bitcoin(Network::Regtest);
+
+        let mut pages = vec![];
+        let (downloaded, headers) = download_fetches(&items, 64, id, |page| {
+            pages.push(page.to_vec());
+            Ok(page
+                .iter()
+                .map(|item| match item {
+                    Fetch::Tx(txid) => {
+                        serialize(txs.iter().find(|tx| tx.txid() == **txid).unwrap())
+                    }
+                    Fetch::Header(height) => serialize(&header(*height)),
+                })
+                .collect())
+        })
+        .unwrap();
+        assert_eq!(pages.len(), 8, "510 items in pages of 64");
+        assert!(pages.iter().all(|page| page.len() <= 64));
+        assert_eq!(pages.concat(), items);
+        assert_eq!(downloaded.len(), 500);
+        assert_eq!(headers.len(), 10);
+
+        let mut store = memory_store();
+        assert_eq!(store.insert_txs(downloaded), 0);
+        assert_eq!(store.insert_headers(headers), 0);
+        assert!(txids.iter().all(|txid| store.cache.all_txs.contains_key(txid)));
+        assert!((1..=10).all(|height| store.cache.headers.contains_key(&height)));
+
+        // a page answered with a missing item
+        let result = download_fetches(&items, 64, id, |page| Ok(vec![vec![]; page.len() - 1]));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_replace_history_keeps_reservations() {
+        let mut store = memory_store();
+        let txid = Txid::from_inner([1u8; 32]);
+
+        // a tx signed while the sync was running, its change is not in the synced history
+        let last_used = Indexes {
+            external: 3,
+            internal: 1,
+        };
+        store.reserve_change_indexes(txid, 2, 2).unwrap();
+        store.cache.indexes.internal = 3;
+        replace_history(&mut store, last_used.clone(), HashMap::new());
+        assert_eq!(store.cache.indexes.internal, 3);
+        assert_eq!(store.cache.last_used, last_used);
+
+        // once the tx is in the history the synced indexes are used
+        let mut txid_height = HashMap::new();
+        txid_height.insert(txid, None);
+        replace_history(&mut store, last_used.clone(), txid_height);
+        assert_eq!(store.cache.indexes, last_used);
+        assert_eq!(store.highest_change_reservation(), None);
+    }
+
+    #[test]
+    fn test_download_previous_txs() {
+        use bitcoin::{OutPoint, Script, TxIn};
+        use gdk_common::be::{BETransaction, BETransactions};
+
+        let spend = |prev: &Transaction, values: &[u64], scripts: &[&Script]| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(prev.txid(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffe,
+                witness: vec![],
+            }],
+            output: values
+                .iter()
+                .zip(scripts)
+                .map(|(value, script)| TxOut {
+                    value: *value,
+                    script_pubkey: (*script).clone(),
+                })
+                .collect(),
+        };
+        let external = Script::from(vec![0x51]);
+        let wallet: Vec<Script> = (0..3u8).map(|i| Script::from(vec![0x00, 0x14, i])).collect();
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: external.clone(),
+            }],
+        };
+        // a chain of wallet txs, the sync found only the last one
+        let first = spend(&funding, &[99_000], &[&wallet[0]]);
+        let second = spend(&first, &[97_000], &[&wallet[1]]);
+        let third = spend(&second, &[50_000, 44_000], &[&wallet[2], &external]);
+        let server: HashMap<Txid, Transaction> = vec![funding, first.clone(), second.clone()]
+            .into_iter()
+            .map(|tx| (tx.txid(), tx))
+            .collect();
+
+        let download = |max_depth| {
+            let mut known: HashSet<Txid> = vec![third.txid()].into_iter().collect();
+            let mut requests = 0;
+            let downloaded = vec![(second.txid(), BETransaction::Bitcoin(second.clone()))];
+            let txs = download_previous_txs(
+                downloaded,
+                &mut known,
+                max_depth,
+                |script| wallet.contains(script),
+                |txids| {
+                    requests += 1;
+                    Ok(txids
+                        .iter()
+                        .map(|txid| (**txid, BETransaction::Bitcoin(server[*txid].clone())))
+                        .collect())
+                },
+            )
+            .unwrap();
+            let mut all_txs = BETransactions::default();
+            all_txs.insert(third.txid(), BETransaction::Bitcoin(third.clone()));
+            for (txid, tx) in txs {
+                all_txs.insert(txid, tx);
+            }
+            (all_txs, requests)
+        };
+
+        let (all_txs, requests) = download(MAX_PREVIOUS_DEPTH);
+        assert_eq!(requests, 2);
+        let fee = |tx: &Transaction| {
+            BETransaction::Bitcoin(tx.clone()).fee(&all_txs, &HashMap::new(), &None).ok()
+        };
+        assert_eq!(fee(&first), Some(1_000));
+        assert_eq!(fee(&second), Some(2_000));
+        assert_eq!(fee(&third), Some(3_000));
+
+        // levels beyond the limit aren't downloaded
+        let (all_txs, requests) = download(2);
+        assert_eq!(requests, 1);
+        assert!(all_txs.get(&first.txid()).is_some());
+        assert!(BETransaction::Bitcoin(first.clone())
+            .fee(&all_txs, &HashMap::new(), &None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_incremental_sync_drops_missing_txs() {
+        use crate::mock::{scripthash, MockElectrum};
+        use bitcoin::hashes::hex::ToHex;
+        use bitcoin::{OutPoint, Script, TxIn};
+        use gdk_common::be::BETransaction;
+        use std::sync::{Arc, RwLock};
+
+        let mut store = memory_store();
+        let batch = store.get_script_batch(0, 0).unwrap().value;
+        for (script, path) in batch.iter() {
+            store.cache.paths.insert(script.clone(), path.clone());
+            store.cache.scripts.insert(path.clone(), script.clone());
+        }
+        let spend = |prev: &Transaction, script: &Script| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(prev.txid(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffe,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: script.clone(),
+            }],
+        };
+        let external = Script::from(vec![0x51]);
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: external.clone(),
+            }],
+        };
+        // an unconfirmed tx replaced while offline, and a confirmed receive already spent
+        let replaced = spend(&funding, &batch[5].0);
+        let received = spend(&funding, &batch[6].0);
+        let spent = spend(&received, &external);
+        let heights = vec![(&replaced, None), (&received, Some(3)), (&spent, Some(4))];
+        for (tx, height) in heights {
+            store.cache.heights.insert(tx.txid(), height);
+        }
+        for tx in vec![&funding, &replaced, &received, &spent] {
+            store.cache.all_txs.insert(tx.txid(), BETransaction::Bitcoin(tx.clone()));
+        }
+        let last_used = Indexes {
+            external: 45,
+            internal: 0,
+        };
+        store.cache.last_used = last_used.clone();
+        store.cache.indexes = last_used;
+
+        let mut network = gdk_common::network::Network::default();
+        network.development = true;
+        let syncer = Syncer {
+            store: Arc::new(RwLock::new(store)),
+            master_blinding: None,
+            network,
+            stats: Default::default(),
+            notify: NativeNotif(None),
+        };
+        let tip = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            time: 10,
+            bits: 0,
+            nonce: 0,
+        };
+        let tip = serialize(&tip).to_hex();
+        let server = MockElectrum::start(move |method, _| match method {
+            "blockchain.headers.subscribe" => Some(json!({"height": 10, "hex": tip.clone()})),
+            "blockchain.scripthash.get_history" => Some(json!([])),
+            _ => None,
+        });
+
+        assert!(syncer.sync(&server.client(), None, false).unwrap());
+        let store = syncer.store.read().unwrap();
+        let synced: HashSet<Txid> = store.cache.heights.keys().cloned().collect();
+        let expected: HashSet<Txid> = vec![received.txid(), spent.txid()].into_iter().collect();
+        assert_eq!(synced, expected, "the replaced tx is dropped");
+        let queried: Vec<Vec<serde_json::Value>> =
+            server.requests("blockchain.scripthash.get_history");
+        assert!(queried.contains(&vec![scripthash(&batch[5].0).into()]), "live script");
+        assert!(!queried.contains(&vec![scripthash(&batch[6].0).into()]), "skipped script");
+    }
+
+    #[test]
+    fn test_sync_huge_history() {
+        use crate::error::Error;
+        use crate::mock::{scripthash, MockElectrum};
+        use crate::NativeNotif;
+        use bitcoin::hashes::hex::ToHex;
+        use bitcoin::{OutPoint, Script, TxIn};
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::sync::{Arc, RwLock};
+
+        let mut store = memory_store();
+        let reused = store.get_script_batch(0, 0).unwrap().value[0].0.clone();
+
+        // a reused address receiving 7 txs, downloaded in pages of 2
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![
+                TxOut {
+                    value: 100_000,
+                    script_pubkey: Script::from(vec![0x51]),
+                };
+                7
+            ],
+        };
+        let received: Vec<Transaction> = (0..7)
+            .map(|vout| Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(funding.txid(), vout),
+                    script_sig: Script::new(),
+                    sequence: 0xffff_fffe,
+                    witness: vec![],
+                }],
+                output: vec![TxOut {
+                    value: 90_000,
+                    script_pubkey: reused.clone(),
+                }],
+            })
+            .collect();
+        let history: Vec<serde_json::Value> =
+            received.iter().map(|tx| json!({"height": 0, "tx_hash": tx.txid()})).collect();
+        let raw: HashMap<String, String> = received
+            .iter()
+            .chain(std::iter::once(&funding))
+            .map(|tx| (tx.txid().to_hex(), serialize(tx).to_hex()))
+            .collect();
+
+        let mut network = gdk_common::network::Network::default();
+        network.development = true;
+        network.history_page_size = Some(2);
+        network.huge_history_threshold = Some(3);
+        let syncer = Syncer {
+            store: Arc::new(RwLock::new(store)),
+            master_blinding: None,
+            network,
+            stats: Default::default(),
+            notify: NativeNotif(None),
+        };
+        let tip = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            time: 10,
+            bits: 0,
+            nonce: 0,
+        };
+        let tip = serialize(&tip).to_hex();
+        let reused_hash = scripthash(&reused);
+        // interrupt the first sync once the second page of the reused address is requested
+        let cancel = Arc::new(AtomicBool::new(false));
+        let tx_requests = Arc::new(AtomicUsize::new(0));
+        let (server_cancel, server_tx_requests) = (cancel.clone(), tx_requests.clone());
+        let server = MockElectrum::start(move |method, params| match method {
+            "blockchain.headers.subscribe" => Some(json!({"height": 10, "hex": tip.clone()})),
+            "blockchain.scripthash.get_history" if params[0] == reused_hash.as_str() => {
+                Some(json!(history.clone()))
+            }
+            "blockchain.scripthash.get_history" => Some(json!([])),
+            "blockchain.transaction.get" => {
+                // 2 txs of the first page, its previous tx and 2 txs of the second page
+                if server_tx_requests.fetch_add(1, Ordering::SeqCst) + 1 == 5 {
+                    server_cancel.store(true, Ordering::SeqCst);
+                }
+                raw.get(params[0].as_str()?).map(|hex| json!(hex))
+            }
+            _ => None,
+        });
+        let client = server.client();
+
+        let result = syncer.sync(&client, Some(&cancel), true);
+        assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+        assert_eq!(syncer.stats.read().unwrap().huge_history_pages, 2);
+        let stored = syncer.store.read().unwrap().cache.all_txs.len();
+        assert_eq!(stored, 5, "the pages downloaded before cancelling are kept");
+
+        assert!(syncer.sync(&client, None, true).unwrap());
+        assert_eq!(syncer.stats.read().unwrap().huge_history_pages, 2, "only the missing txs");
+        let store = syncer.store.read().unwrap();
+        let synced: HashSet<Txid> = store.cache.heights.keys().cloned().collect();
+        let expected: HashSet<Txid> = received.iter().map(|tx| tx.txid()).collect();
+        assert_eq!(synced, expected);
+        let requested = server.requests("blockchain.transaction.get");
+        for tx in received.iter() {
+            let times = requested.iter().filter(|p| p[0] == tx.txid().to_hex().as_str()).count();
+            assert_eq!(times, 1, "every tx is downloaded once");
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! a fake electrum server answering on localhost the json-rpc requests of the electrum client,
+//! to test the code talking to the server without a running one
+
+use crate::interface::ElectrumUrl;
+use crate::retry::{RetryClient, RetryPolicy};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::Script;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Reply = dyn Fn(&str, &[Value]) -> Option<Result<Value, Value>> + Send + Sync;
+
+pub struct MockElectrum {
+    pub url: String,
+    /// method and params of every request received, in order
+    requests: Arc<Mutex<Vec<(String, Vec<Value>)>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl MockElectrum {
+    /// listen on localhost answering each request with the result `reply(method, params)`, when
+    /// it returns `None` the connection is closed without answering
+    pub fn start<F>(reply: F) -> Self
+    where
+        F: Fn(&str, &[Value]) -> Option<Value> + Send + Sync + 'static,
+    {
+        MockElectrum::dropping(0, reply)
+    }
+
+    /// like `start`, but the first `drops` connections are closed as soon as they receive the
+    /// first request. Since it's unread, the client gets a connection reset
+    pub fn dropping<F>(drops: usize, reply: F) -> Self
+    where
+        F: Fn(&str, &[Value]) -> Option<Value> + Send + Sync + 'static,
+    {
+        MockElectrum::listen(drops, move |method, params| reply(method, params).map(Ok))
+    }
+
+    /// like `start`, but `reply` can answer with `Err(error)`, the error object of a json-rpc
+    /// response, as the server does refusing a request
+    pub fn failing<F>(reply: F) -> Self
+    where
+        F: Fn(&str, &[Value]) -> Option<Result<Value, Value>> + Send + Sync + 'static,
+    {
+        MockElectrum::listen(0, reply)
+    }
+
+    fn listen<F>(drops: usize, reply: F) -> Self
+    where
+        F: Fn(&str, &[Value]) -> Option<Result<Value, Value>> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let reply: Arc<Reply> = Arc::new(reply);
+        let (thread_requests, thread_connections) = (requests.clone(), connections.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                if thread_connections.fetch_add(1, Ordering::SeqCst) < drops {
+                    thread::spawn(move || stream.peek(&mut [0u8; 1]));
+                    continue;
+                }
+                let (requests, reply) = (thread_requests.clone(), reply.clone());
+                thread::spawn(move || serve(stream, &requests, reply.as_ref()));
+            }
+        });
+        MockElectrum {
+            url,
+            requests,
+            connections,
+        }
+    }
+
+    /// a plaintext client connected to the server
+    pub fn client(&self) -> RetryClient {
+        let url = ElectrumUrl::Plaintext(self.url.clone(), None);
+        RetryClient::with_policy(&url, RetryPolicy::default()).unwrap()
+    }
+
+    /// the params of the requests received of `method`
+    pub fn requests(&self, method: &str) -> Vec<Vec<Value>> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().filter(|(m, _)| m == method).map(|(_, p)| p.clone()).collect()
+    }
+
+    /// number of connections accepted, the dropped ones included
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// answer the requests of a connection, one json object or one batch array per line
+fn serve(stream: TcpStream, requests: &Mutex<Vec<(String, Vec<Value>)>>, reply: &Reply) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => (),
+        }
+        let request: Value = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        let response = match request {
+            Value::Array(batch) => {
+                let mut responses = vec![];
+                for request in batch.iter() {
+                    match answer(request, requests, reply) {
+                        Some(response) => responses.push(response),
+                        None => return,
+                    }
+                }
+                Value::Array(responses)
+            }
+            request => match answer(&request, requests, reply) {
+                Some(response) => response,
+                None => return,
+            },
+        };
+        if writeln!(stream, "{}", response).is_err() {
+            return;
+        }
+    }
+}
+
+fn answer(
+    request: &Value,
+    requests: &Mutex<Vec<(String, Vec<Value>)>>,
+    reply: &Reply,
+) -> Option<Value> {
+    let method = request["method"].as_str().unwrap_or_default().to_string();
+    let params = request["params"].as_array().cloned().unwrap_or_default();
+    requests.lock().unwrap().push((method.clone(), params.clone()));
+    match reply(&method, &params)? {
+        Ok(result) => Some(json!({"jsonrpc": "2.0", "id": request["id"], "result": result})),
+        Err(error) => Some(json!({"jsonrpc": "2.0", "id": request["id"], "error": error})),
+    }
+}
+
+/// the electrum scripthash of `script`, the hex of its reversed sha256
+pub fn scripthash(script: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).into_inner();
+    hash.reverse();
+    hash.to_hex()
+}
@@ -0,0 +1,217 @@
+use crate::error::Error;
+use crate::interface::ElectrumUrl;
+use electrum_client::Client;
+use gdk_common::network::Network;
+use log::warn;
+use rand::{thread_rng, Rng};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// default number of attempts of a connection or a request to the electrum server
+pub const RETRY_ATTEMPTS: u32 = 3;
+
+/// default delay before the first retry, doubling at every following one
+pub const RETRY_BASE_DELAY_MS: u64 = 500;
+
+/// how many times, and how far apart, connections and requests to the electrum server are
+/// attempted before giving up with `Error::ElectrumUnreachable`
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: RETRY_ATTEMPTS,
+            base_delay: Duration::from_millis(RETRY_BASE_DELAY_MS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_network(network: &Network) -> Self {
+        RetryPolicy {
+            attempts: network.electrum_retries.unwrap_or(RETRY_ATTEMPTS).max(1),
+            base_delay: Duration::from_millis(
+                network.electrum_retry_delay_ms.unwrap_or(RETRY_BASE_DELAY_MS),
+            ),
+        }
+    }
+
+    /// delay before the retry number `retry`, starting from 0: the base delay doubled at every
+    /// retry plus a random jitter up to half of it, so that clients don't retry all together
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.base_delay * 2u32.pow(retry.min(16));
+        let jitter_ms = thread_rng().gen_range(0, delay.as_millis() as u64 / 2 + 1);
+        delay + Duration::from_millis(jitter_ms)
+    }
+
+    /// call `f` until it succeeds, retrying only on network errors. Other errors, like the ones
+    /// returned by the server, are returned immediately
+    pub fn run<T, F>(&self, what: &str, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+    {
+        let mut retry = 0;
+        loop {
+            match f() {
+                Err(e) if is_transient(&e) => {
+                    retry += 1;
+                    if retry >= self.attempts {
+                        return Err(Error::ElectrumUnreachable(format!(
+                            "{} failed {} times, last error {}",
+                            what, retry, e
+                        )));
+                    }
+                    let delay = self.delay(retry - 1);
+                    warn!("{} failed: {}, retrying in {:?}", what, e, delay);
+                    thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// an electrum client whose connection and requests are retried as configured by a
+/// `RetryPolicy`. After a network error the connection is likely closed, so the following
+/// attempts are made on a new connection to the same url
+pub struct RetryClient {
+    url: ElectrumUrl,
+    policy: RetryPolicy,
+    client: Mutex<Option<Client>>,
+}
+
+impl RetryClient {
+    /// connect to `url` with the retry policy configured in `network`
+    pub fn connect(url: &ElectrumUrl, network: &Network) -> Result<Self, Error> {
+        RetryClient::with_policy(url, RetryPolicy::from_network(network))
+    }
+
+    pub fn with_policy(url: &ElectrumUrl, policy: RetryPolicy) -> Result<Self, Error> {
+        let client = policy.run("connection", || url.build_client())?;
+        Ok(RetryClient {
+            url: url.clone(),
+            policy,
+            client: Mutex::new(Some(client)),
+        })
+    }
+
+    /// call `f` with the client until it succeeds, see `RetryPolicy::run`, connecting again
+    /// after network errors
+    pub fn call<T, F>(&self, what: &str, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut(&Client) -> Result<T, Error>,
+    {
+        self.policy.run(what, || {
+            let mut guard =
+                self.client.lock().map_err(|_| Error::Generic("client lock poisoned".into()))?;
+            let client = match guard.take() {
+                Some(client) => client,
+                None => self.url.build_client()?,
+            };
+            let result = f(&client);
+            if !matches!(&result, Err(e) if is_transient(e)) {
+                *guard = Some(client);
+            }
+            result
+        })
+    }
+}
+
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::ClientError(electrum_client::Error::IOError(_)) | Error::StdIOError(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::retry::RetryPolicy;
+    use std::io;
+    use std::time::Duration;
+
+    fn io_err() -> Error {
+        let err = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        Error::ClientError(electrum_client::Error::IOError(err))
+    }
+
+    #[test]
+    fn test_retry() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(1),
+        };
+
+        // a server failing twice then answering
+        let mut calls = 0;
+        let result = policy.run("history", || {
+            calls += 1;
+            if calls <= 2 {
+                Err(io_err())
+            } else {
+                Ok(vec![1u32, 2])
+            }
+        });
+        assert_eq!(result.unwrap(), vec![1, 2]);
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), Error> = policy.run("history", || {
+            calls += 1;
+            Err(io_err())
+        });
+        assert!(matches!(result, Err(Error::ElectrumUnreachable(_))));
+        assert_eq!(calls, 3);
+
+        // errors not caused by the network are not retried
+        let mut calls = 0;
+        let result: Result<(), Error> = policy.run("history", || {
+            calls += 1;
+            Err(Error::InvalidHeaders)
+        });
+        assert!(matches!(result, Err(Error::InvalidHeaders)));
+        assert_eq!(calls, 1);
+
+        let delay = policy.delay(2);
+        assert!(delay >= Duration::from_millis(4) && delay <= Duration::from_millis(6));
+    }
+
+    #[test]
+    fn test_retry_client_reconnects() {
+        use crate::interface::ElectrumUrl;
+        use crate::mock::MockElectrum;
+        use crate::retry::RetryClient;
+        use electrum_client::ElectrumApi;
+
+        // a server dropping the first two connections
+        let server = MockElectrum::dropping(2, |method, _| match method {
+            "server.ping" => Some(serde_json::Value::Null),
+            _ => None,
+        });
+        let url = ElectrumUrl::Plaintext(server.url.clone(), None);
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(1),
+        };
+        let client = RetryClient::with_policy(&url, policy.clone()).unwrap();
+        client.call("ping", |c| Ok(c.ping()?)).unwrap();
+        assert_eq!(server.connections(), 3, "a new connection for every attempt");
+        assert_eq!(server.requests("server.ping").len(), 1);
+
+        // the working connection is kept
+        client.call("ping", |c| Ok(c.ping()?)).unwrap();
+        assert_eq!(server.connections(), 3);
+
+        let server = MockElectrum::dropping(3, |_, _| Some(serde_json::Value::Null));
+        let url = ElectrumUrl::Plaintext(server.url.clone(), None);
+        let client = RetryClient::with_policy(&url, policy).unwrap();
+        let result = client.call("ping", |c| Ok(c.ping()?));
+        assert!(matches!(result, Err(Error::ElectrumUnreachable(_))));
+    }
+}
@@ -11,7 +11,7 @@ use gdk_common::be::{BEBlockHeader, BEOutPoint, BETransaction, BETransactions};
 use gdk_common::be::{ScriptBatch, Unblinded};
 use gdk_common::error::fn_err;
 use gdk_common::model::{FeeEstimate, SPVVerifyResult, Settings};
-use gdk_common::scripts::p2shwpkh_script;
+use gdk_common::scripts::{p2shwpkh_script, ScriptType};
 use gdk_common::wally::{
     asset_blinding_key_to_ec_private_key, ec_public_key_from_private_key, MasterBlindingKey,
 };
@@ -25,13 +25,85 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Instant;
 
 pub const BATCH_SIZE: u32 = 20;
 
+/// the store is shared by the `WalletCtx` and the syncer thread. Updates depending on the current
+/// state, like handing out the next address index or reserving change indexes, are done holding
+/// a single write guard, so that concurrent callers never get the same index
 pub type Store = Arc<RwLock<StoreMeta>>;
 
+/// StoreBackend is where `StoreMeta` persists its data, blobs are identified by a name.
+/// Private data are encrypted by `StoreMeta` before reaching the backend.
+pub trait StoreBackend: Send + Sync {
+    /// returns the blob with the given name, `None` if it was never saved
+    fn load(&self, name: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// saves the blob with the given name, replacing the previous one if any
+    fn save(&self, name: &str, bytes: &[u8]) -> Result<(), Error>;
+}
+
+/// FileBackend saves every blob in a file named as the blob inside `path`, it is the default backend
+pub struct FileBackend {
+    path: PathBuf,
+}
+
+impl FileBackend {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        if !path.exists() {
+            std::fs::create_dir_all(&path)?;
+        }
+        Ok(FileBackend {
+            path,
+        })
+    }
+}
+
+impl StoreBackend for FileBackend {
+    fn load(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let mut path = self.path.clone();
+        path.push(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut file = File::open(&path)?;
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+        Ok(Some(contents))
+    }
+
+    fn save(&self, name: &str, bytes: &[u8]) -> Result<(), Error> {
+        let mut path = self.path.clone();
+        path.push(name);
+        let mut file = File::create(&path)?;
+        file.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+/// MemoryBackend keeps blobs in memory, clones share the same blobs so that dropping a
+/// `StoreMeta` and creating a new one from a clone behaves like reopening a file store
+#[derive(Default, Clone)]
+pub struct MemoryBackend {
+    blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl StoreBackend for MemoryBackend {
+    fn load(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let blobs = self.blobs.lock().map_err(|e| Error::Generic(e.to_string()))?;
+        Ok(blobs.get(name).cloned())
+    }
+
+    fn save(&self, name: &str, bytes: &[u8]) -> Result<(), Error> {
+        let mut blobs = self.blobs.lock().map_err(|e| Error::Generic(e.to_string()))?;
+        blobs.insert(name.to_string(), bytes.to_vec());
+        Ok(())
+    }
+}
+
 /// RawCache is a persisted and encrypted cache of wallet data, contains stuff like wallet transactions
 /// It is fully reconstructable from xpub and data from electrum server (plus master blinding for elements)
 #[derive(Default, Serialize, Deserialize)]
@@ -54,7 +126,7 @@ pub struct RawCache {
     /// unblinded values (only for liquid)
     pub unblinded: HashMap<OutPoint, Unblinded>,
 
-    /// verification status of Txid (could be only Verified or NotVerified, absence means InProgress)
+    /// verification status of Txid (could be only Verified or NotVerified with its reason, absence means InProgress)
     pub txs_verif: HashMap<Txid, SPVVerifyResult>,
 
     /// cached fee_estimates
@@ -65,6 +137,19 @@ pub struct RawCache {
 
     /// max used indexes for external derivation /0/* and internal derivation /1/* (change)
     pub indexes: Indexes,
+
+    /// unix time when unconfirmed wallet txs have been seen for the first time
+    #[serde(default)]
+    pub first_seen: HashMap<Txid, u32>,
+
+    /// highest indexes with history found by the last complete sync, unlike `indexes` they
+    /// don't include addresses handed out or reserved since. Incremental syncs start from here
+    #[serde(default)]
+    pub last_used: Indexes,
+
+    /// script type of the wallet addresses, None for the default p2sh-p2wpkh
+    #[serde(default)]
+    pub script_type: Option<ScriptType>,
 }
 
 /// RawStore contains data that are not extractable from xpub+blockchain
@@ -76,6 +161,39 @@ pub struct RawStore {
 
     /// transaction memos
     memos: HashMap<Txid, String>,
+
+    /// labels of single transaction outputs, keyed by (txid, vout)
+    #[serde(default)]
+    output_labels: HashMap<(Txid, u32), String>,
+
+    /// write-ahead log of internal (change) indexes reserved by signed transactions, with the
+    /// txid using them. Entries are removed once the txid is seen in the wallet history,
+    /// remaining ones are reclaimed when the store is opened again
+    #[serde(default)]
+    change_reservations: HashMap<u32, Txid>,
+
+    /// an archived wallet is read-only, it can be synced and queried but not spent from
+    #[serde(default)]
+    archived: bool,
+
+    /// send change to fresh addresses of the external chain instead of the internal one, so
+    /// that who knows the xpub can't tell change outputs apart from received ones
+    #[serde(default)]
+    change_on_receive_chain: bool,
+
+    /// scripts of addresses derived with a non default script type, they are not derivable
+    /// from the xpub alone so they are synced in addition to the default ones
+    #[serde(default)]
+    extra_scripts: HashMap<Script, DerivationPath>,
+
+    /// nonces of the liquid invoices blinded with a key derived from the script and the nonce,
+    /// needed to unblind the outputs paying them
+    #[serde(default)]
+    invoice_nonces: HashMap<Script, HashSet<u32>>,
+
+    /// outputs, keyed by (txid, vout), the user doesn't want to spend
+    #[serde(default)]
+    frozen: HashSet<(Txid, u32)>,
 }
 
 pub struct StoreMeta {
@@ -84,9 +202,11 @@ pub struct StoreMeta {
     master_blinding: Option<MasterBlindingKey>,
     secp: Secp256k1<All>,
     id: NetworkId,
-    path: PathBuf,
+    backend: Box<dyn StoreBackend>,
     cipher: Aes256GcmSiv,
     first_deriv: [ExtendedPubKey; 2],
+    /// electrum scripthashes of the scripts already hashed, not persisted
+    script_hashes: Mutex<HashMap<Script, [u8; 32]>>,
 }
 
 impl Drop for StoreMeta {
@@ -104,57 +224,99 @@ pub struct Indexes {
 impl RawCache {
     /// create a new RawCache, loading data from a file if any and if there is no error in reading
     /// errors such as corrupted file or model change in the db, result in a empty store that will be repopulated
-    fn new<P: AsRef<Path>>(path: P, cipher: &Aes256GcmSiv) -> Self {
-        Self::try_new(path, cipher).unwrap_or_else(|e| {
+    fn new(backend: &dyn StoreBackend, cipher: &Aes256GcmSiv) -> Self {
+        Self::try_new(backend, cipher).unwrap_or_else(|e| {
             warn!("Initialize cache as default {:?}", e);
             Default::default()
         })
     }
 
-    fn try_new<P: AsRef<Path>>(path: P, cipher: &Aes256GcmSiv) -> Result<Self, Error> {
-        let decrypted = load_decrypt("cache", path, cipher)?;
-        let store = serde_cbor::from_slice(&decrypted)?;
-        Ok(store)
+    fn try_new(backend: &dyn StoreBackend, cipher: &Aes256GcmSiv) -> Result<Self, Error> {
+        load_decrypt("cache", backend, cipher)
     }
 }
 
 impl RawStore {
-    /// create a new RawStore, loading data from a file if any and if there is no error in reading
-    /// errors such as corrupted file or model change in the db, result in a empty store that will be repopulated
-    fn new<P: AsRef<Path>>(path: P, cipher: &Aes256GcmSiv) -> Self {
-        Self::try_new(path, cipher).unwrap_or_else(|e| {
-            warn!("Initialize cache as default {:?}", e);
-            Default::default()
-        })
+    /// create a new RawStore, loading data from a file if any. Unlike the cache its data can't
+    /// be recovered from the blockchain, so an unreadable store is reported as
+    /// `Error::DbCorrupted` instead of being replaced, see `StoreMeta::repair`
+    fn new(backend: &dyn StoreBackend, cipher: &Aes256GcmSiv) -> Result<Self, Error> {
+        load_decrypt("store", backend, cipher)
     }
+}
 
-    fn try_new<P: AsRef<Path>>(path: P, cipher: &Aes256GcmSiv) -> Result<Self, Error> {
-        let decrypted = load_decrypt("store", path, cipher)?;
-        let store = serde_cbor::from_slice(&decrypted)?;
-        Ok(store)
-    }
+/// what `StoreMeta::repair` had to reset to make the db readable again
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Repair {
+    /// the cache was unreadable and has been emptied, a full sync (`WalletCtx::rescan`) into
+    /// the fresh cache rebuilds the wallet history
+    pub rescan_needed: bool,
+    /// the store was unreadable and has been emptied: settings, memos and labels are lost. The
+    /// unreadable blob is kept as `store.corrupted`
+    pub store_reset: bool,
 }
 
-fn load_decrypt<P: AsRef<Path>>(
+/// load and decrypt the blob `name`, the default value if it was never saved. A blob that
+/// can't be decrypted or deserialized, e.g. because truncated or overwritten, is reported as
+/// `Error::DbCorrupted`
+fn load_decrypt<T>(
     name: &str,
-    path: P,
+    backend: &dyn StoreBackend,
     cipher: &Aes256GcmSiv,
-) -> Result<Vec<u8>, Error> {
+) -> Result<T, Error>
+where
+    T: Default + serde::de::DeserializeOwned,
+{
     let now = Instant::now();
-    let mut store_path = PathBuf::from(path.as_ref());
-    store_path.push(name);
-    if !store_path.exists() {
-        return Err(Error::Generic(format!("{:?} do not exist", store_path)));
+    let contents = match backend.load(name)? {
+        Some(contents) => contents,
+        None => return Ok(T::default()),
+    };
+    if contents.len() < 12 {
+        return Err(Error::DbCorrupted(format!("{} is truncated", name)));
     }
-    let mut file = File::open(&store_path)?;
+    let (nonce_bytes, contents) = contents.split_at(12);
+    let nonce = GenericArray::from_slice(nonce_bytes);
+    let decrypted = cipher
+        .decrypt(nonce, contents)
+        .map_err(|_| Error::DbCorrupted(format!("{} can't be decrypted", name)))?;
+    let value = serde_cbor::from_slice(&decrypted)
+        .map_err(|e| Error::DbCorrupted(format!("{} can't be deserialized: {}", name, e)))?;
+    info!("loading {} took {}ms", name, now.elapsed().as_millis());
+    Ok(value)
+}
+
+fn save_encrypted<T: serde::Serialize>(
+    name: &str,
+    value: &T,
+    backend: &dyn StoreBackend,
+    cipher: &Aes256GcmSiv,
+) -> Result<(), Error> {
+    let now = Instant::now();
     let mut nonce_bytes = [0u8; 12];
-    file.read_exact(&mut nonce_bytes)?;
+    thread_rng().fill(&mut nonce_bytes);
     let nonce = GenericArray::from_slice(&nonce_bytes);
-    let mut contents = vec![];
-    file.read_to_end(&mut contents)?;
-    let decrypted = cipher.decrypt(nonce, contents.as_ref())?;
-    info!("loading {:?} took {}ms", &store_path, now.elapsed().as_millis());
-    Ok(decrypted)
+    //TODO is possible to avoid allocs with writer?
+    let plaintext = serde_cbor::to_vec(value)?;
+    let ciphertext = cipher.encrypt(nonce, plaintext.as_ref())?;
+    //TODO should avoid rewriting if not changed? it involves saving plaintext (or struct hash)
+    // in the front of the file
+    let mut blob = nonce_bytes.to_vec();
+    blob.extend(ciphertext);
+    backend.save(name, &blob)?;
+    info!("flushing {} bytes on {} took {}ms", blob.len(), name, now.elapsed().as_millis());
+    Ok(())
+}
+
+/// the cipher of the blobs of the wallet with `xpub`
+fn store_cipher(xpub: &ExtendedPubKey) -> Aes256GcmSiv {
+    let mut enc_key_data = vec![];
+    enc_key_data.extend(&xpub.public_key.to_bytes());
+    enc_key_data.extend(&xpub.chain_code.to_bytes());
+    enc_key_data.extend(&xpub.network.magic().to_be_bytes());
+    let key_bytes = sha256::Hash::hash(&enc_key_data).into_inner();
+    let key = GenericArray::from_slice(&key_bytes);
+    Aes256GcmSiv::new(&key)
 }
 
 impl StoreMeta {
@@ -164,19 +326,19 @@ impl StoreMeta {
         master_blinding: Option<MasterBlindingKey>,
         id: NetworkId,
     ) -> Result<StoreMeta, Error> {
-        let mut enc_key_data = vec![];
-        enc_key_data.extend(&xpub.public_key.to_bytes());
-        enc_key_data.extend(&xpub.chain_code.to_bytes());
-        enc_key_data.extend(&xpub.network.magic().to_be_bytes());
-        let key_bytes = sha256::Hash::hash(&enc_key_data).into_inner();
-        let key = GenericArray::from_slice(&key_bytes);
-        let cipher = Aes256GcmSiv::new(&key);
-        let cache = RawCache::new(path.as_ref(), &cipher);
-        let store = RawStore::new(path.as_ref(), &cipher);
-        let path = path.as_ref().to_path_buf();
-        if !path.exists() {
-            std::fs::create_dir_all(&path)?;
-        }
+        let backend = FileBackend::new(path)?;
+        StoreMeta::with_backend(Box::new(backend), xpub, master_blinding, id)
+    }
+
+    pub fn with_backend(
+        backend: Box<dyn StoreBackend>,
+        xpub: ExtendedPubKey,
+        master_blinding: Option<MasterBlindingKey>,
+        id: NetworkId,
+    ) -> Result<StoreMeta, Error> {
+        let cipher = store_cipher(&xpub);
+        let cache = RawCache::new(backend.as_ref(), &cipher);
+        let store = RawStore::new(backend.as_ref(), &cipher)?;
         let secp = Secp256k1::new();
 
         let first_deriv = [
@@ -184,40 +346,46 @@ impl StoreMeta {
             xpub.derive_pub(&secp, &[ChildNumber::from(1)])?,
         ];
 
-        Ok(StoreMeta {
+        let mut store_meta = StoreMeta {
             cache,
             store,
             master_blinding,
             id,
             cipher,
             secp,
-            path,
+            backend,
             first_deriv,
-        })
+            script_hashes: Mutex::new(HashMap::new()),
+        };
+        store_meta.reclaim_change_reservations()?;
+
+        Ok(store_meta)
+    }
+
+    /// make the db in `backend` of the wallet with `xpub` readable again, replacing the blobs
+    /// that can't be read with empty ones. Returns what has been reset, nothing if the db was
+    /// readable. Must be called before opening the db
+    pub fn repair(backend: &dyn StoreBackend, xpub: &ExtendedPubKey) -> Result<Repair, Error> {
+        let cipher = store_cipher(xpub);
+        let mut repair = Repair::default();
+        if let Err(e) = load_decrypt::<RawCache>("cache", backend, &cipher) {
+            warn!("resetting the cache: {}", e);
+            save_encrypted("cache", &RawCache::default(), backend, &cipher)?;
+            repair.rescan_needed = true;
+        }
+        if let Err(e) = load_decrypt::<RawStore>("store", backend, &cipher) {
+            warn!("resetting the store: {}", e);
+            if let Some(corrupted) = backend.load("store")? {
+                backend.save("store.corrupted", &corrupted)?;
+            }
+            save_encrypted("store", &RawStore::default(), backend, &cipher)?;
+            repair.store_reset = true;
+        }
+        Ok(repair)
     }
 
     fn flush_serializable<T: serde::Serialize>(&self, name: &str, value: &T) -> Result<(), Error> {
-        let now = Instant::now();
-        let mut nonce_bytes = [0u8; 12];
-        thread_rng().fill(&mut nonce_bytes);
-        let nonce = GenericArray::from_slice(&nonce_bytes);
-        //TODO is possible to avoid allocs with writer?
-        let plaintext = serde_cbor::to_vec(value)?;
-        let ciphertext = self.cipher.encrypt(nonce, plaintext.as_ref())?;
-        let mut store_path = self.path.clone();
-        store_path.push(name);
-        //TODO should avoid rewriting if not changed? it involves saving plaintext (or struct hash)
-        // in the front of the file
-        let mut file = File::create(&store_path)?;
-        file.write(&nonce_bytes)?;
-        file.write(&ciphertext)?;
-        info!(
-            "flushing {} bytes on {:?} took {}ms",
-            ciphertext.len() + 16,
-            &store_path,
-            now.elapsed().as_millis()
-        );
-        Ok(())
+        save_encrypted(name, value, self.backend.as_ref(), &self.cipher)
     }
 
     fn flush_store(&self) -> Result<(), Error> {
@@ -237,22 +405,14 @@ impl StoreMeta {
     }
 
     fn read(&self, name: &str) -> Result<Option<Value>, Error> {
-        let mut path = self.path.clone();
-        path.push(name);
-        if path.exists() {
-            let file = File::open(path)?;
-            Ok(Some(serde_json::from_reader(file)?))
-        } else {
-            Ok(None)
+        match self.backend.load(name)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
         }
     }
 
     fn write(&self, name: &str, value: &Value) -> Result<(), Error> {
-        let mut path = self.path.clone();
-        path.push(name);
-        let mut file = File::create(path)?;
-        file.write(&serde_json::to_vec(value)?)?;
-        Ok(())
+        self.backend.save(name, &serde_json::to_vec(value)?)
     }
 
     pub fn read_asset_icons(&self) -> Result<Option<Value>, Error> {
@@ -275,6 +435,19 @@ impl StoreMeta {
         self.write("asset_registry", asset_registry)
     }
 
+    /// electrum scripthash of `script`: the sha256 of the script with reversed bytes, as used by
+    /// the electrum protocol to identify scripts. Hashes are computed once and kept in memory
+    pub fn script_hash(&self, script: &Script) -> Result<[u8; 32], Error> {
+        let mut script_hashes =
+            self.script_hashes.lock().map_err(|e| Error::Generic(e.to_string()))?;
+        let hash = script_hashes.entry(script.clone()).or_insert_with(|| {
+            let mut hash = sha256::Hash::hash(script.as_bytes()).into_inner();
+            hash.reverse();
+            hash
+        });
+        Ok(*hash)
+    }
+
     pub fn get_script_batch(&self, int_or_ext: u32, batch: u32) -> Result<ScriptBatch, Error> {
         let mut result = ScriptBatch::default();
         result.cached = true;
@@ -296,10 +469,10 @@ impl StoreMeta {
                     // Note we are using regtest here because we are not interested in the address, only in script construction
                     let script = match self.id {
                         NetworkId::Bitcoin(network) => {
-                            let address =
-                                Address::p2shwpkh(&second_deriv.public_key, network).unwrap();
-                            trace!("{}/{} {}", int_or_ext as u32, j, address);
-                            address.script_pubkey()
+                            let script = self.script_type().script_pubkey(&second_deriv.public_key);
+                            let address = Address::from_script(&script, network);
+                            trace!("{}/{} {:?}", int_or_ext as u32, j, address);
+                            script
                         }
                         NetworkId::Elements(network) => {
                             let params = match network {
@@ -342,6 +515,17 @@ impl StoreMeta {
         Ok(result)
     }
 
+    /// drop from the cache the txs not in the wallet history, like the previous txs downloaded
+    /// to compute fees, keeping the details of the outputs spent by wallet txs.
+    /// Returns the number of pruned txs
+    pub fn compact(&mut self) -> Result<usize, Error> {
+        let keep: HashSet<Txid> = self.cache.heights.keys().cloned().collect();
+        let pruned = self.cache.all_txs.prune(&keep, &self.cache.unblinded);
+        info!("compact pruned {} txs", pruned);
+        self.flush_cache()?;
+        Ok(pruned)
+    }
+
     pub fn get_bitcoin_tx(&self, txid: &Txid) -> Result<Transaction, Error> {
         match self.cache.all_txs.get(txid) {
             Some(BETransaction::Bitcoin(tx)) => Ok(tx.clone()),
@@ -390,10 +574,367 @@ impl StoreMeta {
         Ok(())
     }
 
+    pub fn remove_memo(&mut self, txid: &Txid) -> Result<(), Error> {
+        if self.store.memos.remove(txid).is_some() {
+            self.flush_store()?;
+        }
+        Ok(())
+    }
+
     pub fn get_memo(&self, txid: &Txid) -> Option<&String> {
         self.store.memos.get(txid)
     }
 
+    pub fn insert_output_label(&mut self, txid: Txid, vout: u32, label: &str) -> Result<(), Error> {
+        if label.is_empty() {
+            self.store.output_labels.remove(&(txid, vout));
+        } else {
+            self.store.output_labels.insert((txid, vout), label.to_string());
+        }
+        self.flush_store()?;
+        Ok(())
+    }
+
+    pub fn set_frozen(&mut self, txid: Txid, vout: u32, frozen: bool) -> Result<(), Error> {
+        if frozen {
+            self.store.frozen.insert((txid, vout));
+        } else {
+            self.store.frozen.remove(&(txid, vout));
+        }
+        self.flush_store()?;
+        Ok(())
+    }
+
+    pub fn is_frozen(&self, txid: Txid, vout: u32) -> bool {
+        self.store.frozen.contains(&(txid, vout))
+    }
+
+    pub fn get_output_label(&self, txid: &Txid, vout: u32) -> Option<&String> {
+        self.store.output_labels.get(&(*txid, vout))
+    }
+
+    /// record that internal indexes `first..first+count` are used by the signed tx `txid`
+    pub fn reserve_change_indexes(
+        &mut self,
+        txid: Txid,
+        first: u32,
+        count: u32,
+    ) -> Result<(), Error> {
+        for index in first..first + count {
+            self.store.change_reservations.insert(index, txid);
+        }
+        self.flush_store()?;
+        Ok(())
+    }
+
+    /// drop reservations whose transaction is now part of the wallet history
+    pub fn commit_change_reservations(&mut self) {
+        let heights = &self.cache.heights;
+        self.store.change_reservations.retain(|_, txid| !heights.contains_key(txid));
+    }
+
+    /// highest internal index reserved by a signed tx not yet in the wallet history
+    pub fn highest_change_reservation(&self) -> Option<u32> {
+        self.store.change_reservations.keys().max().cloned()
+    }
+
+    /// reservations whose transaction never reached the wallet history are orphaned (the
+    /// process crashed or the tx was never broadcasted), move the internal index back to the
+    /// lowest orphaned index so that those change addresses are reused instead of creating a gap.
+    /// The index never goes below the ones used by the wallet history
+    fn reclaim_change_reservations(&mut self) -> Result<(), Error> {
+        let heights = &self.cache.heights;
+        let committed = self
+            .store
+            .change_reservations
+            .iter()
+            .filter(|(_, txid)| heights.contains_key(txid))
+            .map(|(index, _)| *index)
+            .max();
+        self.commit_change_reservations();
+        let orphaned = std::mem::take(&mut self.store.change_reservations);
+        let lowest = match orphaned.keys().min() {
+            Some(lowest) => *lowest,
+            None => return Ok(()),
+        };
+        let used = committed.unwrap_or(0).max(self.cache.last_used.internal);
+        let reclaimed = lowest.saturating_sub(1).max(used);
+        if reclaimed < self.cache.indexes.internal {
+            warn!(
+                "reclaiming {} orphaned change indexes, internal index {} -> {}",
+                orphaned.len(),
+                self.cache.indexes.internal,
+                reclaimed
+            );
+            self.cache.indexes.internal = reclaimed;
+        }
+        self.flush()
+    }
+
+    /// insert in the cache the txs not already there, returns how many were skipped because
+    /// already stored
+    pub fn insert_txs(&mut self, txs: Vec<(Txid, BETransaction)>) -> usize {
+        let mut skipped = 0;
+        for (txid, tx) in txs {
+            if self.cache.all_txs.contains_key(&txid) {
+                skipped += 1;
+            } else {
+                self.cache.all_txs.insert(txid, tx);
+            }
+        }
+        skipped
+    }
+
+    /// insert in the cache the headers, replacing the ones at the same height with a different
+    /// hash, returns how many were skipped because already stored
+    pub fn insert_headers(&mut self, headers: Vec<(u32, BEBlockHeader)>) -> usize {
+        let mut skipped = 0;
+        for (height, header) in headers {
+            match self.cache.headers.get(&height) {
+                Some(stored) if stored.block_hash() == header.block_hash() => skipped += 1,
+                _ => {
+                    self.cache.headers.insert(height, header);
+                }
+            }
+        }
+        skipped
+    }
+
+    pub fn insert_extra_script(
+        &mut self,
+        script: Script,
+        path: DerivationPath,
+    ) -> Result<(), Error> {
+        self.cache.paths.insert(script.clone(), path.clone());
+        self.store.extra_scripts.insert(script, path);
+        self.flush_store()?;
+        Ok(())
+    }
+
+    pub fn extra_scripts(&self) -> &HashMap<Script, DerivationPath> {
+        &self.store.extra_scripts
+    }
+
+    pub fn insert_invoice_nonce(&mut self, script: Script, nonce: u32) -> Result<(), Error> {
+        if self.store.invoice_nonces.entry(script).or_default().insert(nonce) {
+            self.flush_store()?;
+        }
+        Ok(())
+    }
+
+    pub fn invoice_nonces(&self, script: &Script) -> Vec<u32> {
+        self.store
+            .invoice_nonces
+            .get(script)
+            .map(|n| n.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_archived(&mut self, archived: bool) -> Result<(), Error> {
+        self.store.archived = archived;
+        self.flush_store()?;
+        Ok(())
+    }
+
+    pub fn is_archived(&self) -> bool {
+        self.store.archived
+    }
+
+    pub fn set_change_on_receive_chain(&mut self, enabled: bool) -> Result<(), Error> {
+        self.store.change_on_receive_chain = enabled;
+        self.flush_store()?;
+        Ok(())
+    }
+
+    /// distance between the current internal index and the highest internal index used by a tx
+    /// in the wallet history
+    pub fn internal_gap(&self) -> Result<u32, Error> {
+        let mut highest_used = None;
+        for txid in self.cache.heights.keys() {
+            let tx = self
+                .cache
+                .all_txs
+                .get(txid)
+                .ok_or_else(fn_err(&format!("internal_gap no tx {}", txid)))?;
+            for i in 0..tx.output_len() as u32 {
+                let path = match self.cache.paths.get(&tx.output_script(i)) {
+                    Some(path) => path,
+                    None => continue,
+                };
+                if let [ChildNumber::Normal {
+                    index: 1,
+                }, ChildNumber::Normal {
+                    index,
+                }] = path.as_ref()
+                {
+                    highest_used = highest_used.max(Some(*index));
+                }
+            }
+        }
+        Ok(self.cache.indexes.internal.saturating_sub(highest_used.unwrap_or(0)))
+    }
+
+    /// scripts of the wallet derived below the `below` indexes whose history could still change
+    /// without using newer addresses: they receive outputs of unconfirmed txs, or have outputs
+    /// unspent or spent by unconfirmed txs. Incremental syncs query them beside the new batches
+    pub fn live_scripts(&self, below: &Indexes) -> Result<HashMap<Script, DerivationPath>, Error> {
+        let spent = self.spent()?;
+        let mut unconfirmed_spent = HashSet::new();
+        for (txid, _) in self.cache.heights.iter().filter(|(_, h)| h.is_none()) {
+            if let Some(tx) = self.cache.all_txs.get(txid) {
+                unconfirmed_spent.extend(tx.previous_outputs());
+            }
+        }
+        let mut result = HashMap::new();
+        for (txid, height) in self.cache.heights.iter() {
+            let tx = self
+                .cache
+                .all_txs
+                .get(txid)
+                .ok_or_else(fn_err(&format!("live_scripts no tx {}", txid)))?;
+            for vout in 0..tx.output_len() as u32 {
+                let script = tx.output_script(vout);
+                let path = match self.cache.paths.get(&script) {
+                    Some(path) => path,
+                    None => continue,
+                };
+                if !derived_below(path, below) {
+                    continue;
+                }
+                let outpoint = match tx {
+                    BETransaction::Bitcoin(_) => BEOutPoint::new_bitcoin(*txid, vout),
+                    BETransaction::Elements(_) => BEOutPoint::new_elements(*txid, vout),
+                };
+                if height.is_none()
+                    || !spent.contains(&outpoint)
+                    || unconfirmed_spent.contains(&outpoint)
+                {
+                    result.insert(script, path.clone());
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// the known heights of the txs an incremental sync doesn't query: the ones whose wallet
+    /// scripts, paid by the outputs or spent by the inputs, are all derived below the `below`
+    /// indexes and not in `live_scripts`. The history of the other txs is replaced by the one
+    /// returned by the server, so txs replaced or reorged out are dropped
+    pub fn skipped_history(
+        &self,
+        below: &Indexes,
+        live_scripts: &HashMap<Script, DerivationPath>,
+    ) -> HashMap<Txid, Option<u32>> {
+        let skipped = |script: &Script| match self.cache.paths.get(script) {
+            Some(path) => derived_below(path, below) && !live_scripts.contains_key(script),
+            None => true,
+        };
+        let mut result = HashMap::new();
+        for (txid, height) in self.cache.heights.iter() {
+            let tx = match self.cache.all_txs.get(txid) {
+                Some(tx) => tx,
+                None => continue,
+            };
+            let outputs = (0..tx.output_len() as u32).map(|vout| tx.output_script(vout));
+            let prevouts = tx.previous_outputs().into_iter().filter_map(|outpoint| {
+                let prev = self.cache.all_txs.get(&outpoint.txid())?;
+                if (outpoint.vout() as usize) < prev.output_len() {
+                    Some(prev.output_script(outpoint.vout()))
+                } else {
+                    None
+                }
+            });
+            let scripts: Vec<Script> = outputs.chain(prevouts).collect();
+            let is_wallet_tx = scripts.iter().any(|s| self.cache.paths.contains_key(s));
+            if is_wallet_tx && scripts.iter().all(skipped) {
+                result.insert(*txid, *height);
+            }
+        }
+        result
+    }
+
+    /// drop the wallet txs confirmed at or above `from_height` and the unconfirmed ones, with
+    /// their headers, unblinded values and verification status, or all the downloaded data when
+    /// `from_height` is None, so that the next sync downloads them again. Derived scripts and
+    /// the data not recoverable from the blockchain, like settings and memos, are kept. Returns
+    /// the number of txs dropped
+    pub fn clear_history_from(&mut self, from_height: Option<u32>) -> Result<usize, Error> {
+        let cache = &mut self.cache;
+        let removed: HashSet<Txid> = match from_height {
+            None => cache.all_txs.keys().cloned().collect(),
+            Some(from) => cache
+                .heights
+                .iter()
+                .filter(|(_, h)| h.map_or(true, |h| h >= from))
+                .map(|(txid, _)| *txid)
+                .collect(),
+        };
+        info!("clearing {} txs from height {:?}", removed.len(), from_height);
+        for txid in removed.iter() {
+            cache.all_txs.remove(txid);
+            cache.heights.remove(txid);
+            cache.txs_verif.remove(txid);
+            cache.first_seen.remove(txid);
+        }
+        cache.unblinded.retain(|outpoint, _| !removed.contains(&outpoint.txid));
+        let from = from_height.unwrap_or(0);
+        cache.headers.retain(|height, _| *height < from);
+        if from_height.is_none() {
+            cache.indexes = Indexes::default();
+            cache.last_used = Indexes::default();
+        }
+        self.flush()?;
+        Ok(removed.len())
+    }
+
+    /// script type of the addresses derived by the wallet, p2sh-p2wpkh unless set otherwise
+    pub fn script_type(&self) -> ScriptType {
+        self.cache.script_type.unwrap_or(ScriptType::P2shP2wpkh)
+    }
+
+    /// change the script type of the wallet addresses, p2wpkh is supported only in bitcoin.
+    /// The scripts derived so far and the wallet history are dropped, so that the next sync
+    /// scans the wallet with the scripts of the new type
+    pub fn set_script_type(&mut self, script_type: ScriptType) -> Result<(), Error> {
+        match (self.id, script_type) {
+            (_, ScriptType::P2shP2wpkh) | (NetworkId::Bitcoin(_), ScriptType::P2wpkh) => {}
+            _ => return Err(Error::Generic(format!("{} wallets are not supported", script_type))),
+        }
+        if self.script_type() == script_type {
+            return Ok(());
+        }
+        info!("changing wallet script type to {}", script_type);
+        self.cache.script_type = Some(script_type);
+        self.cache.scripts.clear();
+        self.cache.paths.clear();
+        self.cache.heights.clear();
+        self.cache.indexes = Indexes::default();
+        self.cache.last_used = Indexes::default();
+        self.flush()
+    }
+
+    /// whether `script` is of the wallet, an in memory lookup of the scripts derived by the
+    /// syncs, which extend them in the same write adding the txs using them
+    pub fn is_mine(&self, script: &Script) -> bool {
+        self.cache.paths.contains_key(script)
+    }
+
+    pub fn change_on_receive_chain(&self) -> bool {
+        self.store.change_on_receive_chain
+    }
+
+    /// whether `script` is a change script of the wallet txs spending from it: a script of the
+    /// internal chain, or any wallet script if the change is sent to the receive chain
+    pub fn is_change(&self, script: &Script) -> bool {
+        match self.cache.paths.get(script) {
+            Some(path) => {
+                self.change_on_receive_chain()
+                    || path.as_ref().first() == Some(&ChildNumber::from(1))
+            }
+            None => false,
+        }
+    }
+
     pub fn insert_settings(&mut self, settings: Option<Settings>) -> Result<(), Error> {
         self.store.settings = settings;
         self.flush_store()?;
@@ -405,31 +946,229 @@ impl StoreMeta {
     }
 }
 
+/// whether `path` is of an external or internal script derived below the `below` indexes
+fn derived_below(path: &DerivationPath, below: &Indexes) -> bool {
+    match path.as_ref() {
+        [ChildNumber::Normal {
+            index: 0,
+        }, ChildNumber::Normal {
+            index,
+        }] => *index < below.external,
+        [ChildNumber::Normal {
+            index: 1,
+        }, ChildNumber::Normal {
+            index,
+        }] => *index < below.internal,
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::store::StoreMeta;
+    use crate::store::{FileBackend, MemoryBackend, Repair, StoreBackend, StoreMeta};
+    use crate::Error;
+    use bitcoin::blockdata::constants::genesis_block;
     use bitcoin::hashes::hex::FromHex;
+    use bitcoin::hashes::{sha256, Hash};
     use bitcoin::util::bip32::ExtendedPubKey;
     use bitcoin::{Network, Txid};
+    use gdk_common::be::{BEBlockHeader, BETransaction};
     use gdk_common::NetworkId;
     use std::str::FromStr;
     use tempdir::TempDir;
 
+    fn xpub() -> ExtendedPubKey {
+        ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4YfG9CySHqKHFbaLcD7hSDyqRUtCmMKNim5fkiJtTnFeqKsRHMHSK5ddFrhqRr3Ghv1JtuWkBzikuBqKu1xCpjQ9YxoPGgqU").unwrap()
+    }
+
+    fn check_roundtrip<F: Fn() -> StoreMeta>(open: F) {
+        let txid =
+            Txid::from_hex("f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16")
+                .unwrap();
+
+        let mut store = open();
+        store.cache.heights.insert(txid, Some(1));
+        store.insert_memo(txid, "memo").unwrap();
+        drop(store);
+
+        let store = open();
+        assert_eq!(store.cache.heights.get(&txid), Some(&Some(1)));
+        assert_eq!(store.get_memo(&txid), Some(&"memo".to_string()));
+    }
+
     #[test]
     fn test_db_roundtrip() {
         let mut dir = TempDir::new("unit_test").unwrap().into_path();
         dir.push("store");
-        let xpub = ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4YfG9CySHqKHFbaLcD7hSDyqRUtCmMKNim5fkiJtTnFeqKsRHMHSK5ddFrhqRr3Ghv1JtuWkBzikuBqKu1xCpjQ9YxoPGgqU").unwrap();
-        let txid =
+        let id = NetworkId::Bitcoin(Network::Testnet);
+        check_roundtrip(|| StoreMeta::new(&dir, xpub(), None, id).unwrap());
+    }
+
+    #[test]
+    fn test_db_roundtrip_memory() {
+        let backend = MemoryBackend::default();
+        let id = NetworkId::Bitcoin(Network::Testnet);
+        check_roundtrip(|| {
+            StoreMeta::with_backend(Box::new(backend.clone()), xpub(), None, id).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_change_reservations() {
+        let mut dir = TempDir::new("unit_test").unwrap().into_path();
+        dir.push("store");
+        let id = NetworkId::Bitcoin(Network::Testnet);
+        let committed =
             Txid::from_hex("f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16")
                 .unwrap();
+        let orphaned =
+            Txid::from_hex("0437cd7f8525ceed2324359c2d0ba26006d92d856a9c20fa0241106ee5a597c9")
+                .unwrap();
+
+        let mut store = StoreMeta::new(&dir, xpub(), None, id).unwrap();
+        store.cache.indexes.internal = 4;
+        store.reserve_change_indexes(committed, 5, 1).unwrap();
+        store.cache.indexes.internal = 5;
+        store.reserve_change_indexes(orphaned, 6, 2).unwrap();
+        store.cache.indexes.internal = 7;
+        store.cache.heights.insert(committed, None);
+        drop(store); // simulate a crash after signing `orphaned`
+
+        let store = StoreMeta::new(&dir, xpub(), None, id).unwrap();
+        assert_eq!(store.cache.indexes.internal, 5);
+        assert!(store.store.change_reservations.is_empty());
+        drop(store);
+        let store = StoreMeta::new(&dir, xpub(), None, id).unwrap();
+        assert_eq!(store.cache.indexes.internal, 5, "the reclaim is persisted");
+    }
+
+    #[test]
+    fn test_change_reservations_below_used() {
+        let backend = MemoryBackend::default();
+        let id = NetworkId::Bitcoin(Network::Testnet);
+        let open = || StoreMeta::with_backend(Box::new(backend.clone()), xpub(), None, id).unwrap();
+        let broadcasted =
+            Txid::from_hex("f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16")
+                .unwrap();
+        let orphaned =
+            Txid::from_hex("0437cd7f8525ceed2324359c2d0ba26006d92d856a9c20fa0241106ee5a597c9")
+                .unwrap();
 
+        // `orphaned` reserved 5, then `broadcasted` reserved 6 and reached the history
+        let mut store = open();
+        store.reserve_change_indexes(orphaned, 5, 1).unwrap();
+        store.reserve_change_indexes(broadcasted, 6, 1).unwrap();
+        store.cache.indexes.internal = 6;
+        store.cache.heights.insert(broadcasted, None);
+        store.flush().unwrap();
+        drop(store);
+
+        let store = open();
+        assert_eq!(store.cache.indexes.internal, 6, "the change of `broadcasted` isn't reused");
+        assert!(store.store.change_reservations.is_empty());
+
+        // an orphaned reservation after the change of `broadcasted`, found by a sync
+        let mut store = open();
+        store.reserve_change_indexes(orphaned, 7, 1).unwrap();
+        store.cache.indexes.internal = 7;
+        store.cache.last_used.internal = 6;
+        store.flush().unwrap();
+        drop(store);
+        assert_eq!(open().cache.indexes.internal, 6);
+    }
+
+    #[test]
+    fn test_script_hash_cache() {
+        let backend = MemoryBackend::default();
+        let id = NetworkId::Bitcoin(Network::Testnet);
+        let store = StoreMeta::with_backend(Box::new(backend), xpub(), None, id).unwrap();
+        let scripts: Vec<_> =
+            store.get_script_batch(0, 0).unwrap().value.into_iter().map(|(s, _)| s).collect();
+
+        for script in scripts.iter() {
+            let mut expected = sha256::Hash::hash(script.as_bytes()).into_inner();
+            expected.reverse();
+            assert_eq!(store.script_hash(script).unwrap(), expected);
+            assert_eq!(store.script_hash(script).unwrap(), expected, "cached value");
+        }
+        assert_eq!(store.script_hashes.lock().unwrap().len(), scripts.len());
+    }
+
+    #[test]
+    fn test_insert_skips_unchanged() {
+        let backend = MemoryBackend::default();
         let id = NetworkId::Bitcoin(Network::Testnet);
-        let mut store = StoreMeta::new(&dir, xpub, None, id);
-        store.heights.insert(txid, Some(1));
+        let mut store = StoreMeta::with_backend(Box::new(backend), xpub(), None, id).unwrap();
+        let genesis = genesis_block(Network::Testnet);
+        let tx = BETransaction::Bitcoin(genesis.txdata[0].clone());
+        let header = BEBlockHeader::Bitcoin(genesis.header);
+        let mut other = genesis.header;
+        other.nonce += 1;
+
+        assert_eq!(store.insert_txs(vec![(tx.txid(), tx.clone())]), 0);
+        assert_eq!(store.insert_headers(vec![(0, header.clone())]), 0);
+
+        // a second sync finding the same data doesn't change the store
+        assert_eq!(store.insert_txs(vec![(tx.txid(), tx)]), 1);
+        assert_eq!(store.insert_headers(vec![(0, header)]), 1);
+        assert_eq!(store.cache.all_txs.len(), 1);
+
+        // a different header at the same height is replaced
+        assert_eq!(store.insert_headers(vec![(0, BEBlockHeader::Bitcoin(other))]), 0);
+        assert_eq!(store.cache.headers.get(&0).unwrap().block_hash(), other.block_hash());
+    }
+
+    #[test]
+    fn test_db_corrupted() {
+        let mut dir = TempDir::new("unit_test").unwrap().into_path();
+        dir.push("store");
+        let id = NetworkId::Bitcoin(Network::Testnet);
+        let open = || StoreMeta::new(&dir, xpub(), None, id);
+        let txid =
+            Txid::from_hex("f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16")
+                .unwrap();
+        let mut store = open().unwrap();
+        store.cache.heights.insert(txid, Some(1));
+        store.insert_memo(txid, "memo").unwrap();
+        drop(store);
+
+        // an unreadable cache is rebuilt by the sync, an unreadable store is reported
+        let garbage = vec![7u8; 100];
+        std::fs::write(dir.join("cache"), &garbage).unwrap();
+        let store = open().unwrap();
+        assert!(store.cache.heights.is_empty());
+        assert_eq!(store.get_memo(&txid), Some(&"memo".to_string()));
+        drop(store);
+        for bad in [&garbage[..], &garbage[..5], &[][..]].iter() {
+            std::fs::write(dir.join("store"), bad).unwrap();
+            assert!(matches!(open(), Err(Error::DbCorrupted(_))));
+        }
+
+        let backend = FileBackend::new(&dir).unwrap();
+        std::fs::write(dir.join("cache"), &garbage).unwrap();
+        let repair = StoreMeta::repair(&backend, &xpub()).unwrap();
+        assert_eq!(
+            repair,
+            Repair {
+                rescan_needed: true,
+                store_reset: true,
+            }
+        );
+        assert!(backend.load("store.corrupted").unwrap().is_some());
+        let store = open().unwrap();
+        assert_eq!(store.get_memo(&txid), None);
         drop(store);
+        assert_eq!(StoreMeta::repair(&backend, &xpub()).unwrap(), Repair::default());
 
-        let store = StoreMeta::new(&dir, xpub, None, id);
-        assert_eq!(store.heights.get(&txid), Some(Some(&1)));
+        // a store of a different wallet can't be decrypted
+        let other = MemoryBackend::default();
+        let xprv = bitcoin::util::bip32::ExtendedPrivKey::new_master(Network::Testnet, &[1u8; 32])
+            .unwrap();
+        let other_xpub = ExtendedPubKey::from_private(&bitcoin::secp256k1::Secp256k1::new(), &xprv);
+        drop(StoreMeta::with_backend(Box::new(other.clone()), other_xpub, None, id).unwrap());
+        assert!(matches!(
+            StoreMeta::with_backend(Box::new(other), xpub(), None, id),
+            Err(Error::DbCorrupted(_))
+        ));
     }
 }
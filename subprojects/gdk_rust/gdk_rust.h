@@ -43,6 +43,8 @@ GDK_API int GDKRUST_call_session(struct GDKRUST_session* session, const char *me
 
 GDK_API int GDKRUST_spv_verify_tx(const GDKRUST_json* json);
 
+GDK_API int GDKRUST_spv_verify_tx_details(const GDKRUST_json* json, GDKRUST_json** output);
+
 #ifndef SWIG
 /**
  * Set a handler to be called when notifications arrive.
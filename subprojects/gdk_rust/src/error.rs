@@ -20,13 +20,16 @@ impl Error {
         // id_send_all_requires_a_single_output
 
         match *self {
-            Error::Electrum(electrum::error::Error::InsufficientFunds) => {
+            Error::Electrum(electrum::error::Error::InsufficientFunds)
+            | Error::Electrum(electrum::error::Error::EmptyWallet) => {
                 "id_insufficient_funds".to_string()
             }
-            Error::Electrum(electrum::error::Error::InvalidAddress) => {
+            Error::Electrum(electrum::error::Error::InvalidAddress)
+            | Error::Electrum(electrum::error::Error::WrongNetwork(_)) => {
                 "id_invalid_address".to_string()
             }
-            Error::Electrum(electrum::error::Error::InvalidAmount) => {
+            Error::Electrum(electrum::error::Error::InvalidAmount)
+            | Error::Electrum(electrum::error::Error::BelowDustLimit(_, _)) => {
                 "id_invalid_amount".to_string()
             }
             Error::Electrum(electrum::error::Error::PinError) => "id_invalid_pin".to_string(),
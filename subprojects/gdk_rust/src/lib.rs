@@ -368,6 +368,7 @@ where
         "get_transaction_details" => get_transaction_details(session, input),
         "get_balance" => serialize::get_balance(session, input),
         "set_transaction_memo" => set_transaction_memo(session, input),
+        "set_output_label" => set_output_label(session, input),
         "create_transaction" => serialize::create_transaction(session, input),
         "sign_transaction" => session
             .sign_transaction(&serde_json::from_value(input.clone())?)
@@ -481,6 +482,23 @@ pub extern "C" fn GDKRUST_spv_verify_tx(input: *const GDKRUST_json) -> i32 {
     }
 }
 
+/// like `GDKRUST_spv_verify_tx`, but `output` is set to the result with the reason of the
+/// failure, e.g. `{"spv_verified":"not_verified","spv_failure":"merkle_root_mismatch"}`
+#[no_mangle]
+pub extern "C" fn GDKRUST_spv_verify_tx_details(
+    input: *const GDKRUST_json,
+    output: *mut *const GDKRUST_json,
+) -> i32 {
+    init_logging();
+    info!("GDKRUST_spv_verify_tx_details");
+    let input: &Value = &safe_ref!(input).0;
+    let input: SPVVerifyTx = tryit!(serde_json::from_value(input.clone()));
+    let res = tryit!(gdk_electrum::headers::spv_verify_tx(&input));
+    let failure = res.failure().map(|reason| reason.to_string());
+    let details = json!({"spv_verified": res.to_string(), "spv_failure": failure});
+    json_res!(output, details, GA_OK)
+}
+
 #[cfg(not(feature = "android_log"))]
 static LOGGER: SimpleLogger = SimpleLogger;
 
@@ -75,6 +75,7 @@ pub fn txitem_value(tx: &TxListItem) -> Value {
         "transaction_weight" : tx.transaction_weight,
 
         "spv_verified" : tx.spv_verified,
+        "spv_failure" : tx.spv_failure,
     })
 }
 
@@ -216,6 +217,26 @@ where
     session.set_transaction_memo(txid, memo, memo_type as u32).map(|v| json!(v)).map_err(Into::into)
 }
 
+pub fn set_output_label<S, E>(session: &S, input: &Value) -> Result<Value, Error>
+where
+    E: Into<Error>,
+    S: Session<E>,
+{
+    let txid = input["txid"]
+        .as_str()
+        .ok_or_else(|| Error::Other("set_output_label: missing txid".into()))?;
+
+    let vout = input["vout"]
+        .as_u64()
+        .ok_or_else(|| Error::Other("set_output_label: missing vout".into()))?;
+
+    let label = input["label"]
+        .as_str()
+        .ok_or_else(|| Error::Other("set_output_label: missing label".into()))?;
+
+    session.set_output_label(txid, vout as u32, label).map(|v| json!(v)).map_err(Into::into)
+}
+
 pub fn get_balance<S, E>(session: &S, input: &Value) -> Result<Value, Error>
 where
     E: Into<Error>,
@@ -5,6 +5,8 @@ mod test_session;
 
 static MEMO1: &str = "hello memo";
 static MEMO2: &str = "hello memo2";
+static LABEL1: &str = "refund to Alice";
+static LABEL2: &str = "refund to Bob";
 
 #[test]
 fn bitcoin() {
@@ -24,23 +26,33 @@ fn bitcoin() {
     test_session.get_subaccount();
     let txid = test_session.send_tx(&node_address, 10_000, None, Some(MEMO1.to_string())); // p2shwpkh
     test_session.test_set_get_memo(&txid, MEMO1, MEMO2);
+    test_session.test_set_get_output_label(&txid, None, LABEL1);
     test_session.is_verified(&txid, SPVVerifyResult::InProgress);
     test_session.send_tx(&node_bech32_address, 10_000, None, None); // p2wpkh
     test_session.send_tx(&node_legacy_address, 10_000, None, None); // p2pkh
     test_session.send_all(&node_legacy_address, None);
     test_session.mine_block();
     test_session.send_tx_same_script();
+    test_session.sync_cancel();
+    test_session.send_tx_version(1);
+    test_session.sync_incremental();
     test_session.fund(100_000_000, None);
     test_session.send_multi(3, 100_000, vec![]);
     test_session.send_multi(30, 100_000, vec![]);
     test_session.mine_block();
+    test_session.sync_streaming();
     test_session.send_fails();
     test_session.fees();
     test_session.settings();
     test_session.is_verified(&txid, SPVVerifyResult::Verified);
     test_session.reconnect();
     test_session.spv_verify_tx(&txid, 102);
+    test_session.spv_verify_unconfirmed();
     test_session.test_set_get_memo(&txid, MEMO2, ""); // after reconnect memo has been reloaded from disk
+    test_session.test_set_get_output_label(&txid, Some(LABEL1), LABEL2);
+    test_session.coinbase_maturity();
+    test_session.gap_limit();
+    test_session.rescan();
 
     test_session.stop();
 }
@@ -65,6 +77,7 @@ fn liquid() {
     test_session.get_subaccount();
     let txid = test_session.send_tx(&node_address, 10_000, None, Some(MEMO1.to_string()));
     test_session.test_set_get_memo(&txid, MEMO1, MEMO2);
+    test_session.test_set_get_output_label(&txid, None, LABEL1);
     test_session.is_verified(&txid, SPVVerifyResult::InProgress);
     test_session.send_tx(&node_bech32_address, 10_000, None, None);
     test_session.send_tx(&node_legacy_address, 10_000, None, None);
@@ -74,16 +87,23 @@ fn liquid() {
     test_session.send_all(&node_address, test_session.asset_tag());
     test_session.mine_block();
     let assets = test_session.fund(100_000_000, Some(3));
+    test_session.send_tx_version(1);
+    test_session.create_pegout(50_000);
     test_session.send_multi(3, 100_000, vec![]);
     test_session.send_multi(30, 100_000, assets);
     test_session.mine_block();
+    test_session.sync_streaming();
     test_session.send_fails();
     test_session.fees();
     test_session.settings();
     test_session.is_verified(&txid, SPVVerifyResult::Verified);
     test_session.reconnect();
     test_session.spv_verify_tx(&txid, 102);
+    test_session.spv_verify_unconfirmed();
     test_session.test_set_get_memo(&txid, MEMO2, "");
+    test_session.test_set_get_output_label(&txid, Some(LABEL1), LABEL2);
+    test_session.gap_limit();
+    test_session.rescan();
 
     test_session.stop();
 }
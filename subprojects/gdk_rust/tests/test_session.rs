@@ -10,17 +10,20 @@ use gdk_common::session::Session;
 use gdk_common::Network;
 use gdk_common::{ElementsNetwork, NetworkId};
 use gdk_electrum::error::Error;
-use gdk_electrum::{determine_electrum_url_from_net, ElectrumSession};
+use gdk_electrum::retry::RetryClient;
+use gdk_electrum::store::{MemoryBackend, StoreMeta, BATCH_SIZE};
+use gdk_electrum::{determine_electrum_url_from_net, ElectrumSession, NativeNotif, Syncer};
 use log::LevelFilter;
 use log::{info, warn, Metadata, Record};
 use serde_json::Value;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::net::TcpStream;
 use std::process::Child;
 use std::process::Command;
 use std::str::FromStr;
-use std::sync::Once;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Once, RwLock};
 use std::thread;
 use std::time::Duration;
 use tempdir::TempDir;
@@ -384,13 +387,14 @@ impl TestSession {
         };
         let signed_tx = self.session.sign_transaction(&tx).unwrap();
         self.check_fee_rate(fee_rate, &signed_tx, MAX_FEE_PERCENT_DIFF);
+        self.verify_signed_tx(&signed_tx);
         let txid = self.session.broadcast_transaction(&signed_tx.hex).unwrap();
         self.wait_tx_status_change();
 
         self.tx_checks(&signed_tx.hex);
 
         let fee = if asset.is_none() || asset == self.network.policy_asset {
-            tx.fee
+            tx.fee.unwrap()
         } else {
             0
         };
@@ -431,6 +435,24 @@ impl TestSession {
         assert_eq!(self.get_tx_from_list(txid).memo, new);
     }
 
+    /// label the first output of `txid` not belonging to the wallet and check list_tx returns it
+    pub fn test_set_get_output_label(&mut self, txid: &str, old: Option<&str>, new: &str) {
+        let vout = self.get_tx_meta(txid).outputs.iter().find(|o| !o.is_mine).unwrap().vout;
+        assert_eq!(self.get_tx_meta(txid).outputs[vout as usize].label.as_deref(), old);
+        assert!(self.session.set_output_label(txid, vout, &"a".repeat(1025)).is_err());
+        assert!(self.session.set_output_label(txid, vout, new).is_ok());
+        let outputs = self.get_tx_meta(txid).outputs;
+        assert_eq!(outputs[vout as usize].label.as_deref(), Some(new));
+        assert!(outputs.iter().filter(|o| o.vout != vout).all(|o| o.label.is_none()));
+    }
+
+    fn get_tx_meta(&self, txid: &str) -> TransactionMeta {
+        let mut opt = GetTransactionsOpt::default();
+        opt.count = 100;
+        let list = self.session.get_wallet().unwrap().list_tx(&opt).unwrap();
+        list.into_iter().find(|e| e.txid == txid).expect("tx not found in list_tx")
+    }
+
     pub fn is_verified(&mut self, txid: &str, verified: SPVVerifyResult) {
         let tx = self.get_tx_from_list(txid);
         assert_eq!(tx.spv_verified, verified.to_string());
@@ -514,9 +536,12 @@ impl TestSession {
         self.tx_checks(&signed_tx.hex);
 
         if assets.is_empty() {
-            assert_eq!(init_sat - tx.fee - recipients as u64 * amount, self.balance_gdk(None));
+            assert_eq!(
+                init_sat - tx.fee.unwrap() - recipients as u64 * amount,
+                self.balance_gdk(None)
+            );
         } else {
-            assert_eq!(init_sat - tx.fee, self.balance_gdk(None));
+            assert_eq!(init_sat - tx.fee.unwrap(), self.balance_gdk(None));
             for tag in assets {
                 let outputs_for_this_asset = tags.iter().filter(|t| t == &&tag).count() as u64;
                 assert_eq!(
@@ -572,6 +597,304 @@ impl TestSession {
         assert_eq!(2, transaction.input_len());
     }
 
+    /// send a tx with the given transaction version, check the created tx carries it
+    pub fn send_tx_version(&mut self, version: u32) {
+        let mut create_opt = CreateTransaction::default();
+        let address = self.node_getnewaddress(None);
+        create_opt.addressees.push(AddressAmount {
+            address: address.to_string(),
+            satoshi: 10_000,
+            asset_tag: self.asset_tag(),
+        });
+        create_opt.version = Some(version);
+        let tx = self.session.create_transaction(&mut create_opt).unwrap();
+        assert_eq!(tx.create_transaction.as_ref().unwrap().version, Some(version));
+        let signed_tx = self.session.sign_transaction(&tx).unwrap();
+        let transaction = BETransaction::from_hex(&signed_tx.hex, self.network_id).unwrap();
+        assert_eq!(transaction.version(), version);
+        self.session.broadcast_transaction(&signed_tx.hex).unwrap();
+        self.wait_tx_status_change();
+    }
+
+    /// create and broadcast a peg-out tx, check the peg-out output stays explicit after signing
+    pub fn create_pegout(&mut self, satoshi: u64) {
+        let init_sat = self.balance_gdk(None);
+        let script = bitcoin::Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default());
+        let address = bitcoin::Address {
+            payload: bitcoin::util::address::Payload::from_script(&script).unwrap(),
+            network: bitcoin::Network::Regtest,
+        };
+        let tx = {
+            let client = self.session.url.build_client().unwrap();
+            let wallet = self.session.get_wallet().unwrap();
+            assert!(matches!(
+                wallet.create_pegout(DUST_VALUE, &address.to_string(), &client),
+                Err(Error::InvalidAmount)
+            ));
+            wallet.create_pegout(satoshi, &address.to_string(), &client).unwrap()
+        };
+        let signed_tx = self.session.sign_transaction(&tx).unwrap();
+        let transaction = match BETransaction::from_hex(&signed_tx.hex, self.network_id).unwrap() {
+            BETransaction::Elements(tx) => tx,
+            BETransaction::Bitcoin(_) => panic!("peg-out must be an elements tx"),
+        };
+        let genesis_hash =
+            bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Regtest).block_hash();
+        let pegout = transaction
+            .output
+            .iter()
+            .find(|o| o.script_pubkey.is_op_return())
+            .expect("missing peg-out output");
+        let expected = bitcoin::blockdata::script::Builder::new()
+            .push_opcode(bitcoin::blockdata::opcodes::all::OP_RETURN)
+            .push_slice(&genesis_hash[..])
+            .push_slice(script.as_bytes())
+            .into_script()
+            .into_bytes();
+        assert_eq!(pegout.script_pubkey.as_bytes(), &expected[..]);
+        assert_eq!(pegout.value, elements::confidential::Value::Explicit(satoshi));
+        assert_eq!(pegout.nonce, elements::confidential::Nonce::Null);
+
+        let txid = self.session.broadcast_transaction(&signed_tx.hex).unwrap();
+        assert_eq!(txid, transaction.txid().to_string());
+        self.wait_tx_status_change();
+        let expected = init_sat - satoshi - tx.fee.unwrap();
+        assert_eq!(self.balance_gdk(None), expected, "gdk balance does not match");
+    }
+
+    /// a sync cancelled by the progress callback after the first page of txs returns
+    /// Error::Cancelled without touching the wallet history but keeping the downloaded txs, the
+    /// following sync downloads only the other txs and finds the history of the session wallet
+    pub fn sync_cancel(&mut self) {
+        let wallet = self.session.get_wallet().unwrap();
+        let expected: HashSet<_> =
+            wallet.store.read().unwrap().cache.heights.keys().cloned().collect();
+        // every wallet tx is downloaded in its own page, notifying the progress
+        let mut network = self.network.clone();
+        network.huge_history_threshold = Some(0);
+        network.history_page_size = Some(1);
+        let cancel = AtomicBool::new(false);
+        let context = &cancel as *const AtomicBool as *const libc::c_void;
+        let syncer = self.new_syncer(network, NativeNotif(Some((cancel_on_progress, context))));
+        let url = determine_electrum_url_from_net(&self.network).unwrap();
+        let client = RetryClient::connect(&url, &self.network).unwrap();
+
+        let result = syncer.sync(&client, Some(&cancel), true);
+        assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+        assert_eq!(syncer.stats.read().unwrap().huge_history_pages, 1);
+        let stored: HashSet<_> =
+            syncer.store.read().unwrap().cache.all_txs.keys().cloned().collect();
+        assert!(!stored.is_empty(), "the downloaded page is kept");
+        assert!(syncer.store.read().unwrap().cache.heights.is_empty());
+
+        assert!(syncer.sync(&client, None, true).unwrap());
+        let missing = expected.difference(&stored).count();
+        assert!(missing < expected.len());
+        assert_eq!(syncer.stats.read().unwrap().huge_history_pages as usize, missing);
+        let synced: HashSet<_> =
+            syncer.store.read().unwrap().cache.heights.keys().cloned().collect();
+        assert_eq!(synced, expected);
+    }
+
+    /// an incremental sync of a history spanning several batches of scripts queries only the
+    /// batch of the last used index and the following one of each chain, while a full sync
+    /// queries every batch
+    pub fn sync_incremental(&mut self) {
+        let init_sat = self.balance_gdk(None);
+        let count = 10 * BATCH_SIZE;
+        for _ in 0..count {
+            let ap = self.session.get_receive_address(&Value::Null).unwrap();
+            self.node_sendtoaddress(&ap.address, 10_000, None);
+        }
+        let expected = init_sat + count as u64 * 10_000;
+        for _ in 0..120 {
+            if self.balance_gdk(None) == expected {
+                break;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+        assert_eq!(self.balance_gdk(None), expected);
+        // spend and confirm everything, so that no script before the last used batches is live
+        let node_address = self.node_getnewaddress(None);
+        self.send_all(&node_address, None);
+        self.mine_block();
+
+        let syncer = self.new_syncer(self.network.clone(), NativeNotif(None));
+        let url = determine_electrum_url_from_net(&self.network).unwrap();
+        let client = RetryClient::connect(&url, &self.network).unwrap();
+        assert!(syncer.sync(&client, None, true).unwrap());
+        let full_requests = syncer.stats.read().unwrap().history_requests;
+        assert!(full_requests > count / BATCH_SIZE, "every external batch is queried");
+        let synced = syncer.store.read().unwrap().cache.heights.clone();
+
+        assert!(!syncer.sync(&client, None, false).unwrap(), "nothing left to sync");
+        let incremental_requests = syncer.stats.read().unwrap().history_requests;
+        assert_eq!(
+            incremental_requests, 4,
+            "2 batches for each chain, {} in the full sync",
+            full_requests
+        );
+        assert_eq!(syncer.store.read().unwrap().cache.heights, synced);
+    }
+
+    /// a syncer of the session wallet over an empty in memory store
+    fn new_syncer(&self, network: Network, notify: NativeNotif) -> Syncer {
+        let wallet = self.session.get_wallet().unwrap();
+        let master_blinding = wallet.master_blinding.clone();
+        let backend = Box::new(MemoryBackend::default());
+        let store =
+            StoreMeta::with_backend(backend, wallet.xpub, master_blinding.clone(), self.network_id)
+                .unwrap();
+        Syncer {
+            store: Arc::new(RwLock::new(store)),
+            master_blinding,
+            network,
+            stats: Default::default(),
+            notify,
+        }
+    }
+
+    /// funds received after a gap of unused addresses wider than the default gap limit are found
+    /// only when syncing with a greater `gap_limit`
+    pub fn gap_limit(&mut self) {
+        let (xpub, master_blinding) = {
+            let wallet = self.session.get_wallet().unwrap();
+            (wallet.xpub, wallet.master_blinding.clone())
+        };
+        let (network, network_id) = (self.network.clone(), self.network_id);
+        let new_syncer = |gap_limit| {
+            let backend = Box::new(MemoryBackend::default());
+            let store = StoreMeta::with_backend(backend, xpub, master_blinding.clone(), network_id)
+                .unwrap();
+            let mut network = network.clone();
+            network.gap_limit = gap_limit;
+            Syncer {
+                store: Arc::new(RwLock::new(store)),
+                master_blinding: master_blinding.clone(),
+                network,
+                stats: Default::default(),
+                notify: NativeNotif(None),
+            }
+        };
+        let url = determine_electrum_url_from_net(&self.network).unwrap();
+        let client = RetryClient::connect(&url, &self.network).unwrap();
+
+        let syncer = new_syncer(None);
+        syncer.sync(&client, None, true).unwrap();
+        let last_used = syncer.store.read().unwrap().cache.indexes.external;
+        let address = {
+            let wallet = self.session.get_wallet().unwrap();
+            let mut address = wallet.get_address(None).unwrap();
+            while address.pointer < last_used + 41 {
+                address = wallet.get_address(None).unwrap();
+            }
+            address
+        };
+        let txid = self.node_sendtoaddress(&address.address, 10_000, None);
+        let txid = bitcoin::Txid::from_str(&txid).unwrap();
+        self.mine_block();
+
+        let narrow = new_syncer(None);
+        narrow.sync(&client, None, true).unwrap();
+        assert!(!narrow.store.read().unwrap().cache.heights.contains_key(&txid));
+
+        let wide = new_syncer(Some(50));
+        wide.sync(&client, None, true).unwrap();
+        let store = wide.store.read().unwrap();
+        assert!(store.cache.heights.contains_key(&txid));
+        assert_eq!(store.cache.indexes.external, address.pointer);
+    }
+
+    /// a tx corrupted in the store is downloaded again by a rescan, preserving the memos
+    pub fn rescan(&mut self) {
+        let balance = self.balance_gdk(None);
+        let wallet = self.session.get_wallet().unwrap();
+        let (txid, other) = {
+            let mut store = wallet.store.write().unwrap();
+            let mut txids = store.cache.heights.keys().cloned();
+            let (txid, other) = (txids.next().unwrap(), txids.next().unwrap());
+            store.insert_memo(txid, "rescanned").unwrap();
+            let wrong = store.cache.all_txs.get(&other).unwrap().clone();
+            store.cache.all_txs.insert(txid, wrong);
+            (txid, other)
+        };
+        assert_ne!(txid, other);
+        let url = determine_electrum_url_from_net(&self.network).unwrap();
+        let client = RetryClient::connect(&url, &self.network).unwrap();
+
+        wallet.rescan(&client, None).unwrap();
+        let store = wallet.store.read().unwrap();
+        assert_eq!(store.cache.all_txs.get(&txid).unwrap().txid(), txid);
+        assert_eq!(store.get_memo(&txid).map(|m| m.as_str()), Some("rescanned"));
+        drop(store);
+        assert_eq!(self.balance_gdk(None), balance);
+    }
+
+    /// a streaming sync from scratch reaches the same store state of a bulk sync
+    pub fn sync_streaming(&mut self) {
+        let wallet = self.session.get_wallet().unwrap();
+        let new_syncer = || {
+            let backend = Box::new(MemoryBackend::default());
+            let master_blinding = wallet.master_blinding.clone();
+            let store = StoreMeta::with_backend(
+                backend,
+                wallet.xpub,
+                master_blinding.clone(),
+                self.network_id,
+            )
+            .unwrap();
+            Syncer {
+                store: Arc::new(RwLock::new(store)),
+                master_blinding,
+                network: self.network.clone(),
+                stats: Default::default(),
+                notify: NativeNotif(None),
+            }
+        };
+        let url = determine_electrum_url_from_net(&self.network).unwrap();
+        let client = RetryClient::connect(&url, &self.network).unwrap();
+
+        let bulk = new_syncer();
+        assert!(bulk.sync(&client, None, true).unwrap());
+        let streaming = new_syncer();
+        assert!(streaming.sync_streaming(&client, None).unwrap());
+        assert!(!streaming.sync_streaming(&client, None).unwrap(), "nothing left to sync");
+
+        let bulk = bulk.store.read().unwrap();
+        let streaming = streaming.store.read().unwrap();
+        assert!(!bulk.cache.heights.is_empty());
+        assert_eq!(bulk.cache.heights, streaming.cache.heights);
+        assert_eq!(bulk.cache.indexes, streaming.cache.indexes);
+        fn keys<K: Clone + Eq + std::hash::Hash, V>(m: &HashMap<K, V>) -> HashSet<K> {
+            m.keys().cloned().collect()
+        }
+        assert_eq!(keys(&*bulk.cache.all_txs), keys(&*streaming.cache.all_txs));
+        assert_eq!(keys(&bulk.cache.unblinded), keys(&streaming.cache.unblinded));
+        assert_eq!(keys(&bulk.cache.paths), keys(&streaming.cache.paths));
+        assert_eq!(keys(&bulk.cache.headers), keys(&streaming.cache.headers));
+    }
+
+    /// mine a block paying the coinbase to the wallet and check the confirmations it still
+    /// needs to be spendable decrease as blocks are mined
+    pub fn coinbase_maturity(&mut self) {
+        let ap = self.session.get_receive_address(&Value::Null).unwrap();
+        self.node.call::<Value>("generatetoaddress", &[1.into(), ap.address.into()]).unwrap();
+        self.wait_block_status_change();
+        self.wait_tx_status_change();
+
+        let coinbase_remaining = |test_session: &TestSession| {
+            let wallet = test_session.session.get_wallet().unwrap();
+            wallet
+                .all_utxos()
+                .unwrap()
+                .iter()
+                .find_map(|(outpoint, _)| wallet.maturity_remaining(outpoint).unwrap())
+        };
+        assert_eq!(coinbase_remaining(self), Some(99));
+        self.mine_block();
+        assert_eq!(coinbase_remaining(self), Some(98));
+    }
+
     /// check send failure reasons
     pub fn send_fails(&mut self) {
         let init_sat = self.balance_gdk(None);
@@ -592,7 +915,7 @@ impl TestSession {
         create_opt.addressees[0].satoshi = 200; // below dust limit
         assert!(matches!(
             self.session.create_transaction(&mut create_opt),
-            Err(Error::InvalidAmount)
+            Err(Error::BelowDustLimit(200, _))
         ));
 
         create_opt.addressees[0].satoshi = init_sat; // not enough to pay the fee
@@ -608,6 +931,13 @@ impl TestSession {
         ));
         create_opt.subaccount = None;
 
+        create_opt.version = Some(3);
+        assert!(matches!(
+            self.session.create_transaction(&mut create_opt),
+            Err(Error::InvalidVersion(3))
+        ));
+        create_opt.version = None;
+
         create_opt.previous_transaction.insert("txhash".into(), "something".into());
         assert!(matches!(self.session.create_transaction(&mut create_opt), Err(Error::Generic(_))));
         create_opt.previous_transaction.clear();
@@ -620,7 +950,7 @@ impl TestSession {
 
         create_opt.addressees[0].address = "38CMdevthTKYAtxaSkYYtcv5QgkHXdKKk5".to_string(); //
         assert!(
-            matches!(self.session.create_transaction(&mut create_opt), Err(Error::InvalidAddress)),
+            matches!(self.session.create_transaction(&mut create_opt), Err(Error::WrongNetwork(0))),
             "address with different network should fail"
         );
 
@@ -628,19 +958,23 @@ impl TestSession {
             "VJLCbLBTCdxhWyjVLdjcSmGAksVMtabYg15maSi93zknQD2ihC38R7CUd8KbDFnV8A4hiykxnRB3Uv6d"
                 .to_string();
         assert!(
-            matches!(self.session.create_transaction(&mut create_opt), Err(Error::InvalidAddress)),
+            matches!(self.session.create_transaction(&mut create_opt), Err(Error::WrongNetwork(0))),
             "address with different network should fail"
         );
 
-        let addr =
-            "Azpt6vXqrbPuUtsumAioGjKnvukPApDssC1HwoFdSWZaBYJrUVSe5K8x9nk2HVYiYANy9mVQbW3iQ6xU";
-        let mut addr = elements::Address::from_str(addr).unwrap();
-        addr.blinding_pubkey = None;
-        create_opt.addressees[0].address = addr.to_string();
-        assert!(
-            matches!(self.session.create_transaction(&mut create_opt), Err(Error::InvalidAddress)),
-            "unblinded address should fail"
-        );
+        if self.network.liquid {
+            // an elementsregtest address, so it fails for missing the blinding key only
+            let mut addr = elements::Address::from_str(&address).unwrap();
+            addr.blinding_pubkey = None;
+            create_opt.addressees[0].address = addr.to_string();
+            assert!(
+                matches!(
+                    self.session.create_transaction(&mut create_opt),
+                    Err(Error::InvalidAddress)
+                ),
+                "unblinded address should fail"
+            );
+        }
 
         create_opt.addressees.clear();
         assert!(matches!(
@@ -649,6 +983,28 @@ impl TestSession {
         ));
     }
 
+    /// check the signed tx passes wallet verification while a tampered witness fails
+    pub fn verify_signed_tx(&self, signed_tx: &TransactionMeta) {
+        let wallet = self.session.get_wallet().unwrap();
+        wallet.verify_signed_tx(signed_tx).unwrap();
+        let transaction = BETransaction::from_hex(&signed_tx.hex, self.network_id).unwrap();
+        assert_eq!(signed_tx.signed_inputs.len(), transaction.input_len());
+
+        let mut tampered = BETransaction::from_hex(&signed_tx.hex, self.network_id).unwrap();
+        let signature = match &mut tampered {
+            BETransaction::Bitcoin(tx) => &mut tx.input[0].witness[0],
+            BETransaction::Elements(tx) => &mut tx.input[0].witness.script_witness[0],
+        };
+        let len = signature.len();
+        signature[len - 2] ^= 1; // last byte is the sighash type
+        let mut tampered_meta = signed_tx.clone();
+        tampered_meta.hex = hex::encode(tampered.serialize());
+        assert!(matches!(
+            wallet.verify_signed_tx(&tampered_meta),
+            Err(Error::InvalidSignature(0, _))
+        ));
+    }
+
     /// performs checks on transactions, like checking for address reuse in outputs and on liquid confidential commitments inequality
     pub fn tx_checks(&self, hex: &str) {
         match self.network_id {
@@ -746,7 +1102,7 @@ impl TestSession {
 
     pub fn check_fee_rate(&self, req_rate: u64, tx_meta: &TransactionMeta, max_perc_diff: f64) {
         let transaction = BETransaction::from_hex(&tx_meta.hex, self.network_id).unwrap();
-        let real_rate = tx_meta.fee as f64 / (transaction.get_weight() as f64 / 4.0);
+        let real_rate = tx_meta.fee.unwrap() as f64 / (transaction.get_weight() as f64 / 4.0);
         let req_rate = req_rate as f64 / 1000.0;
         assert!(
             ((real_rate - req_rate).abs() / real_rate) < max_perc_diff,
@@ -754,7 +1110,7 @@ impl TestSession {
         ); // percentage difference between fee rate requested vs real fee
         let relay_fee = self.node.get_network_info().unwrap().relay_fee.as_sat() as f64 / 1000.0;
         assert!(
-            real_rate > relay_fee,
+            real_rate >= relay_fee,
             format!("fee rate:{} is under relay_fee:{}", real_rate, relay_fee)
         );
     }
@@ -846,6 +1202,7 @@ impl TestSession {
             tor_proxy: None,
             encryption_key: "".into(),
             headers_to_download: Some(1), // TODO increase to 100 when electrs 2f8759e940a3fe56002d653c29a480ed3bffa416 goes in prod
+            headers_checkpoint_interval: None,
         };
         loop {
             match gdk_electrum::headers::spv_verify_tx(&param) {
@@ -862,6 +1219,29 @@ impl TestSession {
         ));
     }
 
+    /// a tx in the mempool (height 0) is reported as unconfirmed without looking for a proof
+    pub fn spv_verify_unconfirmed(&mut self) {
+        let ap = self.session.get_receive_address(&Value::Null).unwrap();
+        let txid = self.node_sendtoaddress(&ap.address, 10_000, None);
+        self.wait_tx_status_change();
+
+        let temp_dir = TempDir::new("electrum_integration_tests").unwrap();
+        let param = SPVVerifyTx {
+            txid,
+            height: 0,
+            path: format!("{}", &temp_dir.path().display()),
+            network: self.network.clone(),
+            tor_proxy: None,
+            encryption_key: "".into(),
+            headers_to_download: Some(1),
+            headers_checkpoint_interval: None,
+        };
+        assert!(matches!(
+            gdk_electrum::headers::spv_verify_tx(&param),
+            Ok(SPVVerifyResult::Unconfirmed)
+        ));
+    }
+
     /// stop the bitcoin node in the test session
     pub fn stop(&mut self) {
         self.session.disconnect().unwrap();
@@ -928,3 +1308,12 @@ fn to_unconfidential(elements_address: String) -> String {
     address_unconf.blinding_pubkey = None;
     address_unconf.to_string()
 }
+
+/// notification handler setting the `AtomicBool` pointed by `context` at the first sync progress
+extern "C" fn cancel_on_progress(context: *const libc::c_void, json: *const GDKRUST_json) {
+    let json = unsafe { Box::from_raw(json as *mut GDKRUST_json) };
+    if json.0["event"] == "sync_progress" {
+        let cancel = unsafe { &*(context as *const AtomicBool) };
+        cancel.store(true, Ordering::SeqCst);
+    }
+}